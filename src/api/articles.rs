@@ -0,0 +1,246 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use gutp_types::{GutpPost, GutpTag};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use super::comments::Comment;
+use super::{require_login, ApiJson, ApiQuery, ApiResult, CommentPage, Page, PageParams};
+use crate::article::{self, ArticleInput};
+use crate::error::ForumError;
+use crate::gutp::Paging;
+use crate::pagination::{Pager, PAGE_SIZE};
+use crate::permission;
+use crate::slug;
+use crate::tag;
+use crate::AppState;
+use crate::LoggedUser;
+
+#[derive(Serialize, ToSchema)]
+pub struct Article {
+    id: String,
+    title: String,
+    /// markdown
+    content: String,
+    author_id: String,
+    author_nickname: String,
+    subspace_id: String,
+    extlink: String,
+    is_public: bool,
+    created_time: i64,
+    updated_time: i64,
+    /// the page of the article on this site
+    #[schema(example = "/a/42/hello-rust")]
+    path: String,
+}
+
+impl From<GutpPost> for Article {
+    fn from(post: GutpPost) -> Self {
+        Article {
+            path: slug::article_path(&post),
+            id: post.id,
+            title: post.title,
+            content: post.content,
+            author_id: post.author_id,
+            author_nickname: post.author_nickname,
+            subspace_id: post.subspace_id,
+            extlink: post.extlink,
+            is_public: post.is_public,
+            created_time: post.created_time,
+            updated_time: post.updated_time,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TagRef {
+    id: String,
+    caption: String,
+}
+
+impl From<GutpTag> for TagRef {
+    fn from(tag: GutpTag) -> Self {
+        TagRef {
+            id: tag.id,
+            caption: tag.caption,
+        }
+    }
+}
+
+/// An article with its tags, as on its page
+#[derive(Serialize, ToSchema)]
+pub struct ArticleDetail {
+    #[serde(flatten)]
+    article: Article,
+    tags: Vec<TagRef>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct NewArticleBody {
+    subspace_id: String,
+    title: String,
+    content: String,
+    #[serde(default)]
+    extlink: String,
+    /// the tags of the subspace to attach, the others are ignored
+    tag_ids: Option<Vec<String>>,
+}
+
+/// The fields left out stay as they are
+#[derive(Deserialize, ToSchema)]
+pub struct ArticlePatch {
+    title: Option<String>,
+    content: Option<String>,
+    extlink: Option<String>,
+    /// replaces all the tags when present
+    tag_ids: Option<Vec<String>>,
+}
+
+/// Create an article in a subspace
+#[utoipa::path(
+    post,
+    path = "/api/v1/articles",
+    tag = "articles",
+    request_body = NewArticleBody,
+    responses(
+        (status = 201, body = Article),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 404, body = ErrorBody, description = "No such subspace"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn create_article(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    ApiJson(body): ApiJson<NewArticleBody>,
+) -> ApiResult<(StatusCode, Json<Article>)> {
+    let logged_user = require_login(logged_user)?;
+
+    let input = ArticleInput {
+        title: body.title,
+        content: body.content,
+        extlink: body.extlink,
+        tag_ids: body.tag_ids,
+    };
+    let post = article::create_article(&app_state, &logged_user, &body.subspace_id, input).await?;
+    Ok((StatusCode::CREATED, Json(post.into())))
+}
+
+/// An article with its tags
+#[utoipa::path(
+    get,
+    path = "/api/v1/articles/{id}",
+    tag = "articles",
+    params(("id" = String, Path, description = "The id of the article")),
+    responses((status = 200, body = ArticleDetail), (status = 404, body = ErrorBody)),
+)]
+pub async fn view_article(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    Path(id): Path<String>,
+) -> ApiResult<Json<ArticleDetail>> {
+    let post = article::query_post(&app_state, &id).await?;
+    permission::ensure_view_post(&app_state, logged_user.as_deref(), &post).await?;
+    let tags = tag::tags_of_post(&app_state, &post).await?;
+
+    Ok(Json(ArticleDetail {
+        article: post.into(),
+        tags: tags.into_iter().map(TagRef::from).collect(),
+    }))
+}
+
+/// Edit an article, by its author or a moderator
+#[utoipa::path(
+    patch,
+    path = "/api/v1/articles/{id}",
+    tag = "articles",
+    params(("id" = String, Path, description = "The id of the article")),
+    request_body = ArticlePatch,
+    responses(
+        (status = 200, body = Article),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn edit_article(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    Path(id): Path<String>,
+    ApiJson(patch): ApiJson<ArticlePatch>,
+) -> ApiResult<Json<Article>> {
+    let logged_user = require_login(logged_user)?;
+    let post = article::query_post(&app_state, &id).await?;
+
+    let input = ArticleInput {
+        title: patch.title.unwrap_or(post.title),
+        content: patch.content.unwrap_or(post.content),
+        extlink: patch.extlink.unwrap_or(post.extlink),
+        tag_ids: patch.tag_ids,
+    };
+    let post = article::edit_article(&app_state, &logged_user, &post.id, input).await?;
+    Ok(Json(post.into()))
+}
+
+/// Delete an article without comments, by its author or a moderator
+#[utoipa::path(
+    delete,
+    path = "/api/v1/articles/{id}",
+    tag = "articles",
+    params(("id" = String, Path, description = "The id of the article")),
+    responses(
+        (status = 204),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+        (status = 409, body = ErrorBody, description = "It has comments"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn delete_article(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    Path(id): Path<String>,
+) -> ApiResult<StatusCode> {
+    let logged_user = require_login(logged_user)?;
+
+    article::delete_article(&app_state, &logged_user, &id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The comments of an article, the oldest first
+#[utoipa::path(
+    get,
+    path = "/api/v1/articles/{id}/comments",
+    tag = "articles",
+    params(("id" = String, Path, description = "The id of the article"), PageParams),
+    responses((status = 200, body = CommentPage), (status = 404, body = ErrorBody)),
+)]
+pub async fn list_article_comments(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    Path(id): Path<String>,
+    ApiQuery(params): ApiQuery<PageParams>,
+) -> ApiResult<Json<Page<Comment>>> {
+    let post = article::query_post(&app_state, &id).await?;
+    permission::ensure_view_post(&app_state, logged_user.as_deref(), &post).await?;
+
+    // one more than a page, to know whether there is a next one
+    let (page, offset) = Pager::page_offset(params.page, PAGE_SIZE);
+    let comments = app_state
+        .gutp
+        .comments()
+        .list_by_post(&post.id, Paging::new(PAGE_SIZE + 1, offset))
+        .await
+        .map_err(ForumError::gutp(format!(
+            "Query comments of article: {}",
+            post.id
+        )))?;
+    Ok(Json(Page::from_window(comments, page)))
+}
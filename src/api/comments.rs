@@ -0,0 +1,139 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use gutp_types::GutpComment;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use super::{require_login, ApiJson, ApiResult};
+use crate::comment;
+use crate::AppState;
+use crate::LoggedUser;
+
+#[derive(Serialize, ToSchema)]
+pub struct Comment {
+    id: String,
+    /// markdown
+    content: String,
+    author_id: String,
+    author_nickname: String,
+    post_id: String,
+    /// empty for a comment on the article itself
+    parent_comment_id: String,
+    created_time: i64,
+}
+
+impl From<GutpComment> for Comment {
+    fn from(comment: GutpComment) -> Self {
+        Comment {
+            id: comment.id,
+            content: comment.content,
+            author_id: comment.author_id,
+            author_nickname: comment.author_nickname,
+            post_id: comment.post_id,
+            parent_comment_id: comment.parent_comment_id,
+            created_time: comment.created_time,
+        }
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct NewCommentBody {
+    post_id: String,
+    /// the comment replied to, a comment on the article when empty
+    #[serde(default)]
+    parent_comment_id: String,
+    content: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CommentPatch {
+    content: String,
+}
+
+/// Comment on an article, or reply to one of its comments
+#[utoipa::path(
+    post,
+    path = "/api/v1/comments",
+    tag = "comments",
+    request_body = NewCommentBody,
+    responses(
+        (status = 201, body = Comment),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 404, body = ErrorBody, description = "No such article or parent comment"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn create_comment(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    ApiJson(body): ApiJson<NewCommentBody>,
+) -> ApiResult<(StatusCode, Json<Comment>)> {
+    let logged_user = require_login(logged_user)?;
+
+    let (comment, _) = comment::create_comment(
+        &app_state,
+        &logged_user,
+        &body.post_id,
+        &body.parent_comment_id,
+        body.content,
+    )
+    .await?;
+    Ok((StatusCode::CREATED, Json(comment.into())))
+}
+
+/// Edit a comment, by its author or a moderator
+#[utoipa::path(
+    patch,
+    path = "/api/v1/comments/{id}",
+    tag = "comments",
+    params(("id" = String, Path, description = "The id of the comment")),
+    request_body = CommentPatch,
+    responses(
+        (status = 200, body = Comment),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn edit_comment(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    Path(id): Path<String>,
+    ApiJson(patch): ApiJson<CommentPatch>,
+) -> ApiResult<Json<Comment>> {
+    let logged_user = require_login(logged_user)?;
+
+    let (comment, _) = comment::edit_comment(&app_state, &logged_user, &id, patch.content).await?;
+    Ok(Json(comment.into()))
+}
+
+/// Delete a comment, by its author or a moderator
+#[utoipa::path(
+    delete,
+    path = "/api/v1/comments/{id}",
+    tag = "comments",
+    params(("id" = String, Path, description = "The id of the comment")),
+    responses(
+        (status = 204),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn delete_comment(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    Path(id): Path<String>,
+) -> ApiResult<StatusCode> {
+    let logged_user = require_login(logged_user)?;
+
+    comment::delete_comment(&app_state, &logged_user, &id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
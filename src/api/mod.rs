@@ -0,0 +1,460 @@
+use axum::{
+    async_trait,
+    body::Body,
+    extract::{FromRequest, FromRequestParts, Query, State},
+    http::{header, request::Parts, HeaderMap, Request},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, patch, post},
+    Json, Router,
+};
+use redis::aio::ConnectionLike;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+
+use crate::api_token::{ApiTokenStore, BEARER_PREFIX};
+use crate::error::ForumError;
+use crate::pagination::{Pager, PAGE_SIZE};
+use crate::AppState;
+use crate::LoggedUser;
+
+mod articles;
+mod comments;
+mod subspaces;
+mod users;
+
+/// Where the api is mounted, the version changes with a breaking change
+pub const API_PREFIX: &str = "/api/v1";
+
+/// The body of every failed api request
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorBody {
+    /// the kind of failure, e.g. `not_found`
+    #[schema(example = "not_found")]
+    code: String,
+    /// what went wrong, for humans
+    #[schema(example = "Article doesn't exist!")]
+    message: String,
+    /// what was being done
+    #[schema(example = "Query article: 42")]
+    action: String,
+}
+
+/// A `ForumError` answered as json instead of a page
+#[derive(Debug)]
+pub struct ApiError(ForumError);
+
+impl From<ForumError> for ApiError {
+    fn from(err: ForumError) -> Self {
+        ApiError(err)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let err = self.0;
+        let status = err.status_code();
+        if status.is_server_error() {
+            println!("in ApiError: {} {}", status, err);
+        }
+
+        let body = ErrorBody {
+            code: err.code().to_string(),
+            message: err.err_info(),
+            action: err.action(),
+        };
+        let mut response = (status, Json(body)).into_response();
+        if let ForumError::NotLoggedIn = err {
+            response.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                header::HeaderValue::from_static("Bearer"),
+            );
+        }
+        response
+    }
+}
+
+pub type ApiResult<T> = Result<T, ApiError>;
+
+/// `Json`, with the malformed bodies answered as an `ErrorBody`
+pub struct ApiJson<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S, Body> for ApiJson<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: Request<Body>, state: &S) -> Result<Self, Self::Rejection> {
+        let action = format!("Read the json body of {}", req.uri().path());
+        match Json::<T>::from_request(req, state).await {
+            Ok(Json(value)) => Ok(ApiJson(value)),
+            Err(rejection) => Err(ApiError(ForumError::BadRequest(
+                action,
+                rejection.body_text(),
+            ))),
+        }
+    }
+}
+
+/// `Query`, with the malformed queries answered as an `ErrorBody`
+pub struct ApiQuery<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for ApiQuery<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let action = format!("Read the query of {}", parts.uri.path());
+        match Query::<T>::from_request_parts(parts, state).await {
+            Ok(Query(value)) => Ok(ApiQuery(value)),
+            Err(rejection) => Err(ApiError(ForumError::BadRequest(
+                action,
+                rejection.body_text(),
+            ))),
+        }
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct PageParams {
+    /// 1-based, the first page when absent
+    page: Option<usize>,
+}
+
+/// A page of a list, the pages have the same size as the html ones
+#[derive(Serialize, ToSchema)]
+#[aliases(
+    SubspacePage = Page<subspaces::Subspace>,
+    ArticlePage = Page<articles::Article>,
+    CommentPage = Page<comments::Comment>
+)]
+pub struct Page<T> {
+    items: Vec<T>,
+    page: usize,
+    has_next: bool,
+}
+
+impl<T> Page<T> {
+    /// `items` was fetched with `Paging::new(PAGE_SIZE + 1, offset)`
+    fn from_window<U: Into<T>>(items: Vec<U>, page: usize) -> Self {
+        let (items, pager) = Pager::from_window(items, page, PAGE_SIZE, String::new());
+        Page {
+            items: items.into_iter().map(Into::into).collect(),
+            page,
+            has_next: pager.has_next(),
+        }
+    }
+}
+
+/// Log the request in with its `Authorization: Bearer` token, in place of
+/// the session cookie. A token which doesn't work is refused, not ignored
+async fn bearer_middleware(
+    State(app_state): State<AppState>,
+    mut req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let Some(token) = bearer_token(req.headers()) else {
+        return next.run(req).await;
+    };
+
+    let logged_user = match app_state.redis.conn().await {
+        Ok(mut redis_conn) => token_user(&app_state.api_tokens, &mut redis_conn, &token).await,
+        Err(err) => Err(token_error(err)),
+    };
+    match logged_user {
+        Ok(logged_user) => {
+            req.extensions_mut().insert(logged_user);
+            next.run(req).await
+        }
+        Err(err) => err.into_response(),
+    }
+}
+
+// the token of the `Authorization: Bearer` header, if any
+fn bearer_token(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix(BEARER_PREFIX))
+        .map(|token| token.trim().to_string())
+}
+
+fn token_error(err: redis::RedisError) -> ApiError {
+    ApiError(ForumError::Internal(
+        "Verify api token".to_string(),
+        err.to_string(),
+    ))
+}
+
+// the user a token logs in, an unknown or revoked one isn't logged in
+async fn token_user<C: ConnectionLike + Send>(
+    api_tokens: &ApiTokenStore,
+    conn: &mut C,
+    token: &str,
+) -> Result<LoggedUser, ApiError> {
+    match api_tokens.load(conn, token).await {
+        // no session and no csrf token, nothing is carried by the browser
+        Ok(Some(api_token)) => Ok(LoggedUser {
+            user_id: api_token.user_id,
+            session_id: "".to_string(),
+            csrf_token: "".to_string(),
+        }),
+        Ok(None) => Err(ApiError(ForumError::NotLoggedIn)),
+        Err(err) => Err(token_error(err)),
+    }
+}
+
+/// For the api handlers which can only be accessed after login
+pub fn require_login(
+    logged_user: Option<axum::Extension<LoggedUser>>,
+) -> Result<LoggedUser, ApiError> {
+    crate::require_login(logged_user).map_err(ApiError)
+}
+
+struct BearerSecurity;
+
+impl utoipa::Modify for BearerSecurity {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Discux API",
+        description = "The operations of the forum pages, as json. \
+            Log in with an `Authorization: Bearer` token made on the account page, \
+            or with the session cookie and an `X-CSRF-Token` header."
+    ),
+    paths(
+        subspaces::list_subspaces,
+        subspaces::create_subspace,
+        subspaces::view_subspace,
+        subspaces::edit_subspace,
+        subspaces::delete_subspace,
+        subspaces::list_subspace_articles,
+        articles::create_article,
+        articles::view_article,
+        articles::edit_article,
+        articles::delete_article,
+        articles::list_article_comments,
+        comments::create_comment,
+        comments::edit_comment,
+        comments::delete_comment,
+        users::view_me,
+        users::view_user,
+    ),
+    components(schemas(
+        ErrorBody,
+        SubspacePage,
+        ArticlePage,
+        CommentPage,
+        subspaces::Subspace,
+        subspaces::NewSubspaceBody,
+        subspaces::SubspacePatch,
+        articles::Article,
+        articles::ArticleDetail,
+        articles::TagRef,
+        articles::NewArticleBody,
+        articles::ArticlePatch,
+        comments::Comment,
+        comments::NewCommentBody,
+        comments::CommentPatch,
+        users::User,
+    )),
+    modifiers(&BearerSecurity),
+    tags(
+        (name = "subspaces"),
+        (name = "articles"),
+        (name = "comments"),
+        (name = "users"),
+    )
+)]
+pub struct ApiDoc;
+
+/// `/api/v1/openapi.json`
+pub async fn view_openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+async fn api_not_found(uri: axum::http::Uri) -> ApiError {
+    ApiError(ForumError::not_found(
+        format!("Query {}", uri.path()),
+        "No this api.",
+    ))
+}
+
+/// The routes under `API_PREFIX`
+pub fn routes(app_state: AppState) -> Router<AppState> {
+    Router::new()
+        .route("/openapi.json", get(view_openapi))
+        .route(
+            "/subspaces",
+            get(subspaces::list_subspaces).post(subspaces::create_subspace),
+        )
+        .route(
+            "/subspaces/:key",
+            get(subspaces::view_subspace)
+                .patch(subspaces::edit_subspace)
+                .delete(subspaces::delete_subspace),
+        )
+        .route(
+            "/subspaces/:key/articles",
+            get(subspaces::list_subspace_articles),
+        )
+        .route("/articles", post(articles::create_article))
+        .route(
+            "/articles/:id",
+            get(articles::view_article)
+                .patch(articles::edit_article)
+                .delete(articles::delete_article),
+        )
+        .route(
+            "/articles/:id/comments",
+            get(articles::list_article_comments),
+        )
+        .route("/comments", post(comments::create_comment))
+        .route(
+            "/comments/:id",
+            patch(comments::edit_comment).delete(comments::delete_comment),
+        )
+        .route("/me", get(users::view_me))
+        .route("/users/:id", get(users::view_user))
+        .fallback(api_not_found)
+        .layer(middleware::from_fn_with_state(app_state, bearer_middleware))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_redis::FakeRedis;
+    use axum::http::StatusCode;
+
+    async fn body_of(res: Response) -> serde_json::Value {
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    fn request(authorization: Option<&str>) -> Request<Body> {
+        let mut builder = Request::get("/api/v1/me");
+        if let Some(authorization) = authorization {
+            builder = builder.header(header::AUTHORIZATION, authorization);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn error_body_has_the_code_message_and_action() {
+        let err = ForumError::not_found("Query article: 42", "Article doesn't exist!");
+        let res = ApiError(err).into_response();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            body_of(res).await,
+            serde_json::json!({
+                "code": "not_found",
+                "message": "Article doesn't exist!",
+                "action": "Query article: 42",
+            })
+        );
+
+        let err = ForumError::BadRequest("Create article".to_string(), "No title.".to_string());
+        let res = ApiError(err).into_response();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body_of(res).await["code"], "bad_request");
+    }
+
+    #[tokio::test]
+    async fn server_errors_hide_their_details() {
+        let err = ForumError::Internal(
+            "Verify api token".to_string(),
+            "connection refused".to_string(),
+        );
+        let res = ApiError(err).into_response();
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = body_of(res).await;
+        assert_eq!(body["code"], "internal");
+        assert!(!body["message"].as_str().unwrap().contains("refused"));
+    }
+
+    #[tokio::test]
+    async fn not_logged_in_asks_for_a_bearer_token() {
+        let res = ApiError(ForumError::NotLoggedIn).into_response();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(res.headers()[header::WWW_AUTHENTICATE], "Bearer");
+        assert_eq!(body_of(res).await["code"], "not_logged_in");
+    }
+
+    #[tokio::test]
+    async fn malformed_json_is_an_error_body() {
+        let req = Request::post("/api/v1/articles")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from("{\"title\":"))
+            .unwrap();
+        let Err(err) = ApiJson::<serde_json::Value>::from_request(req, &()).await else {
+            panic!("the body is malformed");
+        };
+        let res = err.into_response();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body = body_of(res).await;
+        assert_eq!(body["code"], "bad_request");
+        assert_eq!(body["action"], "Read the json body of /api/v1/articles");
+    }
+
+    #[test]
+    fn bearer_token_of_the_request() {
+        assert_eq!(bearer_token(request(None).headers()), None);
+        // another scheme is left to the other logins
+        let req = request(Some("Basic YWxpY2U6c2VjcmV0"));
+        assert_eq!(bearer_token(req.headers()), None);
+        let req = request(Some("Bearer  abc "));
+        assert_eq!(bearer_token(req.headers()).as_deref(), Some("abc"));
+    }
+
+    #[tokio::test]
+    async fn token_logs_its_user_in() {
+        let (api_tokens, mut redis) = (ApiTokenStore::new("test"), FakeRedis::default());
+        let token = api_tokens.create(&mut redis, "alice", "bot").await.unwrap();
+
+        let req = request(Some(&format!("{}{}", BEARER_PREFIX, token)));
+        let token = bearer_token(req.headers()).unwrap();
+        let logged_user = token_user(&api_tokens, &mut redis, &token).await.unwrap();
+        assert_eq!(logged_user.user_id, "alice");
+        // nothing for csrf_middleware to check
+        assert!(logged_user.session_id.is_empty());
+        assert!(logged_user.csrf_token.is_empty());
+    }
+
+    #[tokio::test]
+    async fn bad_or_revoked_tokens_are_refused() {
+        let (api_tokens, mut redis) = (ApiTokenStore::new("test"), FakeRedis::default());
+        let is_refused = |res: Result<LoggedUser, ApiError>| {
+            matches!(res, Err(ApiError(ForumError::NotLoggedIn)))
+        };
+        assert!(is_refused(
+            token_user(&api_tokens, &mut redis, "unknown").await
+        ));
+
+        let token = api_tokens.create(&mut redis, "alice", "bot").await.unwrap();
+        let tokens = api_tokens.list_by_user(&mut redis, "alice").await.unwrap();
+        api_tokens
+            .revoke(&mut redis, "alice", &tokens[0].id)
+            .await
+            .unwrap();
+        assert!(is_refused(
+            token_user(&api_tokens, &mut redis, &token).await
+        ));
+    }
+}
@@ -0,0 +1,257 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use gutp_types::GutpSubspace;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use super::articles::Article;
+use super::{require_login, ApiJson, ApiQuery, ApiResult, ArticlePage, Page, PageParams};
+use crate::error::ForumError;
+use crate::gutp::Paging;
+use crate::pagination::{Pager, PAGE_SIZE};
+use crate::permission;
+use crate::slug;
+use crate::subspace::{self, SubspaceInput};
+use crate::AppState;
+use crate::LoggedUser;
+
+#[derive(Serialize, ToSchema)]
+pub struct Subspace {
+    id: String,
+    title: String,
+    description: String,
+    /// the url of the banner image, empty without one
+    banner: String,
+    slug: String,
+    owner_id: String,
+    is_public: bool,
+    created_time: i64,
+    /// the page of the subspace on this site
+    #[schema(example = "/s/rust")]
+    path: String,
+}
+
+impl From<GutpSubspace> for Subspace {
+    fn from(sp: GutpSubspace) -> Self {
+        Subspace {
+            path: slug::subspace_path(&sp),
+            id: sp.id,
+            title: sp.title,
+            description: sp.description,
+            banner: sp.banner,
+            slug: sp.slug,
+            owner_id: sp.owner_id,
+            is_public: sp.is_public,
+            created_time: sp.created_time,
+        }
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct NewSubspaceBody {
+    title: String,
+    #[serde(default)]
+    description: String,
+    /// made from the title when empty
+    #[serde(default)]
+    slug: String,
+    #[serde(default = "default_public")]
+    is_public: bool,
+}
+
+fn default_public() -> bool {
+    true
+}
+
+/// The fields left out stay as they are
+#[derive(Deserialize, ToSchema)]
+pub struct SubspacePatch {
+    title: Option<String>,
+    description: Option<String>,
+    slug: Option<String>,
+    is_public: Option<bool>,
+    #[serde(default)]
+    remove_banner: bool,
+}
+
+// the private subspaces are missing to the ones who can't see them
+async fn query_subspace(
+    app_state: &AppState,
+    logged_user: Option<&LoggedUser>,
+    key: &str,
+) -> ApiResult<GutpSubspace> {
+    let action = format!("Query subspace: {}", key);
+    let sp = slug::find_subspace(app_state, key)
+        .await?
+        .ok_or_else(|| ForumError::not_found(&action, "No this subspace."))?;
+    permission::ensure_view_subspace(app_state, logged_user, &sp).await?;
+    Ok(sp)
+}
+
+/// The subspaces, as on the home page
+#[utoipa::path(
+    get,
+    path = "/api/v1/subspaces",
+    tag = "subspaces",
+    responses((status = 200, body = [Subspace]), (status = 502, body = ErrorBody)),
+)]
+pub async fn list_subspaces(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+) -> ApiResult<Json<Vec<Subspace>>> {
+    let subspaces = app_state
+        .gutp
+        .subspaces()
+        .list()
+        .await
+        .map_err(ForumError::gutp("Query subspaces"))?;
+    let subspaces =
+        permission::visible_subspaces(&app_state, logged_user.as_deref(), subspaces).await?;
+    Ok(Json(subspaces.into_iter().map(Subspace::from).collect()))
+}
+
+/// Create a subspace, only the admins can
+#[utoipa::path(
+    post,
+    path = "/api/v1/subspaces",
+    tag = "subspaces",
+    request_body = NewSubspaceBody,
+    responses(
+        (status = 201, body = Subspace),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 409, body = ErrorBody, description = "The slug is taken"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn create_subspace(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    ApiJson(body): ApiJson<NewSubspaceBody>,
+) -> ApiResult<(StatusCode, Json<Subspace>)> {
+    let logged_user = require_login(logged_user)?;
+
+    let input = SubspaceInput {
+        title: body.title,
+        description: body.description,
+        slug: body.slug,
+        is_public: body.is_public,
+    };
+    let sp = subspace::create_subspace(&app_state, &logged_user, input).await?;
+    Ok((StatusCode::CREATED, Json(sp.into())))
+}
+
+/// A subspace, by its slug or its id
+#[utoipa::path(
+    get,
+    path = "/api/v1/subspaces/{key}",
+    tag = "subspaces",
+    params(("key" = String, Path, description = "The slug or the id")),
+    responses((status = 200, body = Subspace), (status = 404, body = ErrorBody)),
+)]
+pub async fn view_subspace(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    Path(key): Path<String>,
+) -> ApiResult<Json<Subspace>> {
+    let sp = query_subspace(&app_state, logged_user.as_deref(), &key).await?;
+    Ok(Json(sp.into()))
+}
+
+/// Edit a subspace, by its owner or an admin
+#[utoipa::path(
+    patch,
+    path = "/api/v1/subspaces/{key}",
+    tag = "subspaces",
+    params(("key" = String, Path, description = "The slug or the id")),
+    request_body = SubspacePatch,
+    responses(
+        (status = 200, body = Subspace),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+        (status = 409, body = ErrorBody, description = "The slug is taken"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn edit_subspace(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    Path(key): Path<String>,
+    ApiJson(patch): ApiJson<SubspacePatch>,
+) -> ApiResult<Json<Subspace>> {
+    let logged_user = require_login(logged_user)?;
+    let sp = query_subspace(&app_state, Some(&logged_user), &key).await?;
+
+    let input = SubspaceInput {
+        title: patch.title.unwrap_or(sp.title),
+        description: patch.description.unwrap_or(sp.description),
+        slug: patch.slug.unwrap_or(sp.slug),
+        is_public: patch.is_public.unwrap_or(sp.is_public),
+    };
+    let sp = subspace::edit_subspace(&app_state, &logged_user, &sp.id, input, patch.remove_banner)
+        .await?;
+    Ok(Json(sp.into()))
+}
+
+/// Delete a subspace without articles, by its owner or an admin
+#[utoipa::path(
+    delete,
+    path = "/api/v1/subspaces/{key}",
+    tag = "subspaces",
+    params(("key" = String, Path, description = "The slug or the id")),
+    responses(
+        (status = 204),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+        (status = 409, body = ErrorBody, description = "It has articles"),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn delete_subspace(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    Path(key): Path<String>,
+) -> ApiResult<StatusCode> {
+    let logged_user = require_login(logged_user)?;
+    let sp = query_subspace(&app_state, Some(&logged_user), &key).await?;
+
+    subspace::delete_subspace(&app_state, &logged_user, &sp.id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The articles of a subspace, as on its page
+#[utoipa::path(
+    get,
+    path = "/api/v1/subspaces/{key}/articles",
+    tag = "subspaces",
+    params(("key" = String, Path, description = "The slug or the id"), PageParams),
+    responses((status = 200, body = ArticlePage), (status = 404, body = ErrorBody)),
+)]
+pub async fn list_subspace_articles(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    Path(key): Path<String>,
+    ApiQuery(params): ApiQuery<PageParams>,
+) -> ApiResult<Json<Page<Article>>> {
+    let sp = query_subspace(&app_state, logged_user.as_deref(), &key).await?;
+
+    // one more than a page, to know whether there is a next one
+    let (page, offset) = Pager::page_offset(params.page, PAGE_SIZE);
+    let posts = app_state
+        .gutp
+        .posts()
+        .list_by_subspace(&sp.id, Paging::new(PAGE_SIZE + 1, offset))
+        .await
+        .map_err(ForumError::gutp(format!(
+            "Query articles of subspace: {}",
+            sp.id
+        )))?;
+    Ok(Json(Page::from_window(posts, page)))
+}
@@ -0,0 +1,78 @@
+use axum::{
+    extract::{Path, State},
+    Extension, Json,
+};
+use gutp_types::GutpUser;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use super::{require_login, ApiResult};
+use crate::error::ForumError;
+use crate::profile::{self, PubSettings};
+use crate::AppState;
+use crate::LoggedUser;
+
+#[derive(Serialize, ToSchema)]
+pub struct User {
+    id: String,
+    nickname: String,
+    avatar: String,
+    bio: String,
+    signup_time: i64,
+}
+
+impl From<GutpUser> for User {
+    fn from(user: GutpUser) -> Self {
+        let settings = PubSettings::of(&user);
+        User {
+            id: user.id,
+            nickname: user.nickname,
+            avatar: user.avatar,
+            bio: settings.bio,
+            signup_time: user.signup_time,
+        }
+    }
+}
+
+/// The logged user, to check a token works
+#[utoipa::path(
+    get,
+    path = "/api/v1/me",
+    tag = "users",
+    responses((status = 200, body = User), (status = 401, body = ErrorBody)),
+    security(("bearer" = [])),
+)]
+pub async fn view_me(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+) -> ApiResult<Json<User>> {
+    let logged_user = require_login(logged_user)?;
+
+    let user = profile::query_user(&app_state, &logged_user.user_id).await?;
+    Ok(Json(user.into()))
+}
+
+/// A user, the private profiles are only shown after login
+#[utoipa::path(
+    get,
+    path = "/api/v1/users/{id}",
+    tag = "users",
+    params(("id" = String, Path, description = "The id of the user")),
+    responses(
+        (status = 200, body = User),
+        (status = 401, body = ErrorBody, description = "The profile is private"),
+        (status = 404, body = ErrorBody),
+    ),
+)]
+pub async fn view_user(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    Path(id): Path<String>,
+) -> ApiResult<Json<User>> {
+    let user = profile::query_user(&app_state, &id).await?;
+    if !PubSettings::of(&user).profile_public && logged_user.is_none() {
+        return Err(ForumError::NotLoggedIn.into());
+    }
+
+    Ok(Json(user.into()))
+}
@@ -0,0 +1,256 @@
+use redis::{aio::ConnectionLike, AsyncCommands, RedisResult};
+use std::collections::HashMap;
+
+// last_used is only written back once per this interval
+const TOUCH_INTERVAL: i64 = 3600;
+
+/// The `Authorization: Bearer` prefix of the api requests
+pub const BEARER_PREFIX: &str = "Bearer ";
+
+/// A token a user created for a bot or a client of the api
+#[derive(Debug, Clone)]
+pub struct ApiToken {
+    /// the sha256 of the token, the token itself is only shown once
+    pub id: String,
+    pub user_id: String,
+    pub name: String,
+    pub created_at: i64,
+    pub last_used: i64,
+}
+
+/// The api tokens in redis, they live until revoked.
+///
+/// Keys:
+/// - `{appid}_api_token:{token_hash}`, a hash with the token fields
+/// - `{appid}_user_api_tokens:{user_id}`, the set of token hashes of this user
+pub struct ApiTokenStore {
+    prefix: String,
+}
+
+fn hash_token(token: &str) -> String {
+    sha256::digest(token).to_lowercase()
+}
+
+impl ApiTokenStore {
+    pub fn new(appid: &str) -> Self {
+        ApiTokenStore {
+            prefix: appid.to_string(),
+        }
+    }
+
+    fn token_key(&self, token_hash: &str) -> String {
+        format!("{}_api_token:{}", self.prefix, token_hash)
+    }
+
+    fn user_index_key(&self, user_id: &str) -> String {
+        format!("{}_user_api_tokens:{}", self.prefix, user_id)
+    }
+
+    /// Create a token for the user, returns the token to show once
+    pub async fn create<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        user_id: &str,
+        name: &str,
+    ) -> RedisResult<String> {
+        let x = rand::random::<[u8; 32]>();
+        let token = sha256::digest(&x).to_lowercase();
+        let token_hash = hash_token(&token);
+        let now = chrono::Utc::now().timestamp();
+
+        redis::pipe()
+            .atomic()
+            .hset_multiple(
+                self.token_key(&token_hash),
+                &[
+                    ("user_id", user_id.to_string()),
+                    ("name", name.to_string()),
+                    ("created_at", now.to_string()),
+                    ("last_used", "0".to_string()),
+                ],
+            )
+            .ignore()
+            .sadd(self.user_index_key(user_id), &token_hash)
+            .ignore()
+            .query_async::<_, ()>(conn)
+            .await?;
+
+        Ok(token)
+    }
+
+    async fn get<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        token_hash: &str,
+    ) -> RedisResult<Option<ApiToken>> {
+        let fields: HashMap<String, String> = conn.hgetall(self.token_key(token_hash)).await?;
+        let Some(user_id) = fields.get("user_id") else {
+            return Ok(None);
+        };
+        let field = |name: &str| fields.get(name).cloned().unwrap_or_default();
+        let timestamp = |name: &str| field(name).parse::<i64>().unwrap_or_default();
+
+        Ok(Some(ApiToken {
+            id: token_hash.to_string(),
+            user_id: user_id.to_owned(),
+            name: field("name"),
+            created_at: timestamp("created_at"),
+            last_used: timestamp("last_used"),
+        }))
+    }
+
+    /// The token behind an `Authorization` header, its last use is recorded
+    pub async fn load<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        token: &str,
+    ) -> RedisResult<Option<ApiToken>> {
+        let Some(mut api_token) = self.get(conn, &hash_token(token)).await? else {
+            return Ok(None);
+        };
+
+        let now = chrono::Utc::now().timestamp();
+        if now - api_token.last_used >= TOUCH_INTERVAL {
+            api_token.last_used = now;
+            let _: () = conn
+                .hset(self.token_key(&api_token.id), "last_used", now)
+                .await?;
+        }
+        Ok(Some(api_token))
+    }
+
+    /// All the tokens of a user, the newest first
+    pub async fn list_by_user<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        user_id: &str,
+    ) -> RedisResult<Vec<ApiToken>> {
+        let index_key = self.user_index_key(user_id);
+        let token_hashes: Vec<String> = conn.smembers(&index_key).await?;
+
+        let mut tokens = vec![];
+        for token_hash in token_hashes {
+            match self.get(conn, &token_hash).await? {
+                Some(api_token) if api_token.user_id == user_id => tokens.push(api_token),
+                _ => {
+                    let _: () = conn.srem(&index_key, &token_hash).await?;
+                }
+            }
+        }
+        tokens.sort_by_key(|api_token| std::cmp::Reverse(api_token.created_at));
+
+        Ok(tokens)
+    }
+
+    /// Revoke one token of this user, returns false if it isn't theirs
+    pub async fn revoke<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        user_id: &str,
+        token_hash: &str,
+    ) -> RedisResult<bool> {
+        let index_key = self.user_index_key(user_id);
+        let is_member: bool = conn.sismember(&index_key, token_hash).await?;
+        if is_member {
+            redis::pipe()
+                .del(self.token_key(token_hash))
+                .ignore()
+                .srem(&index_key, token_hash)
+                .ignore()
+                .query_async::<_, ()>(conn)
+                .await?;
+        }
+        Ok(is_member)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_redis::FakeRedis;
+
+    fn store() -> ApiTokenStore {
+        ApiTokenStore::new("test")
+    }
+
+    #[tokio::test]
+    async fn create_and_load() {
+        let (store, mut redis) = (store(), FakeRedis::default());
+        let token = store.create(&mut redis, "alice", "bot").await.unwrap();
+        // only the hash of the token is kept
+        assert!(!redis.exists(&store.token_key(&token)));
+        assert!(redis.exists(&store.token_key(&hash_token(&token))));
+
+        let api_token = store.load(&mut redis, &token).await.unwrap().unwrap();
+        assert_eq!(api_token.id, hash_token(&token));
+        assert_eq!(api_token.user_id, "alice");
+        assert_eq!(api_token.name, "bot");
+
+        assert!(store.load(&mut redis, "unknown").await.unwrap().is_none());
+        // nor does its hash work as a token
+        assert!(store
+            .load(&mut redis, &api_token.id)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn last_used_is_written_once_an_interval() {
+        let (store, mut redis) = (store(), FakeRedis::default());
+        let token = store.create(&mut redis, "alice", "bot").await.unwrap();
+        let key = store.token_key(&hash_token(&token));
+
+        // never used yet
+        let now = chrono::Utc::now().timestamp();
+        let api_token = store.load(&mut redis, &token).await.unwrap().unwrap();
+        assert!(api_token.last_used >= now);
+
+        // used a moment ago, it's left as it is
+        let recently = now - TOUCH_INTERVAL + 60;
+        redis.hset(&key, "last_used", &recently.to_string());
+        let api_token = store.load(&mut redis, &token).await.unwrap().unwrap();
+        assert_eq!(api_token.last_used, recently);
+
+        // used long ago, it's written back
+        let long_ago = now - TOUCH_INTERVAL - 1;
+        redis.hset(&key, "last_used", &long_ago.to_string());
+        store.load(&mut redis, &token).await.unwrap();
+        let tokens = store.list_by_user(&mut redis, "alice").await.unwrap();
+        assert!(tokens[0].last_used > long_ago);
+    }
+
+    #[tokio::test]
+    async fn list_and_revoke() {
+        let (store, mut redis) = (store(), FakeRedis::default());
+        let first = store.create(&mut redis, "alice", "first").await.unwrap();
+        let second = store.create(&mut redis, "alice", "second").await.unwrap();
+        store.create(&mut redis, "bob", "other").await.unwrap();
+
+        let tokens = store.list_by_user(&mut redis, "alice").await.unwrap();
+        let mut names: Vec<&str> = tokens.iter().map(|t| t.name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, ["first", "second"]);
+
+        // nor can another user revoke it
+        let first_id = hash_token(&first);
+        assert!(!store.revoke(&mut redis, "bob", &first_id).await.unwrap());
+        assert!(store.load(&mut redis, &first).await.unwrap().is_some());
+
+        assert!(store.revoke(&mut redis, "alice", &first_id).await.unwrap());
+        assert!(store.load(&mut redis, &first).await.unwrap().is_none());
+        assert!(store.load(&mut redis, &second).await.unwrap().is_some());
+        let tokens = store.list_by_user(&mut redis, "alice").await.unwrap();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].name, "second");
+
+        // a token gone from redis is pruned from the index
+        redis.expire_now(&store.token_key(&hash_token(&second)));
+        assert!(store
+            .list_by_user(&mut redis, "alice")
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(!redis.exists(&store.user_index_key("alice")));
+    }
+}
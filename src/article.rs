@@ -1,122 +1,296 @@
 use askama::Template;
 use axum::{
-    extract::{Form, Query, RawQuery, State},
-    response::{Html, IntoResponse, Redirect},
+    extract::{Form, Path, Query, RawQuery, State},
+    response::{Html, IntoResponse, Redirect, Response},
     Extension,
 };
-use gutp_types::{GutpComment, GutpPost, GutpSubspace, GutpUser};
+use gutp_types::{GutpComment, GutpPost, GutpSubspace, GutpTag, GutpUser};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
+use crate::comment::{self, ThreadedComment};
+use crate::error::ForumError;
 use crate::filters;
-use crate::redirect_to_error_page;
+use crate::gutp::{NewPost, Paging, PostUpdate};
+use crate::pagination::{MAX_PAGE, PAGE_SIZE};
+use crate::permission::{self, Action, Permission};
+use crate::revision;
+use crate::search;
+use crate::slug;
+use crate::tag::{self, TagChoice};
 use crate::AppState;
 use crate::HtmlTemplate;
-use crate::LoggedUser;
-use crate::{make_get, make_post};
+use crate::{require_login, LoggedUser};
 
 #[derive(Template)]
 #[template(path = "article.html")]
 struct ArticleTemplate {
     post: GutpPost,
-    comments: Vec<GutpComment>,
+    comments: Vec<ThreadedComment>,
+    // only the replies under this comment are shown
+    thread_id: Option<String>,
+    // the "load more" link, when there are comments not shown yet
+    more_comments_url: Option<String>,
+    // the windows of comments around this one, once it's at the cap
+    previous_comments_url: Option<String>,
+    next_comments_url: Option<String>,
     subspace: GutpSubspace,
+    tags: Vec<GutpTag>,
     author: GutpUser,
     logged_user_id: Option<String>,
+    permission: Permission,
 }
 
 #[derive(Deserialize)]
-pub struct ViewArticleParams {
+pub struct ArticlePath {
     id: String,
+    // only for the readers, a stale one is redirected
+    #[serde(default)]
+    slug: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ViewArticleParams {
+    // how many comments are shown, grows by a page with each "load more"
+    comments: Option<usize>,
+    // the offset of the first comment shown
+    from: Option<usize>,
+    // the comment whose thread is continued
+    thread: Option<String>,
+}
+
+// an article page shows at most this many comments, "load more" stops there
+// and the next ones are in the next window
+const MAX_SHOWN_COMMENTS: usize = 5 * PAGE_SIZE;
+
+// the windows past this offset are answered as this one, as the pages of the lists
+const MAX_COMMENT_OFFSET: usize = MAX_PAGE * PAGE_SIZE;
+
+/// The comments an article page shows: `shown` of them from `offset`.
+///
+/// "load more" grows the window a page at a time, so the ones read stay on
+/// the page, up to `MAX_SHOWN_COMMENTS`. Then the link goes to the window
+/// after it, each window is fetched from GUTP by its offset.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct CommentWindow {
+    offset: usize,
+    shown: usize,
+}
+
+impl CommentWindow {
+    fn new(from: Option<usize>, comments: Option<usize>) -> Self {
+        CommentWindow {
+            offset: from.unwrap_or(0).min(MAX_COMMENT_OFFSET),
+            shown: comments
+                .unwrap_or(PAGE_SIZE)
+                .clamp(PAGE_SIZE, MAX_SHOWN_COMMENTS),
+        }
+    }
+
+    /// One more than shown, to know if there are more
+    fn paging(&self) -> Paging {
+        Paging::new(self.shown + 1, self.offset)
+    }
+
+    /// The window grown by a page, when more than `shown` comments were
+    /// fetched and it isn't at the cap yet
+    fn more(&self, fetched: usize) -> Option<CommentWindow> {
+        (fetched > self.shown && self.shown < MAX_SHOWN_COMMENTS).then(|| CommentWindow {
+            offset: self.offset,
+            shown: (self.shown + PAGE_SIZE).min(MAX_SHOWN_COMMENTS),
+        })
+    }
+
+    /// The window after this one, when this one is at the cap and there are more
+    fn next(&self, fetched: usize) -> Option<CommentWindow> {
+        (fetched > self.shown
+            && self.shown == MAX_SHOWN_COMMENTS
+            && self.offset < MAX_COMMENT_OFFSET)
+            .then(|| CommentWindow::new(Some(self.offset + self.shown), None))
+    }
+
+    /// The full window before this one, when this one isn't the first
+    fn previous(&self) -> Option<CommentWindow> {
+        (self.offset > 0).then(|| {
+            let offset = self.offset.saturating_sub(MAX_SHOWN_COMMENTS);
+            CommentWindow::new(Some(offset), Some(self.offset - offset))
+        })
+    }
+
+    // the query params of this window in the article url
+    fn query(&self) -> String {
+        if self.offset > 0 {
+            format!("from={}&comments={}", self.offset, self.shown)
+        } else {
+            format!("comments={}", self.shown)
+        }
+    }
+}
+
+// the comment thread to continue, as a query param of the article url
+fn thread_param(thread_id: &str) -> String {
+    serde_urlencoded::to_string([("thread", thread_id)]).unwrap_or_default()
 }
 
 pub async fn view_article(
+    State(app_state): State<AppState>,
     logged_user: Option<Extension<LoggedUser>>,
+    Path(article_path): Path<ArticlePath>,
     Query(params): Query<ViewArticleParams>,
-) -> impl IntoResponse {
-    let logged_user_id = if let Some(Extension(LoggedUser { user_id })) = logged_user {
-        Some(user_id)
-    } else {
-        None
+    RawQuery(query): RawQuery,
+) -> Result<Response, ForumError> {
+    let logged_user = logged_user.map(|Extension(logged_user)| logged_user);
+    let logged_user_id = logged_user.as_ref().map(|u| u.user_id.to_owned());
+
+    let gutp = &app_state.gutp;
+    let post = query_post(&app_state, &article_path.id).await?;
+
+    // query coresponding subspace of this article
+    // because subspace isn't the care factor, if it's invalid, just git it a default value
+    let subspace = gutp
+        .subspaces()
+        .by_id(&post.subspace_id)
+        .await
+        .ok()
+        .flatten();
+    let has_subspace = subspace.is_some();
+    let subspace = subspace.unwrap_or_default();
+
+    // only decides which links are shown, so fall back to the site level roles
+    let permission = Permission::in_subspace(&app_state, logged_user.as_ref(), &subspace)
+        .await
+        .unwrap_or_else(|_| Permission::site(&app_state, logged_user.as_ref()));
+    // the articles of a private subspace are hidden like a missing one
+    if has_subspace && !permission.can_view(&subspace) {
+        return Err(ForumError::not_found(
+            format!("Query article: {}", post.id),
+            "Article doesn't exist!",
+        ));
+    }
+
+    // reached without the slug, or by a slug since the title changed
+    let path = slug::article_path(&post);
+    let asked = match &article_path.slug {
+        Some(title_slug) => format!("{}/{}", slug::article_id_path(&post.id), title_slug),
+        None => slug::article_id_path(&post.id),
     };
+    if path != asked {
+        return Ok(slug::moved_permanently(&path, query.as_deref()));
+    }
 
-    // #[derive(Serialize)]
-    // struct QueryMaker00 {
-    //     id: String,
-    // }
-
-    // let query_params = QueryMaker00 {
-    //     id: params.id.to_owned(),
-    // };
-
-    // or use this for simple case
-    let query_params = [("id", &params.id)];
-    let posts: Vec<GutpPost> = make_get("/v1/post", &query_params).await.unwrap_or(vec![]);
-    if let Some(post) = posts.into_iter().next() {
-        // continue to query comments
-        let query_params = [("post_id", &post.id)];
-        let comments: Vec<GutpComment> = make_get("/v1/comment/list_by_post", &query_params)
-            .await
-            .unwrap_or(vec![]);
-
-        // TODO: query tags of this article
-        // this is a N:M relationship, so it's relatively complex
-        // let query_params = [("post_id", post.id)];
-        // let post_tags: Vec<GutpPostTag> = make_get("/v1/posttag/list_by_post", &query_params)
-        //     .await
-        //     .unwrap_or(vec![]);
-
-        // query coresponding subspace of this article
-        let query_params = [("id", &post.subspace_id)];
-        let sps: Vec<GutpSubspace> = make_get("/v1/subspace", &query_params)
+    // continue to query comments, only the window shown
+    let window = CommentWindow::new(params.from, params.comments);
+    let mut comments = gutp
+        .comments()
+        .list_by_post(&post.id, window.paging())
+        .await
+        .map_err(ForumError::gutp(format!(
+            "Query comments of article: {}",
+            &post.id
+        )))?;
+    let thread_id = params.thread.filter(|id| !id.is_empty());
+    let thread_query = thread_id
+        .as_ref()
+        .map(|id| format!("&{}", thread_param(id)))
+        .unwrap_or_default();
+    let window_url =
+        |window: CommentWindow| format!("{}?{}{}#comments", path, window.query(), thread_query);
+    let more_comments_url = window.more(comments.len()).map(window_url);
+    let next_comments_url = window.next(comments.len()).map(window_url);
+    let previous_comments_url = window.previous().map(window_url);
+    comments.truncate(window.shown);
+
+    // the thread is built within the window, the replies whose parent is in
+    // an earlier one are shown as top level
+    let mut comments = comment::build_thread(comments, thread_id.as_deref(), |c| {
+        format!(
+            "{}?{}&{}#comments",
+            path,
+            window.query(),
+            thread_param(&c.id)
+        )
+    });
+    if thread_id.is_some() && comments.is_empty() {
+        return Err(ForumError::not_found(
+            format!("Query comment thread of article: {}", post.id),
+            "Comment doesn't exist!",
+        ));
+    }
+
+    // the "edited" markers are kept in redis, they aren't the care factor
+    let comment_ids: Vec<String> = comments.iter().map(|c| c.comment.id.to_owned()).collect();
+    if let Ok(mut redis_conn) = app_state.redis.conn().await {
+        let edited = app_state
+            .comment_history
+            .edited_at(&mut redis_conn, &comment_ids)
             .await
-            .unwrap_or(vec![]);
-        // because subspace isn't the care factor, if it's invalid, just git it a default value
-        let subspace = if sps.is_empty() {
-            Default::default()
-        } else {
-            sps[0].to_owned()
-        };
-
-        // query coresponding author of this article
-        let query_params = [("id", &post.author_id)];
-        let authors: Vec<GutpUser> = make_get("/v1/user", &query_params).await.unwrap_or(vec![]);
-        // because author isn't the care factor, if it's invalid, just git it a default value
-        let author = if authors.is_empty() {
-            Default::default()
-        } else {
-            authors[0].to_owned()
-        };
-
-        // if user logged in, add it
-        // let query_params = [("id", logged_user_id)];
-        // let users: Vec<GutpUser> = make_get("/v1/user", &query_params).await.unwrap_or(vec![]);
-        // // because author isn't the care factor, if it's invalid, just git it a default value
-        // let user = if users.is_empty() {
-        //     None
-        // } else {
-        //     Some(users[0].to_owned())
-        // };
-
-        // render the page
-        HtmlTemplate(ArticleTemplate {
-            post,
-            comments,
-            subspace,
-            author,
-            logged_user_id,
-        })
-        .into_response()
-    } else {
-        let action = format!("Query article: {}", params.id);
-        let err_info = "Article doesn't exist!";
-        redirect_to_error_page(&action, err_info).into_response()
+            .unwrap_or_default();
+        for threaded in comments.iter_mut() {
+            threaded.edited_at = edited.get(&threaded.comment.id).copied();
+        }
     }
+
+    // the tags aren't the care factor either
+    let tags = tag::tags_of_post(&app_state, &post)
+        .await
+        .unwrap_or_default();
+
+    // query coresponding author of this article
+    // because author isn't the care factor, if it's invalid, just git it a default value
+    let author = gutp
+        .users()
+        .by_id(&post.author_id)
+        .await
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+
+    // render the page
+    Ok(HtmlTemplate(ArticleTemplate {
+        post,
+        comments,
+        thread_id,
+        more_comments_url,
+        previous_comments_url,
+        next_comments_url,
+        subspace,
+        tags,
+        author,
+        logged_user_id,
+        permission,
+    })
+    .into_response())
+}
+
+#[derive(Deserialize)]
+pub struct ViewArticleByIdParams {
+    id: String,
+}
+
+/// The old `/article?id=` links, moved to `/a/{id}/{title-slug}`
+pub async fn redirect_article(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    Query(params): Query<ViewArticleByIdParams>,
+    RawQuery(query): RawQuery,
+) -> Result<Response, ForumError> {
+    let post = query_post(&app_state, &params.id).await?;
+    permission::ensure_view_post(&app_state, logged_user.as_deref(), &post).await?;
+
+    // the other params, e.g. the shown comments, are kept
+    let rest = slug::drop_query_param(query.as_deref().unwrap_or_default(), "id");
+    Ok(slug::moved_permanently(
+        &slug::article_path(&post),
+        Some(&rest),
+    ))
 }
 
 #[derive(Template)]
 #[template(path = "article_create.html")]
 struct ArticleCreateTemplate {
     subspace: GutpSubspace,
+    tags: Vec<TagChoice>,
+    csrf_token: String,
 }
 
 #[derive(Deserialize)]
@@ -125,28 +299,35 @@ pub struct ViewArticleCreateParams {
 }
 
 pub async fn view_article_create(
+    State(app_state): State<AppState>,
     logged_user: Option<Extension<LoggedUser>>,
     Query(params): Query<ViewArticleCreateParams>,
-) -> impl IntoResponse {
+) -> Result<impl IntoResponse, ForumError> {
     // check the user login status
-    if logged_user.is_none() {
-        let action = format!("Not logged in");
-        let err_info = "Need login firstly to get proper permission.";
-        return redirect_to_error_page(&action, err_info).into_response();
-    }
+    let logged_user = require_login(logged_user)?;
 
-    let inner_params = [("id", &params.subspace_id)];
-    let subspaces: Vec<GutpSubspace> = make_get("/v1/subspace", &inner_params)
+    let action = format!("Query subspace: {}", &params.subspace_id);
+    let subspace = app_state
+        .gutp
+        .subspaces()
+        .by_id(&params.subspace_id)
         .await
-        .unwrap_or(vec![]);
-    if let Some(subspace) = subspaces.into_iter().next() {
-        // render the page
-        HtmlTemplate(ArticleCreateTemplate { subspace }).into_response()
-    } else {
-        let action = format!("Query subspace: {}", &params.subspace_id);
-        let err_info = "Subspace doesn't exist, article couldn't be added to it!";
-        redirect_to_error_page(&action, err_info).into_response()
-    }
+        .map_err(ForumError::gutp(&action))?
+        .ok_or_else(|| {
+            ForumError::not_found(
+                &action,
+                "Subspace doesn't exist, article couldn't be added to it!",
+            )
+        })?;
+    permission::ensure_view_subspace(&app_state, Some(&logged_user), &subspace).await?;
+    let tags = tag::tag_choices(&app_state, &subspace.id, None).await?;
+
+    // render the page
+    Ok(HtmlTemplate(ArticleCreateTemplate {
+        subspace,
+        tags,
+        csrf_token: logged_user.csrf_token,
+    }))
 }
 
 #[derive(Deserialize)]
@@ -155,68 +336,101 @@ pub struct PostArticleCreateParams {
     title: String,
     content: String,
     extlink: String,
+    // the tag checkboxes, and the other fields
+    #[serde(flatten)]
+    fields: HashMap<String, String>,
 }
 
 pub async fn post_article_create(
+    State(app_state): State<AppState>,
     logged_user: Option<Extension<LoggedUser>>,
     Form(params): Form<PostArticleCreateParams>,
-) -> impl IntoResponse {
+) -> Result<Redirect, ForumError> {
     // check the user login status
-    if logged_user.is_none() {
-        let action = format!("Not logged in");
-        let err_info = "Need login firstly to get proper permission.";
-        return redirect_to_error_page(&action, err_info).into_response();
-    }
-    let Extension(LoggedUser { user_id }) = logged_user.unwrap();
+    let logged_user = require_login(logged_user)?;
 
-    let inner_params = [("id", &user_id)];
-    let users: Vec<GutpUser> = make_get("/v1/user", &inner_params).await.unwrap_or(vec![]);
-    // because author isn't the care factor, if it's invalid, just git it a default value
-    if users.is_empty() {
-        let action = format!("Query user: {}", user_id);
-        let err_info = "Unknown error.";
-        return redirect_to_error_page(&action, err_info).into_response();
-    }
-    let user = users[0].to_owned();
-
-    #[derive(Serialize)]
-    struct InnerArticleCreateParams {
-        title: String,
-        content: String,
-        author_id: String,
-        author_nickname: String,
-        subspace_id: String,
-        extlink: String,
-        profession: String,
-        appid: String,
-        is_public: bool,
-    }
-
-    let inner_params = InnerArticleCreateParams {
+    let input = ArticleInput {
         title: params.title,
         content: params.content,
+        extlink: params.extlink,
+        tag_ids: Some(tag::checked_tag_ids(&params.fields)),
+    };
+    let post = create_article(&app_state, &logged_user, &params.subspace_id, input).await?;
+
+    // redirect to the article page
+    Ok(Redirect::to(&slug::article_path(&post)))
+}
+
+/// What the article forms and the api send
+pub struct ArticleInput {
+    pub title: String,
+    pub content: String,
+    pub extlink: String,
+    // None leaves the tags as they are
+    pub tag_ids: Option<Vec<String>>,
+}
+
+/// Create an article of the logged user, shared by the form and the api
+pub async fn create_article(
+    app_state: &AppState,
+    logged_user: &LoggedUser,
+    subspace_id: &str,
+    input: ArticleInput,
+) -> Result<GutpPost, ForumError> {
+    let gutp = &app_state.gutp;
+    let action = format!("Query user: {}", logged_user.user_id);
+    let user = gutp
+        .users()
+        .by_id(&logged_user.user_id)
+        .await
+        .map_err(ForumError::gutp(&action))?
+        .ok_or_else(|| ForumError::not_found(&action, "Unknown error."))?;
+
+    let action = format!("Query subspace: {}", subspace_id);
+    let subspace = gutp
+        .subspaces()
+        .by_id(subspace_id)
+        .await
+        .map_err(ForumError::gutp(&action))?
+        .ok_or_else(|| {
+            ForumError::not_found(
+                &action,
+                "Subspace doesn't exist, article couldn't be added to it!",
+            )
+        })?;
+    permission::ensure_view_subspace(app_state, Some(logged_user), &subspace).await?;
+    Permission::site(app_state, Some(logged_user)).ensure(
+        Action::CreateArticle,
+        "",
+        format!("Create article in subspace: {}", subspace.id),
+    )?;
+
+    let inner_params = NewPost {
+        title: input.title,
+        content: input.content,
         author_id: user.id.to_owned(),
         author_nickname: user.nickname.to_owned(),
-        subspace_id: params.subspace_id.to_owned(),
-        extlink: params.extlink,
-        profession: crate::APPPROFESSION.to_string(),
-        appid: crate::APPID.to_string(),
+        subspace_id: subspace_id.to_string(),
+        extlink: input.extlink,
+        profession: app_state.config.profession.to_owned(),
+        appid: app_state.config.appid.to_owned(),
         is_public: true,
     };
 
-    let posts: Vec<GutpPost> = make_post("/v1/post/create", &inner_params)
+    let post = gutp
+        .posts()
+        .create(&inner_params)
         .await
-        .unwrap_or(vec![]);
-    if let Some(post) = posts.into_iter().next() {
-        // redirect to the article page
-        let redirect_uri = format!("/article?id={}", post.id);
-        Redirect::to(&redirect_uri).into_response()
-    } else {
-        // redirect to the error page
-        let action = format!("Create article in subspace: {}", &params.subspace_id);
-        let err_info = "Unknown";
-        redirect_to_error_page(&action, err_info).into_response()
+        .map_err(ForumError::gutp(format!(
+            "Create article in subspace: {}",
+            subspace_id
+        )))?;
+    if let Some(tag_ids) = &input.tag_ids {
+        tag::set_post_tags(app_state, &post, tag_ids).await?;
     }
+    search::index_post(app_state, &post).await;
+
+    Ok(post)
 }
 
 #[derive(Template)]
@@ -224,6 +438,8 @@ pub async fn post_article_create(
 struct ArticleEditTemplate {
     post: GutpPost,
     // subspace: GutpSubspace,
+    tags: Vec<TagChoice>,
+    csrf_token: String,
 }
 
 #[derive(Deserialize)]
@@ -232,26 +448,29 @@ pub struct ViewArticleEditParams {
 }
 
 pub async fn view_article_edit(
+    State(app_state): State<AppState>,
     logged_user: Option<Extension<LoggedUser>>,
     Query(params): Query<ViewArticleEditParams>,
-) -> impl IntoResponse {
+) -> Result<impl IntoResponse, ForumError> {
     // check the user login status
-    if logged_user.is_none() {
-        let action = format!("Not logged in");
-        let err_info = "Need login firstly to get proper permission.";
-        return redirect_to_error_page(&action, err_info).into_response();
-    }
+    let logged_user = require_login(logged_user)?;
 
-    let inner_params = [("id", &params.id)];
     // get the old article by request to gutp
-    let posts: Vec<GutpPost> = make_get("/v1/post", &inner_params).await.unwrap_or(vec![]);
-    if let Some(post) = posts.into_iter().next() {
-        HtmlTemplate(ArticleEditTemplate { post }).into_response()
-    } else {
-        let action = format!("Query Article: {}", &params.id);
-        let err_info = "Article doesn't exist!";
-        redirect_to_error_page(&action, err_info).into_response()
-    }
+    let post = query_post(&app_state, &params.id).await?;
+    Permission::for_post(&app_state, Some(&logged_user), &post)
+        .await?
+        .ensure(
+            Action::EditArticle,
+            &post.author_id,
+            format!("Edit article: {}", post.id),
+        )?;
+    let tags = tag::tag_choices(&app_state, &post.subspace_id, Some(&post.id)).await?;
+
+    Ok(HtmlTemplate(ArticleEditTemplate {
+        post,
+        tags,
+        csrf_token: logged_user.csrf_token,
+    }))
 }
 
 #[derive(Deserialize)]
@@ -260,58 +479,77 @@ pub struct PostArticleEditParams {
     title: String,
     content: String,
     extlink: String,
+    // the tag checkboxes, and the other fields
+    #[serde(flatten)]
+    fields: HashMap<String, String>,
 }
 
 pub async fn post_article_edit(
+    State(app_state): State<AppState>,
     logged_user: Option<Extension<LoggedUser>>,
     Form(params): Form<PostArticleEditParams>,
-) -> Redirect {
+) -> Result<Redirect, ForumError> {
     // check the user login status
-    if logged_user.is_none() {
-        let action = format!("Not logged in");
-        let err_info = "Need login firstly to get proper permission.";
-        return redirect_to_error_page(&action, err_info);
-    }
-    let Extension(LoggedUser { user_id }) = logged_user.unwrap();
-
-    #[derive(Serialize)]
-    struct InnerArticleEditParams {
-        id: String,
-        title: String,
-        content: String,
-        author_id: String,
-        extlink: String,
-        is_public: bool,
-    }
+    let logged_user = require_login(logged_user)?;
 
-    let inner_params = InnerArticleEditParams {
-        id: params.id.to_owned(),
+    let input = ArticleInput {
         title: params.title,
         content: params.content,
-        author_id: user_id,
         extlink: params.extlink,
+        tag_ids: Some(tag::checked_tag_ids(&params.fields)),
+    };
+    let post = edit_article(&app_state, &logged_user, &params.id, input).await?;
+
+    // redirect to the article page
+    Ok(Redirect::to(&slug::article_path(&post)))
+}
+
+/// Edit an article, by its author or a moderator. The revision is recorded
+pub async fn edit_article(
+    app_state: &AppState,
+    logged_user: &LoggedUser,
+    id: &str,
+    input: ArticleInput,
+) -> Result<GutpPost, ForumError> {
+    let old_post = query_post(app_state, id).await?;
+    Permission::for_post(app_state, Some(logged_user), &old_post)
+        .await?
+        .ensure(
+            Action::EditArticle,
+            &old_post.author_id,
+            format!("Edit article: {}", old_post.id),
+        )?;
+
+    let inner_params = PostUpdate {
+        id: old_post.id.to_owned(),
+        title: input.title,
+        content: input.content,
+        // the moderators may edit it too, the author stays the same
+        author_id: old_post.author_id.to_owned(),
+        extlink: input.extlink,
         is_public: true,
     };
     // post to gutp
-    let posts: Vec<GutpPost> = make_post("/v1/post/update", &inner_params)
+    let post = app_state
+        .gutp
+        .posts()
+        .update(&inner_params)
         .await
-        .unwrap_or(vec![]);
-    if let Some(post) = posts.into_iter().next() {
-        // redirect to the article page
-        let redirect_uri = format!("/article?id={}", post.id);
-        Redirect::to(&redirect_uri)
-    } else {
-        // redirect to the error page
-        let action = format!("Edit article: {}", &params.id);
-        let err_info = "Unknown";
-        redirect_to_error_page(&action, err_info)
+        .map_err(ForumError::gutp(format!("Edit article: {}", old_post.id)))?;
+    revision::record_edit(app_state, &logged_user.user_id, &old_post, &post).await;
+    if let Some(tag_ids) = &input.tag_ids {
+        tag::set_post_tags(app_state, &post, tag_ids).await?;
     }
+    search::index_post(app_state, &post).await;
+
+    Ok(post)
 }
 
 #[derive(Template)]
 #[template(path = "article_delete.html")]
 struct ArticleDeleteTemplate {
     post: GutpPost,
+    csrf_token: String,
 }
 
 #[derive(Deserialize)]
@@ -320,69 +558,167 @@ pub struct ViewArticleDeleteParams {
 }
 
 pub async fn view_article_delete(
+    State(app_state): State<AppState>,
     logged_user: Option<Extension<LoggedUser>>,
     Query(params): Query<ViewArticleDeleteParams>,
-) -> impl IntoResponse {
+) -> Result<impl IntoResponse, ForumError> {
     // check the user login status
-    if logged_user.is_none() {
-        let action = format!("Not logged in");
-        let err_info = "Need login firstly to get proper permission.";
-        return redirect_to_error_page(&action, err_info).into_response();
-    }
+    let logged_user = require_login(logged_user)?;
 
-    let inner_params = [("id", &params.id)];
-    let posts: Vec<GutpPost> = make_get("/v1/post", &inner_params).await.unwrap_or(vec![]);
-    if let Some(post) = posts.into_iter().next() {
-        // continue to query comments
-        let inner_params = [("post_id", &post.id)];
-        let comments: Vec<GutpComment> = make_get("/v1/comment/list_by_post", &inner_params)
-            .await
-            .unwrap_or(vec![]);
+    let post = deletable_post(&app_state, &logged_user, &params.id).await?;
 
-        if comments.is_empty() {
-            // can be deleted
-            HtmlTemplate(ArticleDeleteTemplate { post }).into_response()
-        } else {
-            // error
-            let action = format!("Intend to delete article: {}", &params.id);
-            let err_info = "Article has comments attached, could not be deleted!";
-            redirect_to_error_page(&action, err_info).into_response()
-        }
-    } else {
-        let action = format!("Query article: {}", params.id);
-        let err_info = "Article doesn't exist!";
-        redirect_to_error_page(&action, err_info).into_response()
-    }
+    // can be deleted
+    Ok(HtmlTemplate(ArticleDeleteTemplate {
+        post,
+        csrf_token: logged_user.csrf_token,
+    }))
 }
 
 #[derive(Deserialize)]
 pub struct PostArticleDeleteParams {
     id: String,
-    subspace_id: String,
 }
 
 pub async fn post_article_delete(
+    State(app_state): State<AppState>,
     logged_user: Option<Extension<LoggedUser>>,
     Form(params): Form<PostArticleDeleteParams>,
-) -> Redirect {
+) -> Result<Redirect, ForumError> {
     // check the user login status
-    if logged_user.is_none() {
-        let action = format!("Not logged in");
-        let err_info = "Need login firstly to get proper permission.";
-        return redirect_to_error_page(&action, err_info);
+    let logged_user = require_login(logged_user)?;
+
+    let post = delete_article(&app_state, &logged_user, &params.id).await?;
+
+    // redirect to the subspace page
+    Ok(Redirect::to(&slug::subspace_id_path(&post.subspace_id)))
+}
+
+/// Delete an article without comments, returns what it was
+pub async fn delete_article(
+    app_state: &AppState,
+    logged_user: &LoggedUser,
+    id: &str,
+) -> Result<GutpPost, ForumError> {
+    let post = deletable_post(app_state, logged_user, id).await?;
+
+    tag::detach_post_tags(app_state, &post.id).await?;
+    app_state
+        .gutp
+        .posts()
+        .delete(&post.id)
+        .await
+        .map_err(ForumError::gutp(format!("Delete article: {}", post.id)))?;
+    search::remove_post(app_state, &post.id);
+
+    Ok(post)
+}
+
+// an article can be deleted by its author or a moderator,
+// and only when no comment is attached to it
+async fn deletable_post(
+    app_state: &AppState,
+    logged_user: &LoggedUser,
+    id: &str,
+) -> Result<GutpPost, ForumError> {
+    let post = query_post(app_state, id).await?;
+    Permission::for_post(app_state, Some(logged_user), &post)
+        .await?
+        .ensure(
+            Action::DeleteArticle,
+            &post.author_id,
+            format!("Delete article: {}", post.id),
+        )?;
+
+    let action = format!("Intend to delete article: {}", post.id);
+    let comments = app_state
+        .gutp
+        .comments()
+        .list_by_post(&post.id, Paging::first())
+        .await
+        .map_err(ForumError::gutp(&action))?;
+    if !comments.is_empty() {
+        return Err(ForumError::conflict(
+            &action,
+            "Article has comments attached, could not be deleted!",
+        ));
     }
 
-    // We must precheck the id, we can do it in the params type definition
-    let inner_params = [("id", &params.id)];
-    let _posts: Vec<GutpPost> = make_post("/v1/post/delete", &inner_params)
+    Ok(post)
+}
+
+pub(crate) async fn query_post(app_state: &AppState, id: &str) -> Result<GutpPost, ForumError> {
+    let action = format!("Query article: {}", id);
+    app_state
+        .gutp
+        .posts()
+        .by_id(id)
         .await
-        .unwrap_or(vec![]);
+        .map_err(ForumError::gutp(&action))?
+        .ok_or_else(|| ForumError::not_found(&action, "Article doesn't exist!"))
+}
 
-    // TODO: process the error branch of deleting
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comment_windows_are_bounded() {
+        let window = CommentWindow::new(None, None);
+        assert_eq!((window.offset, window.shown), (0, PAGE_SIZE));
+        assert_eq!(CommentWindow::new(None, Some(0)).shown, PAGE_SIZE);
+        assert_eq!(
+            CommentWindow::new(None, Some(3 * PAGE_SIZE)).shown,
+            3 * PAGE_SIZE
+        );
+        let window = CommentWindow::new(Some(usize::MAX), Some(usize::MAX));
+        assert_eq!(window.offset, MAX_COMMENT_OFFSET);
+        assert_eq!(window.shown, MAX_SHOWN_COMMENTS);
+    }
 
-    // TODO: redirect to an article list page with a tag
+    #[test]
+    fn load_more_extends_the_shown_comments() {
+        let window = CommentWindow::new(None, None);
+        // the extra one fetched tells there are more
+        let more = window.more(PAGE_SIZE + 1).unwrap();
+        assert_eq!((more.offset, more.shown), (0, 2 * PAGE_SIZE));
+        assert_eq!(more.query(), format!("comments={}", 2 * PAGE_SIZE));
+        assert_eq!(window.more(PAGE_SIZE), None);
+        assert_eq!(window.more(3), None);
+        assert_eq!(window.next(PAGE_SIZE + 1), None);
+        assert_eq!(window.previous(), None);
+    }
 
-    // redirect to index page
-    let redirect_uri = format!("/subspace?id={}", &params.subspace_id);
-    Redirect::to(&redirect_uri)
+    #[test]
+    fn past_the_cap_comes_the_next_window() {
+        let full = CommentWindow::new(None, Some(MAX_SHOWN_COMMENTS));
+        assert_eq!(full.more(MAX_SHOWN_COMMENTS + 1), None);
+        assert_eq!(full.next(MAX_SHOWN_COMMENTS), None);
+
+        // it starts after the shown ones, none is out of reach
+        let next = full.next(MAX_SHOWN_COMMENTS + 1).unwrap();
+        assert_eq!((next.offset, next.shown), (MAX_SHOWN_COMMENTS, PAGE_SIZE));
+        let paging = next.paging();
+        assert_eq!(
+            (paging.offset, paging.limit),
+            (MAX_SHOWN_COMMENTS, PAGE_SIZE + 1)
+        );
+        assert_eq!(
+            next.query(),
+            format!("from={}&comments={}", MAX_SHOWN_COMMENTS, PAGE_SIZE)
+        );
+        let more = next.more(PAGE_SIZE + 1).unwrap();
+        assert_eq!(more.offset, MAX_SHOWN_COMMENTS);
+
+        // and goes back to the full one before it
+        assert_eq!(next.previous(), Some(full));
+        let odd = CommentWindow::new(Some(PAGE_SIZE + 3), None);
+        assert_eq!(
+            odd.previous(),
+            Some(CommentWindow::new(None, Some(PAGE_SIZE + 3)))
+        );
+
+        // the last window has no next one
+        let last = CommentWindow::new(Some(MAX_COMMENT_OFFSET), Some(MAX_SHOWN_COMMENTS));
+        assert_eq!(last.next(MAX_SHOWN_COMMENTS + 1), None);
+    }
 }
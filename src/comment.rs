@@ -6,118 +6,278 @@ use axum::{
 };
 use gutp_types::{GutpComment, GutpPost, GutpUser};
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 
-use crate::redirect_to_error_page;
+use crate::comment_history::CommentRevision;
+use crate::error::ForumError;
+use crate::filters;
+use crate::gutp::{CommentUpdate, NewComment};
+use crate::permission::{self, Action, Permission};
+use crate::search;
+use crate::slug;
+use crate::user::session_conn;
+use crate::AppState;
 use crate::HtmlTemplate;
-use crate::LoggedUser;
-use crate::{make_get, make_post};
+use crate::{require_login, LoggedUser};
+
+// deeper replies are shown behind a "continue thread" link
+const MAX_THREAD_DEPTH: usize = 5;
+
+/// A comment placed in the reply tree, flattened in display order
+pub struct ThreadedComment {
+    pub comment: GutpComment,
+    pub depth: usize,
+    // the time of the last edit, if it was edited
+    pub edited_at: Option<i64>,
+    // set at the depth cap, when the replies aren't shown here
+    pub continue_url: Option<String>,
+}
+
+impl ThreadedComment {
+    // askama can't recurse, so the nesting is an indent
+    pub fn indent(&self) -> usize {
+        self.depth * 24
+    }
+}
+
+/// Build the reply tree from the flat list of gutp.
+///
+/// With `root_id`, only the thread under that comment is built. Otherwise the
+/// roots are the top level comments, and the replies whose parent isn't in
+/// the list (deleted) are shown as top level too.
+pub fn build_thread(
+    mut comments: Vec<GutpComment>,
+    root_id: Option<&str>,
+    continue_url: impl Fn(&GutpComment) -> String,
+) -> Vec<ThreadedComment> {
+    // the replies of a comment are shown oldest first
+    comments.sort_by_key(|c| c.created_time);
+    let ids: HashSet<String> = comments.iter().map(|c| c.id.to_owned()).collect();
+    let mut roots = vec![];
+    let mut children: HashMap<String, Vec<GutpComment>> = HashMap::new();
+    for comment in comments {
+        let is_root = match root_id {
+            Some(root_id) => comment.id == root_id,
+            None => !ids.contains(&comment.parent_comment_id),
+        };
+        if is_root {
+            roots.push(comment);
+        } else {
+            children
+                .entry(comment.parent_comment_id.to_owned())
+                .or_default()
+                .push(comment);
+        }
+    }
+
+    // depth first, the stack holds the next siblings in reverse
+    let mut stack: Vec<(GutpComment, usize)> = roots.into_iter().rev().map(|c| (c, 0)).collect();
+    let mut thread = vec![];
+    while let Some((comment, depth)) = stack.pop() {
+        let replies = children.remove(&comment.id).unwrap_or_default();
+        let mut continue_with = None;
+        if !replies.is_empty() {
+            if depth + 1 < MAX_THREAD_DEPTH {
+                stack.extend(replies.into_iter().rev().map(|c| (c, depth + 1)));
+            } else {
+                continue_with = Some(continue_url(&comment));
+            }
+        }
+        thread.push(ThreadedComment {
+            comment,
+            depth,
+            edited_at: None,
+            continue_url: continue_with,
+        });
+    }
+
+    thread
+}
+
+// the reply starts with the parent quoted, in markdown
+fn quote(comment: &GutpComment) -> String {
+    let mut quoted = format!("> {}:\n", comment.author_nickname);
+    for line in comment.content.lines() {
+        quoted.push_str("> ");
+        quoted.push_str(line);
+        quoted.push('\n');
+    }
+    quoted.push('\n');
+    quoted
+}
+
+// a reply must be under the same article as its parent
+async fn query_parent(
+    app_state: &AppState,
+    post: &GutpPost,
+    parent_id: &str,
+) -> Result<GutpComment, ForumError> {
+    let action = format!("Query comment: {}", parent_id);
+    app_state
+        .gutp
+        .comments()
+        .by_id(parent_id)
+        .await
+        .map_err(ForumError::gutp(&action))?
+        .filter(|parent| parent.post_id == post.id)
+        .ok_or_else(|| {
+            ForumError::not_found(&action, "Comment doesn't exist, couldn't reply to it!")
+        })
+}
 
 #[derive(Template)]
 #[template(path = "comment_create.html")]
 struct CommentCreateTemplate {
     post: GutpPost,
+    parent: Option<GutpComment>,
+    // the textarea starts with it
+    content: String,
+    csrf_token: String,
 }
 
 #[derive(Deserialize)]
 pub struct ViewCommentCreateParams {
     post_id: String,
+    // the comment replied to, none for a top level comment
+    parent_id: Option<String>,
 }
 
 pub async fn view_comment_create(
+    State(app_state): State<AppState>,
     logged_user: Option<Extension<LoggedUser>>,
     Query(params): Query<ViewCommentCreateParams>,
-) -> impl IntoResponse {
+) -> Result<impl IntoResponse, ForumError> {
     // check the user login status
-    if logged_user.is_none() {
-        let action = format!("Not logged in");
-        let err_info = "Need login firstly to get proper permission.";
-        return redirect_to_error_page(&action, err_info).into_response();
-    }
+    let logged_user = require_login(logged_user)?;
 
-    let inner_params = [("id", &params.post_id)];
-    let posts: Vec<GutpPost> = make_get("/v1/post", &inner_params).await.unwrap_or(vec![]);
-    if let Some(post) = posts.into_iter().next() {
-        HtmlTemplate(CommentCreateTemplate { post }).into_response()
-    } else {
-        let action = format!("Query Article: {}", &params.post_id);
-        let err_info = "Article doesn't exist, comment couldn't be added to it!";
-        redirect_to_error_page(&action, err_info).into_response()
-    }
+    let action = format!("Query Article: {}", &params.post_id);
+    let post = app_state
+        .gutp
+        .posts()
+        .by_id(&params.post_id)
+        .await
+        .map_err(ForumError::gutp(&action))?
+        .ok_or_else(|| {
+            ForumError::not_found(
+                &action,
+                "Article doesn't exist, comment couldn't be added to it!",
+            )
+        })?;
+    permission::ensure_view_post(&app_state, Some(&logged_user), &post).await?;
+
+    let parent = match params.parent_id.as_deref().filter(|id| !id.is_empty()) {
+        Some(parent_id) => Some(query_parent(&app_state, &post, parent_id).await?),
+        None => None,
+    };
+    let content = parent.as_ref().map(quote).unwrap_or_default();
+
+    Ok(HtmlTemplate(CommentCreateTemplate {
+        post,
+        parent,
+        content,
+        csrf_token: logged_user.csrf_token,
+    }))
 }
 
 #[derive(Deserialize)]
 pub struct PostCommentCreateParams {
     post_id: String,
+    #[serde(default)]
+    parent_comment_id: String,
     content: String,
 }
 
 pub async fn post_comment_create(
+    State(app_state): State<AppState>,
     logged_user: Option<Extension<LoggedUser>>,
     Form(params): Form<PostCommentCreateParams>,
-) -> impl IntoResponse {
+) -> Result<Redirect, ForumError> {
     // check the user login status
-    if logged_user.is_none() {
-        let action = format!("Not logged in");
-        let err_info = "Need login firstly to get proper permission.";
-        return redirect_to_error_page(&action, err_info);
-    }
-    let Extension(LoggedUser { user_id }) = logged_user.unwrap();
-
-    let inner_params = [("id", &params.post_id)];
-    let posts: Vec<GutpPost> = make_get("/v1/post", &inner_params).await.unwrap_or(vec![]);
-    if let Some(post) = posts.into_iter().next() {
-        // retreive author info
-        let inner_params = [("id", &user_id)];
-        let authors: Vec<GutpUser> = make_get("/v1/user", &inner_params).await.unwrap_or(vec![]);
-        if let Some(author) = authors.into_iter().next() {
-            #[derive(Serialize)]
-            struct InnerCommentCreateParams {
-                content: String,
-                author_id: String,
-                author_nickname: String,
-                post_id: String,
-                parent_comment_id: String,
-                is_public: bool,
-            }
+    let logged_user = require_login(logged_user)?;
 
-            let inner_params = InnerCommentCreateParams {
-                content: params.content.to_owned(),
-                author_id: author.id.to_owned(),
-                author_nickname: author.nickname.to_owned(),
-                post_id: post.id.to_owned(),
-                parent_comment_id: "".to_owned(),
-                is_public: true,
-            };
-
-            let comments: Vec<GutpComment> = make_post("/v1/comment/create", &inner_params)
-                .await
-                .unwrap_or(vec![]);
-            if let Some(comment) = comments.into_iter().next() {
-                // redirect to the article page
-                let redirect_uri = format!("/article?id={}", comment.post_id);
-                Redirect::to(&redirect_uri)
-            } else {
-                // redirect to the error page
-                let action = format!("Create comment for article: {}", &post.id);
-                let err_info = "Unknown";
-                redirect_to_error_page(&action, err_info)
-            }
-        } else {
-            let action = format!("Query author: {}", &user_id);
-            let err_info = "Unknown";
-            redirect_to_error_page(&action, err_info)
-        }
-    } else {
-        let action = format!("Query Article: {}", &params.post_id);
-        let err_info = "Article doesn't exist, comment couldn't be added to it!";
-        redirect_to_error_page(&action, err_info)
+    let (comment, post) = create_comment(
+        &app_state,
+        &logged_user,
+        &params.post_id,
+        &params.parent_comment_id,
+        params.content,
+    )
+    .await?;
+
+    // redirect to the new comment on the article page
+    let redirect_uri = format!("{}#comment-{}", slug::article_path(&post), comment.id);
+    Ok(Redirect::to(&redirect_uri))
+}
+
+/// Comment on an article, or reply to a comment of it when `parent_comment_id`
+/// isn't empty. Shared by the form and the api
+pub async fn create_comment(
+    app_state: &AppState,
+    logged_user: &LoggedUser,
+    post_id: &str,
+    parent_comment_id: &str,
+    content: String,
+) -> Result<(GutpComment, GutpPost), ForumError> {
+    let gutp = &app_state.gutp;
+    let action = format!("Query Article: {}", post_id);
+    let post = gutp
+        .posts()
+        .by_id(post_id)
+        .await
+        .map_err(ForumError::gutp(&action))?
+        .ok_or_else(|| {
+            ForumError::not_found(
+                &action,
+                "Article doesn't exist, comment couldn't be added to it!",
+            )
+        })?;
+    permission::ensure_view_post(app_state, Some(logged_user), &post).await?;
+    Permission::site(app_state, Some(logged_user)).ensure(
+        Action::CreateComment,
+        "",
+        format!("Comment on article: {}", post.id),
+    )?;
+
+    if !parent_comment_id.is_empty() {
+        query_parent(app_state, &post, parent_comment_id).await?;
     }
+
+    // retreive author info
+    let action = format!("Query author: {}", logged_user.user_id);
+    let author = gutp
+        .users()
+        .by_id(&logged_user.user_id)
+        .await
+        .map_err(ForumError::gutp(&action))?
+        .ok_or_else(|| ForumError::not_found(&action, "Unknown"))?;
+
+    let inner_params = NewComment {
+        content,
+        author_id: author.id.to_owned(),
+        author_nickname: author.nickname.to_owned(),
+        post_id: post.id.to_owned(),
+        parent_comment_id: parent_comment_id.to_string(),
+        is_public: true,
+    };
+
+    let comment = gutp
+        .comments()
+        .create(&inner_params)
+        .await
+        .map_err(ForumError::gutp(format!(
+            "Create comment for article: {}",
+            &post.id
+        )))?;
+    search::index_comment(app_state, &post, &comment).await;
+
+    Ok((comment, post))
 }
 
 #[derive(Template)]
 #[template(path = "comment_delete.html")]
 struct CommentDeleteTemplate {
     comment: GutpComment,
+    csrf_token: String,
 }
 
 #[derive(Deserialize)]
@@ -126,53 +286,345 @@ pub struct ViewCommentDeleteParams {
 }
 
 pub async fn view_comment_delete(
+    State(app_state): State<AppState>,
     logged_user: Option<Extension<LoggedUser>>,
     Query(params): Query<ViewCommentDeleteParams>,
-) -> impl IntoResponse {
+) -> Result<impl IntoResponse, ForumError> {
     // check the user login status
-    if logged_user.is_none() {
-        let action = format!("Not logged in");
-        let err_info = "Need login firstly to get proper permission.";
-        return redirect_to_error_page(&action, err_info).into_response();
-    }
+    let logged_user = require_login(logged_user)?;
 
-    let inner_params = [("id", &params.id)];
-    let comments: Vec<GutpComment> = make_get("/v1/comment", &inner_params)
-        .await
-        .unwrap_or(vec![]);
-    if let Some(comment) = comments.into_iter().next() {
-        HtmlTemplate(CommentDeleteTemplate { comment }).into_response()
-    } else {
-        let action = format!("Query comment: {}", &params.id);
-        let err_info = "Comment doesn't exist!";
-        redirect_to_error_page(&action, err_info).into_response()
-    }
+    let (comment, _) = guarded_comment(
+        &app_state,
+        &logged_user,
+        &params.id,
+        Action::DeleteComment,
+        "Delete",
+    )
+    .await?;
+
+    Ok(HtmlTemplate(CommentDeleteTemplate {
+        comment,
+        csrf_token: logged_user.csrf_token,
+    }))
 }
 
 #[derive(Deserialize)]
 pub struct PostCommentDeleteParams {
     id: String,
-    post_id: String,
 }
 
 pub async fn post_comment_delete(
+    State(app_state): State<AppState>,
     logged_user: Option<Extension<LoggedUser>>,
     Form(params): Form<PostCommentDeleteParams>,
-) -> Redirect {
+) -> Result<Redirect, ForumError> {
+    // check the user login status
+    let logged_user = require_login(logged_user)?;
+
+    let (_, post) = delete_comment(&app_state, &logged_user, &params.id).await?;
+
+    Ok(Redirect::to(&slug::article_path(&post)))
+}
+
+/// Delete a comment, by its author or a moderator, returns what it was
+pub async fn delete_comment(
+    app_state: &AppState,
+    logged_user: &LoggedUser,
+    id: &str,
+) -> Result<(GutpComment, GutpPost), ForumError> {
+    let (comment, post) =
+        guarded_comment(app_state, logged_user, id, Action::DeleteComment, "Delete").await?;
+    app_state
+        .gutp
+        .comments()
+        .delete(&comment.id)
+        .await
+        .map_err(ForumError::gutp(format!("Delete comment: {}", comment.id)))?;
+    search::remove_comment(app_state, &comment.id);
+
+    Ok((comment, post))
+}
+
+#[derive(Template)]
+#[template(path = "comment_edit.html")]
+struct CommentEditTemplate {
+    comment: GutpComment,
+    post: GutpPost,
+    csrf_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct ViewCommentEditParams {
+    id: String,
+}
+
+pub async fn view_comment_edit(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    Query(params): Query<ViewCommentEditParams>,
+) -> Result<impl IntoResponse, ForumError> {
     // check the user login status
-    if logged_user.is_none() {
-        let action = format!("Not logged in");
-        let err_info = "Need login firstly to get proper permission.";
-        return redirect_to_error_page(&action, err_info);
+    let logged_user = require_login(logged_user)?;
+
+    let (comment, post) = guarded_comment(
+        &app_state,
+        &logged_user,
+        &params.id,
+        Action::EditComment,
+        "Edit",
+    )
+    .await?;
+
+    Ok(HtmlTemplate(CommentEditTemplate {
+        comment,
+        post,
+        csrf_token: logged_user.csrf_token,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct PostCommentEditParams {
+    id: String,
+    content: String,
+}
+
+pub async fn post_comment_edit(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    Form(params): Form<PostCommentEditParams>,
+) -> Result<Redirect, ForumError> {
+    // check the user login status
+    let logged_user = require_login(logged_user)?;
+
+    let (comment, post) =
+        edit_comment(&app_state, &logged_user, &params.id, params.content).await?;
+
+    let redirect_uri = format!("{}#comment-{}", slug::article_path(&post), comment.id);
+    Ok(Redirect::to(&redirect_uri))
+}
+
+/// Edit a comment, by its author or a moderator. The old body is kept for review
+pub async fn edit_comment(
+    app_state: &AppState,
+    logged_user: &LoggedUser,
+    id: &str,
+    content: String,
+) -> Result<(GutpComment, GutpPost), ForumError> {
+    let (comment, post) =
+        guarded_comment(app_state, logged_user, id, Action::EditComment, "Edit").await?;
+    if content == comment.content {
+        return Ok((comment, post));
     }
 
-    let inner_params = [("id", &params.id)];
-    let _comments: Vec<GutpComment> = make_post("/v1/comment/delete", &inner_params)
+    // the old body is kept before it's replaced, so nothing is lost if the update fails
+    let action = format!("Edit comment: {}", comment.id);
+    let revision = CommentRevision {
+        content: comment.content.to_owned(),
+        editor_id: logged_user.user_id.to_owned(),
+        edited_at: chrono::Utc::now().timestamp(),
+    };
+    let mut redis_conn = session_conn(app_state).await?;
+    app_state
+        .comment_history
+        .record(&mut redis_conn, &comment.id, &revision)
+        .await
+        .map_err(|err| ForumError::Internal(action.clone(), err.to_string()))?;
+
+    let inner_params = CommentUpdate {
+        id: comment.id.to_owned(),
+        content,
+        is_public: comment.is_public,
+    };
+    let comment = app_state
+        .gutp
+        .comments()
+        .update(&inner_params)
+        .await
+        .map_err(ForumError::gutp(&action))?;
+    search::index_comment(app_state, &post, &comment).await;
+
+    Ok((comment, post))
+}
+
+#[derive(Template)]
+#[template(path = "comment_history.html")]
+struct CommentHistoryTemplate {
+    comment: GutpComment,
+    post: GutpPost,
+    // the newest first
+    revisions: Vec<CommentRevision>,
+}
+
+#[derive(Deserialize)]
+pub struct ViewCommentHistoryParams {
+    id: String,
+}
+
+pub async fn view_comment_history(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    Query(params): Query<ViewCommentHistoryParams>,
+) -> Result<impl IntoResponse, ForumError> {
+    // check the user login status
+    let logged_user = require_login(logged_user)?;
+
+    let (comment, post) = guarded_comment(
+        &app_state,
+        &logged_user,
+        &params.id,
+        Action::ReviewComment,
+        "Review",
+    )
+    .await?;
+
+    let mut redis_conn = session_conn(&app_state).await?;
+    let mut revisions = app_state
+        .comment_history
+        .list(&mut redis_conn, &comment.id)
         .await
-        .unwrap_or(vec![]);
+        .map_err(|err| {
+            ForumError::Internal(
+                format!("Query history of comment: {}", comment.id),
+                err.to_string(),
+            )
+        })?;
+    revisions.reverse();
 
-    // TODO: process the error branch of deleting
+    Ok(HtmlTemplate(CommentHistoryTemplate {
+        comment,
+        post,
+        revisions,
+    }))
+}
 
-    let redirect_uri = format!("/article?id={}", &params.post_id);
-    Redirect::to(&redirect_uri)
+// the comment, and its article, if the user can do `action` on it
+async fn guarded_comment(
+    app_state: &AppState,
+    logged_user: &LoggedUser,
+    id: &str,
+    action: Action,
+    verb: &str,
+) -> Result<(GutpComment, GutpPost), ForumError> {
+    let gutp = &app_state.gutp;
+    let what = format!("Query comment: {}", id);
+    let comment = gutp
+        .comments()
+        .by_id(id)
+        .await
+        .map_err(ForumError::gutp(&what))?
+        .ok_or_else(|| ForumError::not_found(&what, "Comment doesn't exist!"))?;
+
+    let what = format!("Query Article: {}", &comment.post_id);
+    let post = gutp
+        .posts()
+        .by_id(&comment.post_id)
+        .await
+        .map_err(ForumError::gutp(&what))?
+        .ok_or_else(|| ForumError::not_found(&what, "Article doesn't exist!"))?;
+
+    Permission::for_post(app_state, Some(logged_user), &post)
+        .await?
+        .ensure(
+            action,
+            &comment.author_id,
+            format!("{} comment: {}", verb, comment.id),
+        )?;
+
+    Ok((comment, post))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn comment(id: &str, parent_id: &str, created_time: i64) -> GutpComment {
+        GutpComment {
+            id: id.to_string(),
+            parent_comment_id: parent_id.to_string(),
+            created_time,
+            ..Default::default()
+        }
+    }
+
+    fn continue_url(c: &GutpComment) -> String {
+        format!("?thread={}", c.id)
+    }
+
+    // (id, depth, continue_url) in display order
+    fn shape(thread: &[ThreadedComment]) -> Vec<(&str, usize, Option<&str>)> {
+        thread
+            .iter()
+            .map(|t| (t.comment.id.as_str(), t.depth, t.continue_url.as_deref()))
+            .collect()
+    }
+
+    // a chain of replies: c0 <- c1 <- c2 ...
+    fn chain(len: usize) -> Vec<GutpComment> {
+        (0..len)
+            .map(|i| {
+                let parent = if i == 0 {
+                    String::new()
+                } else {
+                    format!("c{}", i - 1)
+                };
+                comment(&format!("c{}", i), &parent, i as i64)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn replies_in_order() {
+        let comments = vec![
+            comment("b", "", 2),
+            comment("a1", "a", 3),
+            comment("a", "", 1),
+            comment("b1", "b", 4),
+        ];
+        let thread = build_thread(comments, None, continue_url);
+        assert_eq!(
+            shape(&thread),
+            vec![
+                ("a", 0, None),
+                ("a1", 1, None),
+                ("b", 0, None),
+                ("b1", 1, None)
+            ]
+        );
+    }
+
+    #[test]
+    fn deeper_replies_are_continued() {
+        let thread = build_thread(chain(MAX_THREAD_DEPTH + 2), None, continue_url);
+        let last = MAX_THREAD_DEPTH - 1;
+        // the chain stops at the cap, the last one shown links to the rest
+        assert_eq!(thread.len(), MAX_THREAD_DEPTH);
+        assert_eq!(thread[last].depth, last);
+        assert_eq!(
+            thread[last].continue_url.as_deref(),
+            Some(format!("?thread=c{}", last).as_str())
+        );
+        assert!(thread[..last].iter().all(|t| t.continue_url.is_none()));
+    }
+
+    #[test]
+    fn reply_to_a_missing_parent_is_top_level() {
+        let comments = vec![comment("a", "", 1), comment("orphan", "deleted", 2)];
+        let thread = build_thread(comments, None, continue_url);
+        assert_eq!(shape(&thread), vec![("a", 0, None), ("orphan", 0, None)]);
+    }
+
+    #[test]
+    fn thread_is_rerooted() {
+        let mut comments = chain(5);
+        comments.push(comment("other", "", 100));
+        let thread = build_thread(comments, Some("c2"), continue_url);
+        // only the thread under the root, from depth 0 again
+        assert_eq!(
+            shape(&thread),
+            vec![("c2", 0, None), ("c3", 1, None), ("c4", 2, None)]
+        );
+
+        // an unknown root is an empty thread
+        assert!(build_thread(chain(3), Some("nope"), continue_url).is_empty());
+    }
 }
@@ -0,0 +1,181 @@
+use redis::{aio::ConnectionLike, AsyncCommands, RedisResult};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// the older bodies of a comment edited more than this are dropped
+const MAX_REVISIONS: isize = 50;
+
+/// A body a comment had before an edit
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommentRevision {
+    pub content: String,
+    pub editor_id: String,
+    /// when it was replaced
+    pub edited_at: i64,
+}
+
+/// The prior bodies of the edited comments in redis, kept for the
+/// moderators, they don't expire. Gutp only keeps the current body.
+///
+/// Keys:
+/// - `{appid}_comment_history:{comment_id}`, a list of revisions as json, the oldest first,
+///   the last `MAX_REVISIONS` of them
+/// - `{appid}_comment_edited`, a hash of comment id -> the time of the last edit
+pub struct CommentHistoryStore {
+    prefix: String,
+}
+
+impl CommentHistoryStore {
+    pub fn new(appid: &str) -> Self {
+        CommentHistoryStore {
+            prefix: appid.to_string(),
+        }
+    }
+
+    fn history_key(&self, comment_id: &str) -> String {
+        format!("{}_comment_history:{}", self.prefix, comment_id)
+    }
+
+    fn edited_key(&self) -> String {
+        format!("{}_comment_edited", self.prefix)
+    }
+
+    /// Keep the body being replaced, and mark the comment as edited
+    pub async fn record<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        comment_id: &str,
+        revision: &CommentRevision,
+    ) -> RedisResult<()> {
+        let json = serde_json::to_string(revision).unwrap_or_default();
+        redis::pipe()
+            .atomic()
+            .rpush(self.history_key(comment_id), json)
+            .ignore()
+            .ltrim(self.history_key(comment_id), -MAX_REVISIONS, -1)
+            .ignore()
+            .hset(self.edited_key(), comment_id, revision.edited_at)
+            .ignore()
+            .query_async::<_, ()>(conn)
+            .await
+    }
+
+    /// The prior bodies of a comment, the oldest first
+    pub async fn list<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        comment_id: &str,
+    ) -> RedisResult<Vec<CommentRevision>> {
+        let items: Vec<String> = conn.lrange(self.history_key(comment_id), 0, -1).await?;
+        Ok(items
+            .iter()
+            .filter_map(|item| serde_json::from_str(item).ok())
+            .collect())
+    }
+
+    /// The last edit time of the comments which were edited
+    pub async fn edited_at<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        comment_ids: &[String],
+    ) -> RedisResult<HashMap<String, i64>> {
+        if comment_ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        // HMGET answers a list even for a single field
+        let times: Vec<Option<i64>> = redis::cmd("HMGET")
+            .arg(self.edited_key())
+            .arg(comment_ids)
+            .query_async(conn)
+            .await?;
+        Ok(comment_ids
+            .iter()
+            .zip(times)
+            .filter_map(|(id, time)| time.map(|time| (id.to_owned(), time)))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_redis::FakeRedis;
+
+    fn revision(content: &str, edited_at: i64) -> CommentRevision {
+        CommentRevision {
+            content: content.to_string(),
+            editor_id: "alice".to_string(),
+            edited_at,
+        }
+    }
+
+    fn contents(revisions: &[CommentRevision]) -> Vec<&str> {
+        revisions.iter().map(|r| r.content.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn revisions_are_listed_the_oldest_first() {
+        let (store, mut redis) = (CommentHistoryStore::new("test"), FakeRedis::default());
+        assert!(store.list(&mut redis, "c1").await.unwrap().is_empty());
+
+        for (i, content) in ["first", "second", "third"].iter().enumerate() {
+            store
+                .record(&mut redis, "c1", &revision(content, i as i64))
+                .await
+                .unwrap();
+        }
+        store
+            .record(&mut redis, "c2", &revision("other", 9))
+            .await
+            .unwrap();
+
+        let revisions = store.list(&mut redis, "c1").await.unwrap();
+        assert_eq!(contents(&revisions), ["first", "second", "third"]);
+        assert_eq!(revisions[0].editor_id, "alice");
+        assert_eq!(revisions[2].edited_at, 2);
+    }
+
+    #[tokio::test]
+    async fn only_the_last_revisions_are_kept() {
+        let (store, mut redis) = (CommentHistoryStore::new("test"), FakeRedis::default());
+        let edits = MAX_REVISIONS as i64 + 3;
+        for i in 0..edits {
+            store
+                .record(&mut redis, "c1", &revision(&i.to_string(), i))
+                .await
+                .unwrap();
+        }
+
+        let revisions = store.list(&mut redis, "c1").await.unwrap();
+        assert_eq!(revisions.len(), MAX_REVISIONS as usize);
+        assert_eq!(revisions[0].content, "3");
+        assert_eq!(revisions.last().unwrap().edited_at, edits - 1);
+    }
+
+    #[tokio::test]
+    async fn edited_at_is_the_last_edit() {
+        let (store, mut redis) = (CommentHistoryStore::new("test"), FakeRedis::default());
+        let ids = ["c1".to_string(), "c2".to_string(), "c3".to_string()];
+        assert!(store.edited_at(&mut redis, &ids).await.unwrap().is_empty());
+        assert!(store.edited_at(&mut redis, &[]).await.unwrap().is_empty());
+
+        store
+            .record(&mut redis, "c1", &revision("a", 10))
+            .await
+            .unwrap();
+        store
+            .record(&mut redis, "c1", &revision("b", 20))
+            .await
+            .unwrap();
+        store
+            .record(&mut redis, "c3", &revision("c", 30))
+            .await
+            .unwrap();
+
+        let edited = store.edited_at(&mut redis, &ids).await.unwrap();
+        assert_eq!(edited.len(), 2);
+        assert_eq!(edited["c1"], 20);
+        assert_eq!(edited["c3"], 30);
+        assert!(!edited.contains_key("c2"));
+    }
+}
@@ -0,0 +1,490 @@
+use serde::Deserialize;
+use std::fmt;
+use std::net::SocketAddr;
+
+/// Where the config file is looked for, unless `DISCUX_CONFIG` says otherwise
+pub const DEFAULT_CONFIG_PATH: &str = "tomls/config.toml";
+
+// ten years, the ttl in seconds then fits redis and the cookie max-age
+const MAX_SESSION_TTL_DAYS: usize = 3650;
+
+/// Runtime configuration of the forum binary.
+///
+/// The values are layered: built-in defaults, then the toml file, then
+/// environment variables (`.env` included), the later one wins.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub listen_addr: String,
+    /// the public url of the site, the oauth providers redirect back to it
+    pub site_url: String,
+    pub redis_url: String,
+    pub gutp_host: String,
+    pub appid: String,
+    pub profession: String,
+    /// user ids of the site admins
+    pub admins: Vec<String>,
+    /// an idle session expires after this many days
+    pub session_ttl_days: usize,
+    /// where the uploaded images are saved, served under `/uploads`
+    pub upload_dir: String,
+    /// where the full-text search index is kept
+    pub search_dir: String,
+    /// the language of the pages when neither the visitor nor the browser picks one
+    pub default_locale: String,
+    /// the timezone of the dates when the user hasn't picked one, e.g. "Asia/Shanghai"
+    pub timezone: String,
+    /// the oauth login providers, the ones without client_id are disabled
+    pub github: ProviderConfig,
+    pub gitlab: ProviderConfig,
+    pub gitee: ProviderConfig,
+    pub local_auth: LocalAuthConfig,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ProviderConfig {
+    pub client_id: String,
+    pub client_secret: String,
+    /// the web root of the provider, for a self-hosted instance. Empty for the default one
+    pub base_url: String,
+}
+
+/// The username/password login, for the instances without oauth
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LocalAuthConfig {
+    pub enabled: bool,
+    /// whether the visitors can register by themselves
+    pub allow_signup: bool,
+}
+
+impl Default for LocalAuthConfig {
+    fn default() -> Self {
+        LocalAuthConfig {
+            enabled: false,
+            allow_signup: true,
+        }
+    }
+}
+
+impl ProviderConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.client_id.is_empty()
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen_addr: "127.0.0.1:3333".to_string(),
+            site_url: "http://127.0.0.1:3333".to_string(),
+            redis_url: "redis://127.0.0.1/".to_string(),
+            gutp_host: "http://127.0.0.1:3000".to_string(),
+            appid: "discux".to_string(),
+            profession: "it".to_string(),
+            admins: vec![],
+            session_ttl_days: 60,
+            upload_dir: "uploads".to_string(),
+            search_dir: "search_index".to_string(),
+            default_locale: "en".to_string(),
+            timezone: "Asia/Shanghai".to_string(),
+            github: ProviderConfig::default(),
+            gitlab: ProviderConfig::default(),
+            gitee: ProviderConfig::default(),
+            local_auth: LocalAuthConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(String, std::io::Error),
+    Parse(String, toml::de::Error),
+    Invalid(&'static str, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, err) => write!(f, "couldn't read config {}: {}", path, err),
+            ConfigError::Parse(path, err) => write!(f, "couldn't parse config {}: {}", path, err),
+            ConfigError::Invalid(key, reason) => write!(f, "invalid config `{}`: {}", key, reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Load the config file (if any), apply the env overrides and validate the result
+    pub fn load() -> Result<Self, ConfigError> {
+        let explicit_path = dotenv::var("DISCUX_CONFIG").ok();
+        let path = explicit_path
+            .clone()
+            .unwrap_or_else(|| DEFAULT_CONFIG_PATH.to_string());
+
+        let mut config = match std::fs::read_to_string(&path) {
+            Ok(content) => Self::from_toml(&path, &content)?,
+            // the default file is optional, an explicitly given one isn't
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound && explicit_path.is_none() => {
+                Config::default()
+            }
+            Err(err) => return Err(ConfigError::Read(path, err)),
+        };
+
+        config.apply_env(|key| dotenv::var(key).ok())?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn from_toml(path: &str, content: &str) -> Result<Self, ConfigError> {
+        toml::from_str(content).map_err(|err| ConfigError::Parse(path.to_string(), err))
+    }
+
+    /// `var` looks up an environment variable
+    fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<(), ConfigError> {
+        let overrides: [(&str, &mut String); 17] = [
+            ("DISCUX_LISTEN_ADDR", &mut self.listen_addr),
+            ("DISCUX_SITE_URL", &mut self.site_url),
+            ("DISCUX_REDIS_URL", &mut self.redis_url),
+            ("GUTP_HOST", &mut self.gutp_host),
+            ("DISCUX_APPID", &mut self.appid),
+            ("DISCUX_PROFESSION", &mut self.profession),
+            ("DISCUX_UPLOAD_DIR", &mut self.upload_dir),
+            ("DISCUX_SEARCH_DIR", &mut self.search_dir),
+            ("DISCUX_DEFAULT_LOCALE", &mut self.default_locale),
+            ("DISCUX_TIMEZONE", &mut self.timezone),
+            ("GITHUB_APP_CLIENT_ID", &mut self.github.client_id),
+            ("GITHUB_APP_CLIENT_SECRET", &mut self.github.client_secret),
+            ("GITLAB_APP_CLIENT_ID", &mut self.gitlab.client_id),
+            ("GITLAB_APP_CLIENT_SECRET", &mut self.gitlab.client_secret),
+            ("GITLAB_BASE_URL", &mut self.gitlab.base_url),
+            ("GITEE_APP_CLIENT_ID", &mut self.gitee.client_id),
+            ("GITEE_APP_CLIENT_SECRET", &mut self.gitee.client_secret),
+        ];
+        for (key, field) in overrides {
+            if let Some(value) = var(key) {
+                *field = value;
+            }
+        }
+        let flags: [(&str, &mut bool); 2] = [
+            ("DISCUX_LOCAL_AUTH", &mut self.local_auth.enabled),
+            ("DISCUX_ALLOW_SIGNUP", &mut self.local_auth.allow_signup),
+        ];
+        for (key, field) in flags {
+            if let Some(value) = var(key) {
+                *field = matches!(value.trim(), "1" | "true" | "yes" | "on");
+            }
+        }
+        // comma separated user ids
+        if let Some(value) = var("DISCUX_ADMINS") {
+            self.admins = value
+                .split(',')
+                .map(|id| id.trim().to_string())
+                .filter(|id| !id.is_empty())
+                .collect();
+        }
+        if let Some(value) = var("DISCUX_SESSION_TTL_DAYS") {
+            self.session_ttl_days = value.trim().parse().map_err(|_| {
+                ConfigError::Invalid(
+                    "session_ttl_days",
+                    format!("DISCUX_SESSION_TTL_DAYS={} isn't a number of days", value),
+                )
+            })?;
+        }
+
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        self.socket_addr()?;
+
+        if !self.redis_url.starts_with("redis://") && !self.redis_url.starts_with("rediss://") {
+            return Err(ConfigError::Invalid(
+                "redis_url",
+                format!("{} isn't a redis:// url", self.redis_url),
+            ));
+        }
+        if !self.site_url.starts_with("http://") && !self.site_url.starts_with("https://") {
+            return Err(ConfigError::Invalid(
+                "site_url",
+                format!("{} isn't a http(s) url", self.site_url),
+            ));
+        }
+        if !self.gutp_host.starts_with("http://") && !self.gutp_host.starts_with("https://") {
+            return Err(ConfigError::Invalid(
+                "gutp_host",
+                format!("{} isn't a http(s) url", self.gutp_host),
+            ));
+        }
+        // the appid is part of redis keys and cookie names
+        if self.appid.is_empty()
+            || !self
+                .appid
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Err(ConfigError::Invalid(
+                "appid",
+                "must be non-empty ascii letters, digits or _".to_string(),
+            ));
+        }
+        if !(1..=MAX_SESSION_TTL_DAYS).contains(&self.session_ttl_days) {
+            return Err(ConfigError::Invalid(
+                "session_ttl_days",
+                format!("must be between 1 and {}", MAX_SESSION_TTL_DAYS),
+            ));
+        }
+        if self.upload_dir.is_empty() {
+            return Err(ConfigError::Invalid(
+                "upload_dir",
+                "must not be empty".to_string(),
+            ));
+        }
+        if !crate::i18n::is_supported(&self.default_locale) {
+            return Err(ConfigError::Invalid(
+                "default_locale",
+                format!("{} isn't one of the locales in i18n", self.default_locale),
+            ));
+        }
+        if crate::datetime::parse_timezone(&self.timezone).is_none() {
+            return Err(ConfigError::Invalid(
+                "timezone",
+                format!("{} isn't an IANA timezone name", self.timezone),
+            ));
+        }
+        if self.profession.is_empty() {
+            return Err(ConfigError::Invalid(
+                "profession",
+                "must not be empty".to_string(),
+            ));
+        }
+        let providers = [
+            ("github", &self.github),
+            ("gitlab", &self.gitlab),
+            ("gitee", &self.gitee),
+        ];
+        for (key, provider) in providers {
+            if provider.is_enabled() && provider.client_secret.is_empty() {
+                return Err(ConfigError::Invalid(
+                    key,
+                    "client_secret is required with client_id".to_string(),
+                ));
+            }
+            if !provider.base_url.is_empty()
+                && !provider.base_url.starts_with("http://")
+                && !provider.base_url.starts_with("https://")
+            {
+                return Err(ConfigError::Invalid(
+                    key,
+                    format!("base_url {} isn't a http(s) url", provider.base_url),
+                ));
+            }
+        }
+        if !self.local_auth.enabled && !providers.iter().any(|(_, provider)| provider.is_enabled())
+        {
+            return Err(ConfigError::Invalid(
+                "github",
+                "enable local_auth or give at least one login provider a client_id".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn socket_addr(&self) -> Result<SocketAddr, ConfigError> {
+        self.listen_addr
+            .parse()
+            .map_err(|_| ConfigError::Invalid("listen_addr", self.listen_addr.to_string()))
+    }
+
+    /// A path of the site as a full url, e.g. for the feeds
+    pub fn absolute_url(&self, path: &str) -> String {
+        format!("{}{}", self.site_url.trim_end_matches('/'), path)
+    }
+
+    /// The idle session ttl in seconds, `session_ttl_days` is bounded by `validate`
+    pub fn session_ttl(&self) -> usize {
+        self.session_ttl_days * 24 * 3600
+    }
+
+    pub fn is_admin(&self, user_id: &str) -> bool {
+        self.admins.iter().any(|id| id == user_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const TOML: &str = r#"
+listen_addr = "0.0.0.0:8080"
+appid = "forum"
+session_ttl_days = 30
+
+[local_auth]
+enabled = true
+
+[github]
+client_id = "toml_id"
+client_secret = "toml_secret"
+"#;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        move |key: &str| vars.get(key).cloned()
+    }
+
+    fn valid() -> Config {
+        Config {
+            local_auth: LocalAuthConfig {
+                enabled: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn invalid_key(config: &Config) -> &'static str {
+        match config.validate() {
+            Err(ConfigError::Invalid(key, _)) => key,
+            other => panic!("expected an invalid key, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn env_overrides_the_file_which_overrides_the_defaults() {
+        let mut config = Config::from_toml("config.toml", TOML).unwrap();
+        config
+            .apply_env(env(&[
+                ("DISCUX_APPID", "from_env"),
+                ("GITHUB_APP_CLIENT_SECRET", "env_secret"),
+            ]))
+            .unwrap();
+
+        // the defaults
+        assert_eq!(config.redis_url, "redis://127.0.0.1/");
+        assert_eq!(config.default_locale, "en");
+        // the file
+        assert_eq!(config.listen_addr, "0.0.0.0:8080");
+        assert_eq!(config.session_ttl_days, 30);
+        assert_eq!(config.github.client_id, "toml_id");
+        // the env
+        assert_eq!(config.appid, "from_env");
+        assert_eq!(config.github.client_secret, "env_secret");
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn env_values_are_parsed() {
+        let mut config = Config::default();
+        config
+            .apply_env(env(&[
+                ("DISCUX_LOCAL_AUTH", "yes"),
+                ("DISCUX_ALLOW_SIGNUP", "0"),
+                ("DISCUX_ADMINS", " u1, ,u2 ,"),
+                ("DISCUX_SESSION_TTL_DAYS", " 7 "),
+            ]))
+            .unwrap();
+        assert!(config.local_auth.enabled);
+        assert!(!config.local_auth.allow_signup);
+        assert_eq!(config.admins, ["u1", "u2"]);
+        assert!(config.is_admin("u2") && !config.is_admin(""));
+        assert_eq!(config.session_ttl(), 7 * 24 * 3600);
+
+        let err = Config::default()
+            .apply_env(env(&[("DISCUX_SESSION_TTL_DAYS", "a week")]))
+            .unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid("session_ttl_days", _)));
+    }
+
+    #[test]
+    fn a_bad_file_is_a_parse_error() {
+        let err = Config::from_toml("config.toml", "session_ttl_days = \"60\"").unwrap_err();
+        assert!(matches!(err, ConfigError::Parse(..)));
+    }
+
+    #[test]
+    fn validation_names_the_bad_key() {
+        // a fresh config has no login at all
+        assert_eq!(invalid_key(&Config::default()), "github");
+        assert!(valid().validate().is_ok());
+
+        let cases = [
+            (
+                Config {
+                    listen_addr: "localhost".to_string(),
+                    ..valid()
+                },
+                "listen_addr",
+            ),
+            (
+                Config {
+                    redis_url: "http://127.0.0.1".to_string(),
+                    ..valid()
+                },
+                "redis_url",
+            ),
+            (
+                Config {
+                    appid: "my-forum".to_string(),
+                    ..valid()
+                },
+                "appid",
+            ),
+            (
+                Config {
+                    session_ttl_days: 0,
+                    ..valid()
+                },
+                "session_ttl_days",
+            ),
+            (
+                Config {
+                    session_ttl_days: MAX_SESSION_TTL_DAYS + 1,
+                    ..valid()
+                },
+                "session_ttl_days",
+            ),
+            (
+                Config {
+                    default_locale: "fr".to_string(),
+                    ..valid()
+                },
+                "default_locale",
+            ),
+            (
+                Config {
+                    timezone: "Mars/Olympus".to_string(),
+                    ..valid()
+                },
+                "timezone",
+            ),
+            (
+                Config {
+                    gitee: ProviderConfig {
+                        client_id: "id".to_string(),
+                        ..Default::default()
+                    },
+                    ..valid()
+                },
+                "gitee",
+            ),
+        ];
+        for (config, key) in cases {
+            assert_eq!(invalid_key(&config), key);
+        }
+
+        let longest = Config {
+            session_ttl_days: MAX_SESSION_TTL_DAYS,
+            ..valid()
+        };
+        assert!(longest.validate().is_ok());
+    }
+}
@@ -0,0 +1,358 @@
+use axum::{
+    body::{Body, Bytes},
+    extract::{FromRequest, Multipart, State},
+    http::{header, HeaderMap, HeaderValue, Method, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http_body::{LengthLimitError, Limited};
+use serde::Deserialize;
+use std::sync::Arc;
+
+use crate::api::{self, ApiError};
+use crate::api_token::BEARER_PREFIX;
+use crate::error::ForumError;
+use crate::LoggedUser;
+
+// a form post larger than this won't be buffered for the check
+const MAX_FORM_SIZE: usize = 2 * 1024 * 1024;
+
+pub fn new_token() -> String {
+    let x = rand::random::<[u8; 32]>();
+    sha256::digest(&x).to_lowercase()
+}
+
+// every form carries the token in a hidden `csrf_token` input
+#[derive(Deserialize)]
+struct CsrfForm {
+    csrf_token: Option<String>,
+}
+
+/// The scheme, host and port of the site, as the browsers send it in `Origin`
+#[derive(Clone, Debug, PartialEq)]
+pub struct SiteOrigin(Arc<str>);
+
+impl SiteOrigin {
+    /// From the `site_url` of the config, or any url of the site
+    pub fn of(url: &str) -> Self {
+        let origin = match url.find("://") {
+            Some(start) => match url[start + 3..].find('/') {
+                Some(end) => &url[..start + 3 + end],
+                None => url,
+            },
+            None => url,
+        };
+        SiteOrigin(origin.to_lowercase().into())
+    }
+
+    // the Origin header, or the Referer when a browser leaves it out
+    fn sent_in(&self, headers: &HeaderMap) -> bool {
+        headers
+            .get(header::ORIGIN)
+            .or_else(|| headers.get(header::REFERER))
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|origin| SiteOrigin::of(origin) == *self)
+    }
+}
+
+/// Reject the state-changing requests of a logged in user which don't carry
+/// the csrf token of their session, and the anonymous ones posted from
+/// another site.
+///
+/// Must be layered inside `top_middleware`, it relies on the `LoggedUser` extension.
+pub async fn csrf_middleware(
+    State(site_origin): State<SiteOrigin>,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let path = req.uri().path().to_string();
+    match verify(req, &site_origin).await {
+        Ok(req) => next.run(req).await,
+        Err(err) => reject(&path, err),
+    }
+}
+
+// the request to pass on, with its body put back
+async fn verify(req: Request<Body>, site_origin: &SiteOrigin) -> Result<Request<Body>, ForumError> {
+    if is_safe_method(req.method()) {
+        return Ok(req);
+    }
+    // a bearer token isn't sent by the browser on its own, nothing to forge
+    if is_api_path(req.uri().path()) && has_bearer_token(&req) {
+        return Ok(req);
+    }
+    let action = format!("Verify form token of {}", req.uri().path());
+    let Some(expected) = req
+        .extensions()
+        .get::<LoggedUser>()
+        .map(|u| u.csrf_token.to_owned())
+    else {
+        // without a session there is no token yet. A forged login would sign
+        // the visitor in to another account, so only the forms of this site
+        // are taken. The anonymous api calls are rejected by the handlers
+        if is_api_path(req.uri().path()) || site_origin.sent_in(req.headers()) {
+            return Ok(req);
+        }
+        println!("in csrf_middleware: cross-site post to {}", req.uri());
+        return Err(ForumError::Forbidden(action));
+    };
+
+    let too_large = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok())
+        .is_some_and(|len| len > MAX_FORM_SIZE);
+    if too_large {
+        return Err(form_too_large(action));
+    }
+
+    // the body is consumed here, and put back for the handler. A chunked
+    // body has no length, so it's cut at the limit while read
+    let (parts, body) = req.into_parts();
+    let bytes = match hyper::body::to_bytes(Limited::new(body, MAX_FORM_SIZE)).await {
+        Ok(bytes) => bytes,
+        Err(err) if err.is::<LengthLimitError>() => return Err(form_too_large(action)),
+        Err(err) => return Err(ForumError::BadRequest(action, err.to_string())),
+    };
+
+    let header_token = parts
+        .headers
+        .get("x-csrf-token")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string());
+    let token = match header_token {
+        Some(token) => Some(token),
+        None => match parts.headers.get(header::CONTENT_TYPE) {
+            // the multipart forms (uploads) carry it in their first part
+            Some(content_type) if is_multipart(content_type) => {
+                multipart_token(content_type, bytes.clone()).await
+            }
+            _ => serde_urlencoded::from_bytes::<CsrfForm>(&bytes)
+                .ok()
+                .and_then(|form| form.csrf_token),
+        },
+    };
+
+    match token {
+        Some(token) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => {
+            Ok(Request::from_parts(parts, Body::from(bytes)))
+        }
+        _ => {
+            println!("in csrf_middleware: token mismatch on {}", parts.uri);
+            Err(ForumError::Forbidden(action))
+        }
+    }
+}
+
+fn is_multipart(content_type: &HeaderValue) -> bool {
+    content_type
+        .to_str()
+        .is_ok_and(|v| v.starts_with("multipart/form-data"))
+}
+
+// the form puts the hidden `csrf_token` input before the file, so only the
+// first part is read
+async fn multipart_token(content_type: &HeaderValue, bytes: Bytes) -> Option<String> {
+    let req = Request::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .body(Body::from(bytes))
+        .ok()?;
+    let mut multipart = Multipart::from_request(req, &()).await.ok()?;
+    let field = multipart.next_field().await.ok()??;
+    if field.name() != Some("csrf_token") {
+        return None;
+    }
+    field.text().await.ok()
+}
+
+fn form_too_large(action: String) -> ForumError {
+    ForumError::PayloadTooLarge(action, "The form is too large.".to_string())
+}
+
+fn is_api_path(path: &str) -> bool {
+    path.starts_with(api::API_PREFIX)
+}
+
+fn has_bearer_token(req: &Request<Body>) -> bool {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with(BEARER_PREFIX))
+}
+
+// the api callers get the json error body, like from the api handlers
+fn reject(path: &str, err: ForumError) -> Response {
+    if is_api_path(path) {
+        ApiError::from(err).into_response()
+    } else {
+        err.into_response()
+    }
+}
+
+fn is_safe_method(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    )
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SITE: &str = "https://forum.example.com";
+    const TOKEN: &str = "the-session-token";
+    const BOUNDARY: &str = "XyZ";
+
+    fn site_origin() -> SiteOrigin {
+        SiteOrigin::of("https://Forum.example.com/")
+    }
+
+    fn post(headers: &[(header::HeaderName, &str)], body: &str) -> Request<Body> {
+        let mut builder = Request::post("/user/login")
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded");
+        for (name, value) in headers {
+            builder = builder.header(name, *value);
+        }
+        builder.body(Body::from(body.to_string())).unwrap()
+    }
+
+    // as `top_middleware` leaves it for a session
+    fn logged_in(mut req: Request<Body>) -> Request<Body> {
+        req.extensions_mut().insert(LoggedUser {
+            user_id: "alice".to_string(),
+            session_id: "session".to_string(),
+            csrf_token: TOKEN.to_string(),
+        });
+        req
+    }
+
+    fn multipart(parts: &[(&str, &str)]) -> Request<Body> {
+        let mut body = String::new();
+        for (name, value) in parts {
+            body += &format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                BOUNDARY, name, value
+            );
+        }
+        body += &format!("--{}--\r\n", BOUNDARY);
+        let content_type = format!("multipart/form-data; boundary={}", BOUNDARY);
+        let req = Request::post("/subspace/banner")
+            .header(header::CONTENT_TYPE, content_type)
+            .body(Body::from(body))
+            .unwrap();
+        logged_in(req)
+    }
+
+    fn is_forbidden(res: Result<Request<Body>, ForumError>) -> bool {
+        matches!(res, Err(ForumError::Forbidden(_)))
+    }
+
+    fn is_too_large(res: Result<Request<Body>, ForumError>) -> bool {
+        matches!(res, Err(ForumError::PayloadTooLarge(..)))
+    }
+
+    #[test]
+    fn origin_of_a_url() {
+        assert_eq!(SiteOrigin::of(SITE), site_origin());
+        assert_eq!(
+            SiteOrigin::of("https://forum.example.com/user/login?x=1"),
+            site_origin()
+        );
+        assert_ne!(SiteOrigin::of("http://forum.example.com"), site_origin());
+        assert_ne!(
+            SiteOrigin::of("https://forum.example.com:8443"),
+            site_origin()
+        );
+    }
+
+    #[tokio::test]
+    async fn anonymous_post_from_the_site() {
+        let req = post(&[(header::ORIGIN, SITE)], "username=a");
+        assert!(verify(req, &site_origin()).await.is_ok());
+
+        // the referer when the origin is left out
+        let req = post(
+            &[(header::REFERER, "https://forum.example.com/user/login")],
+            "",
+        );
+        assert!(verify(req, &site_origin()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn anonymous_post_from_elsewhere() {
+        let req = post(&[(header::ORIGIN, "https://evil.example.net")], "");
+        assert!(verify(req, &site_origin()).await.is_err());
+        let req = post(&[(header::ORIGIN, "null")], "");
+        assert!(verify(req, &site_origin()).await.is_err());
+        assert!(verify(post(&[], ""), &site_origin()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn form_token() {
+        let body = format!("csrf_token={}&title=hi", TOKEN);
+        let req = verify(logged_in(post(&[], &body)), &site_origin())
+            .await
+            .unwrap();
+        // the handler still gets the whole form
+        let bytes = hyper::body::to_bytes(req.into_body()).await.unwrap();
+        assert_eq!(bytes, body.as_bytes());
+
+        let req = logged_in(post(&[], "csrf_token=another&title=hi"));
+        assert!(is_forbidden(verify(req, &site_origin()).await));
+        let req = logged_in(post(&[], "title=hi"));
+        assert!(is_forbidden(verify(req, &site_origin()).await));
+    }
+
+    #[tokio::test]
+    async fn session_token_beats_the_origin() {
+        // a logged in user posting from the site still needs the token
+        let req = logged_in(post(&[(header::ORIGIN, SITE)], "title=hi"));
+        assert!(is_forbidden(verify(req, &site_origin()).await));
+    }
+
+    #[tokio::test]
+    async fn header_token() {
+        let name = header::HeaderName::from_static("x-csrf-token");
+        let req = logged_in(post(&[(name.clone(), TOKEN)], "{}"));
+        assert!(verify(req, &site_origin()).await.is_ok());
+        let req = logged_in(post(&[(name, "another")], "{}"));
+        assert!(is_forbidden(verify(req, &site_origin()).await));
+    }
+
+    #[tokio::test]
+    async fn multipart_token_first() {
+        let req = multipart(&[("csrf_token", TOKEN), ("file", "...")]);
+        assert!(verify(req, &site_origin()).await.is_ok());
+
+        // only the first part is read
+        let req = multipart(&[("file", "..."), ("csrf_token", TOKEN)]);
+        assert!(is_forbidden(verify(req, &site_origin()).await));
+        let req = multipart(&[("csrf_token", "another"), ("file", "...")]);
+        assert!(is_forbidden(verify(req, &site_origin()).await));
+    }
+
+    #[tokio::test]
+    async fn size_limit() {
+        let too_large = (MAX_FORM_SIZE + 1).to_string();
+        let req = logged_in(post(&[(header::CONTENT_LENGTH, &too_large)], ""));
+        assert!(is_too_large(verify(req, &site_origin()).await));
+
+        // no length told, cut while read
+        let body = format!("csrf_token={}&content={}", TOKEN, "x".repeat(MAX_FORM_SIZE));
+        let req = logged_in(post(&[], &body));
+        assert!(is_too_large(verify(req, &site_origin()).await));
+
+        let body = format!("csrf_token={}&content={}", TOKEN, "x".repeat(1024));
+        let req = logged_in(post(&[], &body));
+        assert!(verify(req, &site_origin()).await.is_ok());
+    }
+}
@@ -0,0 +1,201 @@
+use axum_extra::extract::cookie::Cookie;
+use chrono::{DateTime, SecondsFormat, Utc};
+use chrono_tz::Tz;
+use std::fmt::Write;
+use std::future::Future;
+use std::sync::OnceLock;
+
+use crate::i18n;
+
+// when the format of the catalog is broken
+const FALLBACK_FORMAT: &str = "%Y-%m-%d %H:%M";
+// older than this, the absolute date is shown instead of "n days ago"
+const MAX_RELATIVE_DAYS: i64 = 30;
+// the choice is kept for a year, like the language
+const COOKIE_MAX_AGE_DAYS: i64 = 365;
+
+static SITE_TIMEZONE: OnceLock<Tz> = OnceLock::new();
+
+tokio::task_local! {
+    // the timezone of the request being handled, set by the top middleware
+    static TIMEZONE: Tz;
+}
+
+/// Set the site default timezone, once at startup
+pub fn init(site_timezone: Tz) {
+    if SITE_TIMEZONE.set(site_timezone).is_err() {
+        println!("in datetime init: the site timezone was already set");
+    }
+}
+
+pub fn parse_timezone(name: &str) -> Option<Tz> {
+    name.parse().ok()
+}
+
+/// The names offered on the profile page, e.g. "Asia/Shanghai"
+pub fn timezones() -> Vec<&'static str> {
+    chrono_tz::TZ_VARIANTS.iter().map(|tz| tz.name()).collect()
+}
+
+fn site_timezone() -> Tz {
+    SITE_TIMEZONE.get().copied().unwrap_or(Tz::UTC)
+}
+
+/// The timezone of a request: the one of the user (carried by a cookie
+/// since login), else the site default
+pub fn negotiate(cookie: Option<&str>) -> Tz {
+    cookie
+        .and_then(parse_timezone)
+        .unwrap_or_else(site_timezone)
+}
+
+/// Run a request handling with its timezone, the dates rendered in it are shown in it
+pub async fn scope<F: Future>(timezone: Tz, f: F) -> F::Output {
+    TIMEZONE.scope(timezone, f).await
+}
+
+fn current_timezone() -> Tz {
+    TIMEZONE
+        .try_with(|tz| *tz)
+        .unwrap_or_else(|_| site_timezone())
+}
+
+pub fn cookie_name(appid: &str) -> String {
+    format!("{}_tz", appid)
+}
+
+pub fn timezone_cookie(appid: &str, name: &str) -> Cookie<'static> {
+    Cookie::build(cookie_name(appid), name.to_string())
+        .path("/")
+        .max_age(cookie::time::Duration::days(COOKIE_MAX_AGE_DAYS))
+        .http_only(true)
+        .finish()
+}
+
+/// The date in the timezone and the format of the request, "-" when the
+/// timestamp is out of range
+pub fn absolute(timestamp: i64) -> String {
+    let Some(utc) = DateTime::from_timestamp(timestamp, 0) else {
+        return "-".to_string();
+    };
+    let local = utc.with_timezone(&current_timezone());
+    format_date(&local, &i18n::translate("date_format"))
+}
+
+// the format comes from the catalog, a bad one must not break the page
+fn format_date(local: &DateTime<Tz>, format: &str) -> String {
+    let mut text = String::new();
+    if write!(text, "{}", local.format(format)).is_err() {
+        text.clear();
+        let _ = write!(text, "{}", local.format(FALLBACK_FORMAT));
+    }
+    text
+}
+
+fn count_text(n: i64, one_key: &str, many_key: &str) -> String {
+    if n == 1 {
+        i18n::translate(one_key)
+    } else {
+        i18n::translate(many_key).replace("{n}", &n.to_string())
+    }
+}
+
+/// "3 hours ago", or None when it's too old (or in the future) to be told so
+pub fn relative(timestamp: i64, now: i64) -> Option<String> {
+    let seconds = now.checked_sub(timestamp)?;
+    let text = match seconds {
+        // a clock a bit ahead of ours
+        -59..=59 => i18n::translate("just_now"),
+        60..=3599 => count_text(seconds / 60, "a_minute_ago", "minutes_ago"),
+        3600..=86399 => count_text(seconds / 3600, "an_hour_ago", "hours_ago"),
+        _ if seconds > 0 && seconds < MAX_RELATIVE_DAYS * 86400 => {
+            count_text(seconds / 86400, "a_day_ago", "days_ago")
+        }
+        _ => return None,
+    };
+    Some(text)
+}
+
+fn escape(text: &str) -> String {
+    askama::MarkupDisplay::new_unsafe(text, askama::Html).to_string()
+}
+
+/// A `<time>` telling how long ago, with the absolute date as its tooltip
+pub fn timeago_html(timestamp: i64) -> String {
+    let Some(utc) = DateTime::from_timestamp(timestamp, 0) else {
+        return "-".to_string();
+    };
+    let absolute = absolute(timestamp);
+    let relative =
+        relative(timestamp, Utc::now().timestamp()).unwrap_or_else(|| absolute.to_owned());
+    format!(
+        r#"<time datetime="{}" title="{}">{}</time>"#,
+        utc.to_rfc3339_opts(SecondsFormat::Secs, true),
+        escape(&absolute),
+        escape(&relative)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // no catalog is loaded in the tests, the texts are their keys
+    fn ago(seconds: i64) -> Option<String> {
+        let now = 1_700_000_000;
+        relative(now - seconds, now)
+    }
+
+    #[test]
+    fn relative_range_boundaries() {
+        let day = 86400;
+        let cases = [
+            (0, Some("just_now")),
+            (59, Some("just_now")),
+            (60, Some("a_minute_ago")),
+            (119, Some("a_minute_ago")),
+            (120, Some("minutes_ago")),
+            (3599, Some("minutes_ago")),
+            (3600, Some("an_hour_ago")),
+            (2 * 3600, Some("hours_ago")),
+            (day - 1, Some("hours_ago")),
+            (day, Some("a_day_ago")),
+            (2 * day, Some("days_ago")),
+            (MAX_RELATIVE_DAYS * day - 1, Some("days_ago")),
+            (MAX_RELATIVE_DAYS * day, None),
+            // a clock a bit ahead is now, the later dates aren't told
+            (-59, Some("just_now")),
+            (-60, None),
+            (-day, None),
+        ];
+        for (seconds, expected) in cases {
+            assert_eq!(ago(seconds).as_deref(), expected, "{} seconds ago", seconds);
+        }
+    }
+
+    #[test]
+    fn out_of_range_timestamps() {
+        assert_eq!(relative(i64::MIN, i64::MAX), None);
+        assert_eq!(relative(i64::MAX, i64::MIN), None);
+        assert_eq!(absolute(i64::MAX), "-");
+        assert_eq!(absolute(i64::MIN), "-");
+        assert_eq!(timeago_html(i64::MAX), "-");
+    }
+
+    #[test]
+    fn absolute_is_in_the_timezone() {
+        let utc = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let shanghai = utc.with_timezone(&parse_timezone("Asia/Shanghai").unwrap());
+        assert_eq!(format_date(&shanghai, "%Y-%m-%d %H:%M"), "2023-11-15 06:13");
+        let paris = utc.with_timezone(&parse_timezone("Europe/Paris").unwrap());
+        assert_eq!(format_date(&paris, "%d/%m/%Y %H:%M"), "14/11/2023 23:13");
+    }
+
+    #[test]
+    fn broken_catalog_format_falls_back() {
+        let utc = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let local = utc.with_timezone(&Tz::UTC);
+        assert_eq!(format_date(&local, "%Y年%m月%d日 %Q"), "2023-11-14 22:13");
+        assert_eq!(format_date(&local, "%"), "2023-11-14 22:13");
+    }
+}
@@ -0,0 +1,190 @@
+use askama::Template;
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use std::fmt;
+
+use crate::filters;
+use crate::gutp::GutpError;
+use crate::HtmlTemplate;
+
+/// The error every handler returns.
+///
+/// It renders `action_error.html` in place, with the status code matching
+/// the kind of failure.
+#[derive(Debug)]
+pub enum ForumError {
+    /// the visitor has to login first
+    NotLoggedIn,
+    /// logged in, but not allowed to do this action
+    Forbidden(String),
+    /// the target object doesn't exist: (action, err_info)
+    NotFound(String, String),
+    /// the action conflicts with the state of the target: (action, err_info)
+    Conflict(String, String),
+    /// the request itself is malformed: (action, err_info)
+    BadRequest(String, String),
+    /// rate limited, e.g. too many failed logins: (action, err_info)
+    TooManyRequests(String, String),
+    /// the request body is over the limit: (action, err_info)
+    PayloadTooLarge(String, String),
+    /// the gutp backend failed
+    Gutp(String, GutpError),
+    /// a third-party service (e.g. the oauth provider) failed: (action, err_info)
+    Upstream(String, String),
+    /// something broke on our side: (action, err_info)
+    Internal(String, String),
+}
+
+impl ForumError {
+    /// To be used with `map_err`, keeps the action as context of the gutp error
+    pub fn gutp(action: impl Into<String>) -> impl FnOnce(GutpError) -> ForumError {
+        let action = action.into();
+        move |err| ForumError::Gutp(action, err)
+    }
+
+    pub fn not_found(action: impl Into<String>, err_info: &str) -> ForumError {
+        ForumError::NotFound(action.into(), err_info.to_string())
+    }
+
+    pub fn conflict(action: impl Into<String>, err_info: &str) -> ForumError {
+        ForumError::Conflict(action.into(), err_info.to_string())
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ForumError::NotLoggedIn => StatusCode::UNAUTHORIZED,
+            ForumError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ForumError::NotFound(..) => StatusCode::NOT_FOUND,
+            ForumError::Conflict(..) => StatusCode::CONFLICT,
+            ForumError::BadRequest(..) => StatusCode::BAD_REQUEST,
+            ForumError::TooManyRequests(..) => StatusCode::TOO_MANY_REQUESTS,
+            ForumError::PayloadTooLarge(..) => StatusCode::PAYLOAD_TOO_LARGE,
+            ForumError::Gutp(..) | ForumError::Upstream(..) => StatusCode::BAD_GATEWAY,
+            ForumError::Internal(..) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// A stable name of the kind of failure, for the api clients
+    pub fn code(&self) -> &'static str {
+        match self {
+            ForumError::NotLoggedIn => "not_logged_in",
+            ForumError::Forbidden(_) => "forbidden",
+            ForumError::NotFound(..) => "not_found",
+            ForumError::Conflict(..) => "conflict",
+            ForumError::BadRequest(..) => "bad_request",
+            ForumError::TooManyRequests(..) => "too_many_requests",
+            ForumError::PayloadTooLarge(..) => "payload_too_large",
+            ForumError::Gutp(..) => "backend_unavailable",
+            ForumError::Upstream(..) => "upstream_failed",
+            ForumError::Internal(..) => "internal",
+        }
+    }
+
+    pub fn action(&self) -> String {
+        match self {
+            ForumError::NotLoggedIn => "Not logged in".to_string(),
+            ForumError::Forbidden(action)
+            | ForumError::NotFound(action, _)
+            | ForumError::Conflict(action, _)
+            | ForumError::BadRequest(action, _)
+            | ForumError::TooManyRequests(action, _)
+            | ForumError::PayloadTooLarge(action, _)
+            | ForumError::Gutp(action, _)
+            | ForumError::Upstream(action, _)
+            | ForumError::Internal(action, _) => action.to_string(),
+        }
+    }
+
+    /// What the visitor is told, the details of the server errors are only logged
+    pub fn err_info(&self) -> String {
+        match self {
+            ForumError::NotLoggedIn => "Need login firstly to get proper permission.".to_string(),
+            ForumError::Forbidden(_) => "You don't have the permission to do this.".to_string(),
+            // don't leak the backend details to the visitor
+            ForumError::Gutp(..) => {
+                "The backend service is unavailable, try again later.".to_string()
+            }
+            ForumError::Upstream(..) => {
+                "A third-party service is unavailable, try again later.".to_string()
+            }
+            ForumError::Internal(..) => {
+                "Something went wrong on our side, try again later.".to_string()
+            }
+            ForumError::NotFound(_, err_info)
+            | ForumError::Conflict(_, err_info)
+            | ForumError::BadRequest(_, err_info)
+            | ForumError::TooManyRequests(_, err_info)
+            | ForumError::PayloadTooLarge(_, err_info) => err_info.to_string(),
+        }
+    }
+}
+
+// with the details, for the logs
+impl fmt::Display for ForumError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ForumError::Gutp(action, err) => write!(f, "{}: {}", action, err),
+            ForumError::Upstream(action, err_info) | ForumError::Internal(action, err_info) => {
+                write!(f, "{}: {}", action, err_info)
+            }
+            _ => write!(f, "{}: {}", self.action(), self.err_info()),
+        }
+    }
+}
+
+impl std::error::Error for ForumError {}
+
+#[derive(Template)]
+#[template(path = "action_error.html")]
+struct ErrorInfoTemplate {
+    action: String,
+    err_info: String,
+}
+
+impl IntoResponse for ForumError {
+    fn into_response(self) -> Response {
+        let status = self.status_code();
+        if status.is_server_error() {
+            println!("in ForumError: {} {}", status, self);
+        }
+
+        let template = ErrorInfoTemplate {
+            action: self.action(),
+            err_info: self.err_info(),
+        };
+        (status, HtmlTemplate(template)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_errors_only_log_their_details() {
+        let errors = [
+            ForumError::Internal(
+                "Create session".to_string(),
+                "Connection refused (os error 111) at 10.0.0.5:6379".to_string(),
+            ),
+            ForumError::Upstream(
+                "Get access token from GitHub".to_string(),
+                "Connection refused (os error 111) at 10.0.0.5:6379".to_string(),
+            ),
+        ];
+        for err in errors {
+            assert!(err.status_code().is_server_error());
+            assert!(!err.err_info().contains("10.0.0.5"), "{}", err.err_info());
+            assert!(err.to_string().contains("10.0.0.5"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn client_errors_tell_what_went_wrong() {
+        let err = ForumError::not_found("Query article: 42", "Article doesn't exist!");
+        assert_eq!(err.err_info(), "Article doesn't exist!");
+        assert_eq!(err.to_string(), "Query article: 42: Article doesn't exist!");
+    }
+}
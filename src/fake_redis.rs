@@ -0,0 +1,274 @@
+//! A redis in memory for the tests of the stores, with only the commands they use
+
+use redis::{aio::ConnectionLike, Arg, Cmd, ErrorKind, Pipeline, RedisFuture, RedisResult, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+enum Entry {
+    Str(Vec<u8>),
+    Hash(BTreeMap<Vec<u8>, Vec<u8>>),
+    Set(BTreeSet<Vec<u8>>),
+    List(Vec<Vec<u8>>),
+}
+
+/// Nothing expires by itself, the tests read the ttls and `expire_now()` the keys
+#[derive(Default)]
+pub struct FakeRedis {
+    entries: HashMap<Vec<u8>, Entry>,
+    ttls: HashMap<Vec<u8>, i64>,
+}
+
+impl FakeRedis {
+    pub fn exists(&self, key: &str) -> bool {
+        self.entries.contains_key(key.as_bytes())
+    }
+
+    /// The ttl last set on `key`, None when it has none
+    pub fn ttl(&self, key: &str) -> Option<i64> {
+        self.ttls.get(key.as_bytes()).copied()
+    }
+
+    /// As redis does once the ttl ran out
+    pub fn expire_now(&mut self, key: &str) {
+        self.entries.remove(key.as_bytes());
+        self.ttls.remove(key.as_bytes());
+    }
+
+    /// Write a plain string, e.g. a value from an older version
+    pub fn set(&mut self, key: &str, value: &str) {
+        self.entries
+            .insert(key.into(), Entry::Str(value.as_bytes().to_vec()));
+    }
+
+    /// Change a field of a hash behind the back of the store
+    pub fn hset(&mut self, key: &str, field: &str, value: &str) {
+        if let Some(Entry::Hash(hash)) = self.entries.get_mut(key.as_bytes()) {
+            hash.insert(field.into(), value.into());
+        }
+    }
+
+    fn run(&mut self, cmd: &Cmd) -> RedisResult<Value> {
+        let args: Vec<Vec<u8>> = cmd
+            .args_iter()
+            .filter_map(|arg| match arg {
+                Arg::Simple(arg) => Some(arg.to_vec()),
+                Arg::Cursor => None,
+            })
+            .collect();
+        let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+        let key = args.get(1).cloned().unwrap_or_default();
+        let rest = args.get(2..).unwrap_or_default();
+
+        let value = match name.as_str() {
+            "GET" => match self.entries.get(&key) {
+                Some(Entry::Str(value)) => Value::Data(value.clone()),
+                Some(_) => return Err(wrong_type()),
+                None => Value::Nil,
+            },
+            "DEL" => {
+                let deleted = args[1..]
+                    .iter()
+                    .filter(|key| {
+                        self.ttls.remove(*key);
+                        self.entries.remove(*key).is_some()
+                    })
+                    .count();
+                Value::Int(deleted as i64)
+            }
+            "EXPIRE" => {
+                if !self.entries.contains_key(&key) {
+                    return Ok(Value::Int(0));
+                }
+                let seconds = String::from_utf8_lossy(&rest[0]).parse().unwrap();
+                self.ttls.insert(key, seconds);
+                Value::Int(1)
+            }
+            "HSET" | "HMSET" => {
+                let hash = self.hash(key)?;
+                let added = rest
+                    .chunks(2)
+                    .filter(|pair| hash.insert(pair[0].clone(), pair[1].clone()).is_none())
+                    .count();
+                if name == "HMSET" {
+                    Value::Okay
+                } else {
+                    Value::Int(added as i64)
+                }
+            }
+            "HGETALL" => match self.entries.get(&key) {
+                Some(Entry::Hash(hash)) => Value::Bulk(
+                    hash.iter()
+                        .flat_map(|(field, value)| [field.clone(), value.clone()])
+                        .map(Value::Data)
+                        .collect(),
+                ),
+                Some(_) => return Err(wrong_type()),
+                None => Value::Bulk(vec![]),
+            },
+            "HGET" => match self.entries.get(&key) {
+                Some(Entry::Hash(hash)) => {
+                    hash.get(&rest[0]).cloned().map_or(Value::Nil, Value::Data)
+                }
+                Some(_) => return Err(wrong_type()),
+                None => Value::Nil,
+            },
+            "HVALS" => match self.entries.get(&key) {
+                Some(Entry::Hash(hash)) => {
+                    Value::Bulk(hash.values().cloned().map(Value::Data).collect())
+                }
+                Some(_) => return Err(wrong_type()),
+                None => Value::Bulk(vec![]),
+            },
+            "HDEL" => {
+                let hash = self.hash(key.clone())?;
+                let removed = rest.iter().filter(|f| hash.remove(*f).is_some()).count();
+                if hash.is_empty() {
+                    self.expire_now(&String::from_utf8_lossy(&key));
+                }
+                Value::Int(removed as i64)
+            }
+            "HMGET" => match self.entries.get(&key) {
+                Some(Entry::Hash(hash)) => Value::Bulk(
+                    rest.iter()
+                        .map(|field| hash.get(field).cloned().map_or(Value::Nil, Value::Data))
+                        .collect(),
+                ),
+                Some(_) => return Err(wrong_type()),
+                None => Value::Bulk(rest.iter().map(|_| Value::Nil).collect()),
+            },
+            "RPUSH" => {
+                let list = self.list_of(key)?;
+                list.extend(rest.iter().cloned());
+                Value::Int(list.len() as i64)
+            }
+            "LRANGE" => match self.entries.get(&key) {
+                Some(Entry::List(list)) => {
+                    let range = list_range(list.len(), &rest[0], &rest[1]);
+                    Value::Bulk(list[range].iter().cloned().map(Value::Data).collect())
+                }
+                Some(_) => return Err(wrong_type()),
+                None => Value::Bulk(vec![]),
+            },
+            "LTRIM" => {
+                let list = self.list_of(key.clone())?;
+                let range = list_range(list.len(), &rest[0], &rest[1]);
+                *list = list[range].to_vec();
+                if list.is_empty() {
+                    self.expire_now(&String::from_utf8_lossy(&key));
+                }
+                Value::Okay
+            }
+            "SADD" => {
+                let set = self.set_of(key)?;
+                let added = rest.iter().filter(|m| set.insert(m.to_vec())).count();
+                Value::Int(added as i64)
+            }
+            "SREM" => {
+                let set = self.set_of(key.clone())?;
+                let removed = rest.iter().filter(|m| set.remove(*m)).count();
+                if set.is_empty() {
+                    self.expire_now(&String::from_utf8_lossy(&key));
+                }
+                Value::Int(removed as i64)
+            }
+            "SMEMBERS" => match self.entries.get(&key) {
+                Some(Entry::Set(set)) => {
+                    Value::Bulk(set.iter().cloned().map(Value::Data).collect())
+                }
+                Some(_) => return Err(wrong_type()),
+                None => Value::Bulk(vec![]),
+            },
+            "SISMEMBER" => match self.entries.get(&key) {
+                Some(Entry::Set(set)) => Value::Int(set.contains(&rest[0]) as i64),
+                Some(_) => return Err(wrong_type()),
+                None => Value::Int(0),
+            },
+            _ => panic!("the fake redis has no {}", name),
+        };
+        Ok(value)
+    }
+
+    fn hash(&mut self, key: Vec<u8>) -> RedisResult<&mut BTreeMap<Vec<u8>, Vec<u8>>> {
+        match self
+            .entries
+            .entry(key)
+            .or_insert(Entry::Hash(BTreeMap::new()))
+        {
+            Entry::Hash(hash) => Ok(hash),
+            _ => Err(wrong_type()),
+        }
+    }
+
+    fn list_of(&mut self, key: Vec<u8>) -> RedisResult<&mut Vec<Vec<u8>>> {
+        match self.entries.entry(key).or_insert(Entry::List(vec![])) {
+            Entry::List(list) => Ok(list),
+            _ => Err(wrong_type()),
+        }
+    }
+
+    fn set_of(&mut self, key: Vec<u8>) -> RedisResult<&mut BTreeSet<Vec<u8>>> {
+        match self
+            .entries
+            .entry(key)
+            .or_insert(Entry::Set(BTreeSet::new()))
+        {
+            Entry::Set(set) => Ok(set),
+            _ => Err(wrong_type()),
+        }
+    }
+}
+
+// the items from `start` to `stop` included, negative ones count from the end
+fn list_range(len: usize, start: &[u8], stop: &[u8]) -> std::ops::Range<usize> {
+    let index = |arg: &[u8]| {
+        let index: i64 = String::from_utf8_lossy(arg).parse().unwrap();
+        if index < 0 {
+            len as i64 + index
+        } else {
+            index
+        }
+    };
+    let start = index(start).clamp(0, len as i64) as usize;
+    let stop = (index(stop) + 1).clamp(start as i64, len as i64) as usize;
+    start..stop
+}
+
+// the error redis answers, `err.code()` is "WRONGTYPE"
+fn wrong_type() -> redis::RedisError {
+    redis::parse_redis_value(
+        b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n",
+    )
+    .err()
+    .unwrap_or_else(|| (ErrorKind::TypeError, "WRONGTYPE").into())
+}
+
+impl ConnectionLike for FakeRedis {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        let result = self.run(cmd);
+        Box::pin(async move { result })
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        pipeline: &'a Pipeline,
+        offset: usize,
+        _count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        let result = pipeline
+            .cmd_iter()
+            .map(|cmd| self.run(cmd))
+            .collect::<RedisResult<Vec<Value>>>()
+            // an atomic pipeline answers all in the reply of its EXEC
+            .map(|values| {
+                if offset > 0 {
+                    vec![Value::Bulk(values)]
+                } else {
+                    values
+                }
+            });
+        Box::pin(async move { result })
+    }
+
+    fn get_db(&self) -> i64 {
+        0
+    }
+}
@@ -0,0 +1,673 @@
+use atom_syndication as atom;
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode, Uri},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, FixedOffset};
+use gutp_types::GutpPost;
+use sha2::{Digest, Sha256};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::error::ForumError;
+use crate::gutp::Paging;
+use crate::i18n;
+use crate::permission;
+use crate::profile::{self, PubSettings};
+use crate::slug;
+use crate::tag;
+use crate::AppState;
+
+/// The latest articles in a feed
+pub const FEED_SIZE: usize = 20;
+
+// the summary is cut at the first blank line after this many chars
+const SUMMARY_LEN: usize = 500;
+
+// the date format of Last-Modified and If-Modified-Since
+const HTTP_DATE_FORMAT: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+// the site feed takes a gutp query per subspace, the readers polling it
+// within this long get the body rendered last
+const SITE_FEED_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FeedFormat {
+    Rss,
+    Atom,
+}
+
+impl FeedFormat {
+    /// `.../atom.xml` is atom, `.../feed.xml` is rss
+    pub fn of_path(path: &str) -> Self {
+        if path.ends_with("/atom.xml") {
+            FeedFormat::Atom
+        } else {
+            FeedFormat::Rss
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            FeedFormat::Rss => "application/rss+xml; charset=utf-8",
+            FeedFormat::Atom => "application/atom+xml; charset=utf-8",
+        }
+    }
+}
+
+/// A feed rendered, with what the conditional requests are checked against
+#[derive(Clone)]
+struct RenderedFeed {
+    body: String,
+    etag: String,
+    updated: i64,
+}
+
+/// The site feed as last rendered, per format, for `SITE_FEED_TTL`
+#[derive(Default)]
+pub struct SiteFeedCache {
+    rendered: Mutex<HashMap<FeedFormat, (Instant, RenderedFeed)>>,
+}
+
+impl SiteFeedCache {
+    fn get(&self, format: FeedFormat) -> Option<RenderedFeed> {
+        let rendered = self.rendered.lock().ok()?;
+        rendered
+            .get(&format)
+            .filter(|(at, _)| at.elapsed() < SITE_FEED_TTL)
+            .map(|(_, feed)| feed.clone())
+    }
+
+    fn put(&self, format: FeedFormat, feed: RenderedFeed) {
+        if let Ok(mut rendered) = self.rendered.lock() {
+            rendered.insert(format, (Instant::now(), feed));
+        }
+    }
+}
+
+/// The articles of a feed and where it's found, the urls are absolute
+struct Feed {
+    title: String,
+    description: String,
+    // the page listing the same articles
+    link: String,
+    // the url of the feed itself
+    self_url: String,
+    posts: Vec<GutpPost>,
+}
+
+// an article edited before the updated_time was kept has 0 there
+fn post_updated(post: &GutpPost) -> i64 {
+    post.updated_time.max(post.created_time)
+}
+
+fn to_datetime(timestamp: i64) -> DateTime<FixedOffset> {
+    DateTime::from_timestamp(timestamp, 0)
+        .unwrap_or_default()
+        .fixed_offset()
+}
+
+fn render_markdown(text: &str) -> String {
+    match askama::filters::markdown(askama::Html, text, None) {
+        Ok(html) => html.to_string(),
+        Err(err) => {
+            println!("in feed render markdown: {}", err);
+            String::new()
+        }
+    }
+}
+
+/// The leading paragraphs of an article, rendered. The markdown is cut
+/// between two blocks, outside of a code fence, so it stays well formed
+pub fn summary_html(content: &str, link: &str) -> String {
+    let mut summary = String::new();
+    let mut in_code = false;
+    let mut cut = false;
+    for line in content.lines() {
+        if line.trim_start().starts_with("```") {
+            in_code = !in_code;
+        }
+        if !in_code && line.trim().is_empty() && summary.chars().count() >= SUMMARY_LEN {
+            cut = true;
+            break;
+        }
+        summary.push_str(line);
+        summary.push('\n');
+    }
+
+    let mut html = render_markdown(&summary);
+    if cut {
+        html.push_str(&format!(
+            r#"<p><a href="{}">{}</a></p>"#,
+            link,
+            i18n::translate("read_more")
+        ));
+    }
+    html
+}
+
+impl Feed {
+    fn new(
+        app_state: &AppState,
+        title: String,
+        description: String,
+        path: &str,
+        feed_path: &str,
+        mut posts: Vec<GutpPost>,
+    ) -> Self {
+        // the latest first, like the pages
+        posts.sort_by_key(|post| Reverse(post.created_time));
+        posts.truncate(FEED_SIZE);
+        let config = &app_state.config;
+        Feed {
+            title,
+            description,
+            link: config.absolute_url(path),
+            self_url: config.absolute_url(feed_path),
+            posts,
+        }
+    }
+
+    /// The last time an article of the feed was posted or edited
+    fn updated(&self) -> i64 {
+        self.posts.iter().map(post_updated).max().unwrap_or(0)
+    }
+
+    fn to_rss(&self, app_state: &AppState) -> String {
+        let items = self
+            .posts
+            .iter()
+            .map(|post| {
+                let link = app_state.config.absolute_url(&slug::article_path(post));
+                rss::Item {
+                    title: Some(post.title.to_owned()),
+                    description: Some(summary_html(&post.content, &link)),
+                    guid: Some(rss::Guid {
+                        value: link.to_owned(),
+                        permalink: true,
+                    }),
+                    link: Some(link),
+                    pub_date: Some(to_datetime(post.created_time).to_rfc2822()),
+                    dublin_core_ext: Some(rss::extension::dublincore::DublinCoreExtension {
+                        creators: vec![post.author_nickname.to_owned()],
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }
+            })
+            .collect();
+
+        let channel = rss::Channel {
+            title: self.title.to_owned(),
+            link: self.link.to_owned(),
+            description: self.description.to_owned(),
+            last_build_date: Some(to_datetime(self.updated()).to_rfc2822()),
+            atom_ext: Some(rss::extension::atom::AtomExtension {
+                links: vec![atom::Link {
+                    href: self.self_url.to_owned(),
+                    rel: "self".to_string(),
+                    mime_type: Some("application/rss+xml".to_string()),
+                    ..Default::default()
+                }],
+            }),
+            items,
+            ..Default::default()
+        };
+        channel.to_string()
+    }
+
+    fn to_atom(&self, app_state: &AppState) -> String {
+        let entries = self
+            .posts
+            .iter()
+            .map(|post| {
+                let link = app_state.config.absolute_url(&slug::article_path(post));
+                atom::Entry {
+                    title: atom::Text::plain(post.title.to_owned()),
+                    id: link.to_owned(),
+                    updated: to_datetime(post_updated(post)),
+                    published: Some(to_datetime(post.created_time)),
+                    authors: vec![atom::Person {
+                        name: post.author_nickname.to_owned(),
+                        uri: Some(
+                            app_state
+                                .config
+                                .absolute_url(&format!("/user/{}", post.author_id)),
+                        ),
+                        ..Default::default()
+                    }],
+                    summary: Some(atom::Text::html(summary_html(&post.content, &link))),
+                    links: vec![atom::Link {
+                        href: link,
+                        rel: "alternate".to_string(),
+                        mime_type: Some("text/html".to_string()),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                }
+            })
+            .collect();
+
+        let feed = atom::Feed {
+            title: atom::Text::plain(self.title.to_owned()),
+            subtitle: Some(atom::Text::plain(self.description.to_owned())),
+            id: self.self_url.to_owned(),
+            updated: to_datetime(self.updated()),
+            links: vec![
+                atom::Link {
+                    href: self.link.to_owned(),
+                    rel: "alternate".to_string(),
+                    mime_type: Some("text/html".to_string()),
+                    ..Default::default()
+                },
+                atom::Link {
+                    href: self.self_url.to_owned(),
+                    rel: "self".to_string(),
+                    mime_type: Some("application/atom+xml".to_string()),
+                    ..Default::default()
+                },
+            ],
+            entries,
+            ..Default::default()
+        };
+        feed.to_string()
+    }
+
+    fn render(&self, app_state: &AppState, format: FeedFormat) -> RenderedFeed {
+        let body = match format {
+            FeedFormat::Rss => self.to_rss(app_state),
+            FeedFormat::Atom => self.to_atom(app_state),
+        };
+        RenderedFeed {
+            etag: etag_of(&body),
+            updated: self.updated(),
+            body,
+        }
+    }
+
+    /// The feed, or a 304 when the reader has it already
+    fn into_response(
+        self,
+        app_state: &AppState,
+        format: FeedFormat,
+        headers: &HeaderMap,
+    ) -> Response {
+        self.render(app_state, format)
+            .into_response(format, headers)
+    }
+}
+
+impl RenderedFeed {
+    fn into_response(self, format: FeedFormat, headers: &HeaderMap) -> Response {
+        let last_modified = to_datetime(self.updated)
+            .format(HTTP_DATE_FORMAT)
+            .to_string();
+
+        let mut response = if is_not_modified(headers, &self.etag, self.updated) {
+            StatusCode::NOT_MODIFIED.into_response()
+        } else {
+            ([(header::CONTENT_TYPE, format.content_type())], self.body).into_response()
+        };
+        for (name, value) in [
+            (header::ETAG, self.etag),
+            (header::LAST_MODIFIED, last_modified),
+        ] {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                response.headers_mut().insert(name, value);
+            }
+        }
+        response
+    }
+}
+
+// a title or a description edited changes the feed but not its Last-Modified,
+// so the etag is taken from the body
+fn etag_of(body: &str) -> String {
+    let digest = Sha256::digest(body.as_bytes());
+    let hex: String = digest[..16].iter().map(|b| format!("{:02x}", b)).collect();
+    format!("\"{}\"", hex)
+}
+
+/// If-None-Match wins over If-Modified-Since, as in RFC 9110
+pub fn is_not_modified(headers: &HeaderMap, etag: &str, updated: i64) -> bool {
+    let header_str = |name| {
+        headers
+            .get(name)
+            .and_then(|v: &HeaderValue| v.to_str().ok())
+    };
+    if let Some(value) = header_str(header::IF_NONE_MATCH) {
+        return value.split(',').any(|tag| {
+            let tag = tag.trim();
+            tag == "*" || tag.trim_start_matches("W/") == etag
+        });
+    }
+    header_str(header::IF_MODIFIED_SINCE)
+        .and_then(|value| DateTime::parse_from_rfc2822(value).ok())
+        .is_some_and(|since| since.timestamp() >= updated)
+}
+
+// the latest public articles of a subspace
+async fn subspace_posts(
+    app_state: &AppState,
+    subspace_id: &str,
+) -> Result<Vec<GutpPost>, ForumError> {
+    let posts = app_state
+        .gutp
+        .posts()
+        .list_by_subspace(subspace_id, Paging::new(FEED_SIZE, 0))
+        .await
+        .map_err(ForumError::gutp(format!(
+            "Query articles of subspace: {}",
+            subspace_id
+        )))?;
+    Ok(posts.into_iter().filter(|post| post.is_public).collect())
+}
+
+/// `/feed.xml` and `/atom.xml`, the latest articles of the whole site
+pub async fn view_site_feed(
+    State(app_state): State<AppState>,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Result<Response, ForumError> {
+    let format = FeedFormat::of_path(uri.path());
+    if let Some(rendered) = app_state.site_feed.get(format) {
+        return Ok(rendered.into_response(format, &headers));
+    }
+
+    let subspaces = app_state
+        .gutp
+        .subspaces()
+        .list()
+        .await
+        .map_err(ForumError::gutp("Query subspaces"))?;
+
+    // gutp has no list across the subspaces, the latest of each are merged.
+    // the private subspaces aren't syndicated
+    let mut posts = vec![];
+    for sp in permission::visible_subspaces(&app_state, None, subspaces).await? {
+        posts.extend(subspace_posts(&app_state, &sp.id).await?);
+    }
+
+    let feed = Feed::new(
+        &app_state,
+        i18n::translate("site_name"),
+        i18n::translate("title"),
+        "/",
+        uri.path(),
+        posts,
+    );
+    let rendered = feed.render(&app_state, format);
+    app_state.site_feed.put(format, rendered.clone());
+    Ok(rendered.into_response(format, &headers))
+}
+
+/// `/subspace/{key}/feed.xml` and `/subspace/{key}/atom.xml`, the key is the id or the slug
+pub async fn view_subspace_feed(
+    State(app_state): State<AppState>,
+    Path(key): Path<String>,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Result<Response, ForumError> {
+    let action = format!("Query subspace feed: {}", key);
+    let sp = slug::find_subspace(&app_state, &key)
+        .await?
+        .ok_or_else(|| ForumError::not_found(&action, "No this subspace."))?;
+    permission::ensure_view_subspace(&app_state, None, &sp).await?;
+
+    let posts = subspace_posts(&app_state, &sp.id).await?;
+    let feed = Feed::new(
+        &app_state,
+        sp.title.to_owned(),
+        sp.description.to_owned(),
+        &slug::subspace_path(&sp),
+        uri.path(),
+        posts,
+    );
+    Ok(feed.into_response(&app_state, FeedFormat::of_path(uri.path()), &headers))
+}
+
+/// `/tag/{id}/feed.xml` and `/tag/{id}/atom.xml`
+pub async fn view_tag_feed(
+    State(app_state): State<AppState>,
+    Path(id): Path<String>,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Result<Response, ForumError> {
+    let (tag, subspace) = tag::query_tag(&app_state, &id).await?;
+    let action = format!("Query tag feed: {}", tag.id);
+    if !tag.is_public || !permission::can_view_subspace(&app_state, None, &subspace).await? {
+        return Err(ForumError::not_found(&action, "Tag doesn't exist!"));
+    }
+
+    let gutp = &app_state.gutp;
+    let post_tags = gutp
+        .post_tags()
+        .list_by_tag(&tag.id, Paging::new(FEED_SIZE, 0))
+        .await
+        .map_err(ForumError::gutp(&action))?;
+    // gutp has no join, the articles are queried one by one
+    let mut posts = vec![];
+    for post_tag in post_tags {
+        if let Some(post) = gutp
+            .posts()
+            .by_id(&post_tag.post_id)
+            .await
+            .map_err(ForumError::gutp(&action))?
+        {
+            if post.is_public {
+                posts.push(post);
+            }
+        }
+    }
+
+    let feed = Feed::new(
+        &app_state,
+        format!("{} - {}", tag.caption, subspace.title),
+        subspace.description.to_owned(),
+        &format!("/tag/{}", tag.id),
+        uri.path(),
+        posts,
+    );
+    Ok(feed.into_response(&app_state, FeedFormat::of_path(uri.path()), &headers))
+}
+
+/// `/user/{id}/feed.xml` and `/user/{id}/atom.xml`, the articles of an author
+pub async fn view_user_feed(
+    State(app_state): State<AppState>,
+    Path(id): Path<String>,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Result<Response, ForumError> {
+    let user = profile::query_user(&app_state, &id).await?;
+    let action = format!("Query user feed: {}", user.id);
+    // the feed readers aren't logged in, it's like the page seen by a visitor
+    let settings = PubSettings::of(&user);
+    if !settings.profile_public || !settings.show_activity {
+        return Err(ForumError::not_found(&action, "No feed of this user."));
+    }
+
+    let post_api = app_state.gutp.posts();
+    let (posts, hidden_subspaces) = tokio::join!(
+        post_api.list_by_author(&user.id, Paging::new(FEED_SIZE, 0)),
+        permission::hidden_subspace_ids(&app_state, None)
+    );
+    let hidden_subspaces = hidden_subspaces?;
+    let posts = posts
+        .map_err(ForumError::gutp(&action))?
+        .into_iter()
+        .filter(|post| post.is_public && !hidden_subspaces.contains(&post.subspace_id))
+        .collect();
+
+    let feed = Feed::new(
+        &app_state,
+        format!("{} - {}", user.nickname, i18n::translate("site_name")),
+        settings.bio.to_owned(),
+        &format!("/user/{}", user.id),
+        uri.path(),
+        posts,
+    );
+    Ok(feed.into_response(&app_state, FeedFormat::of_path(uri.path()), &headers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINK: &str = "http://forum.test/a/1/hello";
+
+    // a paragraph of its own, long enough to reach the summary length
+    fn paragraph(word: &str) -> String {
+        format!("{}\n\n", word.repeat(SUMMARY_LEN))
+    }
+
+    fn rendered(updated: i64) -> RenderedFeed {
+        let body = "<rss></rss>".to_string();
+        RenderedFeed {
+            etag: etag_of(&body),
+            updated,
+            body,
+        }
+    }
+
+    fn headers(pairs: &[(header::HeaderName, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn summary_is_cut_at_a_blank_line_past_the_length() {
+        let content = format!("{}{}", paragraph("a"), paragraph("b"));
+        let html = summary_html(&content, LINK);
+        assert!(html.contains(&"a".repeat(SUMMARY_LEN)), "{}", html);
+        assert!(
+            !html.contains('b'.to_string().repeat(10).as_str()),
+            "{}",
+            html
+        );
+        assert!(html.contains(LINK), "{}", html);
+
+        // the short paragraphs before the length are kept together
+        let content = format!("intro\n\nmore\n\n{}{}", paragraph("c"), paragraph("d"));
+        let html = summary_html(&content, LINK);
+        assert!(html.contains("intro") && html.contains("more"), "{}", html);
+        assert!(html.contains(&"c".repeat(SUMMARY_LEN)), "{}", html);
+        assert!(!html.contains(&"d".repeat(10)), "{}", html);
+    }
+
+    #[test]
+    fn summary_is_not_cut_inside_a_code_fence() {
+        let code = "let x = 1;\n".repeat(SUMMARY_LEN / 10);
+        let content = format!(
+            "```rust\n{}\n{}```\n\nafter the code\n\nthe rest",
+            code, code
+        );
+        let html = summary_html(&content, LINK);
+        // the blank line in the fence is past the length, the cut waits for the fence to close
+        assert_eq!(html.matches("<pre").count(), 1, "{}", html);
+        assert!(html.contains("</code></pre>"), "{}", html);
+        assert!(!html.contains("after the code"), "{}", html);
+        assert!(html.contains(LINK), "{}", html);
+    }
+
+    #[test]
+    fn summary_without_a_blank_line_is_the_whole_content() {
+        let content = "x".repeat(2 * SUMMARY_LEN);
+        let html = summary_html(&content, LINK);
+        assert!(html.contains(&content), "{}", html);
+        assert!(!html.contains(LINK), "{}", html);
+    }
+
+    #[test]
+    fn updated_falls_back_to_the_creation() {
+        let post = GutpPost {
+            created_time: 1_700_000_000,
+            updated_time: 0,
+            ..Default::default()
+        };
+        assert_eq!(post_updated(&post), 1_700_000_000);
+
+        let post = GutpPost {
+            updated_time: 1_700_000_100,
+            ..post
+        };
+        assert_eq!(post_updated(&post), 1_700_000_100);
+        assert_eq!(to_datetime(0).timestamp(), 0);
+    }
+
+    #[test]
+    fn format_of_path() {
+        assert_eq!(FeedFormat::of_path("/atom.xml"), FeedFormat::Atom);
+        assert_eq!(
+            FeedFormat::of_path("/subspace/rust/atom.xml"),
+            FeedFormat::Atom
+        );
+        assert_eq!(FeedFormat::of_path("/feed.xml"), FeedFormat::Rss);
+        assert_eq!(FeedFormat::of_path("/tag/3/feed.xml"), FeedFormat::Rss);
+        assert_eq!(FeedFormat::of_path("/user/atom.xml.bak"), FeedFormat::Rss);
+    }
+
+    #[test]
+    fn conditional_requests_get_a_304() {
+        let updated = 1_700_000_000;
+        let etag = rendered(updated).etag;
+        let last_modified = to_datetime(updated).format(HTTP_DATE_FORMAT).to_string();
+        let earlier = to_datetime(updated - 1)
+            .format(HTTP_DATE_FORMAT)
+            .to_string();
+        let weak_etag = format!("\"other\", W/{}", etag);
+        let (etag_str, last_modified_str) = (etag.as_str(), last_modified.as_str());
+
+        let cases = [
+            (headers(&[]), StatusCode::OK),
+            (
+                headers(&[(header::IF_NONE_MATCH, etag_str)]),
+                StatusCode::NOT_MODIFIED,
+            ),
+            (
+                headers(&[(header::IF_NONE_MATCH, weak_etag.as_str())]),
+                StatusCode::NOT_MODIFIED,
+            ),
+            (
+                headers(&[(header::IF_NONE_MATCH, "*")]),
+                StatusCode::NOT_MODIFIED,
+            ),
+            (
+                headers(&[(header::IF_NONE_MATCH, "\"other\"")]),
+                StatusCode::OK,
+            ),
+            (
+                headers(&[(header::IF_MODIFIED_SINCE, last_modified_str)]),
+                StatusCode::NOT_MODIFIED,
+            ),
+            (
+                headers(&[(header::IF_MODIFIED_SINCE, earlier.as_str())]),
+                StatusCode::OK,
+            ),
+            (
+                headers(&[(header::IF_MODIFIED_SINCE, "yesterday")]),
+                StatusCode::OK,
+            ),
+            // the etag wins over the date
+            (
+                headers(&[
+                    (header::IF_NONE_MATCH, "\"other\""),
+                    (header::IF_MODIFIED_SINCE, last_modified_str),
+                ]),
+                StatusCode::OK,
+            ),
+        ];
+        for (request_headers, status) in cases {
+            let response = rendered(updated).into_response(FeedFormat::Rss, &request_headers);
+            assert_eq!(response.status(), status, "{:?}", request_headers);
+            // the validators come with the 304 too
+            assert_eq!(response.headers()[header::ETAG], etag_str);
+            assert_eq!(response.headers()[header::LAST_MODIFIED], last_modified_str);
+        }
+    }
+}
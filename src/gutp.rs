@@ -0,0 +1,563 @@
+use gutp_types::{
+    GutpComment, GutpModerator, GutpPost, GutpPostDiff, GutpPostTag, GutpSubspace, GutpTag,
+    GutpUser,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt;
+use std::time::Duration;
+
+/// Errors returned by the GUTP backend client
+#[derive(Debug)]
+pub enum GutpError {
+    /// the request couldn't be sent, or the connection broke
+    Request(reqwest::Error),
+    /// gutp answered with a non-success status code
+    Status(reqwest::StatusCode),
+    /// the response body isn't the json we expected
+    Decode(serde_json::Error),
+    /// a create/update endpoint returned an empty list
+    EmptyResponse(&'static str),
+}
+
+impl fmt::Display for GutpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GutpError::Request(err) => write!(f, "gutp request failed: {}", err),
+            GutpError::Status(status) => write!(f, "gutp responded with status {}", status),
+            GutpError::Decode(err) => write!(f, "gutp response couldn't be decoded: {}", err),
+            GutpError::EmptyResponse(path) => write!(f, "gutp returned nothing for {}", path),
+        }
+    }
+}
+
+impl std::error::Error for GutpError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            GutpError::Request(err) => Some(err),
+            GutpError::Decode(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for GutpError {
+    fn from(err: reqwest::Error) -> Self {
+        GutpError::Request(err)
+    }
+}
+
+impl From<serde_json::Error> for GutpError {
+    fn from(err: serde_json::Error) -> Self {
+        GutpError::Decode(err)
+    }
+}
+
+pub type GutpResult<T> = Result<T, GutpError>;
+
+/// The typed client to the GUTP backend.
+///
+/// It is cheap to clone, all clones share the same connection pool.
+#[derive(Clone)]
+pub struct GutpClient {
+    http: reqwest::Client,
+    base_url: String,
+}
+
+impl GutpClient {
+    pub fn new(base_url: &str) -> GutpResult<Self> {
+        let http = reqwest::Client::builder()
+            .user_agent("gutp-discux")
+            .pool_idle_timeout(Duration::from_secs(90))
+            .pool_max_idle_per_host(32)
+            .timeout(Duration::from_secs(10))
+            .build()?;
+
+        Ok(GutpClient {
+            http,
+            base_url: base_url.trim_end_matches('/').to_string(),
+        })
+    }
+
+    pub fn users(&self) -> Users<'_> {
+        Users { client: self }
+    }
+
+    pub fn subspaces(&self) -> Subspaces<'_> {
+        Subspaces { client: self }
+    }
+
+    pub fn posts(&self) -> Posts<'_> {
+        Posts { client: self }
+    }
+
+    pub fn comments(&self) -> Comments<'_> {
+        Comments { client: self }
+    }
+
+    pub fn post_diffs(&self) -> PostDiffs<'_> {
+        PostDiffs { client: self }
+    }
+
+    pub fn tags(&self) -> Tags<'_> {
+        Tags { client: self }
+    }
+
+    pub fn post_tags(&self) -> PostTags<'_> {
+        PostTags { client: self }
+    }
+
+    pub fn moderators(&self) -> Moderators<'_> {
+        Moderators { client: self }
+    }
+
+    async fn get<T: DeserializeOwned, U: Serialize + ?Sized>(
+        &self,
+        path: &'static str,
+        query_param: &U,
+    ) -> GutpResult<Vec<T>> {
+        let url = format!("{}{}", self.base_url, path);
+        let res = self.http.get(&url).query(query_param).send().await?;
+        Self::decode(path, res).await
+    }
+
+    // the list endpoints take the window as `limit` and `offset`
+    async fn get_paged<T: DeserializeOwned, U: Serialize + ?Sized>(
+        &self,
+        path: &'static str,
+        query_param: &U,
+        paging: Paging,
+    ) -> GutpResult<Vec<T>> {
+        let url = format!("{}{}", self.base_url, path);
+        let res = self
+            .http
+            .get(&url)
+            .query(query_param)
+            .query(&paging)
+            .send()
+            .await?;
+        Self::decode(path, res).await
+    }
+
+    async fn post<T: DeserializeOwned, U: Serialize + ?Sized>(
+        &self,
+        path: &'static str,
+        form_param: &U,
+    ) -> GutpResult<Vec<T>> {
+        let url = format!("{}{}", self.base_url, path);
+        let res = self.http.post(&url).form(form_param).send().await?;
+        Self::decode(path, res).await
+    }
+
+    async fn decode<T: DeserializeOwned>(
+        path: &'static str,
+        res: reqwest::Response,
+    ) -> GutpResult<Vec<T>> {
+        let status = res.status();
+        if !status.is_success() {
+            println!("in gutp client: {} -> {}", path, status);
+            return Err(GutpError::Status(status));
+        }
+
+        let text = res.text().await?;
+        let list: Vec<T> = serde_json::from_str(&text)?;
+        Ok(list)
+    }
+
+    // for the queries by id, an empty list means not found
+    async fn get_one<T: DeserializeOwned, U: Serialize + ?Sized>(
+        &self,
+        path: &'static str,
+        query_param: &U,
+    ) -> GutpResult<Option<T>> {
+        let list: Vec<T> = self.get(path, query_param).await?;
+        Ok(list.into_iter().next())
+    }
+
+    // for the mutations, an empty list means gutp refused to do it
+    async fn post_one<T: DeserializeOwned, U: Serialize + ?Sized>(
+        &self,
+        path: &'static str,
+        form_param: &U,
+    ) -> GutpResult<T> {
+        let list: Vec<T> = self.post(path, form_param).await?;
+        list.into_iter()
+            .next()
+            .ok_or(GutpError::EmptyResponse(path))
+    }
+
+    // the delete endpoints return the deleted ids, we don't care about the shape
+    async fn post_delete(&self, path: &'static str, id: &str) -> GutpResult<()> {
+        let _: Vec<serde_json::Value> = self.post(path, &[("id", id)]).await?;
+        Ok(())
+    }
+}
+
+/// A window of a list query
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Paging {
+    pub limit: usize,
+    pub offset: usize,
+}
+
+impl Paging {
+    pub fn new(limit: usize, offset: usize) -> Self {
+        Paging { limit, offset }
+    }
+
+    /// Only tells whether the list is empty
+    pub fn first() -> Self {
+        Paging::new(1, 0)
+    }
+}
+
+#[derive(Serialize)]
+pub struct NewUser {
+    pub account: String,
+    pub oauth_source: String,
+    pub nickname: String,
+    pub avatar: String,
+    pub pub_settings: String,
+    pub ext: String,
+}
+
+#[derive(Serialize)]
+pub struct UserUpdate {
+    pub id: String,
+    pub nickname: String,
+    pub avatar: String,
+    pub pub_settings: String,
+    pub ext: String,
+}
+
+pub struct Users<'a> {
+    client: &'a GutpClient,
+}
+
+impl<'a> Users<'a> {
+    pub async fn by_id(&self, id: &str) -> GutpResult<Option<GutpUser>> {
+        self.client.get_one("/v1/user", &[("id", id)]).await
+    }
+
+    // not under `/v1/user/` like the others, as routed by gutp and mock_data_service
+    pub async fn by_account(&self, account: &str) -> GutpResult<Option<GutpUser>> {
+        self.client
+            .get_one("/v1/user_by_account", &[("account", account)])
+            .await
+    }
+
+    pub async fn create(&self, params: &NewUser) -> GutpResult<GutpUser> {
+        self.client.post_one("/v1/user/create", params).await
+    }
+
+    pub async fn update(&self, params: &UserUpdate) -> GutpResult<GutpUser> {
+        self.client.post_one("/v1/user/update", params).await
+    }
+}
+
+#[derive(Serialize)]
+pub struct NewSubspace {
+    pub title: String,
+    pub description: String,
+    pub banner: String,
+    pub owner_id: String,
+    pub profession: String,
+    pub appid: String,
+    pub is_public: bool,
+    pub slug: String,
+}
+
+#[derive(Serialize)]
+pub struct SubspaceUpdate {
+    pub id: String,
+    pub title: String,
+    pub description: String,
+    pub banner: String,
+    pub slug: String,
+    pub is_public: bool,
+}
+
+pub struct Subspaces<'a> {
+    client: &'a GutpClient,
+}
+
+impl<'a> Subspaces<'a> {
+    pub async fn by_id(&self, id: &str) -> GutpResult<Option<GutpSubspace>> {
+        self.client.get_one("/v1/subspace", &[("id", id)]).await
+    }
+
+    pub async fn list(&self) -> GutpResult<Vec<GutpSubspace>> {
+        let query_params: &[(&str, &str)] = &[];
+        self.client.get("/v1/subspace/list", query_params).await
+    }
+
+    pub async fn create(&self, params: &NewSubspace) -> GutpResult<GutpSubspace> {
+        self.client.post_one("/v1/subspace/create", params).await
+    }
+
+    pub async fn update(&self, params: &SubspaceUpdate) -> GutpResult<GutpSubspace> {
+        self.client.post_one("/v1/subspace/update", params).await
+    }
+
+    pub async fn delete(&self, id: &str) -> GutpResult<()> {
+        self.client.post_delete("/v1/subspace/delete", id).await
+    }
+}
+
+#[derive(Serialize)]
+pub struct NewPost {
+    pub title: String,
+    pub content: String,
+    pub author_id: String,
+    pub author_nickname: String,
+    pub subspace_id: String,
+    pub extlink: String,
+    pub profession: String,
+    pub appid: String,
+    pub is_public: bool,
+}
+
+#[derive(Serialize)]
+pub struct PostUpdate {
+    pub id: String,
+    pub title: String,
+    pub content: String,
+    pub author_id: String,
+    pub extlink: String,
+    pub is_public: bool,
+}
+
+pub struct Posts<'a> {
+    client: &'a GutpClient,
+}
+
+impl<'a> Posts<'a> {
+    pub async fn by_id(&self, id: &str) -> GutpResult<Option<GutpPost>> {
+        self.client.get_one("/v1/post", &[("id", id)]).await
+    }
+
+    /// All the articles of the app, for crawling them
+    pub async fn list(&self, paging: Paging) -> GutpResult<Vec<GutpPost>> {
+        self.client
+            .get_paged("/v1/post/list", &[] as &[(&str, &str)], paging)
+            .await
+    }
+
+    pub async fn list_by_subspace(
+        &self,
+        subspace_id: &str,
+        paging: Paging,
+    ) -> GutpResult<Vec<GutpPost>> {
+        self.client
+            .get_paged(
+                "/v1/post/list_by_subspace",
+                &[("subspace_id", subspace_id)],
+                paging,
+            )
+            .await
+    }
+
+    pub async fn list_by_author(
+        &self,
+        author_id: &str,
+        paging: Paging,
+    ) -> GutpResult<Vec<GutpPost>> {
+        self.client
+            .get_paged(
+                "/v1/post/list_by_author",
+                &[("author_id", author_id)],
+                paging,
+            )
+            .await
+    }
+
+    pub async fn create(&self, params: &NewPost) -> GutpResult<GutpPost> {
+        self.client.post_one("/v1/post/create", params).await
+    }
+
+    pub async fn update(&self, params: &PostUpdate) -> GutpResult<GutpPost> {
+        self.client.post_one("/v1/post/update", params).await
+    }
+
+    pub async fn delete(&self, id: &str) -> GutpResult<()> {
+        self.client.post_delete("/v1/post/delete", id).await
+    }
+}
+
+#[derive(Serialize)]
+pub struct NewPostDiff {
+    pub post_id: String,
+    pub diff: String,
+    pub version_num: i32,
+}
+
+/// The revisions of the articles, one row per edit
+pub struct PostDiffs<'a> {
+    client: &'a GutpClient,
+}
+
+impl<'a> PostDiffs<'a> {
+    pub async fn list_by_post(&self, post_id: &str) -> GutpResult<Vec<GutpPostDiff>> {
+        self.client
+            .get("/v1/postdiff/list_by_post", &[("post_id", post_id)])
+            .await
+    }
+
+    pub async fn create(&self, params: &NewPostDiff) -> GutpResult<GutpPostDiff> {
+        self.client.post_one("/v1/postdiff/create", params).await
+    }
+}
+
+#[derive(Serialize)]
+pub struct NewComment {
+    pub content: String,
+    pub author_id: String,
+    pub author_nickname: String,
+    pub post_id: String,
+    pub parent_comment_id: String,
+    pub is_public: bool,
+}
+
+#[derive(Serialize)]
+pub struct CommentUpdate {
+    pub id: String,
+    pub content: String,
+    pub is_public: bool,
+}
+
+pub struct Comments<'a> {
+    client: &'a GutpClient,
+}
+
+impl<'a> Comments<'a> {
+    pub async fn by_id(&self, id: &str) -> GutpResult<Option<GutpComment>> {
+        self.client.get_one("/v1/comment", &[("id", id)]).await
+    }
+
+    pub async fn list_by_post(
+        &self,
+        post_id: &str,
+        paging: Paging,
+    ) -> GutpResult<Vec<GutpComment>> {
+        self.client
+            .get_paged("/v1/comment/list_by_post", &[("post_id", post_id)], paging)
+            .await
+    }
+
+    pub async fn list_by_author(
+        &self,
+        author_id: &str,
+        paging: Paging,
+    ) -> GutpResult<Vec<GutpComment>> {
+        self.client
+            .get_paged(
+                "/v1/comment/list_by_author",
+                &[("author_id", author_id)],
+                paging,
+            )
+            .await
+    }
+
+    pub async fn create(&self, params: &NewComment) -> GutpResult<GutpComment> {
+        self.client.post_one("/v1/comment/create", params).await
+    }
+
+    pub async fn update(&self, params: &CommentUpdate) -> GutpResult<GutpComment> {
+        self.client.post_one("/v1/comment/update", params).await
+    }
+
+    pub async fn delete(&self, id: &str) -> GutpResult<()> {
+        self.client.post_delete("/v1/comment/delete", id).await
+    }
+}
+
+#[derive(Serialize)]
+pub struct NewTag {
+    pub caption: String,
+    pub subspace_id: String,
+    pub creator_id: String,
+    pub is_subspace_tag: bool,
+    pub is_public: bool,
+}
+
+#[derive(Serialize)]
+pub struct TagUpdate {
+    pub id: String,
+    pub caption: String,
+    pub is_public: bool,
+}
+
+pub struct Tags<'a> {
+    client: &'a GutpClient,
+}
+
+impl<'a> Tags<'a> {
+    pub async fn by_id(&self, id: &str) -> GutpResult<Option<GutpTag>> {
+        self.client.get_one("/v1/tag", &[("id", id)]).await
+    }
+
+    pub async fn list_by_subspace(&self, subspace_id: &str) -> GutpResult<Vec<GutpTag>> {
+        self.client
+            .get("/v1/tag/list_by_subspace", &[("subspace_id", subspace_id)])
+            .await
+    }
+
+    pub async fn create(&self, params: &NewTag) -> GutpResult<GutpTag> {
+        self.client.post_one("/v1/tag/create", params).await
+    }
+
+    pub async fn update(&self, params: &TagUpdate) -> GutpResult<GutpTag> {
+        self.client.post_one("/v1/tag/update", params).await
+    }
+
+    pub async fn delete(&self, id: &str) -> GutpResult<()> {
+        self.client.post_delete("/v1/tag/delete", id).await
+    }
+}
+
+#[derive(Serialize)]
+pub struct NewPostTag {
+    pub post_id: String,
+    pub tag_id: String,
+}
+
+/// The N:M relation between the articles and the tags
+pub struct PostTags<'a> {
+    client: &'a GutpClient,
+}
+
+impl<'a> PostTags<'a> {
+    pub async fn list_by_post(&self, post_id: &str) -> GutpResult<Vec<GutpPostTag>> {
+        self.client
+            .get("/v1/posttag/list_by_post", &[("post_id", post_id)])
+            .await
+    }
+
+    pub async fn list_by_tag(&self, tag_id: &str, paging: Paging) -> GutpResult<Vec<GutpPostTag>> {
+        self.client
+            .get_paged("/v1/posttag/list_by_tag", &[("tag_id", tag_id)], paging)
+            .await
+    }
+
+    pub async fn create(&self, params: &NewPostTag) -> GutpResult<GutpPostTag> {
+        self.client.post_one("/v1/posttag/create", params).await
+    }
+
+    pub async fn delete(&self, id: &str) -> GutpResult<()> {
+        self.client.post_delete("/v1/posttag/delete", id).await
+    }
+}
+
+pub struct Moderators<'a> {
+    client: &'a GutpClient,
+}
+
+impl<'a> Moderators<'a> {
+    pub async fn list_by_subspace(&self, subspace_id: &str) -> GutpResult<Vec<GutpModerator>> {
+        self.client
+            .get(
+                "/v1/moderator/list_by_subspace",
+                &[("subspace_id", subspace_id)],
+            )
+            .await
+    }
+}
@@ -0,0 +1,36 @@
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use serde::Serialize;
+
+use crate::AppState;
+
+#[derive(Serialize)]
+struct HealthStatus {
+    status: &'static str,
+    redis: &'static str,
+}
+
+/// For the operators and the load balancer.
+///
+/// Redis is pinged on each call. When it is down the forum still serves
+/// pages, but nobody can log in, so the status is `degraded` with a 503.
+pub async fn view_health(State(app_state): State<AppState>) -> impl IntoResponse {
+    let redis_ok = match app_state.redis.ping().await {
+        Ok(_) => true,
+        Err(err) => {
+            println!("in health: ping redis: {}", err);
+            false
+        }
+    };
+
+    let (code, status) = if redis_ok {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "degraded")
+    };
+    let health = HealthStatus {
+        status,
+        redis: if redis_ok { "up" } else { "down" },
+    };
+
+    (code, Json(health))
+}
@@ -0,0 +1,237 @@
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap},
+    response::{IntoResponse, Redirect, Response},
+};
+use axum_extra::extract::cookie::{Cookie, CookieJar};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::OnceLock;
+
+use crate::error::ForumError;
+use crate::oauth;
+use crate::AppState;
+
+#[cfg(test)]
+mod tests;
+
+/// Where the translations are loaded from, a table per key with a string per locale
+pub const CATALOG_PATH: &str = "tomls/i18n.toml";
+
+/// The locales offered by the switcher: (code, name in itself)
+pub const LOCALES: [(&str, &str); 2] = [("en", "English"), ("cn", "中文")];
+
+// the choice of the switcher is kept for a year
+const COOKIE_MAX_AGE_DAYS: i64 = 365;
+
+#[derive(Debug)]
+pub enum CatalogError {
+    Read(String, std::io::Error),
+    Parse(String, toml::de::Error),
+}
+
+impl fmt::Display for CatalogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CatalogError::Read(path, err) => write!(f, "couldn't read i18n {}: {}", path, err),
+            CatalogError::Parse(path, err) => write!(f, "couldn't parse i18n {}: {}", path, err),
+        }
+    }
+}
+
+impl std::error::Error for CatalogError {}
+
+/// The translations: key -> locale -> text
+#[derive(Debug, Default)]
+pub struct Catalog {
+    texts: HashMap<String, HashMap<String, String>>,
+    // when the asked locale has no text for a key
+    default_locale: String,
+}
+
+impl Catalog {
+    pub fn parse(content: &str, default_locale: &str) -> Result<Self, toml::de::Error> {
+        Ok(Catalog {
+            texts: toml::from_str(content)?,
+            default_locale: default_locale.to_string(),
+        })
+    }
+
+    pub fn load(path: &str, default_locale: &str) -> Result<Self, CatalogError> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| CatalogError::Read(path.to_string(), err))?;
+        Self::parse(&content, default_locale)
+            .map_err(|err| CatalogError::Parse(path.to_string(), err))
+    }
+
+    #[cfg(test)]
+    pub fn contains(&self, key: &str) -> bool {
+        self.texts.contains_key(key)
+    }
+
+    /// The keys without a text in `locale`
+    #[cfg(test)]
+    pub fn untranslated(&self, locale: &str) -> Vec<&str> {
+        let mut keys: Vec<&str> = self
+            .texts
+            .iter()
+            .filter(|(_, texts)| !texts.contains_key(locale))
+            .map(|(key, _)| key.as_str())
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    /// The text of `key` in `locale`, else in the default locale, else the key itself
+    pub fn text<'a>(&'a self, key: &'a str, locale: &str) -> &'a str {
+        self.texts
+            .get(key)
+            .and_then(|texts| {
+                texts
+                    .get(locale)
+                    .or_else(|| texts.get(&self.default_locale))
+            })
+            .map(|text| text.as_str())
+            .unwrap_or(key)
+    }
+}
+
+static CATALOG: OnceLock<Catalog> = OnceLock::new();
+
+tokio::task_local! {
+    // the locale of the request being handled, set by the top middleware
+    static LOCALE: String;
+}
+
+/// Set the catalog the `i18n` filter reads, once at startup
+pub fn init(catalog: Catalog) {
+    if CATALOG.set(catalog).is_err() {
+        println!("in i18n init: the catalog was already loaded");
+    }
+}
+
+/// Run a request handling with its locale, the templates rendered in it are translated to it
+pub async fn scope<F: Future>(locale: String, f: F) -> F::Output {
+    LOCALE.scope(locale, f).await
+}
+
+/// The locale of the current request, or the default one outside of a request
+pub fn current_locale() -> String {
+    LOCALE
+        .try_with(|locale| locale.to_owned())
+        .unwrap_or_else(|_| {
+            CATALOG
+                .get()
+                .map(|catalog| catalog.default_locale.to_owned())
+                .unwrap_or_else(|| LOCALES[0].0.to_string())
+        })
+}
+
+pub fn is_current(code: &str) -> bool {
+    current_locale() == code
+}
+
+/// The text of `key` in the current locale, see the `i18n` filter
+pub fn translate(key: &str) -> String {
+    let locale = current_locale();
+    match CATALOG.get() {
+        Some(catalog) => catalog.text(key, &locale).to_string(),
+        None => key.to_string(),
+    }
+}
+
+pub fn is_supported(code: &str) -> bool {
+    LOCALES.iter().any(|(supported, _)| *supported == code)
+}
+
+// a language tag of Accept-Language to our locale code, e.g. "zh-CN" -> "cn"
+fn locale_of_tag(tag: &str) -> Option<&'static str> {
+    let primary = tag
+        .split(['-', '_'])
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    match primary.as_str() {
+        "zh" | "cn" => Some("cn"),
+        "en" => Some("en"),
+        _ => None,
+    }
+}
+
+/// The preferred supported locale of an `Accept-Language` header
+pub fn from_accept_language(value: &str) -> Option<&'static str> {
+    let mut tags: Vec<(&str, f32)> = value
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let tag = parts.next()?.trim();
+            let quality = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .and_then(|q| q.parse().ok())
+                .unwrap_or(1.0);
+            (!tag.is_empty() && quality > 0.0).then_some((tag, quality))
+        })
+        .collect();
+    // stable, so the order of the header breaks the ties
+    tags.sort_by(|a, b| b.1.total_cmp(&a.1));
+    tags.into_iter().find_map(|(tag, _)| locale_of_tag(tag))
+}
+
+/// The locale of a request: the cookie of the switcher (set from the user
+/// setting at login), then the browser languages, then the site default
+pub fn negotiate(cookie: Option<&str>, accept_language: Option<&str>, default: &str) -> String {
+    if let Some(code) = cookie.filter(|code| is_supported(code)) {
+        return code.to_string();
+    }
+    accept_language
+        .and_then(from_accept_language)
+        .unwrap_or(default)
+        .to_string()
+}
+
+pub fn cookie_name(appid: &str) -> String {
+    format!("{}_locale", appid)
+}
+
+pub fn locale_cookie(appid: &str, code: &str) -> Cookie<'static> {
+    Cookie::build(cookie_name(appid), code.to_string())
+        .path("/")
+        .max_age(cookie::time::Duration::days(COOKIE_MAX_AGE_DAYS))
+        .http_only(true)
+        .finish()
+}
+
+// back to the page the switcher was clicked on, only on this site
+fn back_path(headers: &HeaderMap, site_url: &str) -> String {
+    let referer = headers
+        .get(header::REFERER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let path = referer
+        .strip_prefix(site_url.trim_end_matches('/'))
+        .filter(|path| path.starts_with('/'));
+    oauth::safe_return_to(path)
+}
+
+/// `/locale/{code}`, the language switcher. It only sets a cookie, the
+/// setting of the user is saved from the profile page
+pub async fn switch_locale(
+    State(app_state): State<AppState>,
+    Path(code): Path<String>,
+    headers: HeaderMap,
+    cookie_jar: CookieJar,
+) -> Result<Response, ForumError> {
+    if !is_supported(&code) {
+        return Err(ForumError::not_found(
+            format!("Switch language: {}", code),
+            "No this language.",
+        ));
+    }
+    let config = &app_state.config;
+    Ok((
+        cookie_jar.add(locale_cookie(&config.appid, &code)),
+        Redirect::to(&back_path(&headers, &config.site_url)),
+    )
+        .into_response())
+}
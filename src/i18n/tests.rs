@@ -0,0 +1,94 @@
+use std::path::{Path, PathBuf};
+
+use super::*;
+
+fn catalog() -> Catalog {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(CATALOG_PATH);
+    Catalog::load(path.to_str().unwrap(), "en").unwrap()
+}
+
+fn templates(dir: &Path, found: &mut Vec<PathBuf>) {
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            templates(&path, found);
+        } else if path.extension().is_some_and(|ext| ext == "html") {
+            found.push(path);
+        }
+    }
+}
+
+// the string literals piped into the filter: `{{ "key"|i18n }}`
+fn i18n_keys(source: &str) -> Vec<String> {
+    let mut keys = Vec::new();
+    for (end, _) in source.match_indices("|i18n") {
+        let before = source[..end].trim_end();
+        if let Some(literal) = before.strip_suffix('"') {
+            if let Some(start) = literal.rfind('"') {
+                keys.push(literal[start + 1..].to_string());
+            }
+        }
+    }
+    keys
+}
+
+#[test]
+fn every_template_key_is_in_the_catalog() {
+    let catalog = catalog();
+    let mut paths = Vec::new();
+    templates(
+        &Path::new(env!("CARGO_MANIFEST_DIR")).join("templates"),
+        &mut paths,
+    );
+    assert!(!paths.is_empty());
+
+    let mut missing = Vec::new();
+    for path in paths {
+        let source = std::fs::read_to_string(&path).unwrap();
+        for key in i18n_keys(&source) {
+            if !catalog.contains(&key) {
+                missing.push(format!("{}: {}", path.display(), key));
+            }
+        }
+    }
+    assert!(
+        missing.is_empty(),
+        "keys missing in the catalog: {:#?}",
+        missing
+    );
+}
+
+#[test]
+fn every_key_is_translated_to_every_locale() {
+    let catalog = catalog();
+    for (code, _) in LOCALES {
+        let keys = catalog.untranslated(code);
+        assert!(keys.is_empty(), "keys without {}: {:?}", code, keys);
+    }
+}
+
+#[test]
+fn text_falls_back_to_the_default_locale_then_the_key() {
+    let catalog = Catalog::parse(
+        "[hello]\nen = \"Hello\"\ncn = \"你好\"\n[bye]\nen = \"Bye\"\n",
+        "en",
+    )
+    .unwrap();
+    assert_eq!(catalog.text("hello", "cn"), "你好");
+    assert_eq!(catalog.text("bye", "cn"), "Bye");
+    assert_eq!(catalog.text("unknown", "cn"), "unknown");
+}
+
+#[test]
+fn negotiate_prefers_the_cookie_then_the_browser() {
+    assert_eq!(negotiate(Some("cn"), Some("en-US"), "en"), "cn");
+    // an unknown cookie value is ignored
+    assert_eq!(negotiate(Some("fr"), Some("zh-CN,zh;q=0.9"), "en"), "cn");
+    assert_eq!(
+        negotiate(None, Some("fr-FR, en;q=0.5, zh;q=0.8"), "en"),
+        "cn"
+    );
+    assert_eq!(negotiate(None, Some("fr, de"), "cn"), "cn");
+    assert_eq!(negotiate(None, Some("zh;q=0, en"), "cn"), "en");
+    assert_eq!(negotiate(None, None, "en"), "en");
+}
@@ -0,0 +1,299 @@
+use redis::{aio::ConnectionLike, AsyncCommands, RedisResult};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::oauth::OauthProfile;
+
+/// An external account linked to a gutp user
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Identity {
+    pub provider: String,
+    /// the stable id on the provider, not the login which can be renamed
+    pub provider_user_id: String,
+    /// the login at the time of the last sign in, for display
+    pub login: String,
+    /// the avatar on the provider, can be picked on the profile page
+    #[serde(default)]
+    pub avatar_url: String,
+    pub linked_at: i64,
+}
+
+impl Identity {
+    pub fn new(provider: &str, profile: &OauthProfile) -> Self {
+        Identity {
+            provider: provider.to_string(),
+            provider_user_id: profile.id.to_owned(),
+            login: profile.login.to_owned(),
+            avatar_url: profile.avatar_url.to_owned(),
+            linked_at: chrono::Utc::now().timestamp(),
+        }
+    }
+
+    /// The username of the password login, see `password.rs`
+    pub fn local(username: &str) -> Self {
+        Identity {
+            provider: crate::password::LOCAL_PROVIDER.to_string(),
+            provider_user_id: username.to_string(),
+            login: username.to_string(),
+            avatar_url: "".to_string(),
+            linked_at: chrono::Utc::now().timestamp(),
+        }
+    }
+
+    fn field(&self) -> String {
+        identity_field(&self.provider, &self.provider_user_id)
+    }
+}
+
+/// Whether a login whose identity isn't linked yet may take the user found by
+/// its account name. Only the users from before the linking have no identity;
+/// with one, the name was renamed away and taken by someone else, or this
+/// identity was unlinked on purpose
+pub fn claimable_by_account(linked: &[Identity]) -> bool {
+    linked.is_empty()
+}
+
+/// The account of a new user whose login is taken by an older account,
+/// made unique by the stable id. No provider allows a `#` in its logins
+pub fn fallback_account(account: &str, provider_user_id: &str) -> String {
+    format!("{}#{}", account, provider_user_id)
+}
+
+// the value of an identity held by `reserve`, not a user id
+const RESERVED_PREFIX: &str = "reserved:";
+// a reservation outlives the creation of the user by far
+const RESERVE_TTL: usize = 60;
+
+fn identity_field(provider: &str, provider_user_id: &str) -> String {
+    format!("{}:{}", provider, provider_user_id)
+}
+
+/// The identity links in redis, they don't expire.
+///
+/// Keys:
+/// - `{appid}_identity:{provider}:{provider_user_id}`, the gutp user id, or
+///   `reserved:{token}` for a while before the user is created
+/// - `{appid}_user_identities:{user_id}`, a hash of the identities of this user
+pub struct IdentityStore {
+    prefix: String,
+}
+
+impl IdentityStore {
+    pub fn new(appid: &str) -> Self {
+        IdentityStore {
+            prefix: appid.to_string(),
+        }
+    }
+
+    fn identity_key(&self, field: &str) -> String {
+        format!("{}_identity:{}", self.prefix, field)
+    }
+
+    fn user_index_key(&self, user_id: &str) -> String {
+        format!("{}_user_identities:{}", self.prefix, user_id)
+    }
+
+    /// The gutp user this identity is linked to
+    pub async fn find<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        provider: &str,
+        provider_user_id: &str,
+    ) -> RedisResult<Option<String>> {
+        let field = identity_field(provider, provider_user_id);
+        let user_id: Option<String> = conn.get(self.identity_key(&field)).await?;
+        Ok(user_id.filter(|user_id| !user_id.starts_with(RESERVED_PREFIX)))
+    }
+
+    /// Hold an identity for a user not created yet, so two signups can't both
+    /// create one for it. Returns the token for `link_reserved`, None when the
+    /// identity is taken or held. It's released after `RESERVE_TTL` anyway
+    pub async fn reserve<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        identity: &Identity,
+    ) -> RedisResult<Option<String>> {
+        let token = format!("{}{}", RESERVED_PREFIX, crate::csrf::new_token());
+        let reserved: Option<String> = redis::cmd("SET")
+            .arg(self.identity_key(&identity.field()))
+            .arg(&token)
+            .arg("NX")
+            .arg("EX")
+            .arg(RESERVE_TTL)
+            .query_async(conn)
+            .await?;
+        Ok(reserved.map(|_| token))
+    }
+
+    /// Link a reserved identity to the user just created for it. Returns
+    /// false when the reservation was lost meanwhile
+    pub async fn link_reserved<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        user_id: &str,
+        identity: &Identity,
+        token: &str,
+    ) -> RedisResult<bool> {
+        // the SET drops the expiry
+        let linked: bool = redis::Script::new(
+            r"
+            if redis.call('GET', KEYS[1]) == ARGV[1] then
+                redis.call('SET', KEYS[1], ARGV[2])
+                return 1
+            end
+            return 0
+            ",
+        )
+        .key(self.identity_key(&identity.field()))
+        .arg(token)
+        .arg(user_id)
+        .invoke_async(conn)
+        .await?;
+        if !linked {
+            return Ok(false);
+        }
+        self.index(conn, user_id, identity).await?;
+        Ok(true)
+    }
+
+    /// Give up a reservation, e.g. the user couldn't be created
+    pub async fn release<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        identity: &Identity,
+        token: &str,
+    ) -> RedisResult<()> {
+        redis::Script::new(
+            r"
+            if redis.call('GET', KEYS[1]) == ARGV[1] then
+                redis.call('DEL', KEYS[1])
+            end
+            ",
+        )
+        .key(self.identity_key(&identity.field()))
+        .arg(token)
+        .invoke_async(conn)
+        .await
+    }
+
+    /// Link an identity to a user, or refresh it if already linked to them.
+    ///
+    /// Returns false when the identity belongs to another user.
+    pub async fn link<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        user_id: &str,
+        identity: &Identity,
+    ) -> RedisResult<bool> {
+        let field = identity.field();
+        let key = self.identity_key(&field);
+        let claimed: bool = conn.set_nx(&key, user_id).await?;
+        if !claimed {
+            let owner: Option<String> = conn.get(&key).await?;
+            if owner.as_deref() != Some(user_id) {
+                return Ok(false);
+            }
+        }
+
+        self.index(conn, user_id, identity).await?;
+        Ok(true)
+    }
+
+    // add the identity to the hash of its user
+    async fn index<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        user_id: &str,
+        identity: &Identity,
+    ) -> RedisResult<()> {
+        let field = identity.field();
+        let index_key = self.user_index_key(user_id);
+        let mut identity = identity.clone();
+        // keep the first link time when it's only a refresh of the login
+        let old: Option<String> = conn.hget(&index_key, &field).await?;
+        if let Some(old) = old.and_then(|old| serde_json::from_str::<Identity>(&old).ok()) {
+            identity.linked_at = old.linked_at;
+        }
+        let value = serde_json::to_string(&identity).unwrap_or_default();
+        conn.hset(&index_key, &field, value).await
+    }
+
+    /// Returns false if this identity isn't linked to the user
+    pub async fn unlink<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        user_id: &str,
+        provider: &str,
+        provider_user_id: &str,
+    ) -> RedisResult<bool> {
+        let field = identity_field(provider, provider_user_id);
+        let key = self.identity_key(&field);
+        let owner: Option<String> = conn.get(&key).await?;
+        if owner.as_deref() != Some(user_id) {
+            return Ok(false);
+        }
+
+        redis::pipe()
+            .atomic()
+            .del(&key)
+            .ignore()
+            .hdel(self.user_index_key(user_id), &field)
+            .ignore()
+            .query_async::<_, ()>(conn)
+            .await?;
+
+        Ok(true)
+    }
+
+    pub async fn list_by_user<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        user_id: &str,
+    ) -> RedisResult<Vec<Identity>> {
+        let fields: HashMap<String, String> = conn.hgetall(self.user_index_key(user_id)).await?;
+        let mut identities: Vec<Identity> = fields
+            .values()
+            .filter_map(|value| serde_json::from_str(value).ok())
+            .collect();
+        identities.sort_by_key(|identity| identity.linked_at);
+
+        Ok(identities)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(id: &str) -> OauthProfile {
+        OauthProfile {
+            id: id.to_string(),
+            login: "alice".to_string(),
+            name: "Alice".to_string(),
+            avatar_url: "".to_string(),
+        }
+    }
+
+    #[test]
+    fn renamed_login_doesnt_take_the_old_account() {
+        // the first "alice" logged in since the linking, under the id 7
+        let linked = [Identity::new("github", &profile("7"))];
+        assert!(!claimable_by_account(&linked));
+
+        // the first "alice" unlinked github and kept the password login
+        let linked = [Identity::local("alice")];
+        assert!(!claimable_by_account(&linked));
+
+        // the first "alice" logged in before the linking existed
+        assert!(claimable_by_account(&[]));
+    }
+
+    #[test]
+    fn new_owner_of_a_login_gets_another_account() {
+        // the new "alice" has the id 42
+        let identity = Identity::new("github", &profile("42"));
+        let account = fallback_account(&identity.login, &identity.provider_user_id);
+        assert_eq!(account, "alice#42");
+        assert_ne!(fallback_account("alice", "7"), account);
+    }
+}
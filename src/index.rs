@@ -7,31 +7,40 @@ use axum::{
 use gutp_types::{GutpComment, GutpPost, GutpSubspace, GutpUser};
 use serde::{Deserialize, Serialize};
 
+use crate::error::ForumError;
+use crate::filters;
+use crate::permission::{self, Permission};
 use crate::AppState;
 use crate::HtmlTemplate;
-use crate::{make_get, make_post};
-use crate::{redirect_to_error_page, LoggedUser};
+use crate::LoggedUser;
 
 #[derive(Template)]
 #[template(path = "index.html")]
 struct IndexTemplate {
     subspaces: Vec<GutpSubspace>,
+    permission: Permission,
 }
 
-pub async fn view_index(logged_user: Option<Extension<LoggedUser>>) -> impl IntoResponse {
-    // check the user login status
-    if let Some(Extension(logged_user)) = logged_user {
-        println!("user: {:?}", logged_user);
-    } else {
-        println!("no user: {:?}", logged_user);
-    }
-
-    let query_params: &[(&str, &str)] = &[];
+pub async fn view_index(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+) -> Result<impl IntoResponse, ForumError> {
     // get subspace tags
-    let subspaces: Vec<GutpSubspace> = make_get("/v1/subspace/list", query_params)
+    let subspaces = app_state
+        .gutp
+        .subspaces()
+        .list()
         .await
-        .unwrap_or(vec![]);
+        .map_err(ForumError::gutp("Query subspaces"))?;
+    // the private ones are only listed to the ones who moderate them
+    let subspaces =
+        permission::visible_subspaces(&app_state, logged_user.as_deref(), subspaces).await?;
+
+    let permission = Permission::site(&app_state, logged_user.as_deref());
 
     // render the page
-    HtmlTemplate(IndexTemplate { subspaces })
+    Ok(HtmlTemplate(IndexTemplate {
+        subspaces,
+        permission,
+    }))
 }
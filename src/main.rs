@@ -2,11 +2,11 @@
 use askama::Template;
 use axum::{
     extract::{Query, RawQuery, State},
-    http::{response, uri::Uri, Request, StatusCode},
+    http::{header, response, uri::Uri, Request, StatusCode},
     middleware::{self, Next},
     response::{Html, IntoResponse, Redirect, Response},
     routing::{get, post},
-    Router,
+    Extension, Router,
 };
 use axum_extra::extract::cookie::{Cookie, CookieJar};
 use redis::AsyncCommands;
@@ -20,26 +20,80 @@ use tower_http::{
     trace::TraceLayer,
 };
 
+mod api;
+mod api_token;
 mod article;
 mod comment;
+mod comment_history;
+mod config;
+mod csrf;
+mod datetime;
+mod error;
+#[cfg(test)]
+mod fake_redis;
+mod feed;
+mod gutp;
+mod health;
+mod i18n;
+mod identity;
 mod index;
+mod oauth;
+mod pagination;
+mod password;
+mod permission;
+mod profile;
+mod redis_pool;
+mod revision;
+mod search;
+mod session;
+mod slug;
 mod subspace;
+mod tag;
+mod upload;
 mod user;
 
 pub struct AppStateInner {
-    // hclient: reqwest::Client,
-    rclient: redis::Client,
+    config: config::Config,
+    gutp: gutp::GutpClient,
+    redis: redis_pool::RedisPool,
+    sessions: session::SessionStore,
+    oauth_states: oauth::OauthStateStore,
+    oauth_providers: oauth::OauthProviders,
+    identities: identity::IdentityStore,
+    passwords: password::PasswordStore,
+    comment_history: comment_history::CommentHistoryStore,
+    api_tokens: api_token::ApiTokenStore,
+    search: search::SearchIndex,
+    site_feed: feed::SiteFeedCache,
 }
 
 pub type AppState = Arc<AppStateInner>;
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct LoggedUser {
     user_id: String,
+    session_id: String,
+    // bound to the session, checked by csrf_middleware
+    csrf_token: String,
 }
 
-pub const APPPROFESSION: &str = "it";
-pub const APPID: &str = "discux";
+// the session id and the csrf token are credentials, they're never printed
+impl Debug for LoggedUser {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LoggedUser")
+            .field("user_id", &self.user_id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// For the handlers which can only be accessed after login
+pub fn require_login(
+    logged_user: Option<Extension<LoggedUser>>,
+) -> Result<LoggedUser, error::ForumError> {
+    logged_user
+        .map(|Extension(logged_user)| logged_user)
+        .ok_or(error::ForumError::NotLoggedIn)
+}
 
 // The customized middleware
 async fn top_middleware<B>(
@@ -52,28 +106,91 @@ async fn top_middleware<B>(
     next: Next<B>,
 ) -> Response {
     // do something with `request`...
-    let cookie_key = format!("{}_sid", &APPID);
-    if let Some(cookie) = cookie_jar.get(&cookie_key) {
-        let mut redis_conn = app_state.rclient.get_async_connection().await.unwrap();
-        // check this session id with redis
-        let key = format!("{}_sid:{}", &APPID, cookie.value());
-        println!("in middleware session_sid: {}", key);
-
-        let result: Result<String, redis::RedisError> = redis_conn.get(&key).await;
-        if let Ok(user_id) = result {
-            println!("ready to insert user_id in Extension: {}", user_id);
-            // insert this user_id to request extension
-            req.extensions_mut().insert(LoggedUser { user_id });
-        } else {
-            // no this session, do nothing
+    let mut slid_session_id = None;
+    let mut stale_cookie = false;
+    if let Some(cookie) = cookie_jar.get(&app_state.sessions.cookie_name()) {
+        // check this session id with redis, the id itself is never logged
+        // when redis is down, go on as an anonymous visitor
+        let loaded = match app_state.redis.conn().await {
+            Ok(mut redis_conn) => {
+                app_state
+                    .sessions
+                    .load(&mut redis_conn, cookie.value())
+                    .await
+            }
+            Err(err) => Err(err),
+        };
+        match loaded {
+            Ok(Some((session, slid))) => {
+                if slid {
+                    slid_session_id = Some(session.id.to_owned());
+                }
+                // insert this user_id to request extension
+                req.extensions_mut().insert(LoggedUser {
+                    user_id: session.user_id,
+                    session_id: session.id,
+                    csrf_token: session.csrf_token,
+                });
+            }
+            Ok(None) => {
+                // no this session (expired, revoked or from before the rework),
+                // the cookie is removed
+                stale_cookie = true;
+            }
+            Err(err) => {
+                println!("in middleware load session: {}", err);
+                app_state.redis.set_available(false);
+            }
         }
     } else {
         // no cookie, do nothing
     }
 
-    let response = next.run(req).await;
+    // the templates rendered by the handler are translated to this locale
+    let accept_language = req
+        .headers()
+        .get(header::ACCEPT_LANGUAGE)
+        .and_then(|v| v.to_str().ok());
+    let locale = i18n::negotiate(
+        cookie_jar
+            .get(&i18n::cookie_name(&app_state.config.appid))
+            .map(|cookie| cookie.value()),
+        accept_language,
+        &app_state.config.default_locale,
+    );
+    // and the dates in them are shown in this timezone
+    let timezone = datetime::negotiate(
+        cookie_jar
+            .get(&datetime::cookie_name(&app_state.config.appid))
+            .map(|cookie| cookie.value()),
+    );
+    let mut response = i18n::scope(locale, datetime::scope(timezone, next.run(req))).await;
 
     // do something with `response`...
+    // the session ttl was slid, so slide the cookie too, or the session is
+    // gone, so remove it, unless the handler has set its own (login, signout)
+    let session_cookie = match slid_session_id {
+        Some(session_id) => Some(app_state.sessions.cookie(&session_id)),
+        None if stale_cookie => {
+            let mut cookie = app_state.sessions.removal_cookie();
+            cookie.make_removal();
+            Some(cookie)
+        }
+        None => None,
+    };
+    if let Some(cookie) = session_cookie {
+        let prefix = format!("{}=", app_state.sessions.cookie_name());
+        let handler_set = response
+            .headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .any(|value| value.as_bytes().starts_with(prefix.as_bytes()));
+        if !handler_set {
+            if let Ok(value) = cookie.to_string().parse() {
+                response.headers_mut().append(header::SET_COOKIE, value);
+            }
+        }
+    }
 
     response
 }
@@ -82,26 +199,130 @@ async fn top_middleware<B>(
 async fn main() {
     dotenv::dotenv().ok();
 
-    // let http_client = reqwest::Client::new();
-    let redis_client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    let config = match config::Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let addr = config.socket_addr().unwrap();
+    // validated by the config
+    if let Some(timezone) = datetime::parse_timezone(&config.timezone) {
+        datetime::init(timezone);
+    }
+    match i18n::Catalog::load(i18n::CATALOG_PATH, &config.default_locale) {
+        Ok(catalog) => i18n::init(catalog),
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    }
+
+    let gutp_client = gutp::GutpClient::new(&config.gutp_host).unwrap();
+    let redis_client = redis::Client::open(config.redis_url.as_str()).unwrap();
+    let redis_pool = redis_pool::RedisPool::new(redis_client);
+    if let Err(err) = redis_pool.ping().await {
+        println!(
+            "redis is unavailable at startup, sessions are disabled until it's back: {}",
+            err
+        );
+    }
+    let session_store = session::SessionStore::new(&config.appid, config.session_ttl());
+
+    let oauth_state_store = oauth::OauthStateStore::new(&config.appid);
+    let oauth_providers = oauth::OauthProviders::from_config(&config);
+    let identity_store = identity::IdentityStore::new(&config.appid);
+    let password_store = password::PasswordStore::new(&config.appid);
+    let comment_history_store = comment_history::CommentHistoryStore::new(&config.appid);
+    let api_token_store = api_token::ApiTokenStore::new(&config.appid);
+    // the commands, instead of serving
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+
+    // the index directory is locked by the one process writing it, so
+    // `reindex` fails while the forum runs, the other commands only read it
+    let search_index = if matches!(args[..], [] | ["reindex"]) {
+        search::SearchIndex::open(&config.search_dir)
+    } else {
+        search::SearchIndex::open_read_only(&config.search_dir)
+    };
+    let search_index = match search_index {
+        Ok(search_index) => search_index,
+        Err(err) => {
+            eprintln!(
+                "couldn't open the search index {}: {}",
+                config.search_dir, err
+            );
+            std::process::exit(1);
+        }
+    };
 
     let app_state: AppState = Arc::new(AppStateInner {
-        // hclient: http_client,
-        rclient: redis_client,
+        config,
+        gutp: gutp_client,
+        redis: redis_pool,
+        sessions: session_store,
+        oauth_states: oauth_state_store,
+        oauth_providers,
+        identities: identity_store,
+        passwords: password_store,
+        comment_history: comment_history_store,
+        api_tokens: api_token_store,
+        search: search_index,
+        site_feed: feed::SiteFeedCache::default(),
     });
 
+    match args[..] {
+        ["password-link", username] => {
+            match user::password_link_command(&app_state, username).await {
+                Ok(link) => println!("{}", link),
+                Err(err) => {
+                    eprintln!("{}", err);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        ["reindex"] => {
+            match search::reindex_command(&app_state).await {
+                Ok((articles, comments)) => {
+                    println!("indexed {} articles, {} comments", articles, comments)
+                }
+                Err(err) => {
+                    eprintln!("{}", err);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        [] => {}
+        _ => {
+            eprintln!("usage: rustcc_forum [password-link <username> | reindex]");
+            std::process::exit(2);
+        }
+    }
+
     let app = Router::new()
         .route("/", get(index::view_index))
-        .route("/subspace", get(subspace::view_subspace))
+        .route("/subspace", get(subspace::redirect_subspace))
+        .route("/s/:key", get(subspace::view_subspace))
         .route(
             "/subspace/create",
             get(subspace::view_subspace_create).post(subspace::post_subspace_create),
         )
+        .route(
+            "/subspace/edit",
+            get(subspace::view_subspace_edit).post(subspace::post_subspace_edit),
+        )
+        .route("/subspace/banner", post(subspace::post_subspace_banner))
         .route(
             "/subspace/delete",
             get(subspace::view_subspace_delete).post(subspace::post_subspace_delete),
         )
-        .route("/article", get(article::view_article))
+        .route("/article", get(article::redirect_article))
+        .route("/a/:id", get(article::view_article))
+        .route("/a/:id/:slug", get(article::view_article))
         .route(
             "/article/create",
             get(article::view_article_create).post(article::post_article_create),
@@ -114,101 +335,112 @@ async fn main() {
             "/article/delete",
             get(article::view_article_delete).post(article::post_article_delete),
         )
+        .route("/article/:id/history", get(revision::view_history))
+        .route("/article/:id/diff", get(revision::view_diff))
+        .route("/article/:id/rollback", post(revision::post_rollback))
+        .route("/search", get(search::view_search))
+        .route("/feed.xml", get(feed::view_site_feed))
+        .route("/atom.xml", get(feed::view_site_feed))
+        .route("/subspace/:key/feed.xml", get(feed::view_subspace_feed))
+        .route("/subspace/:key/atom.xml", get(feed::view_subspace_feed))
+        .route("/tag/:id/feed.xml", get(feed::view_tag_feed))
+        .route("/tag/:id/atom.xml", get(feed::view_tag_feed))
+        .route("/user/:id/feed.xml", get(feed::view_user_feed))
+        .route("/user/:id/atom.xml", get(feed::view_user_feed))
+        .route("/locale/:code", get(i18n::switch_locale))
+        .route("/tag/:id", get(tag::view_tag))
+        .route(
+            "/tag/create",
+            get(tag::view_tag_create).post(tag::post_tag_create),
+        )
+        .route(
+            "/tag/edit",
+            get(tag::view_tag_edit).post(tag::post_tag_edit),
+        )
+        .route(
+            "/tag/delete",
+            get(tag::view_tag_delete).post(tag::post_tag_delete),
+        )
         .route(
             "/comment/create",
             get(comment::view_comment_create).post(comment::post_comment_create),
         )
+        .route(
+            "/comment/edit",
+            get(comment::view_comment_edit).post(comment::post_comment_edit),
+        )
+        .route("/comment/history", get(comment::view_comment_history))
         .route(
             "/comment/delete",
             get(comment::view_comment_delete).post(comment::post_comment_delete),
         )
+        .route("/health", get(health::view_health))
         .route("/user/account", get(user::view_account))
-        .route("/user/signout", get(user::signout))
-        .route("/user/login", get(user::view_login))
+        .route("/user/:id", get(profile::view_user))
+        .route(
+            "/user/profile/edit",
+            get(profile::view_profile_edit).post(profile::post_profile_edit),
+        )
+        .route("/user/profile/avatar", post(profile::post_profile_avatar))
+        .route(
+            "/user/signout",
+            get(user::view_signout).post(user::post_signout),
+        )
+        .route("/user/signout_all", post(user::signout_all))
+        .route("/user/session/revoke", post(user::revoke_session))
+        .route("/user/identity/link", post(user::link_identity))
+        .route("/user/identity/unlink", post(user::unlink_identity))
+        .route("/user/api_token/create", post(user::create_api_token))
+        .route("/user/api_token/revoke", post(user::revoke_api_token))
+        .route("/user/login", get(user::view_login).post(user::post_login))
+        .route(
+            "/user/register",
+            get(user::view_register).post(user::post_register),
+        )
+        .route(
+            "/user/password",
+            get(user::view_change_password).post(user::post_change_password),
+        )
+        .route(
+            "/user/password/reset",
+            get(user::view_password_reset).post(user::post_password_reset),
+        )
+        .route(
+            "/user/password/reset_link",
+            post(user::post_password_reset_link),
+        )
+        .route("/user/login/:provider", get(user::oauth_login))
         .route(
             "/user/github_oauth_callback",
             get(user::github_oauth_callback),
         )
-        .route("/error/info", get(view_error_info))
+        .route("/user/oauth/:provider/callback", get(user::oauth_callback))
+        .nest(api::API_PREFIX, api::routes(app_state.clone()))
+        // the later layer runs first, csrf_middleware needs the LoggedUser
+        .layer(middleware::from_fn_with_state(
+            csrf::SiteOrigin::of(&app_state.config.site_url),
+            csrf::csrf_middleware,
+        ))
         .layer(middleware::from_fn_with_state(
             app_state.clone(),
             top_middleware,
         ))
         .nest_service("/assets", ServeDir::new("assets"))
+        .nest_service(
+            upload::UPLOADS_PATH,
+            ServeDir::new(&app_state.config.upload_dir),
+        )
         .nest_service("/favicon.ico", ServeFile::new("assets/favicon.ico"))
         .with_state(app_state);
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3333));
     println!("reverse proxy listening on {}", addr);
     axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        // the peer address is needed to rate limit the password logins
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .unwrap();
 }
 
-/// helper function
-// call it like:  make_get::<GutpPost>(...)
-// or: let avec: Vec<GutpPost> = make_get(...)
-pub async fn make_get<T: DeserializeOwned + Debug, U: Serialize + ?Sized>(
-    path: &str,
-    query_param: &U,
-) -> anyhow::Result<Vec<T>> {
-    let host = "http://127.0.0.1:3000";
-    let url = format!("{}{}", host, path);
-
-    let client = reqwest::Client::new();
-    let res = client
-        .get(&url)
-        .query(query_param)
-        .header("User-Agent", "gutp-discux")
-        .send()
-        .await?;
-
-    println!("in make get: {:?}", res);
-    let text = res.text().await?;
-    println!("in make get: {:?}", text);
-
-    let list: Vec<T> = serde_json::from_str(&text)?;
-
-    // let list: Vec<T> = res
-    //     .json() // convert the response to coresponding rust type
-    //     .await?;
-
-    // println!("in make_get res: {:?}", list);
-
-    Ok(list)
-}
-
-pub async fn make_post<T: DeserializeOwned + Debug, U: Serialize + ?Sized>(
-    path: &str,
-    form_param: &U,
-) -> anyhow::Result<Vec<T>> {
-    let host = "http://127.0.0.1:3000";
-    let url = format!("{}{}", host, path);
-
-    let client = reqwest::Client::new();
-    let res = client
-        .post(&url)
-        .form(form_param)
-        .header("User-Agent", "gutp-discux")
-        .send()
-        .await?;
-
-    println!("in make post: {:?}", res);
-    let text = res.text().await?;
-    println!("in make post: {:?}", text);
-
-    let list: Vec<T> = serde_json::from_str(&text)?;
-
-    // let list: Vec<T> = res
-    //     .json() // convert the response to coresponding rust type
-    //     .await?;
-
-    // println!("in make_post res: {:?}", list);
-
-    Ok(list)
-}
-
 /// Define the template handler
 pub struct HtmlTemplate<T>(T);
 
@@ -228,26 +460,6 @@ where
     }
 }
 
-#[derive(Template, Deserialize)]
-#[template(path = "action_error.html")]
-struct ErrorInfoTemplate {
-    action: String,
-    err_info: String,
-}
-
-async fn view_error_info(Query(params): Query<ErrorInfoTemplate>) -> impl IntoResponse {
-    // render the page
-    HtmlTemplate(ErrorInfoTemplate {
-        action: params.action,
-        err_info: params.err_info,
-    })
-}
-
-pub fn redirect_to_error_page(action: &str, err_info: &str) -> Redirect {
-    let redirect_uri = format!("/error/info?action={}&err_info={}", action, err_info);
-    Redirect::to(&redirect_uri)
-}
-
 // #[derive(Template)]
 // #[template(source = "{{ t|date }}", ext = "txt")]
 // struct MyFilterTemplate {
@@ -256,9 +468,30 @@ pub fn redirect_to_error_page(action: &str, err_info: &str) -> Redirect {
 
 // Any filter defined in the module `filters` is accessible in your template.
 pub mod filters {
+    // `{{ "key"|i18n }}`, the text of the key in the locale of the request
+    pub fn i18n<T: std::fmt::Display>(key: T) -> ::askama::Result<String> {
+        Ok(crate::i18n::translate(&key.to_string()))
+    }
+
     // This filter does not have extra arguments
+    // the date in the timezone of the user, "-" for a broken timestamp
     pub fn date(t: &i64) -> ::askama::Result<String> {
-        let dt = chrono::NaiveDateTime::from_timestamp_opt(t.clone(), 0).unwrap();
-        Ok(dt.format("%Y-%m-%d %H:%M:%S").to_string())
+        Ok(crate::datetime::absolute(*t))
+    }
+
+    // "3 hours ago", with the full date on hover
+    pub fn timeago(t: &i64) -> ::askama::Result<::askama::MarkupDisplay<::askama::Html, String>> {
+        Ok(::askama::MarkupDisplay::new_safe(
+            crate::datetime::timeago_html(*t),
+            ::askama::Html,
+        ))
+    }
+
+    pub fn article_url(post: &gutp_types::GutpPost) -> ::askama::Result<String> {
+        Ok(crate::slug::article_path(post))
+    }
+
+    pub fn subspace_url(sp: &gutp_types::GutpSubspace) -> ::askama::Result<String> {
+        Ok(crate::slug::subspace_path(sp))
     }
 }
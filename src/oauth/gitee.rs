@@ -0,0 +1,104 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use super::provider::{
+    get_json, request_token, with_query, OAuthProvider, OauthError, OauthProfile,
+};
+use crate::config::ProviderConfig;
+
+/// gitee.com
+pub struct GiteeProvider {
+    http: reqwest::Client,
+    client_id: String,
+    client_secret: String,
+    base_url: String,
+}
+
+impl GiteeProvider {
+    pub fn new(http: reqwest::Client, config: &ProviderConfig) -> Self {
+        let base_url = if config.base_url.is_empty() {
+            "https://gitee.com"
+        } else {
+            config.base_url.trim_end_matches('/')
+        };
+
+        GiteeProvider {
+            http,
+            client_id: config.client_id.to_owned(),
+            client_secret: config.client_secret.to_owned(),
+            base_url: base_url.to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct GiteeUserInfo {
+    id: i64,
+    login: String,
+    name: Option<String>,
+    avatar_url: Option<String>,
+}
+
+#[async_trait]
+impl OAuthProvider for GiteeProvider {
+    fn name(&self) -> &'static str {
+        "gitee"
+    }
+
+    fn display_name(&self) -> &'static str {
+        "Gitee"
+    }
+
+    fn authorize_url(&self, state: &str, code_challenge: &str, redirect_uri: &str) -> String {
+        with_query(
+            &format!("{}/oauth/authorize", self.base_url),
+            &[
+                ("client_id", &self.client_id),
+                ("redirect_uri", redirect_uri),
+                ("response_type", "code"),
+                ("scope", "user_info"),
+                ("state", state),
+                ("code_challenge", code_challenge),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+    }
+
+    async fn exchange_code(
+        &self,
+        code: &str,
+        code_verifier: &str,
+        redirect_uri: &str,
+    ) -> Result<String, OauthError> {
+        request_token(
+            &self.http,
+            &format!("{}/oauth/token", self.base_url),
+            &[
+                ("client_id", &self.client_id),
+                ("client_secret", &self.client_secret),
+                ("code", code),
+                ("code_verifier", code_verifier),
+                ("grant_type", "authorization_code"),
+                ("redirect_uri", redirect_uri),
+            ],
+        )
+        .await
+    }
+
+    async fn fetch_profile(&self, access_token: &str) -> Result<OauthProfile, OauthError> {
+        // gitee takes the token as a query parameter
+        let request = self
+            .http
+            .get(format!("{}/api/v5/user", self.base_url))
+            .query(&[("access_token", access_token)]);
+        let user_info: GiteeUserInfo = get_json(request).await?;
+        println!("in gitee fetch_profile, {:?}", user_info);
+
+        Ok(OauthProfile {
+            id: user_info.id.to_string(),
+            name: user_info.name.unwrap_or_else(|| user_info.login.to_owned()),
+            login: user_info.login,
+            avatar_url: user_info.avatar_url.unwrap_or_default(),
+        })
+    }
+}
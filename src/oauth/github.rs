@@ -0,0 +1,119 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use super::provider::{
+    get_json, request_token, with_query, OAuthProvider, OauthError, OauthProfile,
+};
+use crate::config::ProviderConfig;
+
+/// github.com, or a GitHub Enterprise server when `base_url` is set
+pub struct GithubProvider {
+    http: reqwest::Client,
+    client_id: String,
+    client_secret: String,
+    base_url: String,
+    api_url: String,
+}
+
+impl GithubProvider {
+    pub fn new(http: reqwest::Client, config: &ProviderConfig) -> Self {
+        let (base_url, api_url) = if config.base_url.is_empty() {
+            (
+                "https://github.com".to_string(),
+                "https://api.github.com".to_string(),
+            )
+        } else {
+            let base_url = config.base_url.trim_end_matches('/').to_string();
+            let api_url = format!("{}/api/v3", base_url);
+            (base_url, api_url)
+        };
+
+        GithubProvider {
+            http,
+            client_id: config.client_id.to_owned(),
+            client_secret: config.client_secret.to_owned(),
+            base_url,
+            api_url,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct GithubUserInfo {
+    id: i64,
+    login: String,
+    name: Option<String>,
+    #[serde(default)]
+    avatar_url: String,
+}
+
+#[async_trait]
+impl OAuthProvider for GithubProvider {
+    fn name(&self) -> &'static str {
+        "github"
+    }
+
+    fn display_name(&self) -> &'static str {
+        "GitHub"
+    }
+
+    fn authorize_url(&self, state: &str, code_challenge: &str, redirect_uri: &str) -> String {
+        with_query(
+            &format!("{}/login/oauth/authorize", self.base_url),
+            &[
+                ("client_id", &self.client_id),
+                ("redirect_uri", redirect_uri),
+                ("scope", "read:user"),
+                ("state", state),
+                ("code_challenge", code_challenge),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+    }
+
+    async fn exchange_code(
+        &self,
+        code: &str,
+        code_verifier: &str,
+        redirect_uri: &str,
+    ) -> Result<String, OauthError> {
+        request_token(
+            &self.http,
+            &format!("{}/login/oauth/access_token", self.base_url),
+            &[
+                ("client_id", &self.client_id),
+                ("client_secret", &self.client_secret),
+                ("code", code),
+                ("code_verifier", code_verifier),
+                ("redirect_uri", redirect_uri),
+            ],
+        )
+        .await
+    }
+
+    async fn fetch_profile(&self, access_token: &str) -> Result<OauthProfile, OauthError> {
+        let request = self
+            .http
+            .get(format!("{}/user", self.api_url))
+            .bearer_auth(access_token);
+        let user_info: GithubUserInfo = get_json(request).await?;
+        println!("in github fetch_profile, {:?}", user_info);
+
+        Ok(OauthProfile {
+            id: user_info.id.to_string(),
+            name: user_info.name.unwrap_or_else(|| user_info.login.to_owned()),
+            login: user_info.login,
+            avatar_url: user_info.avatar_url,
+        })
+    }
+
+    // the github users were registered before the other providers, with the bare login
+    fn account(&self, profile: &OauthProfile) -> String {
+        profile.login.to_owned()
+    }
+
+    // the callback url registered in the existing github app
+    fn callback_path(&self) -> String {
+        "/user/github_oauth_callback".to_string()
+    }
+}
@@ -0,0 +1,105 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use super::provider::{
+    get_json, request_token, with_query, OAuthProvider, OauthError, OauthProfile,
+};
+use crate::config::ProviderConfig;
+
+/// gitlab.com, or a self-hosted GitLab when `base_url` is set
+pub struct GitlabProvider {
+    http: reqwest::Client,
+    client_id: String,
+    client_secret: String,
+    base_url: String,
+}
+
+impl GitlabProvider {
+    pub fn new(http: reqwest::Client, config: &ProviderConfig) -> Self {
+        let base_url = if config.base_url.is_empty() {
+            "https://gitlab.com"
+        } else {
+            config.base_url.trim_end_matches('/')
+        };
+
+        GitlabProvider {
+            http,
+            client_id: config.client_id.to_owned(),
+            client_secret: config.client_secret.to_owned(),
+            base_url: base_url.to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct GitlabUserInfo {
+    id: i64,
+    username: String,
+    name: Option<String>,
+    avatar_url: Option<String>,
+}
+
+#[async_trait]
+impl OAuthProvider for GitlabProvider {
+    fn name(&self) -> &'static str {
+        "gitlab"
+    }
+
+    fn display_name(&self) -> &'static str {
+        "GitLab"
+    }
+
+    fn authorize_url(&self, state: &str, code_challenge: &str, redirect_uri: &str) -> String {
+        with_query(
+            &format!("{}/oauth/authorize", self.base_url),
+            &[
+                ("client_id", &self.client_id),
+                ("redirect_uri", redirect_uri),
+                ("response_type", "code"),
+                ("scope", "read_user"),
+                ("state", state),
+                ("code_challenge", code_challenge),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+    }
+
+    async fn exchange_code(
+        &self,
+        code: &str,
+        code_verifier: &str,
+        redirect_uri: &str,
+    ) -> Result<String, OauthError> {
+        request_token(
+            &self.http,
+            &format!("{}/oauth/token", self.base_url),
+            &[
+                ("client_id", &self.client_id),
+                ("client_secret", &self.client_secret),
+                ("code", code),
+                ("code_verifier", code_verifier),
+                ("grant_type", "authorization_code"),
+                ("redirect_uri", redirect_uri),
+            ],
+        )
+        .await
+    }
+
+    async fn fetch_profile(&self, access_token: &str) -> Result<OauthProfile, OauthError> {
+        let request = self
+            .http
+            .get(format!("{}/api/v4/user", self.base_url))
+            .bearer_auth(access_token);
+        let user_info: GitlabUserInfo = get_json(request).await?;
+        println!("in gitlab fetch_profile, {:?}", user_info);
+
+        Ok(OauthProfile {
+            id: user_info.id.to_string(),
+            name: user_info
+                .name
+                .unwrap_or_else(|| user_info.username.to_owned()),
+            login: user_info.username,
+            avatar_url: user_info.avatar_url.unwrap_or_default(),
+        })
+    }
+}
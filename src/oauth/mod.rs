@@ -0,0 +1,269 @@
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use redis::{aio::ConnectionLike, RedisResult};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::config::Config;
+
+mod gitee;
+mod github;
+mod gitlab;
+mod provider;
+#[cfg(test)]
+mod tests;
+
+pub use gitee::GiteeProvider;
+pub use github::GithubProvider;
+pub use gitlab::GitlabProvider;
+pub use provider::{OAuthProvider, OauthError, OauthProfile};
+
+// the user has this long to authorize on the provider side
+const STATE_TTL: usize = 10 * 60;
+// the login, link and callback routes are all under it
+const COOKIE_PATH: &str = "/user";
+
+/// A login in progress, saved under its `state` parameter until the
+/// provider redirects back.
+#[derive(Debug, Clone)]
+pub struct PendingLogin {
+    pub provider: String,
+    // PKCE, the secret half of the code_challenge sent to the provider
+    pub code_verifier: String,
+    pub return_to: String,
+    // set when a logged in user links one more identity, instead of a login
+    pub link_user_id: Option<String>,
+}
+
+impl PendingLogin {
+    pub fn code_challenge(&self) -> String {
+        URL_SAFE_NO_PAD.encode(Sha256::digest(self.code_verifier.as_bytes()))
+    }
+}
+
+/// The pending logins in redis, as `{appid}_oauth_state:{state}` hashes.
+///
+/// The browser which started a login holds a hash of its state in a cookie,
+/// so a callback url sent to someone else can't log them in.
+pub struct OauthStateStore {
+    prefix: String,
+}
+
+impl OauthStateStore {
+    pub fn new(appid: &str) -> Self {
+        OauthStateStore {
+            prefix: appid.to_string(),
+        }
+    }
+
+    fn state_key(&self, state: &str) -> String {
+        format!("{}_oauth_state:{}", self.prefix, state)
+    }
+
+    pub fn cookie_name(&self) -> String {
+        format!("{}_oauth_state", self.prefix)
+    }
+
+    /// Set with the redirect to the provider, it's sent back with the
+    /// callback, a top level navigation
+    pub fn cookie(&self, state: &str) -> Cookie<'static> {
+        Cookie::build(self.cookie_name(), state_hash(state))
+            .path(COOKIE_PATH)
+            .max_age(cookie::time::Duration::seconds(STATE_TTL as i64))
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .finish()
+    }
+
+    /// The cookie must be removed with the same path it was set with
+    pub fn removal_cookie(&self) -> Cookie<'static> {
+        let mut cookie = Cookie::named(self.cookie_name());
+        cookie.set_path(COOKIE_PATH);
+        cookie
+    }
+
+    /// Whether the callback with this `state` comes to the browser which started the login
+    pub fn started_by(&self, cookie_jar: &CookieJar, state: &str) -> bool {
+        cookie_jar
+            .get(&self.cookie_name())
+            .is_some_and(|cookie| cookie.value() == state_hash(state))
+    }
+
+    /// Start a login, returns the `state` to send to the provider
+    pub async fn begin<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        provider: &str,
+        return_to: &str,
+        link_user_id: Option<&str>,
+    ) -> RedisResult<(String, PendingLogin)> {
+        let state = random_string();
+        let pending = PendingLogin {
+            provider: provider.to_string(),
+            code_verifier: random_string(),
+            return_to: return_to.to_string(),
+            link_user_id: link_user_id.map(|id| id.to_string()),
+        };
+
+        let key = self.state_key(&state);
+        redis::pipe()
+            .atomic()
+            .hset_multiple(
+                &key,
+                &[
+                    ("provider", pending.provider.to_owned()),
+                    ("code_verifier", pending.code_verifier.to_owned()),
+                    ("return_to", pending.return_to.to_owned()),
+                    (
+                        "link_user_id",
+                        pending.link_user_id.clone().unwrap_or_default(),
+                    ),
+                ],
+            )
+            .ignore()
+            .expire(&key, STATE_TTL)
+            .ignore()
+            .query_async::<_, ()>(conn)
+            .await?;
+
+        Ok((state, pending))
+    }
+
+    /// Take the login behind a `state` back, it can only be used once
+    pub async fn take<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        state: &str,
+    ) -> RedisResult<Option<PendingLogin>> {
+        let key = self.state_key(state);
+        let (fields, _): (HashMap<String, String>, ()) = redis::pipe()
+            .atomic()
+            .hgetall(&key)
+            .del(&key)
+            .query_async(conn)
+            .await?;
+        let Some(code_verifier) = fields.get("code_verifier") else {
+            return Ok(None);
+        };
+        let field = |name: &str| fields.get(name).cloned().unwrap_or_default();
+
+        Ok(Some(PendingLogin {
+            provider: field("provider"),
+            code_verifier: code_verifier.to_owned(),
+            return_to: field("return_to"),
+            link_user_id: Some(field("link_user_id")).filter(|id| !id.is_empty()),
+        }))
+    }
+}
+
+/// The enabled login providers, in the order of the login page
+pub struct OauthProviders {
+    site_url: String,
+    providers: Vec<Box<dyn OAuthProvider>>,
+}
+
+impl OauthProviders {
+    pub fn from_config(config: &Config) -> Self {
+        let http = reqwest::Client::builder()
+            .user_agent("gutp-discux")
+            .timeout(Duration::from_secs(10))
+            .build()
+            .unwrap_or_default();
+
+        let mut providers: Vec<Box<dyn OAuthProvider>> = vec![];
+        if config.github.is_enabled() {
+            providers.push(Box::new(GithubProvider::new(http.clone(), &config.github)));
+        }
+        if config.gitlab.is_enabled() {
+            providers.push(Box::new(GitlabProvider::new(http.clone(), &config.gitlab)));
+        }
+        if config.gitee.is_enabled() {
+            providers.push(Box::new(GiteeProvider::new(http.clone(), &config.gitee)));
+        }
+
+        Self::new(&config.site_url, providers)
+    }
+
+    pub fn new(site_url: &str, providers: Vec<Box<dyn OAuthProvider>>) -> Self {
+        OauthProviders {
+            site_url: site_url.trim_end_matches('/').to_string(),
+            providers,
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn OAuthProvider> {
+        self.providers
+            .iter()
+            .find(|provider| provider.name() == name)
+            .map(|provider| provider.as_ref())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &dyn OAuthProvider> {
+        self.providers.iter().map(|provider| provider.as_ref())
+    }
+
+    /// Must be the same in the authorize and token requests
+    pub fn redirect_uri(&self, provider: &dyn OAuthProvider) -> String {
+        format!("{}{}", self.site_url, provider.callback_path())
+    }
+}
+
+/// Only the local paths are followed after login, anything else goes to the index
+pub fn safe_return_to(return_to: Option<&str>) -> String {
+    match return_to {
+        Some(path)
+            if path.starts_with('/')
+                && !path.starts_with("//")
+                && !path.contains('\\')
+                && !has_blank_or_control(path) =>
+        {
+            path.to_string()
+        }
+        _ => "/".to_string(),
+    }
+}
+
+// the browsers drop the tabs and newlines of a url, so `/\t/evil.com` goes
+// to `//evil.com`, and a decoded control character breaks the Location
+// header. Checked as sent, and with the %XX escapes decoded
+fn has_blank_or_control(path: &str) -> bool {
+    let blank_or_control = |c: char| c.is_whitespace() || c.is_control();
+    if path.chars().any(blank_or_control) {
+        return true;
+    }
+
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| path.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded)
+        .chars()
+        .any(blank_or_control)
+}
+
+// the state itself is only sent to the provider
+fn state_hash(state: &str) -> String {
+    sha256::digest(state).to_lowercase()
+}
+
+// 64 hex chars, fits both the state and the PKCE verifier (43..128 chars)
+fn random_string() -> String {
+    let x = rand::random::<[u8; 32]>();
+    sha256::digest(&x).to_lowercase()
+}
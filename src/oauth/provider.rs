@@ -0,0 +1,147 @@
+use async_trait::async_trait;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use std::fmt;
+
+/// A login provider of the OAuth2 authorization code flow
+#[async_trait]
+pub trait OAuthProvider: Send + Sync {
+    /// The key in config and urls, also saved as the `oauth_source` of the users
+    fn name(&self) -> &'static str;
+
+    /// Shown on the login page
+    fn display_name(&self) -> &'static str;
+
+    /// Where the user is sent to authorize us
+    fn authorize_url(&self, state: &str, code_challenge: &str, redirect_uri: &str) -> String;
+
+    /// Trade the code of the callback for an access token
+    async fn exchange_code(
+        &self,
+        code: &str,
+        code_verifier: &str,
+        redirect_uri: &str,
+    ) -> Result<String, OauthError>;
+
+    /// The profile of the user behind the access token
+    async fn fetch_profile(&self, access_token: &str) -> Result<OauthProfile, OauthError>;
+
+    /// The gutp account of a profile, namespaced so the same login on two
+    /// providers are two users
+    fn account(&self, profile: &OauthProfile) -> String {
+        format!("{}:{}", self.name(), profile.login)
+    }
+
+    /// The path the provider redirects back to
+    fn callback_path(&self) -> String {
+        format!("/user/oauth/{}/callback", self.name())
+    }
+}
+
+/// The user info we keep from a provider
+#[derive(Debug, Clone)]
+pub struct OauthProfile {
+    /// the stable id on the provider, the login can be renamed
+    pub id: String,
+    pub login: String,
+    pub name: String,
+    pub avatar_url: String,
+}
+
+#[derive(Debug)]
+pub enum OauthError {
+    Request(reqwest::Error),
+    Status(StatusCode),
+    Decode(serde_json::Error),
+    // the provider answered with an oauth error, e.g. a bad code
+    Rejected(String),
+}
+
+impl fmt::Display for OauthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OauthError::Request(err) => write!(f, "request failed: {}", err),
+            OauthError::Status(status) => write!(f, "bad status: {}", status),
+            OauthError::Decode(err) => write!(f, "decode response failed: {}", err),
+            OauthError::Rejected(reason) => write!(f, "rejected: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for OauthError {}
+
+impl From<reqwest::Error> for OauthError {
+    fn from(err: reqwest::Error) -> Self {
+        OauthError::Request(err)
+    }
+}
+
+impl From<serde_json::Error> for OauthError {
+    fn from(err: serde_json::Error) -> Self {
+        OauthError::Decode(err)
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+/// The token endpoint is the same shape for all the providers
+pub(super) async fn request_token(
+    http: &reqwest::Client,
+    url: &str,
+    params: &[(&str, &str)],
+) -> Result<String, OauthError> {
+    let res = http
+        .post(url)
+        .header(reqwest::header::ACCEPT, "application/json")
+        .form(params)
+        .send()
+        .await?;
+    let status = res.status();
+    let bytes = res.bytes().await?;
+
+    // the errors come as json too, with a 4xx status on some providers
+    match serde_json::from_slice::<TokenResponse>(&bytes) {
+        Ok(TokenResponse {
+            access_token: Some(access_token),
+            ..
+        }) => Ok(access_token),
+        Ok(TokenResponse {
+            error,
+            error_description,
+            ..
+        }) if error.is_some() => Err(OauthError::Rejected(
+            error_description.or(error).unwrap_or_default(),
+        )),
+        _ if !status.is_success() => Err(OauthError::Status(status)),
+        Ok(_) => Err(OauthError::Rejected("no access_token".to_string())),
+        Err(err) => Err(OauthError::Decode(err)),
+    }
+}
+
+/// GET some json with the access token
+pub(super) async fn get_json<T: serde::de::DeserializeOwned>(
+    request: reqwest::RequestBuilder,
+) -> Result<T, OauthError> {
+    let res = request
+        .header(reqwest::header::ACCEPT, "application/json")
+        .send()
+        .await?;
+    if !res.status().is_success() {
+        return Err(OauthError::Status(res.status()));
+    }
+    let bytes = res.bytes().await?;
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+pub(super) fn with_query(base: &str, params: &[(&str, &str)]) -> String {
+    format!(
+        "{}?{}",
+        base,
+        serde_urlencoded::to_string(params).unwrap_or_default()
+    )
+}
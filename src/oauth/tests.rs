@@ -0,0 +1,243 @@
+use axum::{
+    extract::{Form, Query},
+    http::{header, HeaderMap, StatusCode},
+    routing::{get, post},
+    Json, Router,
+};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::net::TcpListener;
+
+use super::*;
+use crate::config::ProviderConfig;
+
+const CODE: &str = "fake_code";
+const VERIFIER: &str = "fake_verifier";
+const TOKEN: &str = "fake_token";
+
+// a local provider answering the token and user endpoints of github, gitlab and gitee
+async fn spawn_fake_provider() -> String {
+    async fn token(Form(params): Form<HashMap<String, String>>) -> (StatusCode, Json<Value>) {
+        let param = |name: &str| params.get(name).cloned().unwrap_or_default();
+        if param("code") == CODE
+            && param("code_verifier") == VERIFIER
+            && param("client_secret") == "secret"
+        {
+            let res = json!({ "access_token": TOKEN, "token_type": "bearer" });
+            (StatusCode::OK, Json(res))
+        } else {
+            let res =
+                json!({ "error": "invalid_grant", "error_description": "The code is invalid." });
+            (StatusCode::BAD_REQUEST, Json(res))
+        }
+    }
+
+    async fn user(
+        headers: HeaderMap,
+        Query(query): Query<HashMap<String, String>>,
+    ) -> (StatusCode, Json<Value>) {
+        let bearer = format!("Bearer {}", TOKEN);
+        let authorized = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            == Some(bearer.as_str())
+            || query.get("access_token").map(String::as_str) == Some(TOKEN);
+        if authorized {
+            let res = json!({
+                "id": 42,
+                "login": "alice",
+                "username": "alice",
+                "name": "Alice",
+                "avatar_url": "http://avatars.test/alice.png",
+            });
+            (StatusCode::OK, Json(res))
+        } else {
+            (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({ "message": "401 Unauthorized" })),
+            )
+        }
+    }
+
+    let app = Router::new()
+        .route("/login/oauth/access_token", post(token))
+        .route("/oauth/token", post(token))
+        .route("/api/v3/user", get(user))
+        .route("/api/v4/user", get(user))
+        .route("/api/v5/user", get(user));
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(app.into_make_service())
+            .await
+            .unwrap();
+    });
+
+    format!("http://{}", addr)
+}
+
+fn fake_providers(base_url: &str) -> OauthProviders {
+    let provider = ProviderConfig {
+        client_id: "id".to_string(),
+        client_secret: "secret".to_string(),
+        base_url: base_url.to_string(),
+    };
+    let config = Config {
+        site_url: "http://forum.test/".to_string(),
+        github: provider.clone(),
+        gitlab: provider.clone(),
+        gitee: provider,
+        ..Default::default()
+    };
+    OauthProviders::from_config(&config)
+}
+
+#[tokio::test]
+async fn login_with_every_provider() {
+    let base_url = spawn_fake_provider().await;
+    let providers = fake_providers(&base_url);
+
+    let expected = [
+        (
+            "github",
+            "alice",
+            "http://forum.test/user/github_oauth_callback",
+        ),
+        (
+            "gitlab",
+            "gitlab:alice",
+            "http://forum.test/user/oauth/gitlab/callback",
+        ),
+        (
+            "gitee",
+            "gitee:alice",
+            "http://forum.test/user/oauth/gitee/callback",
+        ),
+    ];
+    for (name, account, redirect_uri) in expected {
+        let provider = providers.get(name).unwrap();
+        assert_eq!(providers.redirect_uri(provider), redirect_uri);
+
+        let url = provider.authorize_url("the_state", "the_challenge", redirect_uri);
+        assert!(url.starts_with(&base_url), "{}", url);
+        assert!(url.contains("state=the_state"), "{}", url);
+        assert!(url.contains("code_challenge=the_challenge"), "{}", url);
+        assert!(url.contains("code_challenge_method=S256"), "{}", url);
+
+        let token = provider
+            .exchange_code(CODE, VERIFIER, redirect_uri)
+            .await
+            .unwrap();
+        assert_eq!(token, TOKEN);
+
+        let profile = provider.fetch_profile(&token).await.unwrap();
+        assert_eq!(profile.id, "42");
+        assert_eq!(profile.name, "Alice");
+        assert_eq!(profile.avatar_url, "http://avatars.test/alice.png");
+        assert_eq!(provider.account(&profile), account);
+    }
+}
+
+#[tokio::test]
+async fn bad_code_and_token_are_rejected() {
+    let base_url = spawn_fake_provider().await;
+    let providers = fake_providers(&base_url);
+
+    for provider in providers.iter() {
+        let redirect_uri = providers.redirect_uri(provider);
+        let err = provider
+            .exchange_code("bad_code", VERIFIER, &redirect_uri)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, OauthError::Rejected(_)), "{}", err);
+
+        let err = provider
+            .exchange_code(CODE, "bad_verifier", &redirect_uri)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, OauthError::Rejected(_)), "{}", err);
+
+        let err = provider.fetch_profile("bad_token").await.unwrap_err();
+        assert!(
+            matches!(err, OauthError::Status(StatusCode::UNAUTHORIZED)),
+            "{}",
+            err
+        );
+    }
+}
+
+#[test]
+fn only_configured_providers_are_enabled() {
+    let config = Config {
+        gitee: ProviderConfig {
+            client_id: "id".to_string(),
+            client_secret: "secret".to_string(),
+            base_url: "".to_string(),
+        },
+        ..Default::default()
+    };
+    let providers = OauthProviders::from_config(&config);
+
+    let names: Vec<&str> = providers.iter().map(|p| p.name()).collect();
+    assert_eq!(names, ["gitee"]);
+    assert!(providers.get("github").is_none());
+}
+
+#[test]
+fn pkce_challenge_is_s256() {
+    // RFC 7636, appendix B
+    let pending = PendingLogin {
+        provider: "github".to_string(),
+        code_verifier: "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk".to_string(),
+        return_to: "/".to_string(),
+        link_user_id: None,
+    };
+    assert_eq!(
+        pending.code_challenge(),
+        "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+    );
+}
+
+#[test]
+fn return_to_is_a_local_path() {
+    assert_eq!(safe_return_to(Some("/a/42?page=2")), "/a/42?page=2");
+    assert_eq!(safe_return_to(Some("/tag/3?x=%2F")), "/tag/3?x=%2F");
+    assert_eq!(safe_return_to(None), "/");
+
+    let rejected = [
+        "https://evil.com",
+        "//evil.com",
+        "/\\evil.com",
+        "/\t/evil.com",
+        "/\n/evil.com",
+        "/a/42\r\n",
+        "/ /evil.com",
+        "/%09/evil.com",
+        "/search?q=rust%20async",
+        "/%0a/evil.com",
+        "/a/42%0d%0aSet-Cookie:%20x=y",
+        "/a/42%0D%0A",
+    ];
+    for return_to in rejected {
+        assert_eq!(safe_return_to(Some(return_to)), "/", "{:?}", return_to);
+    }
+}
+
+#[test]
+fn state_cookie_ties_the_login_to_its_browser() {
+    let store = OauthStateStore::new("discux");
+    let cookie = store.cookie("the_state");
+    assert_eq!(cookie.name(), "discux_oauth_state");
+    // the state itself isn't in the cookie
+    assert_ne!(cookie.value(), "the_state");
+    assert_eq!(cookie.http_only(), Some(true));
+    assert_eq!(cookie.same_site(), Some(SameSite::Lax));
+
+    let jar = CookieJar::new().add(cookie);
+    assert!(store.started_by(&jar, "the_state"));
+    assert!(!store.started_by(&jar, "another_state"));
+    assert!(!store.started_by(&CookieJar::new(), "the_state"));
+}
@@ -0,0 +1,173 @@
+use std::borrow::Borrow;
+
+/// Items per page of the lists
+pub const PAGE_SIZE: usize = 20;
+
+/// The pages past this one are answered as this one, so the offsets can't overflow
+pub const MAX_PAGE: usize = 10_000;
+
+// how many page numbers are shown around the current one
+const PAGE_WINDOW: usize = 4;
+
+/// The page links under a list, rendered by `pagination.html`.
+///
+/// Either the total is known (`from_total`), or only the current window
+/// was fetched from GUTP (`from_window`), then the total is unknown and
+/// the links stop at the next page.
+#[derive(Debug, Clone)]
+pub struct Pager {
+    pub current: usize,
+    total_pages: Option<usize>,
+    has_more: bool,
+    // the url of the list, with its other query params
+    base_url: String,
+}
+
+impl Pager {
+    /// Only the page was fetched, but the total count is known, e.g. from the search index
+    pub fn from_total(
+        current: usize,
+        total_items: usize,
+        page_size: usize,
+        base_url: String,
+    ) -> Pager {
+        let total_pages = total_items.div_ceil(page_size).max(1);
+        Pager {
+            current,
+            total_pages: Some(total_pages),
+            has_more: current < total_pages,
+            base_url,
+        }
+    }
+
+    /// The 1-based page asked in the query, and the offset of its window
+    pub fn page_offset(page: Option<usize>, page_size: usize) -> (usize, usize) {
+        let current = page.unwrap_or(1).clamp(1, MAX_PAGE);
+        (current, (current - 1) * page_size)
+    }
+
+    /// `items` was fetched with `limit = page_size + 1`, the extra one only
+    /// tells there is a next page
+    pub fn from_window<T>(
+        mut items: Vec<T>,
+        current: usize,
+        page_size: usize,
+        base_url: String,
+    ) -> (Vec<T>, Pager) {
+        let has_more = items.len() > page_size;
+        items.truncate(page_size);
+        let pager = Pager {
+            current,
+            total_pages: None,
+            has_more,
+            base_url,
+        };
+        (items, pager)
+    }
+
+    // `Borrow` as the templates pass a `&usize` inside `for` loops
+    pub fn url(&self, page: impl Borrow<usize>) -> String {
+        let separator = if self.base_url.contains('?') {
+            '&'
+        } else {
+            '?'
+        };
+        format!("{}{}page={}", self.base_url, separator, page.borrow())
+    }
+
+    pub fn is_current(&self, page: impl Borrow<usize>) -> bool {
+        *page.borrow() == self.current
+    }
+
+    pub fn is_multi_page(&self) -> bool {
+        self.current > 1 || self.has_more
+    }
+
+    pub fn has_prev(&self) -> bool {
+        self.current > 1
+    }
+
+    pub fn has_next(&self) -> bool {
+        self.has_more
+    }
+
+    pub fn prev(&self) -> usize {
+        self.current - 1
+    }
+
+    pub fn next(&self) -> usize {
+        self.current.saturating_add(1)
+    }
+
+    /// The page numbers to link, around the current one
+    pub fn pages(&self) -> Vec<usize> {
+        let first = self.current.saturating_sub(PAGE_WINDOW).max(1);
+        let last = match self.total_pages {
+            Some(total_pages) => self.current.saturating_add(PAGE_WINDOW).min(total_pages),
+            None if self.has_more => self.current.saturating_add(1),
+            None => self.current,
+        };
+        (first..=last).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page_offset_is_bounded() {
+        assert_eq!(Pager::page_offset(None, PAGE_SIZE), (1, 0));
+        assert_eq!(Pager::page_offset(Some(0), PAGE_SIZE), (1, 0));
+        assert_eq!(Pager::page_offset(Some(3), PAGE_SIZE), (3, 2 * PAGE_SIZE));
+        // the offset of the last page doesn't overflow
+        assert_eq!(
+            Pager::page_offset(Some(usize::MAX), PAGE_SIZE),
+            (MAX_PAGE, (MAX_PAGE - 1) * PAGE_SIZE)
+        );
+        assert_eq!(
+            Pager::page_offset(Some(MAX_PAGE + 1), PAGE_SIZE),
+            Pager::page_offset(Some(MAX_PAGE), PAGE_SIZE)
+        );
+    }
+
+    #[test]
+    fn the_extra_item_of_the_window_tells_a_next_page() {
+        let items: Vec<usize> = (0..PAGE_SIZE + 1).collect();
+        let (items, pager) = Pager::from_window(items, 1, PAGE_SIZE, "/s/rust".to_string());
+        assert_eq!(items.len(), PAGE_SIZE);
+        assert!(pager.has_next());
+        assert!(!pager.has_prev());
+        assert_eq!(pager.pages(), [1, 2]);
+        assert_eq!(pager.url(pager.next()), "/s/rust?page=2");
+
+        let items: Vec<usize> = (0..PAGE_SIZE).collect();
+        let (items, pager) = Pager::from_window(items, 2, PAGE_SIZE, "/s/rust".to_string());
+        assert_eq!(items.len(), PAGE_SIZE);
+        assert!(!pager.has_next());
+        assert!(pager.has_prev());
+        assert_eq!(pager.pages(), [1, 2]);
+    }
+
+    #[test]
+    fn a_short_single_page_has_no_links() {
+        let (items, pager) = Pager::from_window(vec![1, 2, 3], 1, PAGE_SIZE, "/".to_string());
+        assert_eq!(items, [1, 2, 3]);
+        assert!(!pager.is_multi_page());
+    }
+
+    #[test]
+    fn last_page_next_doesnt_overflow() {
+        let (_, pager) = Pager::from_window(vec![0; 2], usize::MAX, 1, "/".to_string());
+        assert_eq!(pager.next(), usize::MAX);
+        assert_eq!(*pager.pages().last().unwrap(), usize::MAX);
+    }
+
+    #[test]
+    fn url_keeps_the_other_params() {
+        let pager = Pager::from_total(2, 45, PAGE_SIZE, "/search?q=rust".to_string());
+        assert_eq!(pager.url(3), "/search?q=rust&page=3");
+        assert_eq!(pager.pages(), [1, 2, 3]);
+        assert!(pager.has_next());
+    }
+}
@@ -0,0 +1,297 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use redis::{aio::ConnectionLike, AsyncCommands, RedisResult};
+use std::sync::OnceLock;
+
+// the attempts are counted in this window
+const FAILURE_WINDOW: usize = 15 * 60;
+const MAX_FAILURES_PER_ACCOUNT: usize = 5;
+const MAX_FAILURES_PER_IP: usize = 20;
+// a reset link is valid for this long
+const RESET_TOKEN_TTL: usize = 60 * 60;
+
+pub const MIN_PASSWORD_LEN: usize = 8;
+pub const MAX_PASSWORD_LEN: usize = 128;
+
+/// The provider name of the local accounts in the identity store
+pub const LOCAL_PROVIDER: &str = "local";
+
+/// Argon2 is slow on purpose, so it runs off the async threads
+pub async fn hash_password(password: String) -> Result<String, String> {
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|err| err.to_string())
+    })
+    .await
+    .map_err(|err| err.to_string())?
+}
+
+pub async fn verify_password(password: String, hash: String) -> bool {
+    tokio::task::spawn_blocking(move || {
+        let Ok(hash) = PasswordHash::new(&hash) else {
+            return false;
+        };
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+    .await
+    .unwrap_or(false)
+}
+
+// hashed once with the same params as the real ones, for `verify_unknown`
+static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+
+/// Takes as long as `verify_password` and never matches. Used when there is
+/// no hash to check, so the response time doesn't tell which usernames exist
+pub async fn verify_unknown(password: String) {
+    let _ = tokio::task::spawn_blocking(move || {
+        let hash = DUMMY_HASH.get_or_init(|| {
+            let salt = SaltString::generate(&mut OsRng);
+            Argon2::default()
+                .hash_password(b"no such account", &salt)
+                .map(|hash| hash.to_string())
+                .unwrap_or_default()
+        });
+        if let Ok(hash) = PasswordHash::new(hash) {
+            let _ = Argon2::default().verify_password(password.as_bytes(), &hash);
+        }
+    })
+    .await;
+}
+
+// the counts include the attempt being made
+fn is_over_limit(account_attempts: usize, ip_attempts: usize) -> bool {
+    account_attempts > MAX_FAILURES_PER_ACCOUNT || ip_attempts > MAX_FAILURES_PER_IP
+}
+
+/// The usernames are case insensitive, and kept to url safe characters
+pub fn normalize_username(username: &str) -> Result<String, &'static str> {
+    let username = username.trim().to_lowercase();
+    if username.len() < 3 || username.len() > 32 {
+        return Err("The username must be 3 to 32 characters.");
+    }
+    if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err("The username can only have letters, digits, _ and -.");
+    }
+    Ok(username)
+}
+
+pub fn check_password_policy(password: &str) -> Result<(), &'static str> {
+    let len = password.chars().count();
+    if len < MIN_PASSWORD_LEN {
+        return Err("The password must be at least 8 characters.");
+    }
+    if len > MAX_PASSWORD_LEN {
+        return Err("The password must be at most 128 characters.");
+    }
+    Ok(())
+}
+
+/// The local credentials in redis.
+///
+/// Keys:
+/// - `{appid}_password:{user_id}`, the argon2 hash, no expiry
+/// - `{appid}_login_failures:{account|ip}:{value}`, counters of the attempts,
+///   less the ones which succeeded
+/// - `{appid}_password_reset:{token}`, the user id of a reset link
+pub struct PasswordStore {
+    prefix: String,
+}
+
+impl PasswordStore {
+    pub fn new(appid: &str) -> Self {
+        PasswordStore {
+            prefix: appid.to_string(),
+        }
+    }
+
+    fn password_key(&self, user_id: &str) -> String {
+        format!("{}_password:{}", self.prefix, user_id)
+    }
+
+    fn failures_key(&self, kind: &str, value: &str) -> String {
+        format!("{}_login_failures:{}:{}", self.prefix, kind, value)
+    }
+
+    fn reset_key(&self, token: &str) -> String {
+        format!("{}_password_reset:{}", self.prefix, token)
+    }
+
+    pub async fn get_hash<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        user_id: &str,
+    ) -> RedisResult<Option<String>> {
+        conn.get(self.password_key(user_id)).await
+    }
+
+    pub async fn set_hash<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        user_id: &str,
+        hash: &str,
+    ) -> RedisResult<()> {
+        conn.set(self.password_key(user_id), hash).await
+    }
+
+    pub async fn remove<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        user_id: &str,
+    ) -> RedisResult<()> {
+        conn.del(self.password_key(user_id)).await
+    }
+
+    /// Count an attempt of this account from this client ip, before the
+    /// password is checked, so the guesses sent at once are all counted.
+    /// Whether it's over the limits, then it must be rejected unchecked.
+    ///
+    /// The window starts at the first attempt, it doesn't slide
+    pub async fn count_attempt<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        username: &str,
+        ip: &str,
+    ) -> RedisResult<bool> {
+        // one script, so a counter never misses its expiry
+        let counts: Vec<usize> = redis::Script::new(
+            r"
+            local counts = {}
+            for i, key in ipairs(KEYS) do
+                counts[i] = redis.call('INCR', key)
+                if counts[i] == 1 then
+                    redis.call('EXPIRE', key, ARGV[1])
+                end
+            end
+            return counts
+            ",
+        )
+        .key(self.failures_key("account", username))
+        .key(self.failures_key("ip", ip))
+        .arg(FAILURE_WINDOW)
+        .invoke_async(conn)
+        .await?;
+
+        Ok(is_over_limit(
+            counts.first().copied().unwrap_or(0),
+            counts.get(1).copied().unwrap_or(0),
+        ))
+    }
+
+    /// After the right password: the account starts over, and the attempt
+    /// isn't held against the ip
+    pub async fn forgive_attempt<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        username: &str,
+        ip: &str,
+    ) -> RedisResult<()> {
+        redis::Script::new(
+            r"
+            redis.call('DEL', KEYS[1])
+            if redis.call('EXISTS', KEYS[2]) == 1 then
+                redis.call('DECR', KEYS[2])
+            end
+            ",
+        )
+        .key(self.failures_key("account", username))
+        .key(self.failures_key("ip", ip))
+        .invoke_async(conn)
+        .await
+    }
+
+    /// A single use token to set a new password without the old one
+    pub async fn issue_reset_token<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        user_id: &str,
+    ) -> RedisResult<String> {
+        let x = rand::random::<[u8; 32]>();
+        let token = sha256::digest(&x).to_lowercase();
+        let _: () = conn
+            .set_ex(self.reset_key(&token), user_id, RESET_TOKEN_TTL)
+            .await?;
+        Ok(token)
+    }
+
+    /// The user of a reset token, without consuming it
+    pub async fn peek_reset_token<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        token: &str,
+    ) -> RedisResult<Option<String>> {
+        conn.get(self.reset_key(token)).await
+    }
+
+    pub async fn take_reset_token<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        token: &str,
+    ) -> RedisResult<Option<String>> {
+        let key = self.reset_key(token);
+        let (user_id, _): (Option<String>, ()) = redis::pipe()
+            .atomic()
+            .get(&key)
+            .del(&key)
+            .query_async(conn)
+            .await?;
+        Ok(user_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn hash_then_verify() {
+        let hash = hash_password("correct horse".to_string()).await.unwrap();
+        assert!(hash.starts_with("$argon2"));
+        assert!(verify_password("correct horse".to_string(), hash.clone()).await);
+        assert!(!verify_password("wrong horse".to_string(), hash).await);
+        // a broken hash is a wrong password, not a panic
+        assert!(!verify_password("correct horse".to_string(), "nope".to_string()).await);
+    }
+
+    #[tokio::test]
+    async fn salted_per_hash() {
+        let first = hash_password("same".to_string()).await.unwrap();
+        let second = hash_password("same".to_string()).await.unwrap();
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn limits_count_the_attempt_made() {
+        assert!(!is_over_limit(MAX_FAILURES_PER_ACCOUNT, 1));
+        assert!(is_over_limit(MAX_FAILURES_PER_ACCOUNT + 1, 1));
+        assert!(!is_over_limit(1, MAX_FAILURES_PER_IP));
+        assert!(is_over_limit(1, MAX_FAILURES_PER_IP + 1));
+    }
+
+    #[test]
+    fn usernames_are_normalized() {
+        assert_eq!(normalize_username("  Alice_01 ").unwrap(), "alice_01");
+        assert!(normalize_username("ab").is_err());
+        assert!(normalize_username(&"a".repeat(33)).is_err());
+        assert!(normalize_username("a b c").is_err());
+        assert!(normalize_username("名字名字").is_err());
+    }
+
+    #[test]
+    fn password_policy() {
+        assert!(check_password_policy("1234567").is_err());
+        assert!(check_password_policy("12345678").is_ok());
+        // counted in chars, not bytes
+        assert!(check_password_policy("密码密码密码密码").is_ok());
+        assert!(check_password_policy(&"x".repeat(MAX_PASSWORD_LEN + 1)).is_err());
+    }
+}
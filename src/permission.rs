@@ -0,0 +1,362 @@
+use std::collections::HashSet;
+
+use gutp_types::{GutpPost, GutpSubspace};
+
+use crate::error::ForumError;
+use crate::AppState;
+use crate::LoggedUser;
+
+/// The mutating actions which are guarded by the policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    CreateSubspace,
+    EditSubspace,
+    DeleteSubspace,
+    CreateArticle,
+    EditArticle,
+    DeleteArticle,
+    CreateComment,
+    EditComment,
+    DeleteComment,
+    ReviewComment,
+    RollbackArticle,
+    ManageTag,
+}
+
+/// What the visitor is in the current context.
+///
+/// Authorship is per object, so it is checked against the `author_id`
+/// given to `can()`, not stored here.
+#[derive(Debug, Clone, Default)]
+pub struct Permission {
+    user_id: Option<String>,
+    site_admin: bool,
+    subspace_owner: bool,
+    subspace_moderator: bool,
+}
+
+impl Permission {
+    /// The roles at site level, no subspace involved
+    pub fn site(app_state: &AppState, logged_user: Option<&LoggedUser>) -> Self {
+        match logged_user {
+            Some(LoggedUser { user_id, .. }) => Permission {
+                user_id: Some(user_id.to_owned()),
+                site_admin: app_state.config.is_admin(user_id),
+                ..Default::default()
+            },
+            None => Permission::default(),
+        }
+    }
+
+    /// The roles inside a subspace, the moderators are queried from gutp
+    pub async fn in_subspace(
+        app_state: &AppState,
+        logged_user: Option<&LoggedUser>,
+        subspace: &GutpSubspace,
+    ) -> Result<Self, ForumError> {
+        let mut permission = Self::site(app_state, logged_user);
+        let Some(user_id) = permission.user_id.clone() else {
+            return Ok(permission);
+        };
+
+        permission.subspace_owner = subspace.owner_id == user_id;
+        if !permission.site_admin && !permission.subspace_owner {
+            let moderators = app_state
+                .gutp
+                .moderators()
+                .list_by_subspace(&subspace.id)
+                .await
+                .map_err(ForumError::gutp(format!(
+                    "Query moderators of subspace: {}",
+                    subspace.id
+                )))?;
+            permission.subspace_moderator = moderators.iter().any(|m| m.user_id == user_id);
+        }
+
+        Ok(permission)
+    }
+
+    /// The roles around an article, through the subspace it belongs to
+    pub async fn for_post(
+        app_state: &AppState,
+        logged_user: Option<&LoggedUser>,
+        post: &GutpPost,
+    ) -> Result<Self, ForumError> {
+        let action = format!("Query subspace: {}", post.subspace_id);
+        let subspace = app_state
+            .gutp
+            .subspaces()
+            .by_id(&post.subspace_id)
+            .await
+            .map_err(ForumError::gutp(&action))?
+            .ok_or_else(|| ForumError::not_found(&action, "No this subspace."))?;
+
+        Self::in_subspace(app_state, logged_user, &subspace).await
+    }
+
+    /// The policy. `author_id` is the author of the target object, pass ""
+    /// for the subspace level actions.
+    pub fn can(&self, action: Action, author_id: &str) -> bool {
+        let Some(user_id) = &self.user_id else {
+            return false;
+        };
+        let is_author = !author_id.is_empty() && user_id == author_id;
+        let can_moderate = self.site_admin || self.subspace_owner || self.subspace_moderator;
+
+        match action {
+            // forum case: only the admins can open a new subspace
+            Action::CreateSubspace => self.site_admin,
+            Action::EditSubspace | Action::DeleteSubspace => self.site_admin || self.subspace_owner,
+            Action::CreateArticle | Action::CreateComment => true,
+            Action::EditArticle
+            | Action::DeleteArticle
+            | Action::RollbackArticle
+            | Action::EditComment
+            | Action::DeleteComment => is_author || can_moderate,
+            // the prior bodies of the edited comments
+            Action::ReviewComment => can_moderate,
+            // the tags of a subspace are kept by the ones who moderate it
+            Action::ManageTag => can_moderate,
+        }
+    }
+
+    /// A private subspace, and all that is in it, is only seen by the ones who
+    /// moderate it. Only meaningful for the permission `in_subspace()` of it
+    pub fn can_view(&self, subspace: &GutpSubspace) -> bool {
+        subspace.is_public || self.site_admin || self.subspace_owner || self.subspace_moderator
+    }
+
+    /// Same as `can()`, but as an error for the handlers
+    pub fn ensure(&self, action: Action, author_id: &str, what: String) -> Result<(), ForumError> {
+        if self.user_id.is_none() {
+            return Err(ForumError::NotLoggedIn);
+        }
+        if self.can(action, author_id) {
+            Ok(())
+        } else {
+            Err(ForumError::Forbidden(what))
+        }
+    }
+
+    // shortcuts for the templates
+
+    pub fn can_create_subspace(&self) -> bool {
+        self.can(Action::CreateSubspace, "")
+    }
+
+    pub fn can_edit_subspace(&self) -> bool {
+        self.can(Action::EditSubspace, "")
+    }
+
+    pub fn can_delete_subspace(&self) -> bool {
+        self.can(Action::DeleteSubspace, "")
+    }
+
+    pub fn can_edit_article(&self, author_id: &str) -> bool {
+        self.can(Action::EditArticle, author_id)
+    }
+
+    pub fn can_delete_article(&self, author_id: &str) -> bool {
+        self.can(Action::DeleteArticle, author_id)
+    }
+
+    pub fn can_edit_comment(&self, author_id: &str) -> bool {
+        self.can(Action::EditComment, author_id)
+    }
+
+    pub fn can_review_comments(&self) -> bool {
+        self.can(Action::ReviewComment, "")
+    }
+
+    pub fn can_delete_comment(&self, author_id: &str) -> bool {
+        self.can(Action::DeleteComment, author_id)
+    }
+
+    pub fn can_manage_tags(&self) -> bool {
+        self.can(Action::ManageTag, "")
+    }
+}
+
+/// Whether the visitor can see the subspace, the visitors not logged in
+/// (and the feed readers) only see the public ones
+pub async fn can_view_subspace(
+    app_state: &AppState,
+    logged_user: Option<&LoggedUser>,
+    subspace: &GutpSubspace,
+) -> Result<bool, ForumError> {
+    if subspace.is_public || logged_user.is_none() {
+        return Ok(subspace.is_public);
+    }
+    let permission = Permission::in_subspace(app_state, logged_user, subspace).await?;
+    Ok(permission.can_view(subspace))
+}
+
+/// A subspace hidden from the visitor is answered as a missing one
+pub async fn ensure_view_subspace(
+    app_state: &AppState,
+    logged_user: Option<&LoggedUser>,
+    subspace: &GutpSubspace,
+) -> Result<(), ForumError> {
+    if can_view_subspace(app_state, logged_user, subspace).await? {
+        Ok(())
+    } else {
+        Err(ForumError::not_found(
+            format!("Query subspace: {}", subspace.id),
+            "No this subspace.",
+        ))
+    }
+}
+
+/// The same for an article, through the subspace it belongs to
+pub async fn ensure_view_post(
+    app_state: &AppState,
+    logged_user: Option<&LoggedUser>,
+    post: &GutpPost,
+) -> Result<(), ForumError> {
+    let action = format!("Query article: {}", post.id);
+    let subspace = app_state
+        .gutp
+        .subspaces()
+        .by_id(&post.subspace_id)
+        .await
+        .map_err(ForumError::gutp(&action))?;
+    // an article left without its subspace isn't hidden by it
+    let Some(subspace) = subspace else {
+        return Ok(());
+    };
+    if can_view_subspace(app_state, logged_user, &subspace).await? {
+        Ok(())
+    } else {
+        Err(ForumError::not_found(&action, "Article doesn't exist!"))
+    }
+}
+
+/// The subspaces the visitor can see, in the same order
+pub async fn visible_subspaces(
+    app_state: &AppState,
+    logged_user: Option<&LoggedUser>,
+    subspaces: Vec<GutpSubspace>,
+) -> Result<Vec<GutpSubspace>, ForumError> {
+    let mut visible = Vec::with_capacity(subspaces.len());
+    for subspace in subspaces {
+        if can_view_subspace(app_state, logged_user, &subspace).await? {
+            visible.push(subspace);
+        }
+    }
+    Ok(visible)
+}
+
+/// The ids of the subspaces hidden from the visitor, to filter what is
+/// listed across the subspaces
+pub async fn hidden_subspace_ids(
+    app_state: &AppState,
+    logged_user: Option<&LoggedUser>,
+) -> Result<HashSet<String>, ForumError> {
+    let subspaces = app_state
+        .gutp
+        .subspaces()
+        .list()
+        .await
+        .map_err(ForumError::gutp("Query subspaces"))?;
+    let mut hidden = HashSet::new();
+    for subspace in subspaces {
+        if !can_view_subspace(app_state, logged_user, &subspace).await? {
+            hidden.insert(subspace.id);
+        }
+    }
+    Ok(hidden)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AUTHOR: &str = "author";
+
+    fn user(user_id: &str) -> Permission {
+        Permission {
+            user_id: Some(user_id.to_string()),
+            ..Default::default()
+        }
+    }
+
+    // in the order of the columns of the table below
+    fn roles() -> [(&'static str, Permission); 6] {
+        [
+            ("visitor", Permission::default()),
+            ("user", user("someone")),
+            ("author", user(AUTHOR)),
+            (
+                "moderator",
+                Permission {
+                    subspace_moderator: true,
+                    ..user("moderator")
+                },
+            ),
+            (
+                "owner",
+                Permission {
+                    subspace_owner: true,
+                    ..user("owner")
+                },
+            ),
+            (
+                "admin",
+                Permission {
+                    site_admin: true,
+                    ..user("admin")
+                },
+            ),
+        ]
+    }
+
+    #[test]
+    fn policy() {
+        #[rustfmt::skip]
+        let table = [
+            // action, author of the target: visitor, user, author, moderator, owner, admin
+            (Action::CreateSubspace, "", [false, false, false, false, false, true]),
+            (Action::EditSubspace, "", [false, false, false, false, true, true]),
+            (Action::DeleteSubspace, "", [false, false, false, false, true, true]),
+            (Action::CreateArticle, "", [false, true, true, true, true, true]),
+            (Action::EditArticle, AUTHOR, [false, false, true, true, true, true]),
+            (Action::DeleteArticle, AUTHOR, [false, false, true, true, true, true]),
+            (Action::RollbackArticle, AUTHOR, [false, false, true, true, true, true]),
+            (Action::CreateComment, "", [false, true, true, true, true, true]),
+            (Action::EditComment, AUTHOR, [false, false, true, true, true, true]),
+            (Action::DeleteComment, AUTHOR, [false, false, true, true, true, true]),
+            (Action::ReviewComment, "", [false, false, false, true, true, true]),
+            (Action::ManageTag, "", [false, false, false, true, true, true]),
+        ];
+        for (action, author_id, allowed) in table {
+            for ((role, permission), allowed) in roles().into_iter().zip(allowed) {
+                assert_eq!(
+                    permission.can(action, author_id),
+                    allowed,
+                    "{:?} by {}",
+                    action,
+                    role
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn no_author_is_nobody() {
+        // the subspace level actions pass "", no user is its author
+        assert!(!user("").can(Action::EditArticle, ""));
+    }
+
+    #[test]
+    fn ensure_tells_the_visitor_to_log_in() {
+        let err = Permission::default()
+            .ensure(Action::CreateComment, "", "Comment".to_string())
+            .unwrap_err();
+        assert!(matches!(err, ForumError::NotLoggedIn));
+
+        let err = user("someone")
+            .ensure(Action::EditArticle, AUTHOR, "Edit".to_string())
+            .unwrap_err();
+        assert!(matches!(err, ForumError::Forbidden(_)));
+    }
+}
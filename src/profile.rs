@@ -0,0 +1,628 @@
+use askama::Template;
+use axum::{
+    extract::{Form, Multipart, Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Redirect, Response},
+    Extension,
+};
+use axum_extra::extract::cookie::CookieJar;
+use futures_util::{stream, StreamExt, TryStreamExt};
+use gutp_types::{GutpComment, GutpPost, GutpUser};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::datetime;
+use crate::error::ForumError;
+use crate::filters;
+use crate::gutp::{Paging, UserUpdate};
+use crate::i18n;
+use crate::pagination::{Pager, PAGE_SIZE};
+use crate::permission;
+use crate::upload;
+use crate::user::session_conn;
+use crate::AppState;
+use crate::HtmlTemplate;
+use crate::{require_login, LoggedUser};
+
+const MAX_NICKNAME_LEN: usize = 32;
+const MAX_BIO_LEN: usize = 500;
+const MAX_AVATAR_SIZE: usize = 1024 * 1024;
+
+/// What the user shares on their public page, kept as json in `pub_settings`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PubSettings {
+    pub bio: String,
+    /// false: only the logged in users can see the profile page
+    pub profile_public: bool,
+    /// list the articles and comments on the profile page
+    pub show_activity: bool,
+    /// the language of the pages, empty to follow the browser
+    pub locale: String,
+    /// the timezone of the dates, e.g. "Europe/Paris", empty for the site one
+    pub timezone: String,
+}
+
+impl Default for PubSettings {
+    fn default() -> Self {
+        PubSettings {
+            bio: "".to_string(),
+            profile_public: true,
+            show_activity: true,
+            locale: "".to_string(),
+            timezone: "".to_string(),
+        }
+    }
+}
+
+impl PubSettings {
+    /// The users from before the settings have an empty string there
+    pub fn of(user: &GutpUser) -> Self {
+        serde_json::from_str(&user.pub_settings).unwrap_or_default()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    pub fn is_timezone(&self, name: &str) -> bool {
+        self.timezone == name
+    }
+}
+
+/// The language and the timezone the user picked follow them to this browser,
+/// the empty (or no more valid) ones leave the cookies as they are
+pub fn preference_cookies(appid: &str, settings: &PubSettings, cookie_jar: CookieJar) -> CookieJar {
+    let mut cookie_jar = cookie_jar;
+    if i18n::is_supported(&settings.locale) {
+        cookie_jar = cookie_jar.add(i18n::locale_cookie(appid, &settings.locale));
+    }
+    if datetime::parse_timezone(&settings.timezone).is_some() {
+        cookie_jar = cookie_jar.add(datetime::timezone_cookie(appid, &settings.timezone));
+    }
+    cookie_jar
+}
+
+fn user_update(user: &GutpUser) -> UserUpdate {
+    UserUpdate {
+        id: user.id.to_owned(),
+        nickname: user.nickname.to_owned(),
+        avatar: user.avatar.to_owned(),
+        pub_settings: user.pub_settings.to_owned(),
+        ext: user.ext.to_owned(),
+    }
+}
+
+/// Give the provider avatar to the users who don't have one, at login
+pub async fn backfill_avatar(app_state: &AppState, user: &GutpUser, avatar_url: &str) {
+    if !user.avatar.is_empty() || avatar_url.is_empty() {
+        return;
+    }
+    let inner_params = UserUpdate {
+        avatar: avatar_url.to_string(),
+        ..user_update(user)
+    };
+    if let Err(err) = app_state.gutp.users().update(&inner_params).await {
+        println!("in backfill_avatar: {}: {}", user.id, err);
+    }
+}
+
+#[derive(Template)]
+#[template(path = "profile_edit.html")]
+struct ProfileEditTemplate {
+    user: GutpUser,
+    settings: PubSettings,
+    // the avatars of the linked accounts, and the current one
+    avatar_choices: Vec<String>,
+    notice: Option<String>,
+    csrf_token: String,
+}
+
+pub async fn query_user(app_state: &AppState, user_id: &str) -> Result<GutpUser, ForumError> {
+    let action = format!("Query user: {}", user_id);
+    app_state
+        .gutp
+        .users()
+        .by_id(user_id)
+        .await
+        .map_err(ForumError::gutp(&action))?
+        .ok_or_else(|| ForumError::not_found(&action, "Unknown."))
+}
+
+async fn avatar_choices(app_state: &AppState, user: &GutpUser) -> Result<Vec<String>, ForumError> {
+    let mut redis_conn = session_conn(app_state).await?;
+    let identities = app_state
+        .identities
+        .list_by_user(&mut redis_conn, &user.id)
+        .await
+        .map_err(|err| ForumError::Internal("List linked accounts".to_string(), err.to_string()))?;
+
+    let mut choices = vec![];
+    if !user.avatar.is_empty() {
+        choices.push(user.avatar.to_owned());
+    }
+    for identity in identities {
+        if !identity.avatar_url.is_empty() && !choices.contains(&identity.avatar_url) {
+            choices.push(identity.avatar_url);
+        }
+    }
+    Ok(choices)
+}
+
+async fn render_edit(
+    app_state: &AppState,
+    user: GutpUser,
+    settings: PubSettings,
+    notice: Option<String>,
+    csrf_token: String,
+) -> Result<ProfileEditTemplate, ForumError> {
+    let avatar_choices = avatar_choices(app_state, &user).await?;
+    Ok(ProfileEditTemplate {
+        user,
+        settings,
+        avatar_choices,
+        notice,
+        csrf_token,
+    })
+}
+
+pub async fn view_profile_edit(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+) -> Result<impl IntoResponse, ForumError> {
+    let logged_user = require_login(logged_user)?;
+
+    let user = query_user(&app_state, &logged_user.user_id).await?;
+    let settings = PubSettings::of(&user);
+    let template = render_edit(&app_state, user, settings, None, logged_user.csrf_token).await?;
+    Ok(HtmlTemplate(template))
+}
+
+#[derive(Deserialize)]
+pub struct PostProfileEditParams {
+    nickname: String,
+    avatar: Option<String>,
+    bio: String,
+    // checkboxes, absent when unchecked
+    profile_public: Option<String>,
+    show_activity: Option<String>,
+    #[serde(default)]
+    locale: String,
+    #[serde(default)]
+    timezone: String,
+}
+
+pub async fn post_profile_edit(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    cookie_jar: CookieJar,
+    Form(params): Form<PostProfileEditParams>,
+) -> Result<Response, ForumError> {
+    let logged_user = require_login(logged_user)?;
+
+    let mut user = query_user(&app_state, &logged_user.user_id).await?;
+    let settings = PubSettings {
+        bio: params.bio.trim().to_string(),
+        profile_public: params.profile_public.is_some(),
+        show_activity: params.show_activity.is_some(),
+        // an unknown one follows the browser
+        locale: Some(params.locale)
+            .filter(|locale| i18n::is_supported(locale))
+            .unwrap_or_default(),
+        // an unknown one is the site timezone
+        timezone: Some(params.timezone)
+            .filter(|timezone| datetime::parse_timezone(timezone).is_some())
+            .unwrap_or_default(),
+    };
+    let nickname = params.nickname.trim().to_string();
+
+    let notice = if nickname.is_empty() || nickname.chars().count() > MAX_NICKNAME_LEN {
+        Some(format!(
+            "The nickname must be 1 to {} characters.",
+            MAX_NICKNAME_LEN
+        ))
+    } else if nickname.chars().any(|c| c.is_control()) {
+        Some("The nickname can't have control characters.".to_string())
+    } else if settings.bio.chars().count() > MAX_BIO_LEN {
+        Some(format!(
+            "The bio must be at most {} characters.",
+            MAX_BIO_LEN
+        ))
+    } else {
+        None
+    };
+    if let Some(notice) = notice {
+        user.nickname = nickname;
+        let template = render_edit(
+            &app_state,
+            user,
+            settings,
+            Some(notice),
+            logged_user.csrf_token,
+        )
+        .await?;
+        return Ok((StatusCode::BAD_REQUEST, HtmlTemplate(template)).into_response());
+    }
+
+    // only the offered avatars can be picked, not any url
+    let mut avatar = user.avatar.to_owned();
+    if let Some(picked) = params.avatar.filter(|picked| *picked != user.avatar) {
+        if !avatar_choices(&app_state, &user).await?.contains(&picked) {
+            return Err(ForumError::BadRequest(
+                "Edit profile".to_string(),
+                "This avatar can't be picked.".to_string(),
+            ));
+        }
+        upload::remove_image(&app_state.config.upload_dir, "avatars", &user.avatar).await;
+        avatar = picked;
+    }
+
+    let inner_params = UserUpdate {
+        nickname,
+        avatar,
+        pub_settings: settings.to_json(),
+        ..user_update(&user)
+    };
+    app_state
+        .gutp
+        .users()
+        .update(&inner_params)
+        .await
+        .map_err(ForumError::gutp(format!("Update user: {}", user.id)))?;
+
+    // the pages switch to the chosen language and timezone right away
+    let cookie_jar = preference_cookies(&app_state.config.appid, &settings, cookie_jar);
+    Ok((cookie_jar, Redirect::to("/user/account")).into_response())
+}
+
+pub async fn post_profile_avatar(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    mut multipart: Multipart,
+) -> Result<Redirect, ForumError> {
+    let logged_user = require_login(logged_user)?;
+    let action = "Upload avatar";
+
+    let user = query_user(&app_state, &logged_user.user_id).await?;
+    let bytes = upload::read_image_field(&mut multipart, "avatar", MAX_AVATAR_SIZE, action).await?;
+    let upload_dir = &app_state.config.upload_dir;
+    let url = upload::save_image(upload_dir, "avatars", &user.id, &bytes, action).await?;
+
+    let inner_params = UserUpdate {
+        avatar: url.to_owned(),
+        ..user_update(&user)
+    };
+    if let Err(err) = app_state.gutp.users().update(&inner_params).await {
+        upload::remove_image(upload_dir, "avatars", &url).await;
+        return Err(ForumError::Gutp(format!("Update user: {}", user.id), err));
+    }
+    // the previous upload isn't reachable any more
+    upload::remove_image(upload_dir, "avatars", &user.avatar).await;
+
+    Ok(Redirect::to("/user/profile/edit"))
+}
+
+#[derive(Template)]
+#[template(path = "user.html")]
+struct UserTemplate {
+    user: GutpUser,
+    settings: PubSettings,
+    is_self: bool,
+    // false when the user hides their activity
+    show_activity: bool,
+    // "articles" or "comments"
+    tab: String,
+    posts: Vec<GutpPost>,
+    comments: Vec<GutpComment>,
+    // the visible ones, not only the page
+    post_count: ActivityCount,
+    comment_count: ActivityCount,
+    pager: Pager,
+}
+
+#[derive(Deserialize)]
+pub struct ViewUserParams {
+    tab: Option<String>,
+    page: Option<usize>,
+}
+
+pub async fn view_user(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    Path(id): Path<String>,
+    Query(params): Query<ViewUserParams>,
+) -> Result<impl IntoResponse, ForumError> {
+    let user = query_user(&app_state, &id).await?;
+    let settings = PubSettings::of(&user);
+    let is_self = logged_user.as_ref().is_some_and(|u| u.user_id == user.id);
+    if !settings.profile_public && logged_user.is_none() {
+        return Err(ForumError::NotLoggedIn);
+    }
+
+    let show_activity = settings.show_activity || is_self;
+    let tab = match params.tab.as_deref() {
+        Some("comments") => "comments",
+        _ => "articles",
+    };
+    let base_url = format!("/user/{}?tab={}", user.id, tab);
+
+    // only the page of the tab shown, one more to know whether there is a next one
+    let (page, offset) = Pager::page_offset(params.page, PAGE_SIZE);
+    let paging = Paging::new(PAGE_SIZE + 1, offset);
+    let action = format!("Query activity of user: {}", user.id);
+    let (mut posts, mut comments, pager) = if tab == "comments" {
+        let comments = if show_activity {
+            app_state
+                .gutp
+                .comments()
+                .list_by_author(&user.id, paging)
+                .await
+                .map_err(ForumError::gutp(&action))?
+        } else {
+            vec![]
+        };
+        let (comments, pager) = Pager::from_window(comments, page, PAGE_SIZE, base_url);
+        (vec![], comments, pager)
+    } else {
+        let posts = if show_activity {
+            app_state
+                .gutp
+                .posts()
+                .list_by_author(&user.id, paging)
+                .await
+                .map_err(ForumError::gutp(&action))?
+        } else {
+            vec![]
+        };
+        let (posts, pager) = Pager::from_window(posts, page, PAGE_SIZE, base_url);
+        (posts, vec![], pager)
+    };
+
+    // the private ones are only listed to their author, and none of a
+    // private subspace the visitor can't see, or of a deleted article
+    let (post_count, comment_count) = if show_activity {
+        let hidden_subspaces =
+            permission::hidden_subspace_ids(&app_state, logged_user.as_deref()).await?;
+        let window = Paging::new(COUNT_LIMIT + 1, 0);
+        let counted_posts = app_state
+            .gutp
+            .posts()
+            .list_by_author(&user.id, window)
+            .await
+            .map_err(ForumError::gutp(&action))?;
+        let counted_comments = app_state
+            .gutp
+            .comments()
+            .list_by_author(&user.id, window)
+            .await
+            .map_err(ForumError::gutp(&action))?;
+
+        // the articles of the page and of the count are looked up together
+        let commented_posts = comments
+            .iter()
+            .chain(counted_comments.iter().take(COUNT_LIMIT))
+            .filter(|comment| comment.is_public || is_self)
+            .map(|comment| &comment.post_id);
+        let hidden_posts =
+            hidden_post_ids(&app_state, &hidden_subspaces, commented_posts, &action).await?;
+
+        let post_shown = |post: &GutpPost| post_is_shown(post, is_self, &hidden_subspaces);
+        let comment_shown =
+            |comment: &GutpComment| comment_is_shown(comment, is_self, &hidden_posts);
+        posts.retain(post_shown);
+        comments.retain(comment_shown);
+        (
+            ActivityCount::of(&counted_posts, post_shown),
+            ActivityCount::of(&counted_comments, comment_shown),
+        )
+    } else {
+        (ActivityCount::default(), ActivityCount::default())
+    };
+
+    Ok(HtmlTemplate(UserTemplate {
+        user,
+        settings,
+        is_self,
+        show_activity,
+        tab: tab.to_string(),
+        posts,
+        comments,
+        post_count,
+        comment_count,
+        pager,
+    }))
+}
+
+// the counts of the profile stop there, GUTP has no count and the lists
+// aren't walked through on every view
+const COUNT_LIMIT: usize = 100;
+
+/// The number of articles or comments the visitor can see among the first
+/// `COUNT_LIMIT` ones, shown as "100+" when there are more
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct ActivityCount {
+    shown: usize,
+    more: bool,
+}
+
+impl ActivityCount {
+    /// `window` is the list from its start, with one more than `COUNT_LIMIT`
+    fn of<T>(window: &[T], is_shown: impl Fn(&T) -> bool) -> Self {
+        ActivityCount {
+            shown: window
+                .iter()
+                .take(COUNT_LIMIT)
+                .filter(|t| is_shown(t))
+                .count(),
+            more: window.len() > COUNT_LIMIT,
+        }
+    }
+}
+
+impl fmt::Display for ActivityCount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.more {
+            write!(f, "{}+", self.shown)
+        } else {
+            write!(f, "{}", self.shown)
+        }
+    }
+}
+
+fn post_is_shown(post: &GutpPost, is_self: bool, hidden_subspaces: &HashSet<String>) -> bool {
+    (post.is_public || is_self) && !hidden_subspaces.contains(&post.subspace_id)
+}
+
+fn comment_is_shown(comment: &GutpComment, is_self: bool, hidden_posts: &HashSet<String>) -> bool {
+    (comment.is_public || is_self) && !hidden_posts.contains(&comment.post_id)
+}
+
+// the articles of the commented posts looked up at once, GUTP has no batch
+const CONCURRENT_LOOKUPS: usize = 8;
+
+/// The articles among `post_ids` whose comments the visitor can't see:
+/// the ones in a hidden subspace, and the deleted ones.
+///
+/// A comment only knows its article, which knows the subspace, so each
+/// distinct article is looked up once, a few at a time.
+async fn hidden_post_ids<'a>(
+    app_state: &AppState,
+    hidden_subspaces: &HashSet<String>,
+    post_ids: impl IntoIterator<Item = &'a String>,
+    action: &str,
+) -> Result<HashSet<String>, ForumError> {
+    let post_ids: HashSet<&String> = post_ids.into_iter().collect();
+    let looked_up: Vec<(&String, Option<GutpPost>)> = stream::iter(post_ids)
+        .map(|post_id| async move {
+            let post = app_state.gutp.posts().by_id(post_id).await;
+            post.map(|post| (post_id, post))
+        })
+        .buffer_unordered(CONCURRENT_LOOKUPS)
+        .try_collect()
+        .await
+        .map_err(ForumError::gutp(action))?;
+    Ok(hidden_of(looked_up, hidden_subspaces))
+}
+
+// `looked_up` has the articles by their id, None when it was deleted
+fn hidden_of<'a>(
+    looked_up: impl IntoIterator<Item = (&'a String, Option<GutpPost>)>,
+    hidden_subspaces: &HashSet<String>,
+) -> HashSet<String> {
+    looked_up
+        .into_iter()
+        .filter(|(_, post)| match post {
+            Some(post) => hidden_subspaces.contains(&post.subspace_id),
+            None => true,
+        })
+        .map(|(post_id, _)| post_id.to_owned())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(id: &str, subspace_id: &str, is_public: bool) -> GutpPost {
+        GutpPost {
+            id: id.to_string(),
+            subspace_id: subspace_id.to_string(),
+            is_public,
+            ..Default::default()
+        }
+    }
+
+    fn comment(id: &str, post_id: &str, is_public: bool) -> GutpComment {
+        GutpComment {
+            id: id.to_string(),
+            post_id: post_id.to_string(),
+            is_public,
+            ..Default::default()
+        }
+    }
+
+    fn set(ids: &[&str]) -> HashSet<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn posts_of_hidden_subspaces_and_private_ones_are_not_counted() {
+        let posts = [
+            post("p1", "public", true),
+            post("p2", "private", true),
+            post("p3", "public", false),
+        ];
+        let hidden = set(&["private"]);
+
+        let count = ActivityCount::of(&posts, |p| post_is_shown(p, false, &hidden));
+        assert_eq!(count.to_string(), "1");
+        // the author sees their private article, not the hidden subspace
+        let count = ActivityCount::of(&posts, |p| post_is_shown(p, true, &hidden));
+        assert_eq!(count.to_string(), "2");
+        let count = ActivityCount::of(&posts, |p| post_is_shown(p, true, &set(&[])));
+        assert_eq!(count.to_string(), "3");
+    }
+
+    #[test]
+    fn comments_of_hidden_or_deleted_articles_are_not_counted() {
+        let comments = [
+            comment("c1", "shown", true),
+            comment("c2", "deleted", true),
+            comment("c3", "shown", false),
+        ];
+        // a deleted article is hidden whatever subspaces the visitor sees
+        let hidden_posts = set(&["deleted"]);
+
+        let count = ActivityCount::of(&comments, |c| comment_is_shown(c, false, &hidden_posts));
+        assert_eq!(count.to_string(), "1");
+        let count = ActivityCount::of(&comments, |c| comment_is_shown(c, true, &hidden_posts));
+        assert_eq!(count.to_string(), "2");
+    }
+
+    #[test]
+    fn articles_deleted_or_in_a_hidden_subspace_are_hidden() {
+        let ids = ["shown", "private", "deleted"].map(String::from);
+        let looked_up = || {
+            [
+                (&ids[0], Some(post("shown", "public", true))),
+                (&ids[1], Some(post("private", "private", true))),
+                (&ids[2], None),
+            ]
+        };
+
+        let comments = [
+            comment("c1", "shown", true),
+            comment("c2", "private", true),
+            comment("c3", "deleted", true),
+        ];
+
+        let hidden = hidden_of(looked_up(), &set(&["private"]));
+        assert_eq!(hidden, set(&["private", "deleted"]));
+        let count = ActivityCount::of(&comments, |c| comment_is_shown(c, false, &hidden));
+        assert_eq!(count.to_string(), "1");
+
+        // a visitor who sees the subspace still can't see the deleted one
+        let hidden = hidden_of(looked_up(), &set(&[]));
+        assert_eq!(hidden, set(&["deleted"]));
+        let count = ActivityCount::of(&comments, |c| comment_is_shown(c, false, &hidden));
+        assert_eq!(count.to_string(), "2");
+    }
+
+    #[test]
+    fn counts_stop_at_the_limit() {
+        let window: Vec<GutpPost> = (0..COUNT_LIMIT)
+            .map(|i| post(&format!("p{}", i), "public", true))
+            .collect();
+        let shown = |p: &GutpPost| post_is_shown(p, false, &HashSet::new());
+        assert_eq!(ActivityCount::of(&window, shown).to_string(), "100");
+
+        let mut window = window;
+        window.push(post("one_more", "public", true));
+        assert_eq!(ActivityCount::of(&window, shown).to_string(), "100+");
+
+        // the hidden ones of the window are left out, the rest is still unknown
+        window[0].is_public = false;
+        assert_eq!(ActivityCount::of(&window, shown).to_string(), "99+");
+    }
+}
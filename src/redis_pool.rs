@@ -0,0 +1,200 @@
+use redis::aio::ConnectionManager;
+use redis::{RedisError, RedisResult};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+// don't hold a request for long when redis is unreachable
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+// after a failed connect, the requests fail fast for this long
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The shared redis connection of the app.
+///
+/// `ConnectionManager` multiplexes all the requests over one connection and
+/// reconnects by itself after a failure, so it is cheap to clone per request.
+/// It is created lazily, so the app can start while redis is down.
+pub struct RedisPool {
+    client: redis::Client,
+    // only locked to clone or store the manager, never across an await
+    manager: std::sync::Mutex<Option<ConnectionManager>>,
+    // held by the one request connecting, the others wait for its outcome
+    connecting: tokio::sync::Mutex<()>,
+    last_failure: std::sync::Mutex<Option<Instant>>,
+    available: AtomicBool,
+}
+
+fn unavailable(reason: &str) -> RedisError {
+    RedisError::from(std::io::Error::new(std::io::ErrorKind::TimedOut, reason))
+}
+
+impl RedisPool {
+    pub fn new(client: redis::Client) -> Self {
+        RedisPool {
+            client,
+            manager: std::sync::Mutex::new(None),
+            connecting: tokio::sync::Mutex::new(()),
+            last_failure: std::sync::Mutex::new(None),
+            available: AtomicBool::new(false),
+        }
+    }
+
+    fn current(&self) -> Option<ConnectionManager> {
+        self.manager.lock().ok()?.clone()
+    }
+
+    fn in_backoff(&self) -> bool {
+        self.last_failure
+            .lock()
+            .ok()
+            .and_then(|last_failure| *last_failure)
+            .is_some_and(|at| at.elapsed() < RETRY_INTERVAL)
+    }
+
+    fn record_failure(&self, failed: bool) {
+        if let Ok(mut last_failure) = self.last_failure.lock() {
+            *last_failure = failed.then(Instant::now);
+        }
+    }
+
+    /// Get a connection, connecting first if this is the first use.
+    ///
+    /// Only one request connects at a time, the others arriving meanwhile
+    /// wait for it (at most `CONNECT_TIMEOUT`) and share its outcome. After a
+    /// failure, no request connects again until `RETRY_INTERVAL` passed, they
+    /// fail fast.
+    pub async fn conn(&self) -> RedisResult<ConnectionManager> {
+        if let Some(manager) = self.current() {
+            return Ok(manager);
+        }
+        if self.in_backoff() {
+            return Err(unavailable("redis is unreachable, retrying later"));
+        }
+        let _connecting = self.connecting.lock().await;
+        // connected, or failed to, by the request holding the lock before
+        if let Some(manager) = self.current() {
+            return Ok(manager);
+        }
+        if self.in_backoff() {
+            return Err(unavailable("redis is unreachable, retrying later"));
+        }
+
+        let connecting = ConnectionManager::new(self.client.clone());
+        let result = match tokio::time::timeout(CONNECT_TIMEOUT, connecting).await {
+            Ok(result) => result,
+            Err(_) => Err(unavailable("connect to redis timed out")),
+        };
+        match result {
+            Ok(new_manager) => {
+                self.record_failure(false);
+                self.set_available(true);
+                if let Ok(mut manager) = self.manager.lock() {
+                    *manager = Some(new_manager.clone());
+                }
+                Ok(new_manager)
+            }
+            Err(err) => {
+                self.record_failure(true);
+                self.set_available(false);
+                Err(err)
+            }
+        }
+    }
+
+    /// Round trip to redis, a change of the state is logged
+    pub async fn ping(&self) -> RedisResult<()> {
+        let mut conn = self.conn().await?;
+        let ping = redis::cmd("PING");
+        let pinging = ping.query_async::<_, String>(&mut conn);
+        let result = match tokio::time::timeout(CONNECT_TIMEOUT, pinging).await {
+            Ok(result) => result.map(|_| ()),
+            Err(_) => Err(unavailable("ping redis timed out")),
+        };
+        self.set_available(result.is_ok());
+        result
+    }
+
+    /// Called by the users of the connection when a command failed
+    pub fn set_available(&self, available: bool) {
+        let was_available = self.available.swap(available, Ordering::Relaxed);
+        if was_available != available {
+            println!(
+                "redis is {}",
+                if available {
+                    "available"
+                } else {
+                    "unavailable"
+                }
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // nothing listens there, the connect is refused at once
+    fn unreachable_pool() -> RedisPool {
+        RedisPool::new(redis::Client::open("redis://127.0.0.1:1/").unwrap())
+    }
+
+    async fn conn_err(pool: &RedisPool) -> RedisError {
+        pool.conn().await.err().expect("redis is unreachable")
+    }
+
+    fn is_fail_fast(err: &RedisError, reason: &str) -> bool {
+        err.to_string().contains(reason)
+    }
+
+    #[tokio::test]
+    async fn backs_off_after_a_failed_connect() {
+        let pool = unreachable_pool();
+        let err = conn_err(&pool).await;
+        assert!(!is_fail_fast(&err, "retrying later"));
+        assert!(pool.in_backoff());
+        assert!(!pool.available.load(Ordering::Relaxed));
+
+        // the next ones fail without connecting
+        let err = conn_err(&pool).await;
+        assert!(is_fail_fast(&err, "retrying later"));
+    }
+
+    #[tokio::test]
+    async fn retries_after_the_interval() {
+        let pool = unreachable_pool();
+        conn_err(&pool).await;
+
+        // as if the interval passed
+        *pool.last_failure.lock().unwrap() = Instant::now().checked_sub(RETRY_INTERVAL);
+        assert!(!pool.in_backoff());
+        let err = conn_err(&pool).await;
+        assert!(!is_fail_fast(&err, "retrying later"));
+        assert!(pool.in_backoff());
+    }
+
+    #[tokio::test]
+    async fn one_request_connects_at_a_time() {
+        let pool = unreachable_pool();
+        let connecting = pool.connecting.lock().await;
+
+        // another request is connecting, this one waits instead of failing
+        let waited = tokio::time::timeout(Duration::from_millis(50), pool.conn()).await;
+        assert!(waited.is_err());
+        assert!(!pool.in_backoff());
+
+        // and gets the outcome of that connect, without connecting again
+        pool.record_failure(true);
+        drop(connecting);
+        let err = conn_err(&pool).await;
+        assert!(is_fail_fast(&err, "retrying later"));
+    }
+
+    #[test]
+    fn a_success_clears_the_backoff() {
+        let pool = unreachable_pool();
+        pool.record_failure(true);
+        assert!(pool.in_backoff());
+        pool.record_failure(false);
+        assert!(!pool.in_backoff());
+    }
+}
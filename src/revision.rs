@@ -0,0 +1,379 @@
+use askama::Template;
+use axum::{
+    extract::{Form, Path, Query, State},
+    response::{IntoResponse, Redirect},
+    Extension,
+};
+use gutp_types::GutpPost;
+use serde::{Deserialize, Serialize};
+use similar::{ChangeTag, TextDiff};
+
+use crate::article::query_post;
+use crate::error::ForumError;
+use crate::filters;
+use crate::gutp::{NewPostDiff, PostUpdate};
+use crate::permission::{self, Action, Permission};
+use crate::search;
+use crate::slug;
+use crate::AppState;
+use crate::HtmlTemplate;
+use crate::{require_login, LoggedUser};
+
+/// What is saved in the `diff` field of a postdiff, as json.
+///
+/// Both sides are kept whole, so every revision can be rebuilt without
+/// replaying patches, the original one from the old side of the first edit.
+#[derive(Serialize, Deserialize)]
+struct EditRecord {
+    editor_id: String,
+    editor_nickname: String,
+    old_title: String,
+    old_content: String,
+    new_title: String,
+    new_content: String,
+}
+
+/// One version of an article, 0 is the original
+#[derive(Debug, Clone)]
+pub struct Revision {
+    pub version: i32,
+    pub title: String,
+    pub content: String,
+    pub editor_id: String,
+    pub editor_nickname: String,
+    pub created_time: i64,
+}
+
+/// All the revisions of the article, the oldest first
+pub async fn list_revisions(
+    app_state: &AppState,
+    post: &GutpPost,
+) -> Result<Vec<Revision>, ForumError> {
+    let mut post_diffs = app_state
+        .gutp
+        .post_diffs()
+        .list_by_post(&post.id)
+        .await
+        .map_err(ForumError::gutp(format!(
+            "Query revisions of article: {}",
+            post.id
+        )))?;
+    // two edits at once may have saved the same version_num, the time orders them
+    post_diffs.sort_by_key(|post_diff| (post_diff.version_num, post_diff.created_time));
+
+    let records: Vec<(i64, EditRecord)> = post_diffs
+        .into_iter()
+        .filter_map(|post_diff| match serde_json::from_str(&post_diff.diff) {
+            Ok(record) => Some((post_diff.created_time, record)),
+            Err(err) => {
+                println!("in list_revisions: skip postdiff {}: {}", post_diff.id, err);
+                None
+            }
+        })
+        .collect();
+
+    let (title, content) = match records.first() {
+        Some((_, record)) => (record.old_title.to_owned(), record.old_content.to_owned()),
+        // never edited since the history is kept
+        None => (post.title.to_owned(), post.content.to_owned()),
+    };
+    let mut revisions = vec![Revision {
+        version: 0,
+        title,
+        content,
+        editor_id: post.author_id.to_owned(),
+        editor_nickname: post.author_nickname.to_owned(),
+        created_time: post.created_time,
+    }];
+    // numbered by the order, so the versions are distinct and without gaps
+    for (version, (created_time, record)) in (1..).zip(records) {
+        revisions.push(Revision {
+            version,
+            title: record.new_title,
+            content: record.new_content,
+            editor_id: record.editor_id,
+            editor_nickname: record.editor_nickname,
+            created_time,
+        });
+    }
+
+    Ok(revisions)
+}
+
+/// Save the edit from `old` to `new` as the next revision, nothing is saved
+/// when the title and content are the same.
+///
+/// The edit is applied already, so a failure is logged and doesn't fail it.
+pub async fn record_edit(app_state: &AppState, editor_id: &str, old: &GutpPost, new: &GutpPost) {
+    if let Err(err) = try_record_edit(app_state, editor_id, old, new).await {
+        println!("in record_edit, article {}: {}", new.id, err);
+    }
+}
+
+// the `version_num` saved only orders the revisions, `list_revisions` numbers them
+async fn try_record_edit(
+    app_state: &AppState,
+    editor_id: &str,
+    old: &GutpPost,
+    new: &GutpPost,
+) -> Result<(), ForumError> {
+    if old.title == new.title && old.content == new.content {
+        return Ok(());
+    }
+
+    let action = format!("Record revision of article: {}", new.id);
+    let post_diffs = app_state.gutp.post_diffs();
+    let last_version = post_diffs
+        .list_by_post(&new.id)
+        .await
+        .map_err(ForumError::gutp(&action))?
+        .iter()
+        .map(|post_diff| post_diff.version_num)
+        .max()
+        .unwrap_or(0);
+
+    // the nickname is only for display, don't fail the edit on it
+    let editor_nickname = app_state
+        .gutp
+        .users()
+        .by_id(editor_id)
+        .await
+        .ok()
+        .flatten()
+        .map(|user| user.nickname)
+        .unwrap_or_default();
+
+    let record = EditRecord {
+        editor_id: editor_id.to_string(),
+        editor_nickname,
+        old_title: old.title.to_owned(),
+        old_content: old.content.to_owned(),
+        new_title: new.title.to_owned(),
+        new_content: new.content.to_owned(),
+    };
+    let diff = serde_json::to_string(&record)
+        .map_err(|err| ForumError::Internal(action.clone(), err.to_string()))?;
+
+    let inner_params = NewPostDiff {
+        post_id: new.id.to_owned(),
+        diff,
+        version_num: last_version + 1,
+    };
+    post_diffs
+        .create(&inner_params)
+        .await
+        .map_err(ForumError::gutp(&action))?;
+
+    Ok(())
+}
+
+/// A line of the rendered diff
+pub struct DiffLine {
+    pub tag: ChangeTag,
+    pub text: String,
+}
+
+impl DiffLine {
+    pub fn class(&self) -> &'static str {
+        match self.tag {
+            ChangeTag::Equal => "diff-equal",
+            ChangeTag::Delete => "diff-delete",
+            ChangeTag::Insert => "diff-insert",
+        }
+    }
+
+    pub fn sign(&self) -> &'static str {
+        match self.tag {
+            ChangeTag::Equal => " ",
+            ChangeTag::Delete => "-",
+            ChangeTag::Insert => "+",
+        }
+    }
+}
+
+fn diff_lines(old: &str, new: &str) -> Vec<DiffLine> {
+    TextDiff::from_lines(old, new)
+        .iter_all_changes()
+        .map(|change| DiffLine {
+            tag: change.tag(),
+            text: change.value().trim_end_matches(['\r', '\n']).to_string(),
+        })
+        .collect()
+}
+
+#[derive(Template)]
+#[template(path = "article_history.html")]
+struct ArticleHistoryTemplate {
+    post: GutpPost,
+    // the newest first
+    revisions: Vec<Revision>,
+    latest_version: i32,
+    can_rollback: bool,
+    csrf_token: String,
+}
+
+pub async fn view_history(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, ForumError> {
+    let post = query_post(&app_state, &id).await?;
+    permission::ensure_view_post(&app_state, logged_user.as_deref(), &post).await?;
+    let mut revisions = list_revisions(&app_state, &post).await?;
+    revisions.reverse();
+    let latest_version = revisions.first().map_or(0, |revision| revision.version);
+
+    // only decides whether the rollback buttons are shown
+    let permission = Permission::for_post(&app_state, logged_user.as_deref(), &post)
+        .await
+        .unwrap_or_else(|_| Permission::site(&app_state, logged_user.as_deref()));
+    let can_rollback = permission.can(Action::RollbackArticle, &post.author_id);
+
+    Ok(HtmlTemplate(ArticleHistoryTemplate {
+        post,
+        revisions,
+        latest_version,
+        can_rollback,
+        csrf_token: logged_user
+            .map(|Extension(logged_user)| logged_user.csrf_token)
+            .unwrap_or_default(),
+    }))
+}
+
+#[derive(Template)]
+#[template(path = "article_diff.html")]
+struct ArticleDiffTemplate {
+    post: GutpPost,
+    from: Revision,
+    to: Revision,
+    title_lines: Vec<DiffLine>,
+    content_lines: Vec<DiffLine>,
+}
+
+#[derive(Deserialize)]
+pub struct ViewDiffParams {
+    from: Option<i32>,
+    to: Option<i32>,
+}
+
+pub async fn view_diff(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    Path(id): Path<String>,
+    Query(params): Query<ViewDiffParams>,
+) -> Result<impl IntoResponse, ForumError> {
+    let post = query_post(&app_state, &id).await?;
+    permission::ensure_view_post(&app_state, logged_user.as_deref(), &post).await?;
+    let revisions = list_revisions(&app_state, &post).await?;
+
+    // by default, what the last edit changed
+    let latest_version = revisions.last().map_or(0, |revision| revision.version);
+    let to_version = params.to.unwrap_or(latest_version);
+    let from_version = params.from.unwrap_or(to_version - 1).max(0);
+
+    let action = format!("Compare revisions of article: {}", post.id);
+    let find = |version: i32| {
+        revisions
+            .iter()
+            .find(|revision| revision.version == version)
+            .cloned()
+            .ok_or_else(|| ForumError::not_found(&action, "No this revision."))
+    };
+    let from = find(from_version)?;
+    let to = find(to_version)?;
+
+    Ok(HtmlTemplate(ArticleDiffTemplate {
+        title_lines: diff_lines(&from.title, &to.title),
+        content_lines: diff_lines(&from.content, &to.content),
+        post,
+        from,
+        to,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct PostRollbackParams {
+    version: i32,
+}
+
+pub async fn post_rollback(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    Path(id): Path<String>,
+    Form(params): Form<PostRollbackParams>,
+) -> Result<Redirect, ForumError> {
+    // check the user login status
+    let logged_user = require_login(logged_user)?;
+
+    let post = query_post(&app_state, &id).await?;
+    let action = format!(
+        "Roll back article: {} to revision {}",
+        post.id, params.version
+    );
+    Permission::for_post(&app_state, Some(&logged_user), &post)
+        .await?
+        .ensure(Action::RollbackArticle, &post.author_id, action.clone())?;
+
+    let revision = list_revisions(&app_state, &post)
+        .await?
+        .into_iter()
+        .find(|revision| revision.version == params.version)
+        .ok_or_else(|| ForumError::not_found(&action, "No this revision."))?;
+
+    // a rollback is an edit too, so it can be rolled back in turn
+    let inner_params = PostUpdate {
+        id: post.id.to_owned(),
+        title: revision.title,
+        content: revision.content,
+        author_id: post.author_id.to_owned(),
+        extlink: post.extlink.to_owned(),
+        is_public: post.is_public,
+    };
+    let new_post = app_state
+        .gutp
+        .posts()
+        .update(&inner_params)
+        .await
+        .map_err(ForumError::gutp(&action))?;
+    record_edit(&app_state, &logged_user.user_id, &post, &new_post).await;
+    search::index_post(&app_state, &new_post).await;
+
+    Ok(Redirect::to(&slug::article_path(&new_post)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(diff: &[DiffLine]) -> Vec<(&'static str, &str)> {
+        diff.iter()
+            .map(|line| (line.sign(), line.text.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn diff_lines_strips_the_line_endings() {
+        let diff = diff_lines("one\r\ntwo\r\n", "one\r\nthree\r\n");
+        assert_eq!(
+            lines(&diff),
+            vec![(" ", "one"), ("-", "two"), ("+", "three")]
+        );
+    }
+
+    #[test]
+    fn diff_lines_of_a_pure_insert() {
+        let diff = diff_lines("one\ntwo\n", "one\nnew\ntwo\n");
+        assert_eq!(lines(&diff), vec![(" ", "one"), ("+", "new"), (" ", "two")]);
+        assert_eq!(lines(&diff_lines("", "only\n")), vec![("+", "only")]);
+    }
+
+    #[test]
+    fn diff_lines_of_a_pure_delete() {
+        let diff = diff_lines("one\ngone\ntwo\n", "one\ntwo\n");
+        assert_eq!(
+            lines(&diff),
+            vec![(" ", "one"), ("-", "gone"), (" ", "two")]
+        );
+        assert_eq!(lines(&diff_lines("only\n", "")), vec![("-", "only")]);
+    }
+}
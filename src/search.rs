@@ -0,0 +1,857 @@
+use askama::Template;
+use axum::{
+    extract::{Query, State},
+    response::IntoResponse,
+    Extension,
+};
+use gutp_types::{GutpComment, GutpPost, GutpSubspace, GutpTag, GutpUser};
+use jieba_rs::Jieba;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tantivy::collector::{Count, TopDocs};
+use tantivy::directory::MmapDirectory;
+use tantivy::query::{BooleanQuery, Occur, QueryParser, TermQuery};
+use tantivy::schema::{
+    Field, IndexRecordOption, Schema, TextFieldIndexing, TextOptions, Value, FAST, STORED, STRING,
+};
+use tantivy::snippet::SnippetGenerator;
+use tantivy::tokenizer::{
+    LowerCaser, RemoveLongFilter, TextAnalyzer, Token, TokenStream, Tokenizer,
+};
+use tantivy::{
+    doc, Index, IndexReader, IndexWriter, ReloadPolicy, TantivyDocument, TantivyError, Term,
+};
+
+use crate::error::ForumError;
+use crate::filters;
+use crate::gutp::{GutpError, Paging};
+use crate::pagination::{Pager, PAGE_SIZE};
+use crate::permission;
+use crate::slug;
+use crate::AppState;
+use crate::HtmlTemplate;
+use crate::LoggedUser;
+
+// the name the text fields refer to their tokenizer by
+const TOKENIZER: &str = "jieba";
+const WRITER_MEMORY: usize = 50_000_000;
+const MAX_QUERY_LEN: usize = 200;
+const SNIPPET_LEN: usize = 200;
+// the window of the gutp lists when crawling them
+const CRAWL_PAGE_SIZE: usize = 100;
+
+const ARTICLE: &str = "article";
+const COMMENT: &str = "comment";
+
+#[derive(Debug)]
+pub enum SearchError {
+    Gutp(GutpError),
+    Index(TantivyError),
+    // opened by a command which doesn't write
+    ReadOnly,
+}
+
+impl fmt::Display for SearchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SearchError::Gutp(err) => write!(f, "{}", err),
+            SearchError::Index(err) => write!(f, "search index: {}", err),
+            SearchError::ReadOnly => write!(f, "search index: opened read only"),
+        }
+    }
+}
+
+impl std::error::Error for SearchError {}
+
+impl From<GutpError> for SearchError {
+    fn from(err: GutpError) -> Self {
+        SearchError::Gutp(err)
+    }
+}
+
+impl From<TantivyError> for SearchError {
+    fn from(err: TantivyError) -> Self {
+        SearchError::Index(err)
+    }
+}
+
+/// Splits the text into words with jieba, so the chinese runs, which have
+/// no spaces, are indexed word by word. The latin words come out as they are.
+#[derive(Clone)]
+struct JiebaTokenizer {
+    jieba: Arc<Jieba>,
+}
+
+struct JiebaTokenStream {
+    tokens: Vec<Token>,
+    index: usize,
+}
+
+impl TokenStream for JiebaTokenStream {
+    fn advance(&mut self) -> bool {
+        self.index += 1;
+        self.index <= self.tokens.len()
+    }
+
+    fn token(&self) -> &Token {
+        &self.tokens[self.index - 1]
+    }
+
+    fn token_mut(&mut self) -> &mut Token {
+        &mut self.tokens[self.index - 1]
+    }
+}
+
+impl Tokenizer for JiebaTokenizer {
+    type TokenStream<'a> = JiebaTokenStream;
+
+    fn token_stream<'a>(&'a mut self, text: &'a str) -> JiebaTokenStream {
+        let mut tokens = Vec::new();
+        // the search mode adds the shorter words inside the long ones,
+        // e.g. "中华人民共和国" is also found by "人民"
+        for word in self.jieba.cut_for_search(text, true) {
+            if !word.chars().any(char::is_alphanumeric) {
+                continue;
+            }
+            // the words are slices of the text, the highlighting needs their byte offsets
+            let offset_from = word.as_ptr() as usize - text.as_ptr() as usize;
+            tokens.push(Token {
+                offset_from,
+                offset_to: offset_from + word.len(),
+                position: tokens.len(),
+                text: word.to_string(),
+                position_length: 1,
+            });
+        }
+        JiebaTokenStream { tokens, index: 0 }
+    }
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30ff}' // kana
+        | '\u{3400}'..='\u{4dbf}'
+        | '\u{4e00}'..='\u{9fff}'
+        | '\u{f900}'..='\u{faff}'
+        | '\u{ac00}'..='\u{d7af}' // hangul
+    )
+}
+
+#[derive(Clone, Copy)]
+struct Fields {
+    // "article" or "comment"
+    kind: Field,
+    // the id of the article or of the comment
+    id: Field,
+    post_id: Field,
+    subspace_id: Field,
+    author_id: Field,
+    author_nickname: Field,
+    // the tags of the article, the comments inherit them
+    tag_id: Field,
+    // only the articles have a title to match
+    title: Field,
+    // shown with the comments
+    post_title: Field,
+    content: Field,
+    created_time: Field,
+}
+
+impl Fields {
+    fn schema() -> (Schema, Fields) {
+        let mut builder = Schema::builder();
+        let text = TextOptions::default()
+            .set_indexing_options(
+                TextFieldIndexing::default()
+                    .set_tokenizer(TOKENIZER)
+                    .set_index_option(IndexRecordOption::WithFreqsAndPositions),
+            )
+            .set_stored();
+        let fields = Fields {
+            kind: builder.add_text_field("kind", STRING | STORED),
+            id: builder.add_text_field("id", STRING | STORED),
+            post_id: builder.add_text_field("post_id", STRING | STORED),
+            subspace_id: builder.add_text_field("subspace_id", STRING | STORED),
+            author_id: builder.add_text_field("author_id", STRING | STORED),
+            author_nickname: builder.add_text_field("author_nickname", STORED),
+            tag_id: builder.add_text_field("tag_id", STRING),
+            title: builder.add_text_field("title", text.clone()),
+            post_title: builder.add_text_field("post_title", STORED),
+            content: builder.add_text_field("content", text),
+            created_time: builder.add_i64_field("created_time", STORED | FAST),
+        };
+        (builder.build(), fields)
+    }
+}
+
+/// An article or a comment matching the query
+#[derive(Debug, Clone)]
+pub struct SearchHit {
+    pub kind: String,
+    pub id: String,
+    pub post_id: String,
+    pub title: String,
+    /// the title with the matched words in `<b>`, escaped. Empty when it doesn't match
+    pub title_html: String,
+    /// the content around the matched words, the same way
+    pub snippet_html: String,
+    /// the head of the content, for the hits which only match by the title
+    pub summary: String,
+    pub author_id: String,
+    pub author_nickname: String,
+    pub created_time: i64,
+}
+
+impl SearchHit {
+    pub fn is_comment(&self) -> bool {
+        self.kind == COMMENT
+    }
+
+    pub fn url(&self) -> String {
+        let path = slug::article_title_path(&self.post_id, &self.title);
+        if self.is_comment() {
+            format!("{}#comment-{}", path, self.id)
+        } else {
+            path
+        }
+    }
+}
+
+/// The full-text index of the articles and the comments, kept on the local disk.
+///
+/// It's fed by the article and comment handlers. It only holds copies, so it
+/// can be rebuilt from gutp at any time with `rustcc_forum reindex`.
+pub struct SearchIndex {
+    index: Index,
+    reader: IndexReader,
+    // tantivy allows one writer per index, it also locks the directory
+    // against another process. None when opened read only
+    writer: Option<Mutex<IndexWriter>>,
+    jieba: Arc<Jieba>,
+    fields: Fields,
+}
+
+impl SearchIndex {
+    /// For the forum and `reindex`, which take the lock of the directory
+    pub fn open(dir: &str) -> Result<Self, SearchError> {
+        Self::open_with(dir, true)
+    }
+
+    /// For the other commands, they can run along with the forum
+    pub fn open_read_only(dir: &str) -> Result<Self, SearchError> {
+        Self::open_with(dir, false)
+    }
+
+    fn open_with(dir: &str, writable: bool) -> Result<Self, SearchError> {
+        std::fs::create_dir_all(dir).map_err(TantivyError::from)?;
+        let directory = MmapDirectory::open(dir).map_err(TantivyError::from)?;
+        let (schema, fields) = Fields::schema();
+        let index = Index::open_or_create(directory, schema)?;
+
+        let jieba = Arc::new(Jieba::new());
+        let analyzer = TextAnalyzer::builder(JiebaTokenizer {
+            jieba: jieba.clone(),
+        })
+        .filter(RemoveLongFilter::limit(40))
+        .filter(LowerCaser)
+        .build();
+        index.tokenizers().register(TOKENIZER, analyzer);
+
+        // reloaded after each commit, so the edits are found right away
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::Manual)
+            .try_into()?;
+        let writer = if writable {
+            Some(Mutex::new(index.writer(WRITER_MEMORY)?))
+        } else {
+            None
+        };
+
+        Ok(SearchIndex {
+            index,
+            reader,
+            writer,
+            jieba,
+            fields,
+        })
+    }
+
+    fn article_doc(&self, post: &GutpPost, tag_ids: &[String]) -> TantivyDocument {
+        let f = &self.fields;
+        let mut doc = doc!(
+            f.kind => ARTICLE,
+            f.id => post.id.as_str(),
+            f.post_id => post.id.as_str(),
+            f.subspace_id => post.subspace_id.as_str(),
+            f.author_id => post.author_id.as_str(),
+            f.author_nickname => post.author_nickname.as_str(),
+            f.title => post.title.as_str(),
+            f.post_title => post.title.as_str(),
+            f.content => post.content.as_str(),
+            f.created_time => post.created_time,
+        );
+        for tag_id in tag_ids {
+            doc.add_text(f.tag_id, tag_id);
+        }
+        doc
+    }
+
+    fn comment_doc(
+        &self,
+        post: &GutpPost,
+        comment: &GutpComment,
+        tag_ids: &[String],
+    ) -> TantivyDocument {
+        let f = &self.fields;
+        let mut doc = doc!(
+            f.kind => COMMENT,
+            f.id => comment.id.as_str(),
+            f.post_id => post.id.as_str(),
+            f.subspace_id => post.subspace_id.as_str(),
+            f.author_id => comment.author_id.as_str(),
+            f.author_nickname => comment.author_nickname.as_str(),
+            f.post_title => post.title.as_str(),
+            f.content => comment.content.as_str(),
+            f.created_time => comment.created_time,
+        );
+        for tag_id in tag_ids {
+            doc.add_text(f.tag_id, tag_id);
+        }
+        doc
+    }
+
+    /// The documents of an article and of its comments, the private ones are left out
+    fn post_docs(
+        &self,
+        post: &GutpPost,
+        comments: &[GutpComment],
+        tag_ids: &[String],
+    ) -> Vec<TantivyDocument> {
+        if !post.is_public {
+            return vec![];
+        }
+        let mut docs = vec![self.article_doc(post, tag_ids)];
+        docs.extend(
+            comments
+                .iter()
+                .filter(|comment| comment.is_public)
+                .map(|comment| self.comment_doc(post, comment, tag_ids)),
+        );
+        docs
+    }
+
+    /// Queue some changes, they are seen after `commit`
+    fn stage(
+        &self,
+        changes: impl FnOnce(&mut IndexWriter) -> tantivy::Result<()>,
+    ) -> Result<(), SearchError> {
+        let mut writer = self.writer()?;
+        Ok(changes(&mut writer)?)
+    }
+
+    fn writer(&self) -> Result<MutexGuard<'_, IndexWriter>, SearchError> {
+        let writer = self.writer.as_ref().ok_or(SearchError::ReadOnly)?;
+        Ok(writer.lock().unwrap_or_else(|err| err.into_inner()))
+    }
+
+    fn commit(&self) -> Result<(), SearchError> {
+        // a commit writes the segments to disk, keep it off the other tasks
+        tokio::task::block_in_place(|| {
+            let mut writer = self.writer()?;
+            writer.commit()?;
+            self.reader.reload()?;
+            Ok(())
+        })
+    }
+
+    fn write(
+        &self,
+        changes: impl FnOnce(&mut IndexWriter) -> tantivy::Result<()>,
+    ) -> Result<(), SearchError> {
+        self.stage(changes)?;
+        self.commit()
+    }
+
+    // without spaces the parser takes a chinese run as one phrase, which misses
+    // when the run is worded differently, so it's split into words first
+    fn split_cjk(&self, q: &str) -> String {
+        q.split_whitespace()
+            .map(|part| {
+                if part.chars().any(is_cjk) && !part.chars().any(|c| c.is_ascii_punctuation()) {
+                    self.jieba
+                        .cut(part, true)
+                        .into_iter()
+                        .filter(|word| word.chars().any(char::is_alphanumeric))
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                } else {
+                    part.to_string()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// The hits of a page, the best first, and how many there are in all.
+    /// The ones in `hidden_subspaces` are left out
+    pub fn search(
+        &self,
+        params: &SearchParams,
+        hidden_subspaces: &HashSet<String>,
+        offset: usize,
+        limit: usize,
+    ) -> Result<(Vec<SearchHit>, usize), SearchError> {
+        let f = &self.fields;
+        let mut parser = QueryParser::for_index(&self.index, vec![f.title, f.content]);
+        // all the words must match, like the other search engines
+        parser.set_conjunction_by_default();
+        parser.set_field_boost(f.title, 2.0);
+        // a stray quote or colon shouldn't be an error, the query is taken as best as it can
+        let (text_query, _) = parser.parse_query_lenient(&self.split_cjk(&params.q));
+
+        let mut clauses = vec![(Occur::Must, text_query.box_clone())];
+        for (field, value) in [
+            (f.subspace_id, &params.subspace),
+            (f.author_id, &params.author),
+            (f.tag_id, &params.tag),
+        ] {
+            if !value.is_empty() {
+                clauses.push((
+                    Occur::Must,
+                    Box::new(TermQuery::new(
+                        Term::from_field_text(field, value),
+                        IndexRecordOption::Basic,
+                    )),
+                ));
+            }
+        }
+        // in the query, so the total and the pages stay right
+        for subspace_id in hidden_subspaces {
+            clauses.push((
+                Occur::MustNot,
+                Box::new(TermQuery::new(
+                    Term::from_field_text(f.subspace_id, subspace_id),
+                    IndexRecordOption::Basic,
+                )),
+            ));
+        }
+        let query = BooleanQuery::new(clauses);
+
+        let searcher = self.reader.searcher();
+        let (top_docs, total) = searcher.search(
+            &query,
+            &(TopDocs::with_limit(limit).and_offset(offset), Count),
+        )?;
+
+        let mut title_snippets = SnippetGenerator::create(&searcher, &*text_query, f.title)?;
+        title_snippets.set_max_num_chars(SNIPPET_LEN);
+        let mut content_snippets = SnippetGenerator::create(&searcher, &*text_query, f.content)?;
+        content_snippets.set_max_num_chars(SNIPPET_LEN);
+
+        let mut hits = Vec::with_capacity(top_docs.len());
+        for (_score, address) in top_docs {
+            let doc: TantivyDocument = searcher.doc(address)?;
+            let text = |field: Field| {
+                doc.get_first(field)
+                    .and_then(|value| value.as_str())
+                    .unwrap_or_default()
+                    .to_string()
+            };
+            let kind = text(f.kind);
+            let title_html = if kind == ARTICLE {
+                title_snippets.snippet_from_doc(&doc).to_html()
+            } else {
+                String::new()
+            };
+            hits.push(SearchHit {
+                id: text(f.id),
+                post_id: text(f.post_id),
+                title: text(f.post_title),
+                title_html,
+                snippet_html: content_snippets.snippet_from_doc(&doc).to_html(),
+                summary: text(f.content).chars().take(SNIPPET_LEN).collect(),
+                author_id: text(f.author_id),
+                author_nickname: text(f.author_nickname),
+                created_time: doc
+                    .get_first(f.created_time)
+                    .and_then(|value| value.as_i64())
+                    .unwrap_or_default(),
+                kind,
+            });
+        }
+        Ok((hits, total))
+    }
+}
+
+async fn post_tag_ids(app_state: &AppState, post_id: &str) -> Result<Vec<String>, GutpError> {
+    let post_tags = app_state.gutp.post_tags().list_by_post(post_id).await?;
+    Ok(post_tags.into_iter().map(|pt| pt.tag_id).collect())
+}
+
+async fn all_comments(app_state: &AppState, post_id: &str) -> Result<Vec<GutpComment>, GutpError> {
+    let mut comments = Vec::new();
+    loop {
+        let page = app_state
+            .gutp
+            .comments()
+            .list_by_post(post_id, Paging::new(CRAWL_PAGE_SIZE, comments.len()))
+            .await?;
+        let is_last = page.len() < CRAWL_PAGE_SIZE;
+        comments.extend(page);
+        if is_last {
+            return Ok(comments);
+        }
+    }
+}
+
+async fn try_index_post(app_state: &AppState, post: &GutpPost) -> Result<(), SearchError> {
+    let tag_ids = post_tag_ids(app_state, &post.id).await?;
+    let comments = all_comments(app_state, &post.id).await?;
+    let search = &app_state.search;
+    let docs = search.post_docs(post, &comments, &tag_ids);
+    search.write(|writer| {
+        writer.delete_term(Term::from_field_text(search.fields.post_id, &post.id));
+        for doc in docs {
+            writer.add_document(doc)?;
+        }
+        Ok(())
+    })
+}
+
+async fn try_index_comment(
+    app_state: &AppState,
+    post: &GutpPost,
+    comment: &GutpComment,
+) -> Result<(), SearchError> {
+    let tag_ids = post_tag_ids(app_state, &post.id).await?;
+    let search = &app_state.search;
+    let doc =
+        (post.is_public && comment.is_public).then(|| search.comment_doc(post, comment, &tag_ids));
+    search.write(|writer| {
+        writer.delete_term(Term::from_field_text(search.fields.id, &comment.id));
+        if let Some(doc) = doc {
+            writer.add_document(doc)?;
+        }
+        Ok(())
+    })
+}
+
+// The index isn't the care factor of the handlers: a failed update is
+// logged, and fixed by the next edit or by a reindex.
+
+/// After an article is created or edited. Its comments are indexed again
+/// as well, they carry its title and tags.
+pub async fn index_post(app_state: &AppState, post: &GutpPost) {
+    if let Err(err) = try_index_post(app_state, post).await {
+        println!("in search index, article {}: {}", post.id, err);
+    }
+}
+
+/// After a comment is created or edited
+pub async fn index_comment(app_state: &AppState, post: &GutpPost, comment: &GutpComment) {
+    if let Err(err) = try_index_comment(app_state, post, comment).await {
+        println!("in search index, comment {}: {}", comment.id, err);
+    }
+}
+
+/// After an article is deleted, along with its comments
+pub fn remove_post(app_state: &AppState, post_id: &str) {
+    let search = &app_state.search;
+    let result = search.write(|writer| {
+        writer.delete_term(Term::from_field_text(search.fields.post_id, post_id));
+        Ok(())
+    });
+    if let Err(err) = result {
+        println!("in search index, article {}: {}", post_id, err);
+    }
+}
+
+/// After a comment is deleted
+pub fn remove_comment(app_state: &AppState, comment_id: &str) {
+    let search = &app_state.search;
+    let result = search.write(|writer| {
+        writer.delete_term(Term::from_field_text(search.fields.id, comment_id));
+        Ok(())
+    });
+    if let Err(err) = result {
+        println!("in search index, comment {}: {}", comment_id, err);
+    }
+}
+
+/// `rustcc_forum reindex`: rebuild the index from all the articles in gutp.
+///
+/// The old index is kept until the crawl is done. Returns the numbers of
+/// the articles and of the comments indexed.
+pub async fn reindex_command(app_state: &AppState) -> Result<(usize, usize), SearchError> {
+    let search = &app_state.search;
+    search.stage(|writer| writer.delete_all_documents().map(|_| ()))?;
+
+    let (mut articles, mut comments, mut offset) = (0, 0, 0);
+    loop {
+        let posts = app_state
+            .gutp
+            .posts()
+            .list(Paging::new(CRAWL_PAGE_SIZE, offset))
+            .await?;
+        offset += posts.len();
+
+        // gutp may serve other apps too
+        for post in posts
+            .iter()
+            .filter(|post| post.appid == app_state.config.appid)
+        {
+            let tag_ids = post_tag_ids(app_state, &post.id).await?;
+            let post_comments = all_comments(app_state, &post.id).await?;
+            let docs = search.post_docs(post, &post_comments, &tag_ids);
+            if !docs.is_empty() {
+                articles += 1;
+                comments += docs.len() - 1;
+            }
+            search.stage(|writer| {
+                for doc in docs {
+                    writer.add_document(doc)?;
+                }
+                Ok(())
+            })?;
+        }
+        println!("crawled {} articles", offset);
+
+        if posts.len() < CRAWL_PAGE_SIZE {
+            break;
+        }
+    }
+
+    search.commit()?;
+    Ok((articles, comments))
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct SearchParams {
+    #[serde(default)]
+    q: String,
+    /// the filters, by id
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    subspace: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    author: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    tag: String,
+    #[serde(skip_serializing)]
+    page: Option<usize>,
+}
+
+#[derive(Template)]
+#[template(path = "search_result.html")]
+struct SearchTemplate {
+    params: SearchParams,
+    // for the subspace filter
+    subspaces: Vec<GutpSubspace>,
+    // the author and the tag filtered by, if any
+    author: Option<GutpUser>,
+    tag: Option<GutpTag>,
+    searched: bool,
+    hits: Vec<SearchHit>,
+    total: usize,
+    pager: Pager,
+}
+
+pub async fn view_search(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    Query(mut params): Query<SearchParams>,
+) -> Result<impl IntoResponse, ForumError> {
+    params.q = params.q.trim().to_string();
+    let action = format!("Search: {}", params.q);
+    if params.q.chars().count() > MAX_QUERY_LEN {
+        return Err(ForumError::BadRequest(
+            action,
+            format!("The query must be at most {} characters.", MAX_QUERY_LEN),
+        ));
+    }
+
+    // the private subspaces are only searched by the ones who can see them
+    let hidden_subspaces =
+        permission::hidden_subspace_ids(&app_state, logged_user.as_deref()).await?;
+
+    // the filter captions aren't the care factor
+    let gutp = &app_state.gutp;
+    let subspaces = gutp
+        .subspaces()
+        .list()
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|sp| !hidden_subspaces.contains(&sp.id))
+        .collect();
+    let author = match params.author.as_str() {
+        "" => None,
+        id => gutp.users().by_id(id).await.ok().flatten(),
+    };
+    let tag = match params.tag.as_str() {
+        "" => None,
+        id => gutp.tags().by_id(id).await.ok().flatten(),
+    };
+
+    let base_url = format!(
+        "/search?{}",
+        serde_urlencoded::to_string(&params).unwrap_or_default()
+    );
+    let (page, offset) = Pager::page_offset(params.page, PAGE_SIZE);
+    let searched = !params.q.is_empty();
+    let (hits, total) = if searched {
+        app_state
+            .search
+            .search(&params, &hidden_subspaces, offset, PAGE_SIZE)
+            .map_err(|err| ForumError::Internal(action, err.to_string()))?
+    } else {
+        (vec![], 0)
+    };
+    let pager = Pager::from_total(page, total, PAGE_SIZE, base_url);
+
+    Ok(HtmlTemplate(SearchTemplate {
+        params,
+        subspaces,
+        author,
+        tag,
+        searched,
+        hits,
+        total,
+        pager,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a fresh index under the temp dir, removed when dropped
+    struct TestIndex {
+        dir: std::path::PathBuf,
+        search: SearchIndex,
+    }
+
+    impl TestIndex {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("forum-search-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            let search = SearchIndex::open(dir.to_str().unwrap()).unwrap();
+            TestIndex { dir, search }
+        }
+
+        fn add(&self, post: &GutpPost, comments: &[GutpComment]) {
+            let docs = self.search.post_docs(post, comments, &[]);
+            self.search
+                .write(|writer| {
+                    for doc in docs {
+                        writer.add_document(doc)?;
+                    }
+                    Ok(())
+                })
+                .unwrap();
+        }
+
+        fn search(&self, q: &str, hidden: &[&str]) -> Vec<SearchHit> {
+            let params = SearchParams {
+                q: q.to_string(),
+                ..Default::default()
+            };
+            let hidden = hidden.iter().map(|id| id.to_string()).collect();
+            let (hits, total) = self.search.search(&params, &hidden, 0, 10).unwrap();
+            assert_eq!(hits.len(), total);
+            hits
+        }
+    }
+
+    impl Drop for TestIndex {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn post(id: &str, subspace_id: &str, title: &str, content: &str) -> GutpPost {
+        GutpPost {
+            id: id.to_string(),
+            subspace_id: subspace_id.to_string(),
+            title: title.to_string(),
+            content: content.to_string(),
+            is_public: true,
+            ..Default::default()
+        }
+    }
+
+    fn ids(hits: &[SearchHit]) -> Vec<&str> {
+        hits.iter().map(|hit| hit.id.as_str()).collect()
+    }
+
+    #[test]
+    fn tokenizer_splits_chinese_into_words() {
+        let mut tokenizer = JiebaTokenizer {
+            jieba: Arc::new(Jieba::new()),
+        };
+        let text = "中华人民共和国, rust!";
+        let mut stream = tokenizer.token_stream(text);
+        let mut words = vec![];
+        while stream.advance() {
+            let token = stream.token();
+            // the offsets point at the word in the text
+            assert_eq!(&text[token.offset_from..token.offset_to], token.text);
+            words.push(token.text.to_owned());
+        }
+        assert!(words.contains(&"人民".to_string()));
+        assert!(words.contains(&"中华人民共和国".to_string()));
+        assert!(words.contains(&"rust".to_string()));
+        assert!(!words.iter().any(|word| word == "," || word == "!"));
+    }
+
+    #[test]
+    fn finds_chinese_by_a_word_inside() {
+        let index = TestIndex::new("words");
+        index.add(
+            &post("1", "s", "中华人民共和国成立", "这是一个中文社区的文章"),
+            &[],
+        );
+        index.add(&post("2", "s", "Rust 入门", "所有权和借用"), &[]);
+
+        assert_eq!(ids(&index.search("人民", &[])), vec!["1"]);
+        // the words of a run are all required, in any order
+        assert_eq!(ids(&index.search("社区文章", &[])), vec!["1"]);
+        assert!(index.search("社区借用", &[]).is_empty());
+        assert_eq!(ids(&index.search("借用", &[])), vec!["2"]);
+    }
+
+    #[test]
+    fn highlights_the_matched_words() {
+        let index = TestIndex::new("highlight");
+        index.add(&post("1", "s", "中文社区", "欢迎来到中文社区"), &[]);
+
+        let hits = index.search("社区", &[]);
+        assert_eq!(hits.len(), 1);
+        assert!(hits[0].title_html.contains("<b>社区</b>"));
+        assert!(hits[0].snippet_html.contains("<b>社区</b>"));
+    }
+
+    #[test]
+    fn leaves_out_the_private_and_the_hidden() {
+        let index = TestIndex::new("hidden");
+        let mut private = post("1", "s", "私有文章", "内容");
+        private.is_public = false;
+        index.add(&private, &[]);
+        index.add(&post("2", "open", "公开文章", "内容"), &[]);
+        index.add(&post("3", "closed", "隐藏文章", "内容"), &[]);
+
+        assert_eq!(ids(&index.search("内容", &["closed"])), vec!["2"]);
+    }
+
+    #[test]
+    fn read_only_index_doesnt_write() {
+        let index = TestIndex::new("read-only");
+        let dir = index.dir.to_str().unwrap();
+        let read_only = SearchIndex::open_read_only(dir).unwrap();
+        let result = read_only.write(|_| Ok(()));
+        assert!(matches!(result, Err(SearchError::ReadOnly)));
+    }
+}
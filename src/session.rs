@@ -0,0 +1,461 @@
+use axum_extra::extract::cookie::Cookie;
+use redis::{aio::ConnectionLike, AsyncCommands, RedisResult};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+use crate::csrf;
+
+// last_seen is only written back (and the ttl slid) once per this interval
+const SLIDE_INTERVAL: i64 = 3600;
+
+/// A logged in device of a user
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub id: String,
+    /// Names the session on the devices list, the id itself is the cookie
+    pub handle: String,
+    pub user_id: String,
+    pub csrf_token: String,
+    pub created_at: i64,
+    pub last_seen: i64,
+    pub user_agent: String,
+}
+
+/// The session store in redis.
+///
+/// Keys:
+/// - `{appid}_sid:{session_id}`, a hash with the session fields
+/// - `{appid}_user_sids:{user_id}`, a hash of the handles of this user's
+///   sessions to their ids
+pub struct SessionStore {
+    prefix: String,
+    ttl: usize,
+}
+
+impl SessionStore {
+    pub fn new(appid: &str, ttl: usize) -> Self {
+        SessionStore {
+            prefix: appid.to_string(),
+            ttl,
+        }
+    }
+
+    /// The name of the session cookie
+    pub fn cookie_name(&self) -> String {
+        format!("{}_sid", self.prefix)
+    }
+
+    fn session_key(&self, session_id: &str) -> String {
+        format!("{}_sid:{}", self.prefix, session_id)
+    }
+
+    fn user_index_key(&self, user_id: &str) -> String {
+        format!("{}_user_sids:{}", self.prefix, user_id)
+    }
+
+    /// The handle of a session, so it can be revoked without knowing its id
+    pub fn handle_of(session_id: &str) -> String {
+        sha256::digest(session_id).to_lowercase()
+    }
+
+    pub fn cookie(&self, session_id: &str) -> Cookie<'static> {
+        Cookie::build(self.cookie_name(), session_id.to_string())
+            // .domain("/")
+            .path("/")
+            //.secure(true)
+            .max_age(cookie::time::Duration::seconds(self.ttl as i64))
+            .http_only(true)
+            .finish()
+    }
+
+    /// The cookie must be removed with the same path it was set with
+    pub fn removal_cookie(&self) -> Cookie<'static> {
+        let mut cookie = Cookie::named(self.cookie_name());
+        cookie.set_path("/");
+        cookie
+    }
+
+    /// Create a new session for the user, with a fresh id and csrf token
+    pub async fn create<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        user_id: &str,
+        user_agent: &str,
+    ) -> RedisResult<Session> {
+        let x = rand::random::<[u8; 32]>();
+        let session_id = sha256::digest(&x).to_lowercase();
+        let now = chrono::Utc::now().timestamp();
+        let session = Session {
+            handle: Self::handle_of(&session_id),
+            id: session_id,
+            user_id: user_id.to_string(),
+            csrf_token: csrf::new_token(),
+            created_at: now,
+            last_seen: now,
+            user_agent: user_agent.to_string(),
+        };
+
+        let key = self.session_key(&session.id);
+        let index_key = self.user_index_key(user_id);
+        redis::pipe()
+            .atomic()
+            .hset_multiple(
+                &key,
+                &[
+                    ("user_id", session.user_id.to_owned()),
+                    ("csrf_token", session.csrf_token.to_owned()),
+                    ("created_at", session.created_at.to_string()),
+                    ("last_seen", session.last_seen.to_string()),
+                    ("user_agent", session.user_agent.to_owned()),
+                ],
+            )
+            .ignore()
+            .expire(&key, self.ttl)
+            .ignore()
+            .hset(&index_key, &session.handle, &session.id)
+            .ignore()
+            .expire(&index_key, self.ttl)
+            .ignore()
+            .query_async::<_, ()>(conn)
+            .await?;
+
+        Ok(session)
+    }
+
+    /// Sign in with a new session id, the one from before the login (if any)
+    /// is dropped, so a planted id doesn't become a logged in one
+    pub async fn rotate<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        old_session_id: Option<&str>,
+        user_id: &str,
+        user_agent: &str,
+    ) -> RedisResult<Session> {
+        if let Some(old_session_id) = old_session_id {
+            // the old one may be gone already, it doesn't block the login
+            let _ = self.destroy(conn, old_session_id).await;
+        }
+        self.create(conn, user_id, user_agent).await
+    }
+
+    async fn get<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        session_id: &str,
+    ) -> RedisResult<Option<Session>> {
+        let key = self.session_key(session_id);
+        let fields: HashMap<String, String> = match conn.hgetall(&key).await {
+            Ok(fields) => fields,
+            // the sessions from before the rework are plain strings, they're dropped
+            Err(err) if err.code() == Some("WRONGTYPE") => {
+                let _: () = conn.del(&key).await?;
+                return Ok(None);
+            }
+            Err(err) => return Err(err),
+        };
+        let Some(user_id) = fields.get("user_id") else {
+            return Ok(None);
+        };
+        let field = |name: &str| fields.get(name).cloned().unwrap_or_default();
+        let timestamp = |name: &str| field(name).parse::<i64>().unwrap_or_default();
+
+        Ok(Some(Session {
+            id: session_id.to_string(),
+            handle: Self::handle_of(session_id),
+            user_id: user_id.to_owned(),
+            csrf_token: field("csrf_token"),
+            created_at: timestamp("created_at"),
+            last_seen: timestamp("last_seen"),
+            user_agent: field("user_agent"),
+        }))
+    }
+
+    /// Load the session behind a cookie, and slide its expiration.
+    ///
+    /// The returned bool tells whether the ttl was slid, so the cookie
+    /// should be sent again.
+    pub async fn load<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        session_id: &str,
+    ) -> RedisResult<Option<(Session, bool)>> {
+        let Some(mut session) = self.get(conn, session_id).await? else {
+            return Ok(None);
+        };
+
+        let now = chrono::Utc::now().timestamp();
+        if now - session.last_seen < SLIDE_INTERVAL {
+            return Ok(Some((session, false)));
+        }
+
+        session.last_seen = now;
+        let key = self.session_key(session_id);
+        let index_key = self.user_index_key(&session.user_id);
+        redis::pipe()
+            .hset(&key, "last_seen", now)
+            .ignore()
+            .expire(&key, self.ttl)
+            .ignore()
+            .expire(&index_key, self.ttl)
+            .ignore()
+            .query_async::<_, ()>(conn)
+            .await?;
+
+        Ok(Some((session, true)))
+    }
+
+    pub async fn destroy<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        session_id: &str,
+    ) -> RedisResult<()> {
+        if let Some(session) = self.get(conn, session_id).await? {
+            let _: () = conn
+                .hdel(self.user_index_key(&session.user_id), &session.handle)
+                .await?;
+        }
+        conn.del(self.session_key(session_id)).await
+    }
+
+    /// All the live sessions of a user, the expired ids are pruned from the index
+    pub async fn list_by_user<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        user_id: &str,
+    ) -> RedisResult<Vec<Session>> {
+        let index_key = self.user_index_key(user_id);
+        let session_ids: HashMap<String, String> = conn.hgetall(&index_key).await?;
+
+        let mut sessions = vec![];
+        for (handle, session_id) in session_ids {
+            match self.get(conn, &session_id).await? {
+                Some(session) if session.user_id == user_id => sessions.push(session),
+                _ => {
+                    let _: () = conn.hdel(&index_key, &handle).await?;
+                }
+            }
+        }
+        sessions.sort_by_key(|session| Reverse(session.last_seen));
+
+        Ok(sessions)
+    }
+
+    /// Revoke one session of this user by its handle, returns false if it
+    /// isn't theirs
+    pub async fn revoke<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        user_id: &str,
+        handle: &str,
+    ) -> RedisResult<bool> {
+        let session_id: Option<String> = conn.hget(self.user_index_key(user_id), handle).await?;
+        match session_id {
+            Some(session_id) => {
+                self.destroy(conn, &session_id).await?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Sign out everywhere
+    pub async fn destroy_all<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        user_id: &str,
+    ) -> RedisResult<()> {
+        let index_key = self.user_index_key(user_id);
+        let session_ids: Vec<String> = conn.hvals(&index_key).await?;
+
+        let mut pipe = redis::pipe();
+        for session_id in &session_ids {
+            pipe.del(self.session_key(session_id)).ignore();
+        }
+        pipe.del(&index_key).ignore();
+        pipe.query_async(conn).await
+    }
+
+    /// Sign out the other devices, e.g. after a password change
+    pub async fn destroy_others<C: ConnectionLike + Send>(
+        &self,
+        conn: &mut C,
+        user_id: &str,
+        keep_session_id: &str,
+    ) -> RedisResult<()> {
+        let index_key = self.user_index_key(user_id);
+        let session_ids: HashMap<String, String> = conn.hgetall(&index_key).await?;
+
+        let mut pipe = redis::pipe();
+        for (handle, session_id) in session_ids.iter().filter(|(_, id)| *id != keep_session_id) {
+            pipe.del(self.session_key(session_id))
+                .ignore()
+                .hdel(&index_key, handle)
+                .ignore();
+        }
+        pipe.query_async(conn).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_redis::FakeRedis;
+
+    const TTL: usize = 7 * 24 * 3600;
+
+    fn store() -> SessionStore {
+        SessionStore::new("test", TTL)
+    }
+
+    #[tokio::test]
+    async fn create_and_load() {
+        let (store, mut redis) = (store(), FakeRedis::default());
+        let session = store.create(&mut redis, "alice", "firefox").await.unwrap();
+        assert_eq!(redis.ttl(&store.session_key(&session.id)), Some(TTL as i64));
+        assert_eq!(redis.ttl(&store.user_index_key("alice")), Some(TTL as i64));
+
+        let (loaded, slid) = store.load(&mut redis, &session.id).await.unwrap().unwrap();
+        assert_eq!(loaded.user_id, "alice");
+        assert_eq!(loaded.csrf_token, session.csrf_token);
+        assert_eq!(loaded.user_agent, "firefox");
+        // just created, nothing to slide yet
+        assert!(!slid);
+
+        assert!(store.load(&mut redis, "unknown").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn rotate_drops_the_old_id() {
+        let (store, mut redis) = (store(), FakeRedis::default());
+        let old = store.create(&mut redis, "alice", "").await.unwrap();
+
+        let new = store
+            .rotate(&mut redis, Some(&old.id), "alice", "")
+            .await
+            .unwrap();
+        assert_ne!(new.id, old.id);
+        assert_ne!(new.csrf_token, old.csrf_token);
+        assert!(store.load(&mut redis, &old.id).await.unwrap().is_none());
+        assert!(store.load(&mut redis, &new.id).await.unwrap().is_some());
+        let sessions = store.list_by_user(&mut redis, "alice").await.unwrap();
+        assert_eq!(sessions.len(), 1);
+
+        // an id unknown to the store doesn't block the login
+        let session = store
+            .rotate(&mut redis, Some("planted"), "bob", "")
+            .await
+            .unwrap();
+        assert!(store.load(&mut redis, &session.id).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn ttl_slides_once_an_interval() {
+        let (store, mut redis) = (store(), FakeRedis::default());
+        let session = store.create(&mut redis, "alice", "").await.unwrap();
+        let key = store.session_key(&session.id);
+        let index_key = store.user_index_key("alice");
+
+        // seen long ago, with little ttl left
+        let long_ago = chrono::Utc::now().timestamp() - SLIDE_INTERVAL - 1;
+        redis.hset(&key, "last_seen", &long_ago.to_string());
+        let _: () = redis::pipe()
+            .expire(&key, 60)
+            .expire(&index_key, 60)
+            .query_async(&mut redis)
+            .await
+            .unwrap();
+
+        let (loaded, slid) = store.load(&mut redis, &session.id).await.unwrap().unwrap();
+        assert!(slid);
+        assert!(loaded.last_seen > long_ago);
+        assert_eq!(redis.ttl(&key), Some(TTL as i64));
+        assert_eq!(redis.ttl(&index_key), Some(TTL as i64));
+
+        // and not again within the interval
+        let (_, slid) = store.load(&mut redis, &session.id).await.unwrap().unwrap();
+        assert!(!slid);
+    }
+
+    #[tokio::test]
+    async fn user_index_is_cleaned() {
+        let (store, mut redis) = (store(), FakeRedis::default());
+        let first = store.create(&mut redis, "alice", "").await.unwrap();
+        let second = store.create(&mut redis, "alice", "").await.unwrap();
+        let third = store.create(&mut redis, "alice", "").await.unwrap();
+        let index_key = store.user_index_key("alice");
+
+        // a signed out session leaves the index
+        store.destroy(&mut redis, &first.id).await.unwrap();
+        let ids: Vec<String> = redis.hvals(&index_key).await.unwrap();
+        assert!(!ids.contains(&first.id));
+
+        // an expired one is pruned when listed
+        redis.expire_now(&store.session_key(&second.id));
+        let sessions = store.list_by_user(&mut redis, "alice").await.unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].id, third.id);
+        let ids: Vec<String> = redis.hvals(&index_key).await.unwrap();
+        assert_eq!(ids, vec![third.id.clone()]);
+
+        // nor can another user revoke it
+        assert!(!store
+            .revoke(&mut redis, "bob", &third.handle)
+            .await
+            .unwrap());
+        assert!(store
+            .revoke(&mut redis, "alice", &third.handle)
+            .await
+            .unwrap());
+        assert!(!redis.exists(&index_key));
+    }
+
+    #[tokio::test]
+    async fn revoke_by_handle_only() {
+        let (store, mut redis) = (store(), FakeRedis::default());
+        let session = store.create(&mut redis, "alice", "").await.unwrap();
+        assert_ne!(session.handle, session.id);
+        let sessions = store.list_by_user(&mut redis, "alice").await.unwrap();
+        assert_eq!(sessions[0].handle, session.handle);
+
+        // the id itself doesn't revoke it, only its handle does
+        assert!(!store
+            .revoke(&mut redis, "alice", &session.id)
+            .await
+            .unwrap());
+        assert!(store.load(&mut redis, &session.id).await.unwrap().is_some());
+        assert!(store
+            .revoke(&mut redis, "alice", &session.handle)
+            .await
+            .unwrap());
+        assert!(store.load(&mut redis, &session.id).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn sign_out_the_others_and_everywhere() {
+        let (store, mut redis) = (store(), FakeRedis::default());
+        let kept = store.create(&mut redis, "alice", "").await.unwrap();
+        let other = store.create(&mut redis, "alice", "").await.unwrap();
+
+        store
+            .destroy_others(&mut redis, "alice", &kept.id)
+            .await
+            .unwrap();
+        assert!(store.load(&mut redis, &other.id).await.unwrap().is_none());
+        let sessions = store.list_by_user(&mut redis, "alice").await.unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].id, kept.id);
+
+        store.destroy_all(&mut redis, "alice").await.unwrap();
+        assert!(store.load(&mut redis, &kept.id).await.unwrap().is_none());
+        assert!(!redis.exists(&store.user_index_key("alice")));
+    }
+
+    #[tokio::test]
+    async fn old_plain_sessions_are_dropped() {
+        let (store, mut redis) = (store(), FakeRedis::default());
+        let key = store.session_key("old");
+        redis.set(&key, "alice");
+        assert!(store.load(&mut redis, "old").await.unwrap().is_none());
+        assert!(!redis.exists(&key));
+    }
+}
@@ -0,0 +1,229 @@
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use gutp_types::{GutpPost, GutpSubspace};
+
+use crate::error::ForumError;
+use crate::AppState;
+
+pub const MAX_SLUG_LEN: usize = 64;
+
+/// A readable url part from any text, the non latin scripts are
+/// transliterated, e.g. the chinese to pinyin: "中文社区" -> "zhong-wen-she-qu"
+pub fn slugify(text: &str) -> String {
+    let ascii = deunicode::deunicode(text).to_lowercase();
+    let mut slug = String::new();
+    for c in ascii.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    // cut at a word boundary when it's too long
+    if slug.len() > MAX_SLUG_LEN {
+        slug.truncate(MAX_SLUG_LEN);
+        if let Some(end) = slug.rfind('-') {
+            slug.truncate(end);
+        }
+    }
+    slug.trim_end_matches('-').to_string()
+}
+
+// `{base}-{n}`, the base is cut so it stays a valid slug
+fn numbered(base: &str, n: usize) -> String {
+    let suffix = format!("-{}", n);
+    let base = &base[..base.len().min(MAX_SLUG_LEN - suffix.len())];
+    format!("{}{}", base.trim_end_matches('-'), suffix)
+}
+
+pub fn is_valid(slug: &str) -> bool {
+    !slug.is_empty()
+        && slug.len() <= MAX_SLUG_LEN
+        && slug
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+/// `/s/{slug}`, or by id for the subspaces without one
+pub fn subspace_path(sp: &GutpSubspace) -> String {
+    if sp.slug.is_empty() {
+        subspace_id_path(&sp.id)
+    } else {
+        format!("/s/{}", sp.slug)
+    }
+}
+
+/// When only the id is at hand, it's redirected to the slug
+pub fn subspace_id_path(id: &str) -> String {
+    format!("/s/{}", id)
+}
+
+/// `/a/{id}/{title-slug}`, the slug is only for the readers, the id is the key
+pub fn article_path(post: &GutpPost) -> String {
+    article_title_path(&post.id, &post.title)
+}
+
+/// `article_path` when only the id and the title are at hand
+pub fn article_title_path(id: &str, title: &str) -> String {
+    let slug = slugify(title);
+    if slug.is_empty() {
+        article_id_path(id)
+    } else {
+        format!("/a/{}/{}", id, slug)
+    }
+}
+
+/// When only the id is at hand, it's redirected to the title slug
+pub fn article_id_path(id: &str) -> String {
+    format!("/a/{}", id)
+}
+
+/// A 301, the old and the non canonical urls are moved for good
+pub fn moved_permanently(path: &str, query: Option<&str>) -> Response {
+    let location = match query.filter(|query| !query.is_empty()) {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    };
+    (
+        StatusCode::MOVED_PERMANENTLY,
+        [(header::LOCATION, location)],
+    )
+        .into_response()
+}
+
+/// A query string without the param `name`, for the redirects of the old urls
+pub fn drop_query_param(query: &str, name: &str) -> String {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty() && pair.split('=').next() != Some(name))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// The slug to save for a subspace: the one asked, or one made from the title.
+///
+/// An asked slug must be free, a generated one gets a number when it's taken.
+pub async fn subspace_slug(
+    app_state: &AppState,
+    action: &str,
+    subspace_id: &str,
+    asked: &str,
+    title: &str,
+) -> Result<String, ForumError> {
+    let asked = asked.trim().to_lowercase();
+    if !asked.is_empty() && !is_valid(&asked) {
+        return Err(ForumError::BadRequest(
+            action.to_string(),
+            format!(
+                "The slug must be at most {} letters, digits or dashes.",
+                MAX_SLUG_LEN
+            ),
+        ));
+    }
+
+    let subspaces = app_state
+        .gutp
+        .subspaces()
+        .list()
+        .await
+        .map_err(ForumError::gutp(action))?;
+    let is_taken = |slug: &str| {
+        subspaces
+            .iter()
+            .any(|sp| sp.id != subspace_id && (sp.slug == slug || sp.id == slug))
+    };
+
+    if !asked.is_empty() {
+        if is_taken(&asked) {
+            return Err(ForumError::conflict(
+                action,
+                "This slug is used by another subspace.",
+            ));
+        }
+        return Ok(asked);
+    }
+
+    let base = slugify(title);
+    if base.is_empty() {
+        // nothing readable in the title, the subspace is reached by id
+        return Ok(base);
+    }
+    let mut slug = base.to_owned();
+    let mut n = 2;
+    while is_taken(&slug) {
+        slug = numbered(&base, n);
+        n += 1;
+    }
+    Ok(slug)
+}
+
+/// The subspace behind `/s/{key}`, the key is its slug or its id
+pub async fn find_subspace(
+    app_state: &AppState,
+    key: &str,
+) -> Result<Option<GutpSubspace>, ForumError> {
+    let action = format!("Query subspace: {}", key);
+    let gutp = &app_state.gutp;
+    let subspaces = gutp
+        .subspaces()
+        .list()
+        .await
+        .map_err(ForumError::gutp(&action))?;
+    if let Some(sp) = subspaces.into_iter().find(|sp| sp.slug == key) {
+        return Ok(Some(sp));
+    }
+    gutp.subspaces()
+        .by_id(key)
+        .await
+        .map_err(ForumError::gutp(&action))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slugify_transliterates() {
+        assert_eq!(slugify("中文社区"), "zhong-wen-she-qu");
+        assert_eq!(slugify("Café Rust"), "cafe-rust");
+    }
+
+    #[test]
+    fn slugify_collapses_the_punctuation() {
+        assert_eq!(slugify("  Hello, World!! -- Rust  "), "hello-world-rust");
+        assert_eq!(slugify("a/b\\c?d"), "a-b-c-d");
+        assert_eq!(slugify("!!!"), "");
+    }
+
+    #[test]
+    fn slugify_cuts_at_a_word_boundary() {
+        let title = "word ".repeat(20);
+        let slug = slugify(&title);
+        assert!(slug.len() <= MAX_SLUG_LEN);
+        assert!(is_valid(&slug));
+        assert!(slug.ends_with("word"));
+
+        // one long word is cut where it must
+        assert_eq!(slugify(&"x".repeat(100)), "x".repeat(MAX_SLUG_LEN));
+    }
+
+    #[test]
+    fn numbered_stays_valid() {
+        assert_eq!(numbered("rust", 2), "rust-2");
+
+        let base = "a".repeat(MAX_SLUG_LEN);
+        let slug = numbered(&base, 12);
+        assert_eq!(slug.len(), MAX_SLUG_LEN);
+        assert!(slug.ends_with("-12"));
+        assert!(is_valid(&slug));
+
+        // no double dash when the cut falls after one
+        let base = format!("{}-{}", "a".repeat(MAX_SLUG_LEN - 3), "bc");
+        assert_eq!(
+            numbered(&base, 2),
+            format!("{}-2", "a".repeat(MAX_SLUG_LEN - 3))
+        );
+    }
+}
@@ -1,200 +1,417 @@
 use askama::Template;
 use axum::{
-    extract::{Form, Query, RawQuery, State},
-    response::{Html, IntoResponse, Redirect},
+    extract::{Form, Multipart, Path, Query, RawQuery, State},
+    response::{Html, IntoResponse, Redirect, Response},
     Extension,
 };
-use gutp_types::{GutpComment, GutpPost, GutpSubspace};
+use gutp_types::{GutpComment, GutpPost, GutpSubspace, GutpTag};
 use serde::{Deserialize, Serialize};
 
+use crate::error::ForumError;
 use crate::filters;
-use crate::redirect_to_error_page;
+use crate::gutp::{NewSubspace, Paging, SubspaceUpdate};
+use crate::pagination::{Pager, PAGE_SIZE};
+use crate::permission::{self, Action, Permission};
+use crate::slug;
+use crate::tag;
+use crate::upload;
+use crate::AppState;
 use crate::HtmlTemplate;
-use crate::LoggedUser;
-use crate::{make_get, make_post};
+use crate::{require_login, LoggedUser};
+
+const MAX_TITLE_LEN: usize = 64;
+const MAX_DESCRIPTION_LEN: usize = 500;
+const MAX_BANNER_SIZE: usize = 1536 * 1024;
+
+// the upload dir of the banners
+const BANNERS: &str = "banners";
 
 #[derive(Template)]
 #[template(path = "subspace.html")]
 struct SubspaceTemplate {
     subspace: GutpSubspace,
     posts: Vec<GutpPost>,
+    pager: Pager,
+    tags: Vec<GutpTag>,
+    permission: Permission,
 }
 
 #[derive(Deserialize)]
 pub struct ViewSubspaceParams {
-    id: String,
+    page: Option<usize>,
 }
 
+/// `/s/{key}`, the key is the slug, or the id of the subspaces without one
 pub async fn view_subspace(
+    State(app_state): State<AppState>,
     logged_user: Option<Extension<LoggedUser>>,
+    Path(key): Path<String>,
     Query(params): Query<ViewSubspaceParams>,
-) -> impl IntoResponse {
-    let inner_params = [("id", &params.id)];
-    let subspaces: Vec<GutpSubspace> = make_get("/v1/subspace", &inner_params)
-        .await
-        .unwrap_or(vec![]);
-    if let Some(sp) = subspaces.into_iter().next() {
-        let inner_params = [("subspace_id", &sp.id)];
-        let posts: Vec<GutpPost> = make_get("/v1/post/list_by_subspace", &inner_params)
-            .await
-            .unwrap_or(vec![]);
-        HtmlTemplate(SubspaceTemplate {
-            subspace: sp,
-            posts,
-        })
-        .into_response()
-    } else {
-        // redirect to the error page
-        let action = format!("Query subspace: {}", params.id);
-        let err_info = "No this subspace.";
-        redirect_to_error_page(&action, err_info).into_response()
+    RawQuery(query): RawQuery,
+) -> Result<Response, ForumError> {
+    let gutp = &app_state.gutp;
+    let action = format!("Query subspace: {}", key);
+    let sp = slug::find_subspace(&app_state, &key)
+        .await?
+        .ok_or_else(|| ForumError::not_found(&action, "No this subspace."))?;
+    let permission = Permission::in_subspace(&app_state, logged_user.as_deref(), &sp).await?;
+    if !permission.can_view(&sp) {
+        return Err(ForumError::not_found(&action, "No this subspace."));
+    }
+
+    // reached by id, or by a slug since changed
+    let path = slug::subspace_path(&sp);
+    if path != slug::subspace_id_path(&key) {
+        return Ok(slug::moved_permanently(&path, query.as_deref()));
     }
+
+    // one more than a page, to know whether there is a next one
+    let (page, offset) = Pager::page_offset(params.page, PAGE_SIZE);
+    let posts = gutp
+        .posts()
+        .list_by_subspace(&sp.id, Paging::new(PAGE_SIZE + 1, offset))
+        .await
+        .map_err(ForumError::gutp(format!(
+            "Query articles of subspace: {}",
+            sp.id
+        )))?;
+    let (posts, pager) = Pager::from_window(posts, page, PAGE_SIZE, path);
+
+    let tags = tag::subspace_tags(&app_state, &sp.id).await?;
+
+    Ok(HtmlTemplate(SubspaceTemplate {
+        subspace: sp,
+        posts,
+        pager,
+        tags,
+        permission,
+    })
+    .into_response())
+}
+
+#[derive(Deserialize)]
+pub struct ViewSubspaceByIdParams {
+    id: String,
+}
+
+/// The old `/subspace?id=` links, moved to `/s/{slug}`
+pub async fn redirect_subspace(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    Query(params): Query<ViewSubspaceByIdParams>,
+    RawQuery(query): RawQuery,
+) -> Result<Response, ForumError> {
+    let action = format!("Query subspace: {}", params.id);
+    let sp = app_state
+        .gutp
+        .subspaces()
+        .by_id(&params.id)
+        .await
+        .map_err(ForumError::gutp(&action))?
+        .ok_or_else(|| ForumError::not_found(&action, "No this subspace."))?;
+    permission::ensure_view_subspace(&app_state, logged_user.as_deref(), &sp).await?;
+
+    // the other params, e.g. the page, are kept
+    let rest = slug::drop_query_param(query.as_deref().unwrap_or_default(), "id");
+    Ok(slug::moved_permanently(
+        &slug::subspace_path(&sp),
+        Some(&rest),
+    ))
 }
 
 #[derive(Template)]
 #[template(path = "subspace_create.html")]
-struct SubspaceCreateTemplate {}
-
-#[derive(Deserialize)]
-pub struct ViewSubspaceCreateParams {}
+struct SubspaceCreateTemplate {
+    csrf_token: String,
+}
 
 pub async fn view_subspace_create(
+    State(app_state): State<AppState>,
     logged_user: Option<Extension<LoggedUser>>,
-    Query(params): Query<ViewSubspaceCreateParams>,
-) -> impl IntoResponse {
+) -> Result<impl IntoResponse, ForumError> {
     // check the user login status
-    // TODO: who can create a new subspace?
     // For forum case, only admin has the permission to create a new subspace
-    // for blog case, every on can create their own blog subspace
-    if logged_user.is_none() {
-        let action = format!("Not logged in");
-        let err_info = "Need login firstly to get proper permission.";
-        return redirect_to_error_page(&action, err_info).into_response();
-    }
+    let logged_user = require_login(logged_user)?;
+    Permission::site(&app_state, Some(&logged_user)).ensure(
+        Action::CreateSubspace,
+        "",
+        "Create subspace".to_string(),
+    )?;
 
-    HtmlTemplate(SubspaceCreateTemplate {}).into_response()
+    Ok(HtmlTemplate(SubspaceCreateTemplate {
+        csrf_token: logged_user.csrf_token,
+    }))
 }
 
 #[derive(Deserialize)]
 pub struct PostSubspaceCreateParams {
     title: String,
     description: String,
+    // made from the title when empty
+    #[serde(default)]
+    slug: String,
 }
 
 pub async fn post_subspace_create(
+    State(app_state): State<AppState>,
     logged_user: Option<Extension<LoggedUser>>,
     Form(params): Form<PostSubspaceCreateParams>,
-) -> impl IntoResponse {
+) -> Result<Redirect, ForumError> {
     // check the user login status
-    if logged_user.is_none() {
-        let action = format!("Not logged in");
-        let err_info = "Need login firstly to get proper permission.";
-        return redirect_to_error_page(&action, err_info);
-    }
-    let Extension(LoggedUser { user_id }) = logged_user.unwrap();
-
-    #[derive(Serialize)]
-    struct InnerSubspaceCreateParams {
-        title: String,
-        description: String,
-        banner: String,
-        owner_id: String,
-        profession: String,
-        appid: String,
-        is_public: bool,
-        slug: String,
-    }
+    let logged_user = require_login(logged_user)?;
 
-    let inner_params = InnerSubspaceCreateParams {
+    let input = SubspaceInput {
         title: params.title,
         description: params.description,
-        banner: "".to_string(),
-        owner_id: user_id,
-        profession: crate::APPPROFESSION.to_string(),
-        appid: crate::APPID.to_string(),
+        slug: params.slug,
         is_public: true,
-        slug: "".to_string(),
     };
+    let sp = create_subspace(&app_state, &logged_user, input).await?;
 
-    let subspaces: Vec<GutpSubspace> = make_post("/v1/subspace/create", &inner_params)
-        .await
-        .unwrap_or(vec![]);
-    if let Some(sp) = subspaces.into_iter().next() {
-        let redirect_uri = format!("/subspace?id={}", sp.id);
-        Redirect::to(&redirect_uri)
-    } else {
-        // redirect to the error page
-        let action = format!("Create subspace");
-        let err_info = "Unknown";
-        redirect_to_error_page(&action, err_info)
+    Ok(Redirect::to(&slug::subspace_path(&sp)))
+}
+
+/// What the subspace forms and the api send
+pub struct SubspaceInput {
+    pub title: String,
+    pub description: String,
+    // made from the title when empty
+    pub slug: String,
+    pub is_public: bool,
+}
+
+// the title and the description fit in the pages
+fn check_subspace_input(action: &str, input: &SubspaceInput) -> Result<(), ForumError> {
+    let bad_request = |info: String| ForumError::BadRequest(action.to_string(), info);
+    let title_len = input.title.trim().chars().count();
+    if title_len == 0 || title_len > MAX_TITLE_LEN {
+        return Err(bad_request(format!(
+            "The title must be 1 to {} characters.",
+            MAX_TITLE_LEN
+        )));
+    }
+    if input.description.chars().count() > MAX_DESCRIPTION_LEN {
+        return Err(bad_request(format!(
+            "The description must be at most {} characters.",
+            MAX_DESCRIPTION_LEN
+        )));
     }
+    Ok(())
+}
+
+/// Create a subspace owned by the logged user, only the admins can
+pub async fn create_subspace(
+    app_state: &AppState,
+    logged_user: &LoggedUser,
+    input: SubspaceInput,
+) -> Result<GutpSubspace, ForumError> {
+    let action = "Create subspace";
+    Permission::site(app_state, Some(logged_user)).ensure(
+        Action::CreateSubspace,
+        "",
+        action.to_string(),
+    )?;
+    check_subspace_input(action, &input)?;
+
+    let title = input.title.trim().to_string();
+    let slug = slug::subspace_slug(app_state, action, "", &input.slug, &title).await?;
+    let inner_params = NewSubspace {
+        title,
+        description: input.description.trim().to_string(),
+        banner: "".to_string(),
+        owner_id: logged_user.user_id.to_owned(),
+        profession: app_state.config.profession.to_owned(),
+        appid: app_state.config.appid.to_owned(),
+        is_public: input.is_public,
+        slug,
+    };
+
+    app_state
+        .gutp
+        .subspaces()
+        .create(&inner_params)
+        .await
+        .map_err(ForumError::gutp(action))
 }
 
-/*
 #[derive(Template)]
 #[template(path = "subspace_edit.html")]
 struct SubspaceEditTemplate {
     subspace: GutpSubspace,
+    csrf_token: String,
 }
 
-struct ViewSubspaceEditParams {
+#[derive(Deserialize)]
+pub struct ViewSubspaceEditParams {
     id: String,
 }
 
 pub async fn view_subspace_edit(
-    State(client): State<Client>,
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
     Query(params): Query<ViewSubspaceEditParams>,
-    RawQuery(query): RawQuery,
-) -> impl IntoResponse {
+) -> Result<impl IntoResponse, ForumError> {
     // check the user login status
+    let logged_user = require_login(logged_user)?;
+    let sp = editable_subspace(&app_state, &logged_user, &params.id).await?;
 
-    // We must specify the tag_id, We can do it in the params type definition
-    let sp_id = params.id;
-
-    let res_bytes = make_get(client, "/v1/subspace", query).await;
-    let sps: Vec<GutpSubspace> = serde_json::from_slice(res_bytes).unwrap_or(vec![]);
-    if let Some(sp) = sps.into_iter().next() {
-        HtmlTemplate(SubspaceEditTemplate { subspace: sp })
-    } else {
-        let action = format!("Query subspace: {}", sp_id);
-        let err_info = "Subspace doesn't exist!";
-        redirect_to_error_page(&action, err_info)
-    }
+    Ok(HtmlTemplate(SubspaceEditTemplate {
+        subspace: sp,
+        csrf_token: logged_user.csrf_token,
+    }))
 }
 
-struct PostSubspaceEditParams {
+#[derive(Deserialize)]
+pub struct PostSubspaceEditParams {
     id: String,
+    title: String,
+    description: String,
+    slug: String,
+    // the checkboxes are only sent when checked
+    is_public: Option<String>,
+    remove_banner: Option<String>,
 }
 
 pub async fn post_subspace_edit(
-    State(client): State<Client>,
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
     Form(params): Form<PostSubspaceEditParams>,
-    body: String,
-) -> Redirect {
+) -> Result<Redirect, ForumError> {
+    // check the user login status
+    let logged_user = require_login(logged_user)?;
+
+    let input = SubspaceInput {
+        title: params.title,
+        description: params.description,
+        slug: params.slug,
+        is_public: params.is_public.is_some(),
+    };
+    let remove_banner = params.remove_banner.is_some();
+    let sp = edit_subspace(&app_state, &logged_user, &params.id, input, remove_banner).await?;
+
+    Ok(Redirect::to(&slug::subspace_path(&sp)))
+}
+
+/// Edit a subspace, by its owner or an admin
+pub async fn edit_subspace(
+    app_state: &AppState,
+    logged_user: &LoggedUser,
+    id: &str,
+    input: SubspaceInput,
+    remove_banner: bool,
+) -> Result<GutpSubspace, ForumError> {
+    let sp = editable_subspace(app_state, logged_user, id).await?;
+
+    let action = format!("Edit subspace: {}", sp.id);
+    check_subspace_input(&action, &input)?;
+    let title = input.title.trim().to_string();
+    let slug = slug::subspace_slug(app_state, &action, &sp.id, &input.slug, &title).await?;
+
+    let inner_params = SubspaceUpdate {
+        title,
+        description: input.description.trim().to_string(),
+        banner: if remove_banner {
+            "".to_string()
+        } else {
+            sp.banner.to_owned()
+        },
+        slug,
+        is_public: input.is_public,
+        ..subspace_update(&sp)
+    };
+    let updated = app_state
+        .gutp
+        .subspaces()
+        .update(&inner_params)
+        .await
+        .map_err(ForumError::gutp(&action))?;
+    if remove_banner {
+        upload::remove_image(&app_state.config.upload_dir, BANNERS, &sp.banner).await;
+    }
+
+    Ok(updated)
+}
+
+#[derive(Deserialize)]
+pub struct PostSubspaceBannerParams {
+    id: String,
+}
+
+// a multipart form, the csrf token comes in its first part
+pub async fn post_subspace_banner(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    Query(params): Query<PostSubspaceBannerParams>,
+    mut multipart: Multipart,
+) -> Result<Redirect, ForumError> {
     // check the user login status
+    let logged_user = require_login(logged_user)?;
+    let sp = editable_subspace(&app_state, &logged_user, &params.id).await?;
+
+    let action = format!("Upload banner of subspace: {}", sp.id);
+    let bytes =
+        upload::read_image_field(&mut multipart, "banner", MAX_BANNER_SIZE, &action).await?;
+    let upload_dir = &app_state.config.upload_dir;
+    let url = upload::save_image(upload_dir, BANNERS, &sp.id, &bytes, &action).await?;
+
+    let inner_params = SubspaceUpdate {
+        banner: url.to_owned(),
+        ..subspace_update(&sp)
+    };
+    if let Err(err) = app_state.gutp.subspaces().update(&inner_params).await {
+        upload::remove_image(upload_dir, BANNERS, &url).await;
+        return Err(ForumError::Gutp(action, err));
+    }
+    // the previous upload isn't reachable any more
+    upload::remove_image(upload_dir, BANNERS, &sp.banner).await;
 
-    // We must precheck the tag_id, we can do it in the params type definition
-    let id = params.id;
-
-    // post to gutp
-    let res_bytes = make_post(client, "/v1/subspace/edit", body).await;
-    let sps: Vec<GutpSubspace> = serde_json::from_slice(res_bytes).unwrap_or(vec![]);
-    if let Some(sp) = sps.into_iter().next() {
-        let redirect_uri = format!("/subspace?id={}", id);
-        Redirect::to(&redirect_uri)
-    } else {
-        // redirect to the error page
-        let action = format!("Edit subspace: {}", id);
-        let err_info = "Unknown";
-        redirect_to_error_page(&action, err_info)
+    let redirect_uri = format!("/subspace/edit?id={}", sp.id);
+    Ok(Redirect::to(&redirect_uri))
+}
+
+// the update which leaves everything as it is
+fn subspace_update(sp: &GutpSubspace) -> SubspaceUpdate {
+    SubspaceUpdate {
+        id: sp.id.to_owned(),
+        title: sp.title.to_owned(),
+        description: sp.description.to_owned(),
+        banner: sp.banner.to_owned(),
+        slug: sp.slug.to_owned(),
+        is_public: sp.is_public,
     }
 }
-*/
+
+// only the owner of the subspace and the admins can edit it
+async fn editable_subspace(
+    app_state: &AppState,
+    logged_user: &LoggedUser,
+    id: &str,
+) -> Result<GutpSubspace, ForumError> {
+    let action = format!("Query subspace: {}", id);
+    let sp = app_state
+        .gutp
+        .subspaces()
+        .by_id(id)
+        .await
+        .map_err(ForumError::gutp(&action))?
+        .ok_or_else(|| ForumError::not_found(&action, "No this subspace."))?;
+
+    Permission::in_subspace(app_state, Some(logged_user), &sp)
+        .await?
+        .ensure(
+            Action::EditSubspace,
+            "",
+            format!("Edit subspace: {}", sp.id),
+        )?;
+
+    Ok(sp)
+}
 
 #[derive(Template)]
 #[template(path = "subspace_delete.html")]
 struct SubspaceDeleteTemplate {
     subspace: GutpSubspace,
+    csrf_token: String,
 }
 
 #[derive(Deserialize)]
@@ -203,40 +420,20 @@ pub struct ViewSubspaceDeleteParams {
 }
 
 pub async fn view_subspace_delete(
+    State(app_state): State<AppState>,
     logged_user: Option<Extension<LoggedUser>>,
     Query(params): Query<ViewSubspaceDeleteParams>,
-) -> impl IntoResponse {
+) -> Result<impl IntoResponse, ForumError> {
     // check the user login status
-    if logged_user.is_none() {
-        let action = format!("Not logged in");
-        let err_info = "Need login firstly to get proper permission.";
-        return redirect_to_error_page(&action, err_info).into_response();
-    }
+    let logged_user = require_login(logged_user)?;
 
-    let inner_params = [("id", &params.id)];
-    let subspaces: Vec<GutpSubspace> = make_get("/v1/subspace", &inner_params)
-        .await
-        .unwrap_or(vec![]);
-    if let Some(sp) = subspaces.into_iter().next() {
-        let inner_params = [("subspace_id", &sp.id)];
-        let posts: Vec<GutpPost> = make_get("/v1/post/list_by_subspace_id", &inner_params)
-            .await
-            .unwrap_or(vec![]);
-        if posts.is_empty() {
-            // can be deleted
-            HtmlTemplate(SubspaceDeleteTemplate { subspace: sp }).into_response()
-        } else {
-            // error
-            let action = format!("Intend to delete subspace: {}", sp.id);
-            let err_info = "This subspace has article attached, could not be deleted!";
-            redirect_to_error_page(&action, err_info).into_response()
-        }
-    } else {
-        // redirect to the error page
-        let action = format!("Query subspace: {}", params.id);
-        let err_info = "No this subspace.";
-        redirect_to_error_page(&action, err_info).into_response()
-    }
+    let sp = deletable_subspace(&app_state, &logged_user, &params.id).await?;
+
+    // can be deleted
+    Ok(HtmlTemplate(SubspaceDeleteTemplate {
+        subspace: sp,
+        csrf_token: logged_user.csrf_token,
+    }))
 }
 
 #[derive(Deserialize)]
@@ -245,44 +442,73 @@ pub struct PostSubspaceDeleteParams {
 }
 
 pub async fn post_subspace_delete(
+    State(app_state): State<AppState>,
     logged_user: Option<Extension<LoggedUser>>,
     Form(params): Form<PostSubspaceDeleteParams>,
-) -> impl IntoResponse {
+) -> Result<Redirect, ForumError> {
     // check the user login status
-    if logged_user.is_none() {
-        let action = format!("Not logged in");
-        let err_info = "Need login firstly to get proper permission.";
-        return redirect_to_error_page(&action, err_info);
-    }
+    let logged_user = require_login(logged_user)?;
 
-    let inner_params = [("id", &params.id)];
-    let subspaces: Vec<GutpSubspace> = make_get("/v1/subspace", &inner_params)
+    delete_subspace(&app_state, &logged_user, &params.id).await?;
+
+    // redirect to index page
+    Ok(Redirect::to("/"))
+}
+
+/// Delete a subspace without articles, returns what it was
+pub async fn delete_subspace(
+    app_state: &AppState,
+    logged_user: &LoggedUser,
+    id: &str,
+) -> Result<GutpSubspace, ForumError> {
+    let sp = deletable_subspace(app_state, logged_user, id).await?;
+    app_state
+        .gutp
+        .subspaces()
+        .delete(&sp.id)
         .await
-        .unwrap_or(vec![]);
-    if let Some(sp) = subspaces.into_iter().next() {
-        let inner_params = [("subspace_id", &sp.id)];
-        let posts: Vec<GutpPost> = make_get("/v1/post/list_by_subspace", &inner_params)
-            .await
-            .unwrap_or(vec![]);
-        if posts.is_empty() {
-            // can be deleted
-            let inner_params = [("id", &sp.id)];
-            let _sps: Vec<GutpSubspace> = make_post("/v1/subspace/delete", &inner_params)
-                .await
-                .unwrap_or(vec![]);
-
-            // redirect to index page
-            Redirect::to("/")
-        } else {
-            // error
-            let action = format!("Intend to delete subspace: {}", sp.id);
-            let err_info = "This subspace has article attached, could not be deleted!";
-            redirect_to_error_page(&action, err_info)
-        }
-    } else {
-        // redirect to the error page
-        let action = format!("Query subspace: {}", params.id);
-        let err_info = "No this subspace.";
-        redirect_to_error_page(&action, err_info)
+        .map_err(ForumError::gutp(format!("Delete subspace: {}", sp.id)))?;
+    upload::remove_image(&app_state.config.upload_dir, BANNERS, &sp.banner).await;
+
+    Ok(sp)
+}
+
+// a subspace can only be deleted by its owner or the admins,
+// and only when no article is attached to it
+async fn deletable_subspace(
+    app_state: &AppState,
+    logged_user: &LoggedUser,
+    id: &str,
+) -> Result<GutpSubspace, ForumError> {
+    let gutp = &app_state.gutp;
+    let action = format!("Query subspace: {}", id);
+    let sp = gutp
+        .subspaces()
+        .by_id(id)
+        .await
+        .map_err(ForumError::gutp(&action))?
+        .ok_or_else(|| ForumError::not_found(&action, "No this subspace."))?;
+
+    Permission::in_subspace(app_state, Some(logged_user), &sp)
+        .await?
+        .ensure(
+            Action::DeleteSubspace,
+            "",
+            format!("Delete subspace: {}", sp.id),
+        )?;
+
+    let action = format!("Intend to delete subspace: {}", sp.id);
+    let posts = gutp
+        .posts()
+        .list_by_subspace(&sp.id, Paging::first())
+        .await
+        .map_err(ForumError::gutp(&action))?;
+    if !posts.is_empty() {
+        return Err(ForumError::conflict(
+            &action,
+            "This subspace has article attached, could not be deleted!",
+        ));
     }
+
+    Ok(sp)
 }
@@ -1,178 +1,649 @@
 use askama::Template;
 use axum::{
-    extract::{RawQuery, State, Form, Query,},
-    response::{Html, IntoResponse},
+    extract::{Form, Path, Query, State},
+    response::{IntoResponse, Redirect},
+    Extension,
 };
-
+use gutp_types::{GutpPost, GutpSubspace, GutpTag};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::error::ForumError;
+use crate::filters;
+use crate::gutp::{NewPostTag, NewTag, Paging, TagUpdate};
+use crate::pagination::{Pager, PAGE_SIZE};
+use crate::permission::{self, Action, Permission};
+use crate::slug;
+use crate::AppState;
 use crate::HtmlTemplate;
+use crate::{require_login, LoggedUser};
+
+const MAX_CAPTION_LEN: usize = 32;
+
+// the article forms send a `tag_{id}` checkbox per tag of the subspace
+const TAG_FIELD_PREFIX: &str = "tag_";
+
+/// A tag of the subspace, as a checkbox of the article forms
+pub struct TagChoice {
+    pub tag: GutpTag,
+    pub checked: bool,
+}
+
+impl TagChoice {
+    pub fn field_name(&self) -> String {
+        format!("{}{}", TAG_FIELD_PREFIX, self.tag.id)
+    }
+}
+
+/// The tag ids checked in an article form
+pub fn checked_tag_ids(fields: &HashMap<String, String>) -> Vec<String> {
+    fields
+        .keys()
+        .filter_map(|name| name.strip_prefix(TAG_FIELD_PREFIX))
+        .map(|id| id.to_string())
+        .collect()
+}
+
+pub async fn subspace_tags(
+    app_state: &AppState,
+    subspace_id: &str,
+) -> Result<Vec<GutpTag>, ForumError> {
+    let mut tags = app_state
+        .gutp
+        .tags()
+        .list_by_subspace(subspace_id)
+        .await
+        .map_err(ForumError::gutp(format!(
+            "Query tags of subspace: {}",
+            subspace_id
+        )))?;
+    tags.sort_by(|a, b| b.weight.cmp(&a.weight).then(a.caption.cmp(&b.caption)));
+    Ok(tags)
+}
+
+/// The tags of the subspace, checked when attached to the article
+pub async fn tag_choices(
+    app_state: &AppState,
+    subspace_id: &str,
+    post_id: Option<&str>,
+) -> Result<Vec<TagChoice>, ForumError> {
+    let tags = subspace_tags(app_state, subspace_id).await?;
+    let attached = match post_id {
+        Some(post_id) => attached_tag_ids(app_state, post_id).await?,
+        None => HashMap::new(),
+    };
+
+    Ok(tags
+        .into_iter()
+        .map(|tag| TagChoice {
+            checked: attached.contains_key(&tag.id),
+            tag,
+        })
+        .collect())
+}
+
+/// The tags shown on the article page
+pub async fn tags_of_post(
+    app_state: &AppState,
+    post: &GutpPost,
+) -> Result<Vec<GutpTag>, ForumError> {
+    let attached = attached_tag_ids(app_state, &post.id).await?;
+    if attached.is_empty() {
+        return Ok(vec![]);
+    }
+    let tags = subspace_tags(app_state, &post.subspace_id).await?;
+    Ok(tags
+        .into_iter()
+        .filter(|tag| attached.contains_key(&tag.id))
+        .collect())
+}
+
+// tag_id -> the id of the posttag row binding it to the article
+async fn attached_tag_ids(
+    app_state: &AppState,
+    post_id: &str,
+) -> Result<HashMap<String, String>, ForumError> {
+    let post_tags = app_state
+        .gutp
+        .post_tags()
+        .list_by_post(post_id)
+        .await
+        .map_err(ForumError::gutp(format!(
+            "Query tags of article: {}",
+            post_id
+        )))?;
+    Ok(post_tags
+        .into_iter()
+        .map(|post_tag| (post_tag.tag_id, post_tag.id))
+        .collect())
+}
+
+/// Attach exactly these tags to the article, the ids not belonging to
+/// its subspace are ignored
+pub async fn set_post_tags(
+    app_state: &AppState,
+    post: &GutpPost,
+    tag_ids: &[String],
+) -> Result<(), ForumError> {
+    let action = format!("Tag article: {}", post.id);
+    let tags = subspace_tags(app_state, &post.subspace_id).await?;
+    let attached = attached_tag_ids(app_state, &post.id).await?;
+    let post_tags = app_state.gutp.post_tags();
+
+    let (to_attach, to_detach) = tag_changes(&tags, &attached, tag_ids);
+    for post_tag_id in to_detach {
+        post_tags
+            .delete(&post_tag_id)
+            .await
+            .map_err(ForumError::gutp(&action))?;
+    }
+    for tag_id in to_attach {
+        let inner_params = NewPostTag {
+            post_id: post.id.to_owned(),
+            tag_id,
+        };
+        post_tags
+            .create(&inner_params)
+            .await
+            .map_err(ForumError::gutp(&action))?;
+    }
+
+    Ok(())
+}
+
+// the tag ids to attach, and the posttag ids to delete, for the article to
+// have the `wanted` ones among the `tags` of its subspace. `attached` is
+// tag_id -> posttag id
+fn tag_changes(
+    tags: &[GutpTag],
+    attached: &HashMap<String, String>,
+    wanted: &[String],
+) -> (Vec<String>, Vec<String>) {
+    let mut to_attach = vec![];
+    let mut to_detach = vec![];
+    for tag in tags {
+        let is_wanted = wanted.contains(&tag.id);
+        match attached.get(&tag.id) {
+            Some(post_tag_id) if !is_wanted => to_detach.push(post_tag_id.to_owned()),
+            None if is_wanted => to_attach.push(tag.id.to_owned()),
+            _ => {}
+        }
+    }
+    (to_attach, to_detach)
+}
+
+/// Before the article is deleted, so no tag page links to it
+pub async fn detach_post_tags(app_state: &AppState, post_id: &str) -> Result<(), ForumError> {
+    let action = format!("Untag article: {}", post_id);
+    let attached = attached_tag_ids(app_state, post_id).await?;
+    for post_tag_id in attached.values() {
+        app_state
+            .gutp
+            .post_tags()
+            .delete(post_tag_id)
+            .await
+            .map_err(ForumError::gutp(&action))?;
+    }
+    Ok(())
+}
+
+#[derive(Template)]
+#[template(path = "tag.html")]
+struct TagTemplate {
+    tag: GutpTag,
+    subspace: GutpSubspace,
+    posts: Vec<GutpPost>,
+    pager: Pager,
+    permission: Permission,
+}
+
+#[derive(Deserialize)]
+pub struct ViewTagParams {
+    page: Option<usize>,
+}
+
+pub async fn view_tag(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    Path(id): Path<String>,
+    Query(params): Query<ViewTagParams>,
+) -> Result<impl IntoResponse, ForumError> {
+    let gutp = &app_state.gutp;
+    let (tag, subspace) = query_tag(&app_state, &id).await?;
+    permission::ensure_view_subspace(&app_state, logged_user.as_deref(), &subspace).await?;
+
+    // one more than a page, to know whether there is a next one
+    let (page, offset) = Pager::page_offset(params.page, PAGE_SIZE);
+    let action = format!("Query articles of tag: {}", tag.id);
+    let post_tags = gutp
+        .post_tags()
+        .list_by_tag(&tag.id, Paging::new(PAGE_SIZE + 1, offset))
+        .await
+        .map_err(ForumError::gutp(&action))?;
+    let (post_tags, pager) =
+        Pager::from_window(post_tags, page, PAGE_SIZE, format!("/tag/{}", tag.id));
+
+    // gutp has no join, the articles are queried one by one
+    let mut posts = vec![];
+    for post_tag in post_tags {
+        if let Some(post) = gutp
+            .posts()
+            .by_id(&post_tag.post_id)
+            .await
+            .map_err(ForumError::gutp(&action))?
+        {
+            posts.push(post);
+        }
+    }
+
+    let permission = Permission::in_subspace(&app_state, logged_user.as_deref(), &subspace)
+        .await
+        .unwrap_or_else(|_| Permission::site(&app_state, logged_user.as_deref()));
+
+    Ok(HtmlTemplate(TagTemplate {
+        tag,
+        subspace,
+        posts,
+        pager,
+        permission,
+    }))
+}
 
 #[derive(Template)]
 #[template(path = "tag_create.html")]
 struct TagCreateTemplate {
-    subspace_id: String,
+    subspace: GutpSubspace,
+    csrf_token: String,
 }
 
-struct ViewTagCreateParams {
+#[derive(Deserialize)]
+pub struct ViewTagCreateParams {
     subspace_id: String,
 }
 
 pub async fn view_tag_create(
-    State(client): State<Client>,
-    Query(params): Query<ViewTagCreateParams>
-    RawQuery(query): RawQuery,
-) -> impl IntoResponse {
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    Query(params): Query<ViewTagCreateParams>,
+) -> Result<impl IntoResponse, ForumError> {
     // check the user login status
+    let logged_user = require_login(logged_user)?;
+    let subspace = managed_subspace(&app_state, &logged_user, &params.subspace_id).await?;
 
-    // We must specify the subspace_id
-    let subspace_id = params.subspace_id;
-
-    // render the page
-    HtmlTemplate(TagCreateTemplate {
-        subspace_id,
-    })
+    Ok(HtmlTemplate(TagCreateTemplate {
+        subspace,
+        csrf_token: logged_user.csrf_token,
+    }))
 }
 
-struct PostTagCreateParams {
+#[derive(Deserialize)]
+pub struct PostTagCreateParams {
     subspace_id: String,
+    caption: String,
 }
 
 pub async fn post_tag_create(
-    State(client): State<Client>,
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
     Form(params): Form<PostTagCreateParams>,
-    body: String,
-) -> impl IntoResponse {
+) -> Result<Redirect, ForumError> {
     // check the user login status
-
-    // TODO: parse form params
-
-    // forward post form body to gutp
-    let res_bytes = make_post(client, "/v1/tag/create", body).await;
-    let tags: Vec<GutpTag> = serde_json::from_slice(res_bytes).unwrap_or(vec![]);
-    let tag = tags[0].clone();
+    let logged_user = require_login(logged_user)?;
+    let subspace = managed_subspace(&app_state, &logged_user, &params.subspace_id).await?;
+
+    let action = format!("Create tag in subspace: {}", subspace.id);
+    let caption = check_caption(&app_state, &action, &subspace.id, "", &params.caption).await?;
+
+    let inner_params = NewTag {
+        caption,
+        subspace_id: subspace.id,
+        creator_id: logged_user.user_id,
+        is_subspace_tag: true,
+        is_public: true,
+    };
+    let tag = app_state
+        .gutp
+        .tags()
+        .create(&inner_params)
+        .await
+        .map_err(ForumError::gutp(&action))?;
 
     // redirect to the article list by tag page
-    let redirect_uri = format!("/article/list_by_tag?tag_id={}", tag.id);
-    Redirect::to(&redirect_uri)
+    let redirect_uri = format!("/tag/{}", tag.id);
+    Ok(Redirect::to(&redirect_uri))
 }
 
 #[derive(Template)]
 #[template(path = "tag_edit.html")]
 struct TagEditTemplate {
     tag: GutpTag,
+    csrf_token: String,
 }
 
-struct ViewTagEditParams {
+#[derive(Deserialize)]
+pub struct ViewTagEditParams {
     id: String,
 }
 
 pub async fn view_tag_edit(
-    State(client): State<Client>,
-    Query(params): Query<ViewTagEditParams>
-    RawQuery(query): RawQuery,
-) -> impl IntoResponse {
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    Query(params): Query<ViewTagEditParams>,
+) -> Result<impl IntoResponse, ForumError> {
     // check the user login status
+    let logged_user = require_login(logged_user)?;
+    let (tag, _) = managed_tag(&app_state, &logged_user, &params.id).await?;
 
-    // We must specify the tag_id, We can do it in the params type definition
-    // let tag_id = params.id;
-
-    // get the old tag by request to gutp
-    let res_bytes = make_get(client, "/v1/tag", query).await;
-    let tags: Vec<GutpTag> = serde_json::from_slice(res_bytes).unwrap_or(vec![]);
-    let tag = tags[0].clone();
-
-    // render the page
-    HtmlTemplate(TagEditTemplate {
-        tag: tag,
-    })
+    Ok(HtmlTemplate(TagEditTemplate {
+        tag,
+        csrf_token: logged_user.csrf_token,
+    }))
 }
 
-struct PostTagEditParams {
+#[derive(Deserialize)]
+pub struct PostTagEditParams {
     id: String,
+    caption: String,
 }
 
 pub async fn post_tag_edit(
-    State(client): State<Client>,
-    Form(params): Form<PostTagEditParams>
-    body: String,
-) -> Redirect {
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    Form(params): Form<PostTagEditParams>,
+) -> Result<Redirect, ForumError> {
     // check the user login status
-
-    // We must precheck the tag_id, we can do it in the params type definition
-    // let tag_id = params.id;
-
-    // post to gutp
-    let res_bytes = make_post(client, "/v1/tag/edit", body).await;
-    let tags: Vec<GutpTag> = serde_json::from_slice(res_bytes).unwrap_or(vec![]);
-    let tag = tags[0].clone();
+    let logged_user = require_login(logged_user)?;
+    let (tag, subspace) = managed_tag(&app_state, &logged_user, &params.id).await?;
+
+    let action = format!("Edit tag: {}", tag.id);
+    let caption =
+        check_caption(&app_state, &action, &subspace.id, &tag.id, &params.caption).await?;
+
+    let inner_params = TagUpdate {
+        id: tag.id,
+        caption,
+        is_public: tag.is_public,
+    };
+    let tag = app_state
+        .gutp
+        .tags()
+        .update(&inner_params)
+        .await
+        .map_err(ForumError::gutp(&action))?;
 
     // redirect to the article list by tag page
-    let redirect_uri = format!("/article/list_by_tag?tag_id={}", tag.id);
-    Redirect::to(&redirect_uri)
+    let redirect_uri = format!("/tag/{}", tag.id);
+    Ok(Redirect::to(&redirect_uri))
 }
 
 #[derive(Template)]
 #[template(path = "tag_delete.html")]
 struct TagDeleteTemplate {
     tag: GutpTag,
+    csrf_token: String,
 }
 
-
-struct ViewTagDeleteParams {
+#[derive(Deserialize)]
+pub struct ViewTagDeleteParams {
     id: String,
 }
 
 pub async fn view_tag_delete(
-    State(client): State<Client>,
-    Query(params): Query<ViewTagDeleteParams>
-    RawQuery(query): RawQuery,
-) -> impl IntoResponse {
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    Query(params): Query<ViewTagDeleteParams>,
+) -> Result<impl IntoResponse, ForumError> {
     // check the user login status
+    let logged_user = require_login(logged_user)?;
+    let tag = deletable_tag(&app_state, &logged_user, &params.id).await?;
 
-    // We must specify the tag_id, We can do it in the params type definition
-    // let tag_id = params.tag_id;
-
-    // get the old tag by request to gutp
-    let res_bytes = make_get(client, "/v1/tag", query).await;
-    let tags: Vec<GutpTag> = serde_json::from_slice(res_bytes).unwrap_or(vec![]);
-    let tag = tags[0].clone();
-
-    // render the page
-    HtmlTemplate(TagDeleteTemplate {
-        tag: tag,
-    })
+    Ok(HtmlTemplate(TagDeleteTemplate {
+        tag,
+        csrf_token: logged_user.csrf_token,
+    }))
 }
 
-struct PostTagDeleteParams {
+#[derive(Deserialize)]
+pub struct PostTagDeleteParams {
     id: String,
 }
 
 pub async fn post_tag_delete(
-    State(client): State<Client>,
-    Form(params): Form<PostTagDeleteParams>
-    body: String,
-) -> Redirect {
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    Form(params): Form<PostTagDeleteParams>,
+) -> Result<Redirect, ForumError> {
     // check the user login status
+    let logged_user = require_login(logged_user)?;
+    let tag = deletable_tag(&app_state, &logged_user, &params.id).await?;
+
+    app_state
+        .gutp
+        .tags()
+        .delete(&tag.id)
+        .await
+        .map_err(ForumError::gutp(format!("Delete tag: {}", tag.id)))?;
+
+    // back to the subspace it belonged to
+    Ok(Redirect::to(&slug::subspace_id_path(&tag.subspace_id)))
+}
+
+pub async fn query_tag(
+    app_state: &AppState,
+    id: &str,
+) -> Result<(GutpTag, GutpSubspace), ForumError> {
+    let action = format!("Query tag: {}", id);
+    let tag = app_state
+        .gutp
+        .tags()
+        .by_id(id)
+        .await
+        .map_err(ForumError::gutp(&action))?
+        .ok_or_else(|| ForumError::not_found(&action, "Tag doesn't exist!"))?;
+
+    let action = format!("Query subspace: {}", tag.subspace_id);
+    let subspace = app_state
+        .gutp
+        .subspaces()
+        .by_id(&tag.subspace_id)
+        .await
+        .map_err(ForumError::gutp(&action))?
+        .ok_or_else(|| ForumError::not_found(&action, "No this subspace."))?;
+
+    Ok((tag, subspace))
+}
+
+// the tags are managed by the owner and the moderators of the subspace
+async fn managed_subspace(
+    app_state: &AppState,
+    logged_user: &LoggedUser,
+    subspace_id: &str,
+) -> Result<GutpSubspace, ForumError> {
+    let action = format!("Query subspace: {}", subspace_id);
+    let subspace = app_state
+        .gutp
+        .subspaces()
+        .by_id(subspace_id)
+        .await
+        .map_err(ForumError::gutp(&action))?
+        .ok_or_else(|| ForumError::not_found(&action, "No this subspace."))?;
+
+    Permission::in_subspace(app_state, Some(logged_user), &subspace)
+        .await?
+        .ensure(
+            Action::ManageTag,
+            "",
+            format!("Manage tags of subspace: {}", subspace.id),
+        )?;
+
+    Ok(subspace)
+}
 
-    // We must precheck the tag_id, we can do it in the params type definition
-    let tag_id = params.id;
+async fn managed_tag(
+    app_state: &AppState,
+    logged_user: &LoggedUser,
+    id: &str,
+) -> Result<(GutpTag, GutpSubspace), ForumError> {
+    let (tag, subspace) = query_tag(app_state, id).await?;
+    Permission::in_subspace(app_state, Some(logged_user), &subspace)
+        .await?
+        .ensure(Action::ManageTag, "", format!("Manage tag: {}", tag.id))?;
+
+    Ok((tag, subspace))
+}
 
-    // check whether some articles attaching to this tag
-    let res_bytes = make_get(client, "/v1/post/list_by_tag", body).await;
-    let posts: Vec<GutpPost> = serde_json::from_slice(res_bytes).unwrap_or(vec![]);
-    if !posts.is_empty() {
-        // if this tag has attached articles, couldn't be deleted
-        // redirect to the error info page
-        let action = format!("Delete Tag: {}", tag_id);
-        let err_info = "This tag is holding attached articles, couldn't be deleted!";
-        let redirect_uri = format!("/error/info?action={}&err_info={}", action, err_info);
-        Redirect::to(&redirect_uri)
-    } else {
-        // post to gutp
-        let res_bytes = make_post(client, "/v1/tag/delete", body).await;
-        let _posts: Vec<GutpPost> = serde_json::from_slice(res_bytes).unwrap_or(vec![]);
+// a tag can only be deleted when no article is attached to it
+async fn deletable_tag(
+    app_state: &AppState,
+    logged_user: &LoggedUser,
+    id: &str,
+) -> Result<GutpTag, ForumError> {
+    let (tag, _) = managed_tag(app_state, logged_user, id).await?;
+
+    let action = format!("Intend to delete tag: {}", tag.id);
+    let post_tags = app_state
+        .gutp
+        .post_tags()
+        .list_by_tag(&tag.id, Paging::first())
+        .await
+        .map_err(ForumError::gutp(&action))?;
+    if !post_tags.is_empty() {
+        return Err(ForumError::conflict(
+            &action,
+            "This tag is holding attached articles, couldn't be deleted!",
+        ));
+    }
+
+    Ok(tag)
+}
 
-        // redirect to the article list by tag page
-        Redirect::to("/")
+// trimmed, not too long, and unique in the subspace, `tag_id` is the one being edited
+async fn check_caption(
+    app_state: &AppState,
+    action: &str,
+    subspace_id: &str,
+    tag_id: &str,
+    caption: &str,
+) -> Result<String, ForumError> {
+    let Some(caption) = trimmed_caption(caption) else {
+        return Err(ForumError::BadRequest(
+            action.to_string(),
+            format!("The tag must be 1 to {} characters.", MAX_CAPTION_LEN),
+        ));
+    };
+
+    let tags = subspace_tags(app_state, subspace_id).await?;
+    if is_caption_taken(&tags, tag_id, caption) {
+        return Err(ForumError::conflict(
+            action,
+            "This tag already exists in the subspace.",
+        ));
     }
+
+    Ok(caption.to_string())
+}
+
+fn trimmed_caption(caption: &str) -> Option<&str> {
+    let caption = caption.trim();
+    let len = caption.chars().count();
+    (1..=MAX_CAPTION_LEN).contains(&len).then_some(caption)
+}
+
+// the captions differing only by case are the same tag
+fn is_caption_taken(tags: &[GutpTag], tag_id: &str, caption: &str) -> bool {
+    let caption = caption.to_lowercase();
+    tags.iter()
+        .any(|tag| tag.id != tag_id && tag.caption.to_lowercase() == caption)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(id: &str, caption: &str) -> GutpTag {
+        GutpTag {
+            id: id.to_string(),
+            caption: caption.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn checked_tags_are_the_tag_fields() {
+        let fields: HashMap<String, String> = [
+            ("title", "Hello"),
+            ("content", "tag_3"),
+            ("tag_1", "on"),
+            ("tag_2", "on"),
+        ]
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+        let mut checked = checked_tag_ids(&fields);
+        checked.sort();
+        assert_eq!(checked, ids(&["1", "2"]));
+    }
+
+    #[test]
+    fn tag_changes_attach_and_detach() {
+        let tags = [tag("t1", "rust"), tag("t2", "async"), tag("t3", "web")];
+        // t1 and t2 attached, by the posttags p1 and p2
+        let attached: HashMap<String, String> = [("t1", "p1"), ("t2", "p2")]
+            .iter()
+            .map(|(tag_id, id)| (tag_id.to_string(), id.to_string()))
+            .collect();
+
+        let (to_attach, to_detach) = tag_changes(&tags, &attached, &ids(&["t2", "t3"]));
+        assert_eq!(to_attach, ids(&["t3"]));
+        assert_eq!(to_detach, ids(&["p1"]));
+
+        // nothing changes when the same ones are checked
+        let (to_attach, to_detach) = tag_changes(&tags, &attached, &ids(&["t1", "t2"]));
+        assert!(to_attach.is_empty() && to_detach.is_empty());
+
+        // all unchecked
+        let (to_attach, mut to_detach) = tag_changes(&tags, &attached, &[]);
+        to_detach.sort();
+        assert!(to_attach.is_empty());
+        assert_eq!(to_detach, ids(&["p1", "p2"]));
+    }
+
+    #[test]
+    fn tag_changes_ignore_the_other_subspaces() {
+        let tags = [tag("t1", "rust")];
+        // t9 is a tag of another subspace, attached before the article moved
+        let attached: HashMap<String, String> = [("t9", "p9")]
+            .iter()
+            .map(|(tag_id, id)| (tag_id.to_string(), id.to_string()))
+            .collect();
+
+        let (to_attach, to_detach) = tag_changes(&tags, &attached, &ids(&["t1", "t8"]));
+        assert_eq!(to_attach, ids(&["t1"]));
+        assert!(to_detach.is_empty());
+    }
+
+    #[test]
+    fn captions_are_trimmed_and_bounded() {
+        assert_eq!(trimmed_caption("  rust  "), Some("rust"));
+        assert_eq!(trimmed_caption("   "), None);
+        assert_eq!(trimmed_caption(""), None);
+        let longest = "标".repeat(MAX_CAPTION_LEN);
+        assert_eq!(trimmed_caption(&longest), Some(longest.as_str()));
+        assert_eq!(trimmed_caption(&"x".repeat(MAX_CAPTION_LEN + 1)), None);
+    }
+
+    #[test]
+    fn captions_are_unique_whatever_the_case() {
+        let tags = [tag("t1", "Rust"), tag("t2", "Web")];
+        assert!(is_caption_taken(&tags, "", "rust"));
+        assert!(is_caption_taken(&tags, "", "RUST"));
+        assert!(!is_caption_taken(&tags, "", "go"));
+        // the tag being edited can keep its caption, or change its case
+        assert!(!is_caption_taken(&tags, "t1", "rust"));
+        assert!(is_caption_taken(&tags, "t1", "web"));
+    }
+}
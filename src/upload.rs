@@ -0,0 +1,110 @@
+use axum::extract::Multipart;
+use std::path::PathBuf;
+
+use crate::error::ForumError;
+
+/// The uploaded images are served under this path
+pub const UPLOADS_PATH: &str = "/uploads";
+
+/// The image formats we accept, told by their magic bytes, not by the file name
+fn image_extension(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("png")
+    } else if bytes.starts_with(b"\xff\xd8\xff") {
+        Some("jpg")
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some("gif")
+    } else if bytes.len() > 12 && bytes.starts_with(b"RIFF") && &bytes[8..12] == b"WEBP" {
+        Some("webp")
+    } else {
+        None
+    }
+}
+
+/// Read the file field `name` of a multipart form, at most `max_size` bytes
+pub async fn read_image_field(
+    multipart: &mut Multipart,
+    name: &str,
+    max_size: usize,
+    action: &str,
+) -> Result<Vec<u8>, ForumError> {
+    let bad_request = |info: &str| ForumError::BadRequest(action.to_string(), info.to_string());
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|err| bad_request(&err.to_string()))?
+    {
+        if field.name() != Some(name) {
+            continue;
+        }
+        let bytes = field
+            .bytes()
+            .await
+            .map_err(|err| bad_request(&err.to_string()))?;
+        if bytes.is_empty() {
+            return Err(bad_request("No file is chosen."));
+        }
+        if bytes.len() > max_size {
+            return Err(bad_request(&format!(
+                "The image must be smaller than {} KB.",
+                max_size / 1024
+            )));
+        }
+        return Ok(bytes.to_vec());
+    }
+    Err(bad_request("No file is chosen."))
+}
+
+/// Save an image under `{upload_dir}/{kind}/`, returns its url
+pub async fn save_image(
+    upload_dir: &str,
+    kind: &str,
+    owner_id: &str,
+    bytes: &[u8],
+    action: &str,
+) -> Result<String, ForumError> {
+    let extension = image_extension(bytes).ok_or_else(|| {
+        ForumError::BadRequest(
+            action.to_string(),
+            "Only png, jpeg, gif and webp images are accepted.".to_string(),
+        )
+    })?;
+
+    // the owner id is only a hint for the operators, keep it safe for a path
+    let owner_id: String = owner_id
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-')
+        .take(64)
+        .collect();
+    let random = hex_suffix();
+    let file_name = format!("{}-{}.{}", owner_id, random, extension);
+
+    let dir = PathBuf::from(upload_dir).join(kind);
+    let internal = |err: std::io::Error| ForumError::Internal(action.to_string(), err.to_string());
+    tokio::fs::create_dir_all(&dir).await.map_err(internal)?;
+    tokio::fs::write(dir.join(&file_name), bytes)
+        .await
+        .map_err(internal)?;
+
+    Ok(format!("{}/{}/{}", UPLOADS_PATH, kind, file_name))
+}
+
+/// Remove an image saved by `save_image`, the other urls are left alone
+pub async fn remove_image(upload_dir: &str, kind: &str, url: &str) {
+    let prefix = format!("{}/{}/", UPLOADS_PATH, kind);
+    let Some(file_name) = url.strip_prefix(&prefix) else {
+        return;
+    };
+    if file_name.contains('/') || file_name.contains("..") {
+        return;
+    }
+    let path = PathBuf::from(upload_dir).join(kind).join(file_name);
+    if let Err(err) = tokio::fs::remove_file(&path).await {
+        println!("in remove_image: {}: {}", path.display(), err);
+    }
+}
+
+fn hex_suffix() -> String {
+    let x = rand::random::<[u8; 8]>();
+    x.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -1,259 +1,1377 @@
 use askama::Template;
 use axum::{
-    extract::{Form, Query, RawQuery, State},
-    http::header,
-    response::{Html, IntoResponse, Redirect},
+    extract::{ConnectInfo, Form, Path, Query, RawQuery, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
     Extension,
 };
 use axum_extra::extract::cookie::{Cookie, CookieJar};
 use gutp_types::{GutpComment, GutpPost, GutpSubspace, GutpUser};
-use redis::AsyncCommands;
+use redis::{aio::ConnectionManager, AsyncCommands};
 use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
 
-use crate::redirect_to_error_page;
+use crate::api;
+use crate::api_token::ApiToken;
+use crate::error::ForumError;
+use crate::filters;
+use crate::gutp::NewUser;
+use crate::i18n;
+use crate::identity::{self, Identity};
+use crate::oauth::{self, OAuthProvider, OauthProfile};
+use crate::password;
+use crate::profile::{preference_cookies, PubSettings};
+use crate::session::{Session, SessionStore};
 use crate::AppState;
 use crate::HtmlTemplate;
-use crate::LoggedUser;
-use crate::{make_get, make_post};
+use crate::{require_login, LoggedUser};
 
-const TTL: usize = 60 * 24 * 3600;
+// a login button
+struct ProviderLink {
+    name: String,
+    display_name: String,
+}
 
 #[derive(Template)]
 #[template(path = "login.html")]
 struct LoginTemplate {
-    client_id: String,
+    providers: Vec<ProviderLink>,
+    // the username/password form
+    local_auth: bool,
+    allow_signup: bool,
+    return_to: String,
+    notice: Option<String>,
+}
+
+fn provider_links(app_state: &AppState) -> Vec<ProviderLink> {
+    app_state
+        .oauth_providers
+        .iter()
+        .map(|provider| ProviderLink {
+            name: provider.name().to_string(),
+            display_name: provider.display_name().to_string(),
+        })
+        .collect()
+}
+
+impl LoginTemplate {
+    fn new(app_state: &AppState, return_to: String, notice: Option<String>) -> Self {
+        let local_auth = &app_state.config.local_auth;
+        LoginTemplate {
+            providers: provider_links(app_state),
+            local_auth: local_auth.enabled,
+            allow_signup: local_auth.enabled && local_auth.allow_signup,
+            return_to,
+            notice,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ViewLoginParams {
+    return_to: Option<String>,
 }
 
-pub async fn view_login() -> impl IntoResponse {
-    let client_id = dotenv::var("GITHUB_APP_CLIENT_ID").unwrap();
-    HtmlTemplate(LoginTemplate { client_id })
+pub async fn view_login(
+    State(app_state): State<AppState>,
+    Query(params): Query<ViewLoginParams>,
+) -> impl IntoResponse {
+    let return_to = oauth::safe_return_to(params.return_to.as_deref());
+    HtmlTemplate(LoginTemplate::new(&app_state, return_to, None))
+}
+
+// the login button, it goes to the provider with a fresh state and PKCE challenge
+pub async fn oauth_login(
+    State(app_state): State<AppState>,
+    Path(provider_name): Path<String>,
+    cookie_jar: CookieJar,
+    Query(params): Query<ViewLoginParams>,
+) -> Result<Response, ForumError> {
+    let action = format!("Login with {}", provider_name);
+    let provider = app_state
+        .oauth_providers
+        .get(&provider_name)
+        .ok_or_else(|| ForumError::not_found(&action, "No this login provider."))?;
+
+    let return_to = oauth::safe_return_to(params.return_to.as_deref());
+    let mut redis_conn = session_conn(&app_state).await?;
+    let (state, pending) = app_state
+        .oauth_states
+        .begin(&mut redis_conn, provider.name(), &return_to, None)
+        .await
+        .map_err(|err| ForumError::Internal(action.to_owned(), err.to_string()))?;
+
+    let redirect_uri = app_state.oauth_providers.redirect_uri(provider);
+    let authorize_uri = provider.authorize_url(&state, &pending.code_challenge(), &redirect_uri);
+    Ok((
+        cookie_jar.add(app_state.oauth_states.cookie(&state)),
+        Redirect::to(&authorize_uri),
+    )
+        .into_response())
 }
 
 #[derive(Template)]
 #[template(path = "account.html")]
 struct AccountTemplate {
     user: GutpUser,
+    identities: Vec<Identity>,
+    // to link one more identity
+    providers: Vec<ProviderLink>,
+    sessions: Vec<Session>,
+    current_session_handle: String,
+    // for the bots and the other clients of the api
+    api_tokens: Vec<ApiToken>,
+    local_auth: bool,
+    // the admins can issue password reset links
+    is_admin: bool,
+    csrf_token: String,
 }
 
-pub async fn view_account(logged_user: Option<Extension<LoggedUser>>) -> impl IntoResponse {
-    // if logged_user_id.is_none() {
-    //     let action = format!("Not logged in");
-    //     let err_info = "Need login firstly to get proper permission.";
-    //     return redirect_to_error_page(&action, err_info);
-    // }
-
-    // has login info
-    if let Some(Extension(LoggedUser { user_id })) = logged_user {
-        // render user info page
-        let inner_params = [("id", &user_id)];
-        let users: Vec<GutpUser> = make_get("/v1/user", &inner_params).await.unwrap_or(vec![]);
-        if let Some(user) = users.into_iter().next() {
-            HtmlTemplate(AccountTemplate { user }).into_response()
-        } else {
-            let action = format!("Query user: {}", &user_id);
-            let err_info = "Unknown.";
-            redirect_to_error_page(&action, err_info).into_response()
-        }
-    } else {
-        // if not logged in, redirect to login page
-        let redirect_uri = format!("/user/login");
-        Redirect::to(&redirect_uri).into_response()
-    }
+pub async fn view_account(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+) -> Result<Response, ForumError> {
+    // if not logged in, redirect to login page
+    let Some(Extension(logged_user)) = logged_user else {
+        return Ok(Redirect::to("/user/login?return_to=/user/account").into_response());
+    };
+
+    // render user info page
+    let action = format!("Query user: {}", &logged_user.user_id);
+    let user = app_state
+        .gutp
+        .users()
+        .by_id(&logged_user.user_id)
+        .await
+        .map_err(ForumError::gutp(&action))?
+        .ok_or_else(|| ForumError::not_found(&action, "Unknown."))?;
+
+    let mut redis_conn = session_conn(&app_state).await?;
+    let identities = app_state
+        .identities
+        .list_by_user(&mut redis_conn, &logged_user.user_id)
+        .await
+        .map_err(|err| ForumError::Internal("List linked accounts".to_string(), err.to_string()))?;
+
+    // the devices this user is logged in on
+    let sessions = app_state
+        .sessions
+        .list_by_user(&mut redis_conn, &logged_user.user_id)
+        .await
+        .map_err(|err| ForumError::Internal("List sessions".to_string(), err.to_string()))?;
+
+    let api_tokens = app_state
+        .api_tokens
+        .list_by_user(&mut redis_conn, &logged_user.user_id)
+        .await
+        .map_err(|err| ForumError::Internal("List api tokens".to_string(), err.to_string()))?;
+
+    Ok(HtmlTemplate(AccountTemplate {
+        user,
+        identities,
+        providers: provider_links(&app_state),
+        sessions,
+        current_session_handle: SessionStore::handle_of(&logged_user.session_id),
+        api_tokens,
+        local_auth: app_state.config.local_auth.enabled,
+        is_admin: app_state.config.is_admin(&logged_user.user_id),
+        csrf_token: logged_user.csrf_token,
+    })
+    .into_response())
 }
 
 #[derive(Deserialize)]
-pub struct GithubOauthCallbackParams {
-    code: String,
+pub struct OauthCallbackParams {
+    code: Option<String>,
+    state: Option<String>,
+    // set when the user didn't authorize, e.g. `access_denied`
+    error: Option<String>,
 }
 
+// the catalog key of the notice for an `error` of the callback (RFC 6749, 4.1.2.1)
+fn oauth_error_key(error: &str) -> &'static str {
+    match error {
+        "access_denied" => "oauth_access_denied",
+        "server_error" | "temporarily_unavailable" => "oauth_unavailable",
+        _ => "oauth_refused",
+    }
+}
+
+// the callback url registered in the github app before the other providers
 pub async fn github_oauth_callback(
     State(app_state): State<AppState>,
-    Query(params): Query<GithubOauthCallbackParams>,
-) -> impl IntoResponse {
-    let mut redis_conn = app_state.rclient.get_async_connection().await.unwrap();
-    // returned from github
-    let code = params.code;
-    println!("in github_oauth_callback, code: {}", code);
-
-    // get github app client_id and secret
-    let client_id = dotenv::var("GITHUB_APP_CLIENT_ID").unwrap();
-    let client_secret = dotenv::var("GITHUB_APP_CLIENT_SECRET").unwrap();
-    println!("in github_oauth_callback, client_id: {}", client_id);
-    println!("in github_oauth_callback, client_secret: {}", client_secret);
-
-    if let Ok(github_credentials) = get_github_token(&code, &client_id, &client_secret).await {
-        // use this access_token to retreive user info
-        if let Ok(github_user_info) = get_github_user_info(&github_credentials.access_token).await {
-            let account = github_user_info.login.to_owned();
-            // now we get user info from github
-            // we use the account to check whether this user exist in gutp
-            let inner_params = [("account", &account)];
-            let users: Vec<GutpUser> = make_get("/v1/user/get_by_account", &inner_params)
-                .await
-                .unwrap_or(vec![]);
-            if let Some(user) = users.into_iter().next() {
-                // if user exists, log it in
-                login_user(redis_conn, &user.id).await.into_response()
-            } else {
-                // if user doesn't exist, register it
-
-                #[derive(Serialize)]
-                struct InnerUserCreateParams {
-                    pub account: String,
-                    pub oauth_source: String,
-                    pub nickname: String,
-                    pub avatar: String,
-                    pub pub_settings: String,
-                    pub ext: String,
-                }
+    logged_user: Option<Extension<LoggedUser>>,
+    cookie_jar: CookieJar,
+    headers: HeaderMap,
+    Query(params): Query<OauthCallbackParams>,
+) -> Result<Response, ForumError> {
+    login_with_provider(
+        app_state,
+        "github",
+        logged_user,
+        cookie_jar,
+        headers,
+        params,
+    )
+    .await
+}
 
-                let inner_params = InnerUserCreateParams {
-                    account: github_user_info.login.to_owned(),
-                    oauth_source: "github".to_owned(),
-                    nickname: github_user_info.name.to_owned(),
-                    avatar: "".to_owned(),
-                    pub_settings: "".to_owned(),
-                    ext: "".to_owned(),
-                };
-                let users: Vec<GutpUser> = make_post("/v1/user/create", &inner_params)
-                    .await
-                    .unwrap_or(vec![]);
-                if let Some(user) = users.into_iter().next() {
-                    // registerd successfully
-                    login_user(redis_conn, &user.id).await.into_response()
-                } else {
-                    // redirect to the error page
-                    let action = format!("Register user: {}", &account);
-                    let err_info = "Unknown";
-                    redirect_to_error_page(&action, err_info).into_response()
-                }
-            }
-        } else {
-            // error on getting github user info
-            let action = format!("Get user info from github");
-            let err_info = "Failed to get response from github";
-            redirect_to_error_page(&action, err_info).into_response()
+pub async fn oauth_callback(
+    State(app_state): State<AppState>,
+    Path(provider_name): Path<String>,
+    logged_user: Option<Extension<LoggedUser>>,
+    cookie_jar: CookieJar,
+    headers: HeaderMap,
+    Query(params): Query<OauthCallbackParams>,
+) -> Result<Response, ForumError> {
+    login_with_provider(
+        app_state,
+        &provider_name,
+        logged_user,
+        cookie_jar,
+        headers,
+        params,
+    )
+    .await
+}
+
+async fn login_with_provider(
+    app_state: AppState,
+    provider_name: &str,
+    logged_user: Option<Extension<LoggedUser>>,
+    cookie_jar: CookieJar,
+    headers: HeaderMap,
+    params: OauthCallbackParams,
+) -> Result<Response, ForumError> {
+    let action = format!("Login with {}", provider_name);
+    let provider = app_state
+        .oauth_providers
+        .get(provider_name)
+        .ok_or_else(|| ForumError::not_found(&action, "No this login provider."))?;
+    let mut redis_conn = session_conn(&app_state).await?;
+
+    // the state is single use, even when the login failed, and it must come
+    // back to the browser which started the login
+    let pending = match &params.state {
+        Some(state) if app_state.oauth_states.started_by(&cookie_jar, state) => app_state
+            .oauth_states
+            .take(&mut redis_conn, state)
+            .await
+            .map_err(|err| ForumError::Internal(action.to_owned(), err.to_string()))?,
+        _ => None,
+    };
+    let Some(pending) = pending.filter(|p| p.provider == provider.name()) else {
+        return Err(ForumError::BadRequest(
+            action,
+            "The login request is expired or invalid, please try again.".to_string(),
+        ));
+    };
+    let cookie_jar = cookie_jar.remove(app_state.oauth_states.removal_cookie());
+
+    // the text of the error comes with the link, only its code picks a message
+    if let Some(error) = params.error {
+        println!("in oauth_callback, {}: error: {:?}", provider_name, error);
+        let notice =
+            i18n::translate(oauth_error_key(&error)).replace("{provider}", provider.display_name());
+        let template = LoginTemplate::new(&app_state, pending.return_to, Some(notice));
+        return Ok((cookie_jar, HtmlTemplate(template)).into_response());
+    }
+
+    let Some(code) = params.code else {
+        return Err(ForumError::BadRequest(
+            action,
+            "No authorization code in the callback.".to_string(),
+        ));
+    };
+    // returned from the provider, the code itself is a secret
+    println!("in oauth_callback, {}: got a code", provider_name);
+
+    let redirect_uri = app_state.oauth_providers.redirect_uri(provider);
+    let access_token = provider
+        .exchange_code(&code, &pending.code_verifier, &redirect_uri)
+        .await
+        .map_err(|err| {
+            // error on getting access token
+            println!(
+                "in oauth_callback, {}: exchange_code: {}",
+                provider_name, err
+            );
+            ForumError::Upstream(
+                format!("Get access token from {}", provider.display_name()),
+                "Failed to request access token".to_string(),
+            )
+        })?;
+
+    // use this access_token to retreive user info
+    let profile = provider.fetch_profile(&access_token).await.map_err(|err| {
+        // error on getting user info
+        println!(
+            "in oauth_callback, {}: fetch_profile: {}",
+            provider_name, err
+        );
+        ForumError::Upstream(
+            format!("Get user info from {}", provider.display_name()),
+            "Failed to get response from the provider".to_string(),
+        )
+    })?;
+
+    let identity = Identity::new(provider.name(), &profile);
+
+    // linking one more identity to the logged in user, no new session
+    if let Some(link_user_id) = &pending.link_user_id {
+        let logged_user = require_login(logged_user)?;
+        let action = format!(
+            "Link {} account: {}",
+            provider.display_name(),
+            profile.login
+        );
+        if &logged_user.user_id != link_user_id {
+            return Err(ForumError::Forbidden(action));
         }
+        let linked = app_state
+            .identities
+            .link(&mut redis_conn, link_user_id, &identity)
+            .await
+            .map_err(|err| ForumError::Internal(action.to_owned(), err.to_string()))?;
+        if !linked {
+            return Err(ForumError::conflict(
+                &action,
+                "This account is already linked to another user.",
+            ));
+        }
+        return Ok((cookie_jar, Redirect::to(&pending.return_to)).into_response());
+    }
+
+    let user = resolve_user(&app_state, &mut redis_conn, provider, &profile).await?;
+    crate::profile::backfill_avatar(&app_state, &user, &profile.avatar_url).await;
+    // the identities from before linking existed are recorded at their next login
+    let linked = app_state
+        .identities
+        .link(&mut redis_conn, &user.id, &identity)
+        .await
+        .map_err(|err| ForumError::Internal(action.to_owned(), err.to_string()))?;
+    // a concurrent login of the same identity linked it first, to its user
+    let user_id = if linked {
+        user.id
     } else {
-        // error on getting github access token
-        let action = format!("Get access token from github");
-        let err_info = "Failed to request access token from github";
-        redirect_to_error_page(&action, err_info).into_response()
+        app_state
+            .identities
+            .find(&mut redis_conn, provider.name(), &profile.id)
+            .await
+            .map_err(|err| ForumError::Internal(action.to_owned(), err.to_string()))?
+            .ok_or_else(|| {
+                ForumError::conflict(&action, "This account is being linked, please try again.")
+            })?
+    };
+
+    login_user(
+        &app_state,
+        redis_conn,
+        cookie_jar,
+        &headers,
+        &user_id,
+        &pending.return_to,
+    )
+    .await
+}
+
+// find the gutp user of an identity, register one if nobody has it
+async fn resolve_user(
+    app_state: &AppState,
+    redis_conn: &mut ConnectionManager,
+    provider: &dyn OAuthProvider,
+    profile: &OauthProfile,
+) -> Result<GutpUser, ForumError> {
+    // a linked identity first, it doesn't change when the login is renamed
+    let linked_user_id = app_state
+        .identities
+        .find(redis_conn, provider.name(), &profile.id)
+        .await
+        .map_err(|err| ForumError::Internal("Find linked account".to_string(), err.to_string()))?;
+    if let Some(user_id) = linked_user_id {
+        let action = format!("Query user: {}", user_id);
+        let user = app_state
+            .gutp
+            .users()
+            .by_id(&user_id)
+            .await
+            .map_err(ForumError::gutp(&action))?;
+        if let Some(user) = user {
+            return Ok(user);
+        }
+    }
+
+    // then the account, as the users were looked up before the linking. The
+    // login may have been renamed away and taken by someone else since, who
+    // gets an account of their own
+    let login_account = provider.account(profile);
+    let accounts = [
+        login_account.clone(),
+        identity::fallback_account(&login_account, &profile.id),
+    ];
+    for account in &accounts {
+        let user = app_state
+            .gutp
+            .users()
+            .by_account(account)
+            .await
+            .map_err(ForumError::gutp(format!("Query user: {}", account)))?;
+        let Some(user) = user else {
+            return register_oauth_user(app_state, provider, profile, account).await;
+        };
+        // if user exists, log it in, unless it's linked to other identities
+        let linked = app_state
+            .identities
+            .list_by_user(redis_conn, &user.id)
+            .await
+            .map_err(|err| {
+                ForumError::Internal("List linked accounts".to_string(), err.to_string())
+            })?;
+        if identity::claimable_by_account(&linked) {
+            return Ok(user);
+        }
     }
+
+    Err(ForumError::conflict(
+        format!("Log in with {}: {}", provider.display_name(), profile.login),
+        "This login belonged to other accounts, which are linked to different identities.",
+    ))
 }
 
-#[derive(Deserialize, Debug)]
-struct GithubCredentials {
-    access_token: String,
+async fn register_oauth_user(
+    app_state: &AppState,
+    provider: &dyn OAuthProvider,
+    profile: &OauthProfile,
+    account: &str,
+) -> Result<GutpUser, ForumError> {
+    let inner_params = NewUser {
+        account: account.to_owned(),
+        oauth_source: provider.name().to_owned(),
+        nickname: profile.name.to_owned(),
+        avatar: profile.avatar_url.to_owned(),
+        pub_settings: "".to_owned(),
+        ext: "".to_owned(),
+    };
+    app_state
+        .gutp
+        .users()
+        .create(&inner_params)
+        .await
+        .map_err(ForumError::gutp(format!("Register user: {}", account)))
 }
 
-async fn get_github_token(
-    code: &str,
-    client_id: &str,
-    client_secret: &str,
-) -> Result<GithubCredentials, reqwest::Error> {
-    let params = [
-        ("client_id", client_id),
-        ("client_secret", client_secret),
-        ("code", code),
-        ("accept", "json"),
-    ];
+#[derive(Deserialize)]
+pub struct PostLinkIdentityParams {
+    provider: String,
+}
 
-    let client = reqwest::Client::new();
-    let res = client
-        .post("https://github.com/login/oauth/access_token")
-        .form(&params)
-        .send()
-        .await?;
+// like oauth_login, but the callback links the identity to the logged in user
+pub async fn link_identity(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    cookie_jar: CookieJar,
+    Form(params): Form<PostLinkIdentityParams>,
+) -> Result<Response, ForumError> {
+    let logged_user = require_login(logged_user)?;
 
-    // println!("in get_github_token, {:?}", res.text().await?);
+    let action = format!("Link {} account", params.provider);
+    let provider = app_state
+        .oauth_providers
+        .get(&params.provider)
+        .ok_or_else(|| ForumError::not_found(&action, "No this login provider."))?;
+
+    let mut redis_conn = session_conn(&app_state).await?;
+    let (state, pending) = app_state
+        .oauth_states
+        .begin(
+            &mut redis_conn,
+            provider.name(),
+            "/user/account",
+            Some(&logged_user.user_id),
+        )
+        .await
+        .map_err(|err| ForumError::Internal(action.to_owned(), err.to_string()))?;
 
-    let res: GithubCredentials = serde_urlencoded::from_str(&res.text().await?).unwrap();
-    println!("in get_github_token, {:?}", res);
+    let redirect_uri = app_state.oauth_providers.redirect_uri(provider);
+    let authorize_uri = provider.authorize_url(&state, &pending.code_challenge(), &redirect_uri);
+    Ok((
+        cookie_jar.add(app_state.oauth_states.cookie(&state)),
+        Redirect::to(&authorize_uri),
+    )
+        .into_response())
+}
 
-    Ok(res)
+#[derive(Deserialize)]
+pub struct PostUnlinkIdentityParams {
+    provider: String,
+    provider_user_id: String,
 }
 
-#[derive(Deserialize, Debug)]
-struct GithubUserInfo {
-    login: String,
-    name: String,
+pub async fn unlink_identity(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    Form(params): Form<PostUnlinkIdentityParams>,
+) -> Result<Redirect, ForumError> {
+    let logged_user = require_login(logged_user)?;
+
+    let action = format!("Unlink {} account", params.provider);
+    let mut redis_conn = session_conn(&app_state).await?;
+    let identities = app_state
+        .identities
+        .list_by_user(&mut redis_conn, &logged_user.user_id)
+        .await
+        .map_err(|err| ForumError::Internal(action.to_owned(), err.to_string()))?;
+    // the user must keep a way to log in
+    if identities.len() <= 1 {
+        return Err(ForumError::conflict(
+            &action,
+            "The last linked account can't be unlinked.",
+        ));
+    }
+
+    let unlinked = app_state
+        .identities
+        .unlink(
+            &mut redis_conn,
+            &logged_user.user_id,
+            &params.provider,
+            &params.provider_user_id,
+        )
+        .await
+        .map_err(|err| ForumError::Internal(action.to_owned(), err.to_string()))?;
+    if !unlinked {
+        return Err(ForumError::not_found(&action, "No this linked account."));
+    }
+    // without its identity the password can't be used any more
+    if params.provider == password::LOCAL_PROVIDER {
+        app_state
+            .passwords
+            .remove(&mut redis_conn, &logged_user.user_id)
+            .await
+            .map_err(|err| ForumError::Internal(action.to_owned(), err.to_string()))?;
+    }
+
+    Ok(Redirect::to("/user/account"))
 }
 
-async fn get_github_user_info(access_token: &str) -> Result<GithubUserInfo, reqwest::Error> {
-    let client = reqwest::Client::new();
-    let res = client
-        .get("https://api.github.com/user")
-        .header("User-Agent", "gutp-discux")
-        .bearer_auth(access_token)
-        .send()
-        .await?;
+pub(crate) async fn session_conn(app_state: &AppState) -> Result<ConnectionManager, ForumError> {
+    app_state.redis.conn().await.map_err(|err| {
+        ForumError::Internal("Connect to session store".to_string(), err.to_string())
+    })
+}
+
+async fn login_user(
+    app_state: &AppState,
+    mut conn: ConnectionManager,
+    cookie_jar: CookieJar,
+    headers: &HeaderMap,
+    user_id: &str,
+    return_to: &str,
+) -> Result<Response, ForumError> {
+    let sessions = &app_state.sessions;
+    // first, set session key in server cache, in place of the one from before login
+    let old_session_id = cookie_jar
+        .get(&sessions.cookie_name())
+        .map(|cookie| cookie.value().to_string());
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let session = sessions
+        .rotate(&mut conn, old_session_id.as_deref(), user_id, user_agent)
+        .await
+        .map_err(|err| ForumError::Internal("Create session".to_string(), err.to_string()))?;
+    let mut cookie_jar = cookie_jar.add(sessions.cookie(&session.id));
+
+    // the language and the timezone the user picked follow them to this browser,
+    // the user isn't the care factor here
+    if let Some(user) = app_state.gutp.users().by_id(user_id).await.ok().flatten() {
+        let settings = PubSettings::of(&user);
+        cookie_jar = preference_cookies(&app_state.config.appid, &settings, cookie_jar);
+    }
+
+    Ok((cookie_jar, Redirect::to(return_to)).into_response())
+}
 
-    let user_info: GithubUserInfo = res.json().await?;
-    println!("in get_github_user_info, {:?}", user_info);
+#[derive(Template)]
+#[template(path = "signout.html")]
+struct SignoutTemplate {
+    csrf_token: String,
+}
 
-    Ok(user_info)
+// the old links to `/user/signout` get a confirmation, the form posts it
+pub async fn view_signout(
+    logged_user: Option<Extension<LoggedUser>>,
+) -> Result<impl IntoResponse, ForumError> {
+    let logged_user = require_login(logged_user)?;
+    Ok(HtmlTemplate(SignoutTemplate {
+        csrf_token: logged_user.csrf_token,
+    }))
 }
 
-async fn login_user(conn: redis::aio::Connection, user_id: &str) -> impl IntoResponse {
-    // first, set session key in server cache
-    let cookiestr = set_session(conn, user_id).await;
+pub async fn post_signout(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    cookie_jar: CookieJar,
+) -> Result<Response, ForumError> {
+    let logged_user = require_login(logged_user)?;
 
-    let cookie_key = format!("{}_sid", &crate::APPID);
-    let cookie = Cookie::build(&cookie_key, &cookiestr)
-        // .domain("/")
-        .path("/")
-        //.secure(true)
-        .max_age(cookie::time::Duration::seconds(TTL as i64))
-        .http_only(true)
-        .finish();
+    let mut redis_conn = session_conn(&app_state).await?;
+    println!("in signout: user: {}", logged_user.user_id);
+    app_state
+        .sessions
+        .destroy(&mut redis_conn, &logged_user.session_id)
+        .await
+        .map_err(|err| ForumError::Internal("Sign out".to_string(), err.to_string()))?;
 
-    (
-        [(header::SET_COOKIE, cookie.to_string())],
+    Ok((
+        cookie_jar.remove(app_state.sessions.removal_cookie()),
         Redirect::to("/"),
     )
+        .into_response())
 }
 
-pub async fn set_session(mut conn: redis::aio::Connection, user_id: &str) -> String {
-    let x = rand::random::<[u8; 32]>();
-    let cookie = sha256::digest(&x).to_lowercase();
-    let cookie_key = format!("{}_sid:{}", &crate::APPID, cookie);
-    let _: Result<(), redis::RedisError> = conn.set(&cookie_key, user_id).await;
-    let _: Result<(), redis::RedisError> = conn.expire(&cookie, TTL).await;
+pub async fn signout_all(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    cookie_jar: CookieJar,
+) -> Result<Response, ForumError> {
+    let logged_user = require_login(logged_user)?;
+
+    let mut redis_conn = session_conn(&app_state).await?;
+    app_state
+        .sessions
+        .destroy_all(&mut redis_conn, &logged_user.user_id)
+        .await
+        .map_err(|err| ForumError::Internal("Sign out everywhere".to_string(), err.to_string()))?;
 
-    cookie
+    Ok((
+        cookie_jar.remove(app_state.sessions.removal_cookie()),
+        Redirect::to("/"),
+    )
+        .into_response())
 }
 
-pub async fn clear_session(mut conn: redis::aio::Connection, session_id: &str) {
-    let session_key = format!("{}_sid:{}", &crate::APPID, session_id);
-    println!("in clear_session: session_key: {session_key}");
-    let _: Result<(), redis::RedisError> = conn.del(&session_key).await;
+#[derive(Deserialize)]
+pub struct PostRevokeSessionParams {
+    handle: String,
 }
 
-pub async fn signout(
+pub async fn revoke_session(
     State(app_state): State<AppState>,
     logged_user: Option<Extension<LoggedUser>>,
+    Form(params): Form<PostRevokeSessionParams>,
+) -> Result<Redirect, ForumError> {
+    let logged_user = require_login(logged_user)?;
+
+    let action = format!("Revoke session: {}", params.handle);
+    let mut redis_conn = session_conn(&app_state).await?;
+    let revoked = app_state
+        .sessions
+        .revoke(&mut redis_conn, &logged_user.user_id, &params.handle)
+        .await
+        .map_err(|err| ForumError::Internal(action.to_owned(), err.to_string()))?;
+    if !revoked {
+        return Err(ForumError::not_found(&action, "No this session."));
+    }
+
+    Ok(Redirect::to("/user/account"))
+}
+
+// the tokens of the api, managed on the account page
+
+const MAX_API_TOKEN_NAME_LEN: usize = 64;
+
+#[derive(Template)]
+#[template(path = "api_token_created.html")]
+struct ApiTokenCreatedTemplate {
+    name: String,
+    token: String,
+    openapi_url: String,
+}
+
+#[derive(Deserialize)]
+pub struct PostCreateApiTokenParams {
+    name: String,
+}
+
+pub async fn create_api_token(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    Form(params): Form<PostCreateApiTokenParams>,
+) -> Result<impl IntoResponse, ForumError> {
+    let logged_user = require_login(logged_user)?;
+
+    let action = "Create api token";
+    let name = params.name.trim().to_string();
+    if name.is_empty() || name.chars().count() > MAX_API_TOKEN_NAME_LEN {
+        return Err(ForumError::BadRequest(
+            action.to_string(),
+            format!(
+                "The name must be 1 to {} characters.",
+                MAX_API_TOKEN_NAME_LEN
+            ),
+        ));
+    }
+
+    let mut redis_conn = session_conn(&app_state).await?;
+    let token = app_state
+        .api_tokens
+        .create(&mut redis_conn, &logged_user.user_id, &name)
+        .await
+        .map_err(redis_error(action))?;
+
+    // the token isn't kept, it's only shown this once
+    Ok(HtmlTemplate(ApiTokenCreatedTemplate {
+        name,
+        token,
+        openapi_url: app_state
+            .config
+            .absolute_url(&format!("{}/openapi.json", api::API_PREFIX)),
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct PostRevokeApiTokenParams {
+    token_id: String,
+}
+
+pub async fn revoke_api_token(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    Form(params): Form<PostRevokeApiTokenParams>,
+) -> Result<Redirect, ForumError> {
+    let logged_user = require_login(logged_user)?;
+
+    let action = format!("Revoke api token: {}", params.token_id);
+    let mut redis_conn = session_conn(&app_state).await?;
+    let revoked = app_state
+        .api_tokens
+        .revoke(&mut redis_conn, &logged_user.user_id, &params.token_id)
+        .await
+        .map_err(redis_error(&action))?;
+    if !revoked {
+        return Err(ForumError::not_found(&action, "No this api token."));
+    }
+
+    Ok(Redirect::to("/user/account"))
+}
+
+// the username/password login, enabled by `local_auth` in config
+
+fn local_auth_enabled(app_state: &AppState, action: &str) -> Result<(), ForumError> {
+    if app_state.config.local_auth.enabled {
+        Ok(())
+    } else {
+        Err(ForumError::not_found(
+            action,
+            "The password login is disabled.",
+        ))
+    }
+}
+
+fn redis_error(action: &str) -> impl FnOnce(redis::RedisError) -> ForumError {
+    let action = action.to_string();
+    move |err| ForumError::Internal(action, err.to_string())
+}
+
+// the peer is the reverse proxy when it's on the same host
+fn client_ip(peer: &SocketAddr, headers: &HeaderMap) -> String {
+    let forwarded = headers
+        .get("x-forwarded-for")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(',').next())
+        .map(|v| v.trim().to_string());
+    match forwarded {
+        Some(ip) if peer.ip().is_loopback() && !ip.is_empty() => ip,
+        _ => peer.ip().to_string(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PostLoginParams {
+    username: String,
+    password: String,
+    return_to: Option<String>,
+}
+
+pub async fn post_login(
+    State(app_state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
     cookie_jar: CookieJar,
-) -> impl IntoResponse {
-    if logged_user.is_none() {
-        let action = format!("Not logged in");
-        let err_info = "Need login firstly to get proper permission.";
-        return redirect_to_error_page(&action, err_info).into_response();
+    headers: HeaderMap,
+    Form(params): Form<PostLoginParams>,
+) -> Result<Response, ForumError> {
+    let action = "Login with password";
+    local_auth_enabled(&app_state, action)?;
+
+    let return_to = oauth::safe_return_to(params.return_to.as_deref());
+    let failed = |app_state: &AppState, return_to: String| {
+        let notice = "Wrong username or password.".to_string();
+        let template = LoginTemplate::new(app_state, return_to, Some(notice));
+        Ok((StatusCode::UNAUTHORIZED, HtmlTemplate(template)).into_response())
+    };
+    let Ok(username) = password::normalize_username(&params.username) else {
+        return failed(&app_state, return_to);
+    };
+
+    let ip = client_ip(&peer, &headers);
+    let mut redis_conn = session_conn(&app_state).await?;
+    let passwords = &app_state.passwords;
+    if passwords
+        .count_attempt(&mut redis_conn, &username, &ip)
+        .await
+        .map_err(redis_error(action))?
+    {
+        return Err(ForumError::TooManyRequests(
+            action.to_string(),
+            "Too many failed logins, try again in 15 minutes.".to_string(),
+        ));
     }
 
-    let mut redis_conn = app_state.rclient.get_async_connection().await.unwrap();
-    let cookie_key = format!("{}_sid", &crate::APPID);
-    if let Some(cookie) = cookie_jar.get(&cookie_key) {
-        println!("in signout: cookie: {cookie}");
-        clear_session(redis_conn, &cookie.value()).await;
-        (
-            // TODO: seems this action of removing can't work
-            cookie_jar.remove(Cookie::named(cookie_key)),
-            Redirect::to("/"),
-        )
-            .into_response()
+    let user_id = app_state
+        .identities
+        .find(&mut redis_conn, password::LOCAL_PROVIDER, &username)
+        .await
+        .map_err(redis_error(action))?;
+    let hash = match &user_id {
+        Some(user_id) => passwords
+            .get_hash(&mut redis_conn, user_id)
+            .await
+            .map_err(redis_error(action))?,
+        None => None,
+    };
+    let verified = match hash {
+        Some(hash) => password::verify_password(params.password, hash).await,
+        // as slow as a wrong password, not to tell the unknown usernames
+        None => {
+            password::verify_unknown(params.password).await;
+            false
+        }
+    };
+
+    match user_id {
+        Some(user_id) if verified => {
+            passwords
+                .forgive_attempt(&mut redis_conn, &username, &ip)
+                .await
+                .map_err(redis_error(action))?;
+            login_user(
+                &app_state, redis_conn, cookie_jar, &headers, &user_id, &return_to,
+            )
+            .await
+        }
+        _ => {
+            println!("in post_login: failed login of {} from {}", username, ip);
+            failed(&app_state, return_to)
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "register.html")]
+struct RegisterTemplate {
+    username: String,
+    notice: Option<String>,
+}
+
+fn signup_allowed(app_state: &AppState, action: &str) -> Result<(), ForumError> {
+    let local_auth = &app_state.config.local_auth;
+    if local_auth.enabled && local_auth.allow_signup {
+        Ok(())
     } else {
-        let redirect_uri = format!("/login");
-        Redirect::to(&redirect_uri).into_response()
+        Err(ForumError::not_found(action, "The registration is closed."))
+    }
+}
+
+pub async fn view_register(
+    State(app_state): State<AppState>,
+) -> Result<impl IntoResponse, ForumError> {
+    signup_allowed(&app_state, "Register")?;
+    Ok(HtmlTemplate(RegisterTemplate {
+        username: "".to_string(),
+        notice: None,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct PostRegisterParams {
+    username: String,
+    password: String,
+    password_confirm: String,
+}
+
+pub async fn post_register(
+    State(app_state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    cookie_jar: CookieJar,
+    headers: HeaderMap,
+    Form(params): Form<PostRegisterParams>,
+) -> Result<Response, ForumError> {
+    let action = "Register";
+    signup_allowed(&app_state, action)?;
+
+    // the form is shown again with what went wrong
+    let rejected = |notice: &str| {
+        let template = RegisterTemplate {
+            username: params.username.to_owned(),
+            notice: Some(notice.to_string()),
+        };
+        Ok((StatusCode::BAD_REQUEST, HtmlTemplate(template)).into_response())
+    };
+    let username = match password::normalize_username(&params.username) {
+        Ok(username) => username,
+        Err(reason) => return rejected(reason),
+    };
+    if let Err(reason) = password::check_password_policy(&params.password) {
+        return rejected(reason);
+    }
+    if params.password != params.password_confirm {
+        return rejected("The two passwords are not the same.");
+    }
+
+    // each signup hashes a password, so it's limited as the logins are,
+    // and a successful one is still counted
+    let ip = client_ip(&peer, &headers);
+    let mut redis_conn = session_conn(&app_state).await?;
+    if app_state
+        .passwords
+        .count_attempt(&mut redis_conn, &username, &ip)
+        .await
+        .map_err(redis_error(action))?
+    {
+        return Err(ForumError::TooManyRequests(
+            action.to_string(),
+            "Too many signups, try again in 15 minutes.".to_string(),
+        ));
+    }
+
+    let taken = app_state
+        .identities
+        .find(&mut redis_conn, password::LOCAL_PROVIDER, &username)
+        .await
+        .map_err(redis_error(action))?;
+    if taken.is_some() {
+        return rejected("This username is taken.");
+    }
+
+    // hashed before the user is created, so a failure leaves nothing behind
+    let hash = password::hash_password(params.password)
+        .await
+        .map_err(|err| ForumError::Internal(action.to_string(), err))?;
+    let user = create_local_user(&app_state, &mut redis_conn, &username).await?;
+    if let Err(err) = app_state
+        .passwords
+        .set_hash(&mut redis_conn, &user.id, &hash)
+        .await
+    {
+        // gutp can't delete a user, but without its username nobody can log
+        // in to it, and the username can be registered again
+        let _ = app_state
+            .identities
+            .unlink(
+                &mut redis_conn,
+                &user.id,
+                password::LOCAL_PROVIDER,
+                &username,
+            )
+            .await;
+        return Err(redis_error(action)(err));
+    }
+
+    login_user(&app_state, redis_conn, cookie_jar, &headers, &user.id, "/").await
+}
+
+// claim the username of a local account, and register its gutp user. The
+// username is held first, so losing the race leaves no user behind
+async fn create_local_user(
+    app_state: &AppState,
+    redis_conn: &mut ConnectionManager,
+    username: &str,
+) -> Result<GutpUser, ForumError> {
+    let action = format!("Register user: {}", username);
+    let identities = &app_state.identities;
+    let identity = Identity::local(username);
+    let Some(reservation) = identities
+        .reserve(redis_conn, &identity)
+        .await
+        .map_err(redis_error(&action))?
+    else {
+        return Err(ForumError::conflict(&action, "This username is taken."));
+    };
+
+    let inner_params = NewUser {
+        account: format!("{}:{}", password::LOCAL_PROVIDER, username),
+        oauth_source: password::LOCAL_PROVIDER.to_owned(),
+        nickname: username.to_owned(),
+        avatar: "".to_owned(),
+        pub_settings: "".to_owned(),
+        ext: "".to_owned(),
+    };
+    let user = match app_state.gutp.users().create(&inner_params).await {
+        Ok(user) => user,
+        Err(err) => {
+            let _ = identities
+                .release(redis_conn, &identity, &reservation)
+                .await;
+            return Err(ForumError::gutp(&action)(err));
+        }
+    };
+
+    let linked = identities
+        .link_reserved(redis_conn, &user.id, &identity, &reservation)
+        .await
+        .map_err(redis_error(&action))?;
+    if linked {
+        Ok(user)
+    } else {
+        // held past RESERVE_TTL, gutp must have been very slow
+        Err(ForumError::conflict(&action, "This username is taken."))
+    }
+}
+
+#[derive(Template)]
+#[template(path = "user_change_pwd_page.html")]
+struct ChangePasswordTemplate {
+    // None when the user only has oauth logins yet, then a username is chosen
+    username: Option<String>,
+    notice: Option<String>,
+    csrf_token: String,
+}
+
+// the username of the local identity of a user, if any
+async fn local_username(
+    app_state: &AppState,
+    redis_conn: &mut ConnectionManager,
+    user_id: &str,
+) -> Result<Option<String>, ForumError> {
+    let identities = app_state
+        .identities
+        .list_by_user(redis_conn, user_id)
+        .await
+        .map_err(redis_error("List linked accounts"))?;
+    Ok(identities
+        .into_iter()
+        .find(|identity| identity.provider == password::LOCAL_PROVIDER)
+        .map(|identity| identity.provider_user_id))
+}
+
+pub async fn view_change_password(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+) -> Result<impl IntoResponse, ForumError> {
+    let logged_user = require_login(logged_user)?;
+    local_auth_enabled(&app_state, "Change password")?;
+
+    let mut redis_conn = session_conn(&app_state).await?;
+    let username = local_username(&app_state, &mut redis_conn, &logged_user.user_id).await?;
+
+    Ok(HtmlTemplate(ChangePasswordTemplate {
+        username,
+        notice: None,
+        csrf_token: logged_user.csrf_token,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct PostChangePasswordParams {
+    // for the first password
+    username: Option<String>,
+    old_password: Option<String>,
+    new_password: String,
+    new_password_confirm: String,
+}
+
+pub async fn post_change_password(
+    State(app_state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    logged_user: Option<Extension<LoggedUser>>,
+    Form(params): Form<PostChangePasswordParams>,
+) -> Result<Response, ForumError> {
+    let logged_user = require_login(logged_user)?;
+    let action = "Change password";
+    local_auth_enabled(&app_state, action)?;
+
+    let mut redis_conn = session_conn(&app_state).await?;
+    let passwords = &app_state.passwords;
+    let username = local_username(&app_state, &mut redis_conn, &logged_user.user_id).await?;
+
+    let rejected = |notice: &str| {
+        let template = ChangePasswordTemplate {
+            username: username.clone(),
+            notice: Some(notice.to_string()),
+            csrf_token: logged_user.csrf_token.to_owned(),
+        };
+        Ok((StatusCode::BAD_REQUEST, HtmlTemplate(template)).into_response())
+    };
+    if let Err(reason) = password::check_password_policy(&params.new_password) {
+        return rejected(reason);
+    }
+    if params.new_password != params.new_password_confirm {
+        return rejected("The two passwords are not the same.");
+    }
+
+    match &username {
+        // changing, the old password is required. It's guessed like at the
+        // login, so it's limited the same way
+        Some(username) => {
+            let ip = client_ip(&peer, &headers);
+            if passwords
+                .count_attempt(&mut redis_conn, username, &ip)
+                .await
+                .map_err(redis_error(action))?
+            {
+                return Err(ForumError::TooManyRequests(
+                    action.to_string(),
+                    "Too many wrong passwords, try again in 15 minutes.".to_string(),
+                ));
+            }
+
+            let hash = passwords
+                .get_hash(&mut redis_conn, &logged_user.user_id)
+                .await
+                .map_err(redis_error(action))?;
+            let old_password = params.old_password.clone().unwrap_or_default();
+            let verified = match hash {
+                Some(hash) => password::verify_password(old_password, hash).await,
+                None => {
+                    password::verify_unknown(old_password).await;
+                    false
+                }
+            };
+            if !verified {
+                println!(
+                    "in post_change_password: wrong old password of {} from {}",
+                    username, ip
+                );
+                return rejected("The old password is wrong.");
+            }
+            passwords
+                .forgive_attempt(&mut redis_conn, username, &ip)
+                .await
+                .map_err(redis_error(action))?;
+        }
+        // the first password of an oauth user, along with a username
+        None => {
+            let new_username = match password::normalize_username(
+                params.username.as_deref().unwrap_or_default(),
+            ) {
+                Ok(username) => username,
+                Err(reason) => return rejected(reason),
+            };
+            let linked = app_state
+                .identities
+                .link(
+                    &mut redis_conn,
+                    &logged_user.user_id,
+                    &Identity::local(&new_username),
+                )
+                .await
+                .map_err(redis_error(action))?;
+            if !linked {
+                return rejected("This username is taken.");
+            }
+        }
     }
+
+    let hash = password::hash_password(params.new_password)
+        .await
+        .map_err(|err| ForumError::Internal(action.to_string(), err))?;
+    passwords
+        .set_hash(&mut redis_conn, &logged_user.user_id, &hash)
+        .await
+        .map_err(redis_error(action))?;
+    // whoever knew the old password is signed out
+    app_state
+        .sessions
+        .destroy_others(
+            &mut redis_conn,
+            &logged_user.user_id,
+            &logged_user.session_id,
+        )
+        .await
+        .map_err(redis_error(action))?;
+
+    Ok(Redirect::to("/user/account").into_response())
+}
+
+#[derive(Template)]
+#[template(path = "password_reset.html")]
+struct PasswordResetTemplate {
+    token: String,
+    notice: Option<String>,
+    // empty for the anonymous visitors, who are the usual case
+    csrf_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct ViewPasswordResetParams {
+    token: String,
+}
+
+pub async fn view_password_reset(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    Query(params): Query<ViewPasswordResetParams>,
+) -> Result<impl IntoResponse, ForumError> {
+    let action = "Reset password";
+    local_auth_enabled(&app_state, action)?;
+
+    let mut redis_conn = session_conn(&app_state).await?;
+    app_state
+        .passwords
+        .peek_reset_token(&mut redis_conn, &params.token)
+        .await
+        .map_err(redis_error(action))?
+        .ok_or_else(|| ForumError::not_found(action, "The reset link is expired or used."))?;
+
+    Ok(HtmlTemplate(PasswordResetTemplate {
+        token: params.token,
+        notice: None,
+        csrf_token: logged_user
+            .map(|Extension(u)| u.csrf_token)
+            .unwrap_or_default(),
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct PostPasswordResetParams {
+    token: String,
+    new_password: String,
+    new_password_confirm: String,
+}
+
+pub async fn post_password_reset(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    Form(params): Form<PostPasswordResetParams>,
+) -> Result<Response, ForumError> {
+    let action = "Reset password";
+    local_auth_enabled(&app_state, action)?;
+
+    let rejected = |notice: &str| {
+        let template = PasswordResetTemplate {
+            token: params.token.to_owned(),
+            notice: Some(notice.to_string()),
+            csrf_token: logged_user
+                .as_ref()
+                .map(|u| u.csrf_token.to_owned())
+                .unwrap_or_default(),
+        };
+        Ok((StatusCode::BAD_REQUEST, HtmlTemplate(template)).into_response())
+    };
+    if let Err(reason) = password::check_password_policy(&params.new_password) {
+        return rejected(reason);
+    }
+    if params.new_password != params.new_password_confirm {
+        return rejected("The two passwords are not the same.");
+    }
+
+    // the token is only consumed by a valid new password
+    let mut redis_conn = session_conn(&app_state).await?;
+    let passwords = &app_state.passwords;
+    let user_id = passwords
+        .take_reset_token(&mut redis_conn, &params.token)
+        .await
+        .map_err(redis_error(action))?
+        .ok_or_else(|| ForumError::not_found(action, "The reset link is expired or used."))?;
+
+    let hash = password::hash_password(params.new_password)
+        .await
+        .map_err(|err| ForumError::Internal(action.to_string(), err))?;
+    passwords
+        .set_hash(&mut redis_conn, &user_id, &hash)
+        .await
+        .map_err(redis_error(action))?;
+    app_state
+        .sessions
+        .destroy_all(&mut redis_conn, &user_id)
+        .await
+        .map_err(redis_error(action))?;
+
+    Ok(Redirect::to("/user/login").into_response())
+}
+
+#[derive(Template)]
+#[template(path = "password_reset_link.html")]
+struct PasswordResetLinkTemplate {
+    username: String,
+    link: String,
+}
+
+#[derive(Deserialize)]
+pub struct PostPasswordResetLinkParams {
+    username: String,
+}
+
+// there is no mail, the admin hands the link over
+pub async fn post_password_reset_link(
+    State(app_state): State<AppState>,
+    logged_user: Option<Extension<LoggedUser>>,
+    Form(params): Form<PostPasswordResetLinkParams>,
+) -> Result<impl IntoResponse, ForumError> {
+    let logged_user = require_login(logged_user)?;
+    let action = format!("Issue password reset link: {}", params.username);
+    if !app_state.config.is_admin(&logged_user.user_id) {
+        return Err(ForumError::Forbidden(action));
+    }
+    local_auth_enabled(&app_state, &action)?;
+
+    let username = password::normalize_username(&params.username)
+        .map_err(|reason| ForumError::BadRequest(action.to_owned(), reason.to_string()))?;
+    let mut redis_conn = session_conn(&app_state).await?;
+    let user_id = app_state
+        .identities
+        .find(&mut redis_conn, password::LOCAL_PROVIDER, &username)
+        .await
+        .map_err(redis_error(&action))?
+        .ok_or_else(|| ForumError::not_found(&action, "No this username."))?;
+
+    let link = password_reset_link(&app_state, &mut redis_conn, &user_id).await?;
+    Ok(HtmlTemplate(PasswordResetLinkTemplate { username, link }))
+}
+
+async fn password_reset_link(
+    app_state: &AppState,
+    redis_conn: &mut ConnectionManager,
+    user_id: &str,
+) -> Result<String, ForumError> {
+    let token = app_state
+        .passwords
+        .issue_reset_token(redis_conn, user_id)
+        .await
+        .map_err(redis_error("Issue password reset link"))?;
+    Ok(format!(
+        "{}/user/password/reset?token={}",
+        app_state.config.site_url.trim_end_matches('/'),
+        token
+    ))
+}
+
+/// The `password-link <username>` command, to bootstrap an admin account
+/// or to recover one. The local account is created if it doesn't exist.
+pub async fn password_link_command(
+    app_state: &AppState,
+    username: &str,
+) -> Result<String, ForumError> {
+    let username = password::normalize_username(username).map_err(|reason| {
+        ForumError::BadRequest("Password link".to_string(), reason.to_string())
+    })?;
+    let mut redis_conn = session_conn(app_state).await?;
+    let user_id = app_state
+        .identities
+        .find(&mut redis_conn, password::LOCAL_PROVIDER, &username)
+        .await
+        .map_err(redis_error("Password link"))?;
+    let user_id = match user_id {
+        Some(user_id) => user_id,
+        None => {
+            let user = create_local_user(app_state, &mut redis_conn, &username).await?;
+            println!("created user {} with id {}", username, user.id);
+            user.id
+        }
+    };
+
+    password_reset_link(app_state, &mut redis_conn, &user_id).await
 }